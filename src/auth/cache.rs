@@ -1,10 +1,12 @@
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use tokio::sync::RwLock;
 use tracing::{debug, info};
 
 use crate::copilot::client::fetch_copilot_token;
+use crate::copilot::types::CopilotTokenResponse;
 
 /// Buffer in seconds — refresh a token if it expires within this window.
 const EXPIRY_BUFFER_SECS: u64 = 120;
@@ -32,12 +34,15 @@ impl CachedToken {
 /// since the exchange is idempotent.
 pub struct TokenCache {
 	entries: RwLock<HashMap<String, CachedToken>>,
+	/// How long a token exchange may take.
+	exchange_timeout: Duration,
 }
 
 impl TokenCache {
-	pub fn new() -> Self {
+	pub fn new(exchange_timeout: Duration) -> Self {
 		Self {
 			entries: RwLock::new(HashMap::new()),
+			exchange_timeout,
 		}
 	}
 
@@ -48,6 +53,21 @@ impl TokenCache {
 		client: &reqwest::Client,
 		vscode_version: &str,
 	) -> Result<String, reqwest::Error> {
+		self.get_copilot_token_with(gh_token, || {
+			fetch_copilot_token(client, gh_token, vscode_version, self.exchange_timeout)
+		})
+		.await
+	}
+
+	pub async fn get_copilot_token_with<F, Fut>(
+		&self,
+		gh_token: &str,
+		exchange: F,
+	) -> Result<String, reqwest::Error>
+	where
+		F: FnOnce() -> Fut,
+		Fut: Future<Output = Result<CopilotTokenResponse, reqwest::Error>>,
+	{
 		// Fast path: read lock, check cache
 		{
 			let cache = self.entries.read().await;
@@ -60,7 +80,7 @@ impl TokenCache {
 		}
 
 		// Slow path: exchange and cache
-		let resp = fetch_copilot_token(client, gh_token, vscode_version).await?;
+		let resp = exchange().await?;
 		info!(
 			expires_at = resp.expires_at,
 			refresh_in = resp.refresh_in,
@@ -89,7 +109,8 @@ impl TokenCache {
 		client: &reqwest::Client,
 		vscode_version: &str,
 	) -> Result<u64, reqwest::Error> {
-		let resp = fetch_copilot_token(client, gh_token, vscode_version).await?;
+		let resp =
+			fetch_copilot_token(client, gh_token, vscode_version, self.exchange_timeout).await?;
 		let refresh_in = resp.refresh_in;
 
 		let mut cache = self.entries.write().await;
@@ -104,6 +125,20 @@ impl TokenCache {
 		Ok(refresh_in)
 	}
 
+	/// Drop the cached token for `gh_token`, or every cached token, so the
+	/// next use exchanges afresh. Returns how many were dropped.
+	pub async fn evict(&self, gh_token: Option<&str>) -> usize {
+		let mut cache = self.entries.write().await;
+		match gh_token {
+			Some(gh_token) => usize::from(cache.remove(gh_token).is_some()),
+			None => {
+				let evicted = cache.len();
+				cache.clear();
+				evicted
+			}
+		}
+	}
+
 	/// Remove expired entries. Call periodically to prevent unbounded growth
 	/// if many distinct GH tokens are used.
 	pub async fn evict_expired(&self) {
@@ -121,3 +156,45 @@ impl TokenCache {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use super::*;
+
+	async fn get(cache: &TokenCache, gh_token: &str, exchanges: &AtomicU32) -> String {
+		cache
+			.get_copilot_token_with(gh_token, || async {
+				let n = exchanges.fetch_add(1, Ordering::SeqCst) + 1;
+				Ok(CopilotTokenResponse {
+					token: format!("copilot-{n}"),
+					refresh_in: 1500,
+					expires_at: SystemTime::now()
+						.duration_since(UNIX_EPOCH)
+						.unwrap()
+						.as_secs() + 1800,
+				})
+			})
+			.await
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn evicted_tokens_exchanged_afresh() {
+		let cache = TokenCache::new(Duration::from_secs(10));
+		let exchanges = AtomicU32::new(0);
+
+		assert_eq!(get(&cache, "gh-a", &exchanges).await, "copilot-1");
+		assert_eq!(get(&cache, "gh-b", &exchanges).await, "copilot-2");
+		assert_eq!(get(&cache, "gh-a", &exchanges).await, "copilot-1");
+
+		assert_eq!(cache.evict(Some("gh-a")).await, 1);
+		assert_eq!(cache.evict(Some("gh-a")).await, 0);
+		assert_eq!(get(&cache, "gh-a", &exchanges).await, "copilot-3");
+		assert_eq!(get(&cache, "gh-b", &exchanges).await, "copilot-2");
+
+		assert_eq!(cache.evict(None).await, 2);
+		assert_eq!(get(&cache, "gh-b", &exchanges).await, "copilot-4");
+	}
+}
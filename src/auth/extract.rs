@@ -1,4 +1,8 @@
+use std::borrow::Cow;
+
 use axum::http::HeaderMap;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
 
 const GH_TOKEN_PREFIXES: &[&str] = &["ghp_", "gho_", "ghu_", "github_pat_"];
 
@@ -6,6 +10,17 @@ fn looks_like_gh_token(s: &str) -> bool {
 	GH_TOKEN_PREFIXES.iter().any(|p| s.starts_with(p))
 }
 
+/// Decode a GitHub token some clients send base64-encoded, in any of the
+/// common alphabets and with or without padding.
+fn decode_base64_gh_token(s: &str) -> Option<String> {
+	[STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD]
+		.iter()
+		.find_map(|engine| engine.decode(s).ok())
+		.and_then(|bytes| String::from_utf8(bytes).ok())
+		.map(|token| token.trim().to_string())
+		.filter(|token| looks_like_gh_token(token))
+}
+
 /// Try to extract a GitHub token from request headers.
 ///
 /// Checks (in order):
@@ -13,13 +28,32 @@ fn looks_like_gh_token(s: &str) -> bool {
 /// 2. `authorization: Bearer <token>` (OpenAI / general)
 /// 3. `api-key` (Azure convention)
 ///
-/// Only returns the value if it looks like a GitHub token (known prefix).
-pub fn extract_gh_token(headers: &HeaderMap) -> Option<&str> {
+/// Only returns the value if it looks like a GitHub token (known prefix), or
+/// with `accept_base64` (`ACCEPT_BASE64_KEYS`), if it decodes to one.
+pub fn extract_gh_token(headers: &HeaderMap, accept_base64: bool) -> Option<Cow<'_, str>> {
+	find_token(headers, |t| {
+		if looks_like_gh_token(t) {
+			Some(Cow::Borrowed(t))
+		} else if accept_base64 {
+			decode_base64_gh_token(t).map(Cow::Owned)
+		} else {
+			None
+		}
+	})
+}
+
+/// Try to extract a raw Copilot API token (which skips the GitHub exchange)
+/// from the same headers as [`extract_gh_token`], recognised by `prefix`.
+pub fn extract_copilot_token<'a>(headers: &'a HeaderMap, prefix: &str) -> Option<&'a str> {
+	find_token(headers, |t| t.starts_with(prefix).then_some(t))
+}
+
+fn find_token<'a, T>(headers: &'a HeaderMap, accept: impl Fn(&'a str) -> Option<T>) -> Option<T> {
 	// x-api-key (Anthropic)
 	if let Some(val) = header_str(headers, "x-api-key")
-		&& looks_like_gh_token(val)
+		&& let Some(token) = accept(val)
 	{
-		return Some(val);
+		return Some(token);
 	}
 
 	// Authorization: Bearer ... (OpenAI / generic)
@@ -27,18 +61,16 @@ pub fn extract_gh_token(headers: &HeaderMap) -> Option<&str> {
 		let token = val
 			.strip_prefix("Bearer ")
 			.or_else(|| val.strip_prefix("bearer "));
-		if let Some(token) = token
-			&& looks_like_gh_token(token)
-		{
+		if let Some(token) = token.and_then(&accept) {
 			return Some(token);
 		}
 	}
 
 	// api-key (Azure)
 	if let Some(val) = header_str(headers, "api-key")
-		&& looks_like_gh_token(val)
+		&& let Some(token) = accept(val)
 	{
-		return Some(val);
+		return Some(token);
 	}
 
 	None
@@ -57,7 +89,7 @@ mod tests {
 	fn anthropic_x_api_key() {
 		let mut h = HeaderMap::new();
 		h.insert("x-api-key", HeaderValue::from_static("ghp_abc123"));
-		assert_eq!(extract_gh_token(&h), Some("ghp_abc123"));
+		assert_eq!(extract_gh_token(&h, false).as_deref(), Some("ghp_abc123"));
 	}
 
 	#[test]
@@ -67,21 +99,24 @@ mod tests {
 			"authorization",
 			HeaderValue::from_static("Bearer gho_token123"),
 		);
-		assert_eq!(extract_gh_token(&h), Some("gho_token123"));
+		assert_eq!(extract_gh_token(&h, false).as_deref(), Some("gho_token123"));
 	}
 
 	#[test]
 	fn bearer_lowercase() {
 		let mut h = HeaderMap::new();
 		h.insert("authorization", HeaderValue::from_static("bearer ghp_low"));
-		assert_eq!(extract_gh_token(&h), Some("ghp_low"));
+		assert_eq!(extract_gh_token(&h, false).as_deref(), Some("ghp_low"));
 	}
 
 	#[test]
 	fn azure_api_key() {
 		let mut h = HeaderMap::new();
 		h.insert("api-key", HeaderValue::from_static("github_pat_foobar"));
-		assert_eq!(extract_gh_token(&h), Some("github_pat_foobar"));
+		assert_eq!(
+			extract_gh_token(&h, false).as_deref(),
+			Some("github_pat_foobar")
+		);
 	}
 
 	#[test]
@@ -95,13 +130,13 @@ mod tests {
 			"authorization",
 			HeaderValue::from_static("Bearer sk-proj-something"),
 		);
-		assert_eq!(extract_gh_token(&h), None);
+		assert_eq!(extract_gh_token(&h, false).as_deref(), None);
 	}
 
 	#[test]
 	fn no_headers() {
 		let h = HeaderMap::new();
-		assert_eq!(extract_gh_token(&h), None);
+		assert_eq!(extract_gh_token(&h, false).as_deref(), None);
 	}
 
 	#[test]
@@ -112,13 +147,67 @@ mod tests {
 			"authorization",
 			HeaderValue::from_static("Bearer gho_second"),
 		);
-		assert_eq!(extract_gh_token(&h), Some("ghp_first"));
+		assert_eq!(extract_gh_token(&h, false).as_deref(), Some("ghp_first"));
 	}
 
 	#[test]
 	fn ghu_prefix_accepted() {
 		let mut h = HeaderMap::new();
 		h.insert("x-api-key", HeaderValue::from_static("ghu_usertoken"));
-		assert_eq!(extract_gh_token(&h), Some("ghu_usertoken"));
+		assert_eq!(
+			extract_gh_token(&h, false).as_deref(),
+			Some("ghu_usertoken")
+		);
+	}
+
+	#[test]
+	fn base64_gh_token_accepted_when_enabled() {
+		// "ghp_abc123" and "gho_token123\n"
+		let mut h = HeaderMap::new();
+		h.insert("x-api-key", HeaderValue::from_static("Z2hwX2FiYzEyMw=="));
+		assert_eq!(extract_gh_token(&h, false), None);
+		assert_eq!(extract_gh_token(&h, true).as_deref(), Some("ghp_abc123"));
+
+		let mut h = HeaderMap::new();
+		h.insert(
+			"authorization",
+			HeaderValue::from_static("Bearer Z2hvX3Rva2VuMTIzCg"),
+		);
+		assert_eq!(extract_gh_token(&h, true).as_deref(), Some("gho_token123"));
+
+		// Plain tokens still work, and base64 of anything else is still rejected
+		let mut h = HeaderMap::new();
+		h.insert("x-api-key", HeaderValue::from_static("ghp_plain"));
+		assert_eq!(extract_gh_token(&h, true).as_deref(), Some("ghp_plain"));
+		h.insert("x-api-key", HeaderValue::from_static("c2stYW50LWFwaTAz"));
+		assert_eq!(extract_gh_token(&h, true), None);
+	}
+
+	const COPILOT_TOKEN: &str = "tid=abc;exp=1700000000;sku=free;8kp=1:deadbeef";
+
+	#[test]
+	fn copilot_token_distinguished_from_gh_token() {
+		let mut h = HeaderMap::new();
+		h.insert("x-api-key", HeaderValue::from_static(COPILOT_TOKEN));
+		assert_eq!(extract_gh_token(&h, false).as_deref(), None);
+		assert_eq!(extract_copilot_token(&h, "tid="), Some(COPILOT_TOKEN));
+
+		let mut h = HeaderMap::new();
+		h.insert("x-api-key", HeaderValue::from_static("ghu_usertoken"));
+		assert_eq!(
+			extract_gh_token(&h, false).as_deref(),
+			Some("ghu_usertoken")
+		);
+		assert_eq!(extract_copilot_token(&h, "tid="), None);
+	}
+
+	#[test]
+	fn copilot_token_as_bearer() {
+		let mut h = HeaderMap::new();
+		h.insert(
+			"authorization",
+			HeaderValue::from_static("Bearer tid=abc;exp=1"),
+		);
+		assert_eq!(extract_copilot_token(&h, "tid="), Some("tid=abc;exp=1"));
 	}
 }
@@ -1,25 +1,32 @@
+use std::borrow::Cow;
 use std::sync::Arc;
 
 use axum::Json;
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
-use tracing::error;
+use tracing::{debug, error};
 
-use super::extract::extract_gh_token;
+use super::extract::{extract_copilot_token, extract_gh_token};
 use crate::state::AppState;
 
 /// Resolve a valid Copilot API token for this request.
 ///
-/// 1. Check request headers for a GitHub token (Anthropic / OpenAI / Bearer conventions).
-/// 2. Fall back to the default `GH_TOKEN` from the environment.
-/// 3. If neither is available, return 403.
-/// 4. Exchange the GH token for a short-lived Copilot token (cached).
+/// 1. A raw Copilot token in the request headers is used as-is, skipping the exchange.
+/// 2. Check request headers for a GitHub token (Anthropic / OpenAI / Bearer conventions).
+/// 3. Fall back to the default `GH_TOKEN` from the environment.
+/// 4. If neither is available, return 403.
+/// 5. Exchange the GH token for a short-lived Copilot token (cached).
 pub async fn resolve_copilot_token(
 	state: &Arc<AppState>,
 	headers: &HeaderMap,
 ) -> Result<String, Response> {
-	let gh_token = extract_gh_token(headers)
-        .map(|s| s.to_string())
+	if let Some(copilot_token) = extract_copilot_token(headers, &state.copilot_token_prefix) {
+		debug!("using Copilot token from request headers, skipping exchange");
+		return Ok(copilot_token.to_string());
+	}
+
+	let gh_token = extract_gh_token(headers, state.accept_base64_keys)
+        .map(|s| s.into_owned())
         .or_else(|| state.default_github_token.clone())
         .ok_or_else(|| {
             (
@@ -54,3 +61,51 @@ pub async fn resolve_copilot_token(
 				.into_response()
 		})
 }
+
+/// The credentials this request authenticates with, as
+/// [`resolve_copilot_token`] would pick them, without exchanging anything.
+pub fn request_credentials<'a>(
+	state: &'a AppState,
+	headers: &'a HeaderMap,
+) -> Option<Cow<'a, str>> {
+	extract_copilot_token(headers, &state.copilot_token_prefix)
+		.map(Cow::Borrowed)
+		.or_else(|| extract_gh_token(headers, state.accept_base64_keys))
+		.or(state.default_github_token.as_deref().map(Cow::Borrowed))
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::HeaderValue;
+
+	use super::*;
+	use crate::rename::ModelRenamer;
+
+	fn state() -> Arc<AppState> {
+		Arc::new(
+			AppState::new(
+				None,
+				"individual".to_string(),
+				"1.100.0".to_string(),
+				ModelRenamer::from_env(),
+			)
+			.unwrap(),
+		)
+	}
+
+	#[tokio::test]
+	async fn copilot_token_used_without_exchange() {
+		let mut h = HeaderMap::new();
+		h.insert("x-api-key", HeaderValue::from_static("tid=abc;exp=1"));
+		let token = resolve_copilot_token(&state(), &h).await.unwrap();
+		assert_eq!(token, "tid=abc;exp=1");
+	}
+
+	#[tokio::test]
+	async fn unrecognised_credential_rejected() {
+		let mut h = HeaderMap::new();
+		h.insert("x-api-key", HeaderValue::from_static("sk-ant-api03-x"));
+		let resp = resolve_copilot_token(&state(), &h).await.unwrap_err();
+		assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+	}
+}
@@ -1,7 +1,9 @@
+use std::fmt::Display;
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Duration;
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::state::AppState;
 
@@ -25,6 +27,55 @@ pub async fn initial_token_exchange(state: &AppState) -> anyhow::Result<()> {
 	Ok(())
 }
 
+/// Run `op` up to `retries + 1` times, doubling the delay between attempts.
+/// Returns the last error if every attempt fails.
+pub async fn retry_with_backoff<T, E, F, Fut>(
+	retries: u32,
+	initial_delay: Duration,
+	mut op: F,
+) -> Result<T, E>
+where
+	E: Display,
+	F: FnMut() -> Fut,
+	Fut: Future<Output = Result<T, E>>,
+{
+	let mut delay = initial_delay;
+	let mut attempt = 0;
+	loop {
+		attempt += 1;
+		match op().await {
+			Ok(value) => return Ok(value),
+			Err(e) if attempt > retries => return Err(e),
+			Err(e) => {
+				warn!(
+					error = %e,
+					attempt,
+					retry_in = ?delay,
+					"attempt failed, retrying"
+				);
+				tokio::time::sleep(delay).await;
+				delay = delay.saturating_mul(2);
+			}
+		}
+	}
+}
+
+/// Refresh this long before the Copilot token would be due.
+const REFRESH_MARGIN_SECS: u64 = 60;
+
+/// How long to wait before the next refresh, given GitHub's `refresh_in`.
+/// Never less than `floor`, so a tiny `refresh_in` can't spin the loop.
+fn refresh_delay(refresh_in: u64, floor: Duration) -> Duration {
+	if refresh_in <= REFRESH_MARGIN_SECS {
+		warn!(
+			refresh_in,
+			floor = ?floor,
+			"upstream refresh_in is suspiciously small"
+		);
+	}
+	Duration::from_secs(refresh_in.saturating_sub(REFRESH_MARGIN_SECS)).max(floor)
+}
+
 /// Spawn a background loop that proactively refreshes the Copilot token
 /// for the default GH token. Only runs if a default token is configured.
 pub fn spawn_refresh_loop(state: Arc<AppState>) {
@@ -39,23 +90,23 @@ pub fn spawn_refresh_loop(state: Arc<AppState>) {
 		tokio::time::sleep(Duration::from_secs(600)).await;
 
 		loop {
-			let sleep_secs = match state
+			let delay = match state
 				.token_cache
 				.refresh(&gh_token, &state.client, &state.vscode_version)
 				.await
 			{
 				Ok(refresh_in) => {
-					let delay = refresh_in.saturating_sub(60);
-					info!(refresh_in, delay, "default copilot token refreshed");
+					let delay = refresh_delay(refresh_in, state.token_refresh_floor);
+					info!(refresh_in, ?delay, "default copilot token refreshed");
 					delay
 				}
 				Err(e) => {
 					error!(error = %e, "failed to refresh default copilot token, retrying in 30s");
-					30
+					Duration::from_secs(30)
 				}
 			};
 
-			tokio::time::sleep(Duration::from_secs(sleep_secs)).await;
+			tokio::time::sleep(delay).await;
 		}
 	});
 
@@ -67,3 +118,60 @@ pub fn spawn_refresh_loop(state: Arc<AppState>) {
 		}
 	});
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn retry_succeeds_on_later_attempt() {
+		let calls = AtomicU32::new(0);
+		let result = retry_with_backoff(3, Duration::from_millis(1), || async {
+			match calls.fetch_add(1, Ordering::SeqCst) {
+				0 | 1 => Err("github unavailable"),
+				n => Ok(n),
+			}
+		})
+		.await;
+
+		assert_eq!(result, Ok(2));
+		assert_eq!(calls.load(Ordering::SeqCst), 3);
+	}
+
+	#[tokio::test]
+	async fn retry_gives_up_after_exhausting_retries() {
+		let calls = AtomicU32::new(0);
+		let result: Result<(), _> = retry_with_backoff(2, Duration::from_millis(1), || async {
+			calls.fetch_add(1, Ordering::SeqCst);
+			Err("github unavailable")
+		})
+		.await;
+
+		assert_eq!(result, Err("github unavailable"));
+		assert_eq!(calls.load(Ordering::SeqCst), 3);
+	}
+
+	#[test]
+	fn refresh_delay_has_a_floor() {
+		let floor = Duration::from_secs(30);
+		assert_eq!(refresh_delay(1500, floor), Duration::from_secs(1440));
+		assert_eq!(refresh_delay(75, floor), floor);
+		assert_eq!(refresh_delay(5, floor), floor);
+		assert_eq!(refresh_delay(0, floor), floor);
+	}
+
+	#[tokio::test]
+	async fn no_retries_means_single_attempt() {
+		let calls = AtomicU32::new(0);
+		let result: Result<(), _> = retry_with_backoff(0, Duration::from_millis(1), || async {
+			calls.fetch_add(1, Ordering::SeqCst);
+			Err("nope")
+		})
+		.await;
+
+		assert!(result.is_err());
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+	}
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::StreamExt;
+use serde::Serialize;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_CAPACITY: usize = 100;
+
+/// Message batches, processed in the background at most
+/// `BATCH_CONCURRENCY` requests at a time, and kept for `BATCH_TTL_SECS`
+/// after they were created.
+///
+/// At most `BATCH_MAX_STORED` are kept: past that, the oldest ended batch
+/// makes way for a new one, and if none has ended, new ones are refused.
+/// Each batch is only visible to the credentials that submitted it.
+pub struct BatchStore {
+	ttl: Duration,
+	concurrency: usize,
+	capacity: usize,
+	batches: Arc<Mutex<HashMap<String, Batch>>>,
+}
+
+/// A hash of the credentials a batch was submitted with.
+pub type BatchOwner = [u8; 32];
+
+/// Every stored batch is still in progress, so there's no room for another.
+#[derive(Debug, thiserror::Error)]
+#[error("too many message batches in progress, the limit is {limit}")]
+pub struct StoreFull {
+	pub limit: usize,
+}
+
+struct Batch {
+	owner: BatchOwner,
+	created_at: SystemTime,
+	ended_at: Option<SystemTime>,
+	custom_ids: Vec<String>,
+	/// Results in request order, `None` while still processing.
+	results: Vec<Option<BatchResult>>,
+}
+
+/// The outcome of one request in a batch.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatchResult {
+	Succeeded { message: serde_json::Value },
+	Errored { error: serde_json::Value },
+}
+
+/// A line of a batch's results, as Anthropic returns them.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchResultLine {
+	pub custom_id: String,
+	pub result: BatchResult,
+}
+
+/// A batch, as Anthropic reports it.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageBatch {
+	pub id: String,
+	pub r#type: &'static str,
+	pub processing_status: &'static str,
+	pub request_counts: RequestCounts,
+	pub created_at: String,
+	pub ended_at: Option<String>,
+	pub expires_at: String,
+	pub archived_at: Option<String>,
+	pub cancel_initiated_at: Option<String>,
+	pub results_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RequestCounts {
+	pub processing: usize,
+	pub succeeded: usize,
+	pub errored: usize,
+	pub canceled: usize,
+	pub expired: usize,
+}
+
+impl BatchStore {
+	pub fn new(ttl: Duration, concurrency: usize, capacity: usize) -> Self {
+		Self {
+			ttl,
+			concurrency: concurrency.max(1),
+			capacity: capacity.max(1),
+			batches: Arc::default(),
+		}
+	}
+
+	pub fn from_env() -> Self {
+		let ttl = env::var("BATCH_TTL_SECS")
+			.ok()
+			.and_then(|v| v.parse().ok())
+			.map_or(DEFAULT_TTL, Duration::from_secs);
+		let concurrency = env::var("BATCH_CONCURRENCY")
+			.ok()
+			.and_then(|v| v.parse().ok())
+			.unwrap_or(DEFAULT_CONCURRENCY);
+		let capacity = env::var("BATCH_MAX_STORED")
+			.ok()
+			.and_then(|v| v.parse().ok())
+			.unwrap_or(DEFAULT_CAPACITY);
+		Self::new(ttl, concurrency, capacity)
+	}
+
+	pub fn ttl(&self) -> Duration {
+		self.ttl
+	}
+
+	pub fn concurrency(&self) -> usize {
+		self.concurrency
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Store a new batch of `(custom_id, request)` for `owner` and start
+	/// running each request through `process` in the background.
+	pub fn submit<T, F, Fut>(
+		&self,
+		owner: BatchOwner,
+		requests: Vec<(String, T)>,
+		process: F,
+	) -> Result<MessageBatch, StoreFull>
+	where
+		T: Send + 'static,
+		F: Fn(T) -> Fut + Send + 'static,
+		Fut: Future<Output = BatchResult> + Send,
+	{
+		self.evict_expired();
+		self.make_room()?;
+
+		let id = format!("msgbatch_{}", Uuid::new_v4().simple());
+		let (custom_ids, requests): (Vec<_>, Vec<_>) = requests.into_iter().unzip();
+		let batch = Batch {
+			owner,
+			created_at: SystemTime::now(),
+			ended_at: None,
+			results: vec![None; custom_ids.len()],
+			custom_ids,
+		};
+		let info = self.info(&id, &batch);
+		self.batches.lock().unwrap().insert(id.clone(), batch);
+		info!(%id, requests = requests.len(), "message batch submitted");
+
+		let batches = Arc::clone(&self.batches);
+		let concurrency = self.concurrency;
+		tokio::spawn(async move {
+			let mut results = futures::stream::iter(requests.into_iter().enumerate())
+				.map(move |(i, request)| {
+					let result = process(request);
+					async move { (i, result.await) }
+				})
+				.buffer_unordered(concurrency);
+			while let Some((i, result)) = results.next().await {
+				// Gone if it expired while processing
+				if let Some(batch) = batches.lock().unwrap().get_mut(&id) {
+					batch.results[i] = Some(result);
+				}
+			}
+			if let Some(batch) = batches.lock().unwrap().get_mut(&id) {
+				batch.ended_at = Some(SystemTime::now());
+			}
+			debug!(%id, "message batch ended");
+		});
+
+		Ok(info)
+	}
+
+	/// The batch `id`, if `owner` submitted it.
+	pub fn get(&self, owner: &BatchOwner, id: &str) -> Option<MessageBatch> {
+		self.evict_expired();
+		let batches = self.batches.lock().unwrap();
+		batches
+			.get(id)
+			.filter(|batch| batch.owner == *owner)
+			.map(|batch| self.info(id, batch))
+	}
+
+	/// The results of a batch `owner` submitted, in request order, once it
+	/// has ended.
+	pub fn results(&self, owner: &BatchOwner, id: &str) -> Option<Vec<BatchResultLine>> {
+		self.evict_expired();
+		let batches = self.batches.lock().unwrap();
+		let batch = batches
+			.get(id)
+			.filter(|batch| batch.owner == *owner && batch.ended_at.is_some())?;
+		Some(
+			batch
+				.custom_ids
+				.iter()
+				.zip(&batch.results)
+				.filter_map(|(custom_id, result)| {
+					Some(BatchResultLine {
+						custom_id: custom_id.clone(),
+						result: result.clone()?,
+					})
+				})
+				.collect(),
+		)
+	}
+
+	fn evict_expired(&self) {
+		let mut batches = self.batches.lock().unwrap();
+		batches.retain(|_, batch| batch.created_at.elapsed().is_ok_and(|age| age < self.ttl));
+	}
+
+	/// Drop the oldest ended batches until there's room for another.
+	fn make_room(&self) -> Result<(), StoreFull> {
+		let mut batches = self.batches.lock().unwrap();
+		while batches.len() >= self.capacity {
+			let oldest = batches
+				.iter()
+				.filter(|(_, batch)| batch.ended_at.is_some())
+				.min_by_key(|(_, batch)| batch.created_at)
+				.map(|(id, _)| id.clone());
+			let Some(oldest) = oldest else {
+				return Err(StoreFull {
+					limit: self.capacity,
+				});
+			};
+			debug!(id = %oldest, "dropping oldest ended message batch to make room");
+			batches.remove(&oldest);
+		}
+		Ok(())
+	}
+
+	fn info(&self, id: &str, batch: &Batch) -> MessageBatch {
+		let mut counts = RequestCounts::default();
+		for result in &batch.results {
+			match result {
+				None => counts.processing += 1,
+				Some(BatchResult::Succeeded { .. }) => counts.succeeded += 1,
+				Some(BatchResult::Errored { .. }) => counts.errored += 1,
+			}
+		}
+		let ended = batch.ended_at.is_some();
+		MessageBatch {
+			id: id.to_string(),
+			r#type: "message_batch",
+			processing_status: if ended { "ended" } else { "in_progress" },
+			request_counts: counts,
+			created_at: timestamp(batch.created_at),
+			ended_at: batch.ended_at.map(timestamp),
+			expires_at: timestamp(batch.created_at + self.ttl),
+			archived_at: None,
+			cancel_initiated_at: None,
+			results_url: ended.then(|| format!("/v1/messages/batches/{id}/results")),
+		}
+	}
+}
+
+fn timestamp(time: SystemTime) -> String {
+	humantime::format_rfc3339_seconds(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use tokio::sync::oneshot;
+
+	use super::*;
+
+	const OWNER: BatchOwner = [1; 32];
+
+	/// Poll until the batch ends, as a client would.
+	async fn wait_for_end(store: &BatchStore, id: &str) -> MessageBatch {
+		for _ in 0..200 {
+			let batch = store.get(&OWNER, id).unwrap();
+			if batch.processing_status == "ended" {
+				return batch;
+			}
+			tokio::time::sleep(Duration::from_millis(5)).await;
+		}
+		panic!("batch {id} never ended");
+	}
+
+	#[tokio::test]
+	async fn batch_runs_to_completion() {
+		let store = BatchStore::new(DEFAULT_TTL, 2, DEFAULT_CAPACITY);
+		let in_flight = Arc::new(AtomicUsize::new(0));
+		let most_in_flight = Arc::new(AtomicUsize::new(0));
+
+		let requests = (0..5).map(|n| (format!("req-{n}"), n)).collect::<Vec<_>>();
+		let (counting, most) = (Arc::clone(&in_flight), Arc::clone(&most_in_flight));
+		let batch = store.submit(OWNER, requests, move |n: u32| {
+			let (in_flight, most) = (Arc::clone(&counting), Arc::clone(&most));
+			async move {
+				let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+				most.fetch_max(now, Ordering::SeqCst);
+				tokio::time::sleep(Duration::from_millis(10)).await;
+				in_flight.fetch_sub(1, Ordering::SeqCst);
+				if n == 3 {
+					BatchResult::Errored {
+						error: serde_json::json!({"type": "api_error"}),
+					}
+				} else {
+					BatchResult::Succeeded {
+						message: serde_json::json!({"n": n}),
+					}
+				}
+			}
+		});
+		let batch = batch.unwrap();
+		assert_eq!(batch.processing_status, "in_progress");
+		assert!(batch.id.starts_with("msgbatch_"));
+		assert!(batch.results_url.is_none());
+		assert!(store.results(&OWNER, &batch.id).is_none());
+
+		let ended = wait_for_end(&store, &batch.id).await;
+		assert_eq!(
+			ended.request_counts,
+			RequestCounts {
+				succeeded: 4,
+				errored: 1,
+				..Default::default()
+			}
+		);
+		assert!(ended.ended_at.is_some());
+		assert!(most_in_flight.load(Ordering::SeqCst) <= 2);
+
+		let results = store.results(&OWNER, &batch.id).unwrap();
+		let ids: Vec<&str> = results.iter().map(|r| r.custom_id.as_str()).collect();
+		assert_eq!(ids, ["req-0", "req-1", "req-2", "req-3", "req-4"]);
+		assert_eq!(
+			results[1].result,
+			BatchResult::Succeeded {
+				message: serde_json::json!({"n": 1})
+			}
+		);
+		assert!(matches!(results[3].result, BatchResult::Errored { .. }));
+	}
+
+	#[tokio::test]
+	async fn expired_batches_forgotten() {
+		let store = BatchStore::new(Duration::from_millis(20), 1, DEFAULT_CAPACITY);
+		let batch = store
+			.submit(OWNER, vec![("a".to_string(), ())], succeed)
+			.unwrap();
+		assert!(store.get(&OWNER, &batch.id).is_some());
+		tokio::time::sleep(Duration::from_millis(40)).await;
+		assert!(store.get(&OWNER, &batch.id).is_none());
+	}
+
+	async fn succeed(_: ()) -> BatchResult {
+		BatchResult::Succeeded {
+			message: serde_json::Value::Null,
+		}
+	}
+
+	#[tokio::test]
+	async fn batches_only_visible_to_their_owner() {
+		let store = BatchStore::new(DEFAULT_TTL, 1, DEFAULT_CAPACITY);
+		let batch = store
+			.submit(OWNER, vec![("a".to_string(), ())], succeed)
+			.unwrap();
+		wait_for_end(&store, &batch.id).await;
+
+		let other = [2; 32];
+		assert!(store.get(&other, &batch.id).is_none());
+		assert!(store.results(&other, &batch.id).is_none());
+		assert!(store.results(&OWNER, &batch.id).is_some());
+	}
+
+	/// Submit a batch that runs until the returned sender is used or dropped.
+	fn submit_held(store: &BatchStore) -> Result<(MessageBatch, oneshot::Sender<()>), StoreFull> {
+		let (release, gate) = oneshot::channel::<()>();
+		let gate = Mutex::new(Some(gate));
+		let batch = store.submit(OWNER, vec![("held".to_string(), ())], move |()| {
+			let gate = gate.lock().unwrap().take();
+			async move {
+				if let Some(gate) = gate {
+					let _ = gate.await;
+				}
+				succeed(()).await
+			}
+		})?;
+		Ok((batch, release))
+	}
+
+	#[tokio::test]
+	async fn oldest_ended_batch_makes_room() {
+		let store = BatchStore::new(DEFAULT_TTL, 1, 2);
+		let first = store
+			.submit(OWNER, vec![("a".to_string(), ())], succeed)
+			.unwrap();
+		wait_for_end(&store, &first.id).await;
+
+		let (second, _second_release) = submit_held(&store).unwrap();
+		let (third, _third_release) = submit_held(&store).unwrap();
+		assert!(store.get(&OWNER, &first.id).is_none());
+		assert!(store.get(&OWNER, &second.id).is_some());
+		assert!(store.get(&OWNER, &third.id).is_some());
+
+		assert!(matches!(submit_held(&store), Err(StoreFull { limit: 2 })));
+	}
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::{Stream, StreamExt};
+use tokio::sync::oneshot;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::coalesce::CoalesceKey;
+
+/// A hash of the credentials a stream was requested with.
+pub type StreamOwner = CoalesceKey;
+
+type Streams = Arc<Mutex<HashMap<String, (StreamOwner, oneshot::Sender<()>)>>>;
+
+/// In-flight streams that clients can cancel by request ID, with the same
+/// credentials they requested them with.
+#[derive(Default)]
+pub struct StreamRegistry {
+	streams: Streams,
+}
+
+/// Forgets a stream's registration once it ends or is dropped.
+struct Registration {
+	id: String,
+	streams: Streams,
+}
+
+impl Drop for Registration {
+	fn drop(&mut self) {
+		self.streams.lock().unwrap().remove(&self.id);
+	}
+}
+
+impl StreamRegistry {
+	/// Register a stream for `owner` under a fresh request ID. The returned
+	/// stream ends as soon as it's cancelled, dropping `stream` (and whatever
+	/// upstream connection it holds).
+	pub fn register<S>(
+		&self,
+		owner: StreamOwner,
+		stream: S,
+	) -> (String, impl Stream<Item = S::Item> + use<S>)
+	where
+		S: Stream + Send + 'static,
+		S::Item: Send,
+	{
+		let id = format!("req_{}", Uuid::new_v4().simple());
+		let (tx, mut cancelled) = oneshot::channel();
+		self.streams.lock().unwrap().insert(id.clone(), (owner, tx));
+		let registration = Registration {
+			id: id.clone(),
+			streams: Arc::clone(&self.streams),
+		};
+
+		let stream = async_stream::stream! {
+			let _registration = registration;
+			let mut stream = std::pin::pin!(stream);
+			loop {
+				tokio::select! {
+					_ = &mut cancelled => {
+						debug!(id = %_registration.id, "stream cancelled");
+						break;
+					}
+					item = stream.next() => match item {
+						Some(item) => yield item,
+						None => break,
+					},
+				}
+			}
+		};
+		(id, stream)
+	}
+
+	/// Cancel the stream registered as `id` by `owner`. Returns false if
+	/// there's no such stream, it has already finished, or it's someone else's.
+	pub fn cancel(&self, owner: &StreamOwner, id: &str) -> bool {
+		let mut streams = self.streams.lock().unwrap();
+		if streams.get(id).is_none_or(|(o, _)| o != owner) {
+			return false;
+		}
+		streams
+			.remove(id)
+			.is_some_and(|(_, tx)| tx.send(()).is_ok())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const OWNER: StreamOwner = [1; 32];
+
+	#[tokio::test]
+	async fn cancelling_a_registered_stream_terminates_it() {
+		let registry = StreamRegistry::default();
+		let source = futures::stream::iter([1, 2]).chain(futures::stream::pending());
+		let (id, stream) = registry.register(OWNER, source);
+		let mut stream = std::pin::pin!(stream);
+
+		assert_eq!(stream.next().await, Some(1));
+		assert_eq!(stream.next().await, Some(2));
+		assert!(registry.cancel(&OWNER, &id));
+		assert_eq!(stream.next().await, None);
+		assert!(!registry.cancel(&OWNER, &id));
+	}
+
+	#[tokio::test]
+	async fn only_the_owner_can_cancel() {
+		let registry = StreamRegistry::default();
+		let (id, stream) = registry.register(OWNER, futures::stream::iter([1]));
+
+		assert!(!registry.cancel(&[2; 32], &id));
+		assert_eq!(stream.collect::<Vec<_>>().await, [1]);
+	}
+
+	#[tokio::test]
+	async fn finished_stream_is_unregistered() {
+		let registry = StreamRegistry::default();
+		let (id, stream) = registry.register(OWNER, futures::stream::iter([1]));
+
+		assert_eq!(stream.collect::<Vec<_>>().await, [1]);
+		assert!(!registry.cancel(&OWNER, &id));
+		assert!(!registry.cancel(&OWNER, "req_unknown"));
+	}
+}
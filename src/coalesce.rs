@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::{BoxStream, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+/// How far a subscriber may fall behind the upstream before it's cut off.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Fans a single source stream out to every identical request that arrives
+/// while it's still running.
+///
+/// The first request for a key starts the source on a background task; later
+/// ones replay what has been produced so far, then follow live over a
+/// broadcast channel. The flight is forgotten once the source ends, or
+/// dropped (cancelling the source) once every subscriber has gone.
+pub struct Coalescer<T> {
+	flights: Arc<Mutex<HashMap<CoalesceKey, SharedFlight<T>>>>,
+}
+
+/// A SHA-256 digest, so that one user's request can't collide with another's.
+pub type CoalesceKey = [u8; 32];
+
+type SharedFlight<T> = Arc<Mutex<Flight<T>>>;
+
+struct Flight<T> {
+	history: Vec<T>,
+	tx: broadcast::Sender<T>,
+}
+
+impl<T> Default for Coalescer<T> {
+	fn default() -> Self {
+		Self {
+			flights: Arc::default(),
+		}
+	}
+}
+
+impl<T: Clone + Send + 'static> Coalescer<T> {
+	/// Subscribe to the flight for `key`, starting it with `start` if there
+	/// isn't one. Returns whether this call started it, and the items.
+	pub fn join<S>(
+		&self,
+		key: CoalesceKey,
+		start: impl FnOnce() -> S,
+	) -> (bool, BoxStream<'static, T>)
+	where
+		S: Stream<Item = T> + Send + 'static,
+	{
+		let mut flights = self.flights.lock().unwrap();
+		if let Some(flight) = flights.get(&key) {
+			debug!("joining in-flight stream");
+			return (false, subscribe(flight));
+		}
+
+		let (tx, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+		let flight = Arc::new(Mutex::new(Flight {
+			history: Vec::new(),
+			tx: tx.clone(),
+		}));
+		flights.insert(key, Arc::clone(&flight));
+		let subscription = subscribe(&flight);
+
+		let source = start();
+		let registry = Arc::clone(&self.flights);
+		tokio::spawn(async move {
+			let mut source = std::pin::pin!(source);
+			loop {
+				tokio::select! {
+					item = source.next() => {
+						let Some(item) = item else { break };
+						let mut flight = flight.lock().unwrap();
+						flight.history.push(item.clone());
+						// No receivers is fine: someone may be about to join
+						let _ = flight.tx.send(item);
+					}
+					() = tx.closed() => {
+						// Checked again under the registry lock, as a request
+						// may have joined since
+						let mut flights = registry.lock().unwrap();
+						if flight.lock().unwrap().tx.receiver_count() == 0 {
+							debug!("every subscriber has gone, dropping the in-flight stream");
+							flights.remove(&key);
+							return;
+						}
+					}
+				}
+			}
+
+			// Dropping the last handle on the flight closes the channel
+			registry.lock().unwrap().remove(&key);
+		});
+
+		(true, subscription)
+	}
+}
+
+fn subscribe<T: Clone + Send + 'static>(flight: &Mutex<Flight<T>>) -> BoxStream<'static, T> {
+	let (history, mut rx) = {
+		let flight = flight.lock().unwrap();
+		(flight.history.clone(), flight.tx.subscribe())
+	};
+
+	async_stream::stream! {
+		for item in history {
+			yield item;
+		}
+		loop {
+			match rx.recv().await {
+				Ok(item) => yield item,
+				Err(RecvError::Closed) => break,
+				Err(RecvError::Lagged(missed)) => {
+					warn!(missed, "coalesced stream subscriber fell behind, ending its stream");
+					break;
+				}
+			}
+		}
+	}
+	.boxed()
+}
+
+/// Hash the parts of a request that make it identical to another.
+pub fn coalesce_key(parts: &[&[u8]]) -> CoalesceKey {
+	let mut hasher = Sha256::new();
+	for part in parts {
+		// Length-prefixed, so that moving bytes between parts changes the key
+		hasher.update((part.len() as u64).to_le_bytes());
+		hasher.update(part);
+	}
+	hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use tokio::sync::oneshot;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn identical_concurrent_streams_share_one_source() {
+		let coalescer = Coalescer::default();
+		let starts = Arc::new(AtomicU32::new(0));
+		let (release, gate) = oneshot::channel::<()>();
+
+		let source = {
+			let starts = Arc::clone(&starts);
+			move || {
+				starts.fetch_add(1, Ordering::SeqCst);
+				async_stream::stream! {
+					yield "message_start";
+					gate.await.unwrap();
+					yield "content_block_delta";
+					yield "message_stop";
+				}
+			}
+		};
+
+		let key = coalesce_key(&[b"token", b"body"]);
+		let (first_leads, first) = coalescer.join(key, source);
+		let (second_leads, second) = coalescer.join(key, || -> BoxStream<'static, &str> {
+			panic!("second request must not start another upstream")
+		});
+		release.send(()).unwrap();
+
+		let (first, second): (Vec<_>, Vec<_>) = tokio::join!(first.collect(), second.collect());
+		let expected = ["message_start", "content_block_delta", "message_stop"];
+		assert!(first_leads);
+		assert!(!second_leads);
+		assert_eq!(first, expected);
+		assert_eq!(second, expected);
+		assert_eq!(starts.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn late_subscriber_replays_history() {
+		let coalescer = Coalescer::default();
+		let (release, gate) = oneshot::channel::<()>();
+		let (produced, wait_produced) = oneshot::channel::<()>();
+
+		let key = coalesce_key(&[b"late"]);
+		let (_, first) = coalescer.join(key, move || {
+			async_stream::stream! {
+				yield 1;
+				yield 2;
+				produced.send(()).unwrap();
+				gate.await.unwrap();
+				yield 3;
+			}
+		});
+		let first = tokio::spawn(first.collect::<Vec<_>>());
+		wait_produced.await.unwrap();
+
+		let (leads, second) = coalescer.join(key, || futures::stream::empty().boxed());
+		release.send(()).unwrap();
+
+		assert!(!leads);
+		assert_eq!(second.collect::<Vec<_>>().await, [1, 2, 3]);
+		assert_eq!(first.await.unwrap(), [1, 2, 3]);
+	}
+
+	#[tokio::test]
+	async fn abandoned_flight_drops_its_source() {
+		struct Dropped(Option<oneshot::Sender<()>>);
+		impl Drop for Dropped {
+			fn drop(&mut self) {
+				self.0.take().unwrap().send(()).unwrap();
+			}
+		}
+
+		let coalescer = Coalescer::default();
+		let key = coalesce_key(&[b"abandoned"]);
+		let (dropped, wait_dropped) = oneshot::channel::<()>();
+		let (_, first) = coalescer.join(key, move || {
+			async_stream::stream! {
+				let _guard = Dropped(Some(dropped));
+				yield 1;
+				futures::future::pending::<()>().await;
+			}
+		});
+		let mut first = first;
+		assert_eq!(first.next().await, Some(1));
+		drop(first);
+
+		tokio::time::timeout(std::time::Duration::from_secs(1), wait_dropped)
+			.await
+			.expect("the source should be dropped")
+			.unwrap();
+		let (leads, _) = coalescer.join(key, futures::stream::empty);
+		assert!(leads);
+	}
+
+	#[test]
+	fn key_separates_parts() {
+		assert_ne!(coalesce_key(&[b"ab", b"c"]), coalesce_key(&[b"a", b"bc"]));
+	}
+
+	#[tokio::test]
+	async fn finished_flight_is_forgotten() {
+		let coalescer = Coalescer::default();
+		let key = coalesce_key(&[b"done"]);
+
+		let (_, first) = coalescer.join(key, || futures::stream::iter([1]));
+		assert_eq!(first.collect::<Vec<_>>().await, [1]);
+		tokio::task::yield_now().await;
+
+		let (leads, second) = coalescer.join(key, || futures::stream::iter([2]));
+		assert!(leads);
+		assert_eq!(second.collect::<Vec<_>>().await, [2]);
+	}
+}
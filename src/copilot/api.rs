@@ -1,30 +1,78 @@
+use std::net::SocketAddr;
+
 use reqwest::header::{HeaderMap, HeaderValue};
+use tracing::warn;
 use uuid::Uuid;
 
 const EDITOR_PLUGIN_VERSION: &str = "copilot-chat/0.26.7";
 const USER_AGENT: &str = "GitHubCopilotChat/0.26.7";
 const API_VERSION: &str = "2025-04-01";
+pub const DEFAULT_INTEGRATION_ID: &str = "vscode-chat";
 
 pub const GITHUB_API_BASE_URL: &str = "https://api.github.com";
 
+/// The Copilot API for an account type: `individual`, a segment like
+/// `business`, or a full hostname or URL used as-is.
 pub fn copilot_base_url(account_type: &str) -> String {
 	match account_type {
 		"individual" => "https://api.githubcopilot.com".to_string(),
+		url if url.contains("://") => url.trim_end_matches('/').to_string(),
+		host if host.contains('.') => format!("https://{}", host.trim_end_matches('/')),
 		other => format!("https://api.{other}.githubcopilot.com"),
 	}
 }
 
-pub fn copilot_headers(copilot_token: &str, vscode_version: &str, vision: bool) -> HeaderMap {
+/// The downstream client's address and user agent, forwarded upstream for
+/// audit when `FORWARD_CLIENT_INFO` is enabled.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+	/// `x-forwarded-for` chain, with the connecting peer appended.
+	pub forwarded_for: Option<String>,
+	pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+	pub fn from_request(headers: &HeaderMap, peer: SocketAddr) -> Self {
+		let header = |name| {
+			headers
+				.get(name)
+				.and_then(|v| v.to_str().ok())
+				.map(str::trim)
+				.filter(|v| !v.is_empty())
+		};
+		let peer = peer.ip().to_string();
+		Self {
+			forwarded_for: Some(match header("x-forwarded-for") {
+				Some(chain) => format!("{chain}, {peer}"),
+				None => peer,
+			}),
+			user_agent: header("user-agent").map(str::to_string),
+		}
+	}
+}
+
+pub fn copilot_headers(
+	copilot_token: &str,
+	vscode_version: &str,
+	integration_id: &str,
+	vision: bool,
+	client_info: Option<&ClientInfo>,
+) -> HeaderMap {
 	let mut headers = HeaderMap::new();
 	headers.insert(
 		"authorization",
 		format!("Bearer {copilot_token}").parse().unwrap(),
 	);
 	headers.insert("content-type", HeaderValue::from_static("application/json"));
-	headers.insert(
-		"copilot-integration-id",
-		HeaderValue::from_static("vscode-chat"),
-	);
+	match HeaderValue::from_str(integration_id) {
+		Ok(value) => {
+			headers.insert("copilot-integration-id", value);
+		}
+		Err(_) => warn!(
+			integration_id,
+			"invalid COPILOT_INTEGRATION_ID, sending none"
+		),
+	}
 	headers.insert(
 		"editor-version",
 		format!("vscode/{vscode_version}").parse().unwrap(),
@@ -53,6 +101,18 @@ pub fn copilot_headers(copilot_token: &str, vscode_version: &str, vision: bool)
 	if vision {
 		headers.insert("copilot-vision-request", HeaderValue::from_static("true"));
 	}
+	if let Some(info) = client_info {
+		// Our own user-agent must stay, so the client's goes in its own header
+		let forwarded = [
+			("x-forwarded-for", &info.forwarded_for),
+			("x-forwarded-user-agent", &info.user_agent),
+		];
+		for (name, value) in forwarded {
+			if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+				headers.insert(name, value);
+			}
+		}
+	}
 	headers
 }
 
@@ -83,3 +143,76 @@ pub fn github_headers(github_token: &str, vscode_version: &str) -> HeaderMap {
 	);
 	headers
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn client_headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+		let mut h = HeaderMap::new();
+		for (name, value) in pairs {
+			h.insert(*name, HeaderValue::from_static(value));
+		}
+		h
+	}
+
+	#[test]
+	fn base_url_from_account_type() {
+		assert_eq!(
+			copilot_base_url("individual"),
+			"https://api.githubcopilot.com"
+		);
+		assert_eq!(
+			copilot_base_url("business"),
+			"https://api.business.githubcopilot.com"
+		);
+		assert_eq!(
+			copilot_base_url("api.foo.githubcopilot.com"),
+			"https://api.foo.githubcopilot.com"
+		);
+		assert_eq!(
+			copilot_base_url("https://api.foo.githubcopilot.com/"),
+			"https://api.foo.githubcopilot.com"
+		);
+	}
+
+	#[test]
+	fn client_info_forwarded_when_given() {
+		let info = ClientInfo::from_request(
+			&client_headers(&[("user-agent", "claude-cli/1.0")]),
+			"192.0.2.7:51000".parse().unwrap(),
+		);
+		let headers = copilot_headers("tok", "1.100.0", DEFAULT_INTEGRATION_ID, false, Some(&info));
+		assert_eq!(headers["x-forwarded-for"], "192.0.2.7");
+		assert_eq!(headers["x-forwarded-user-agent"], "claude-cli/1.0");
+		assert_eq!(headers["user-agent"], USER_AGENT);
+	}
+
+	#[test]
+	fn client_info_not_forwarded_by_default() {
+		let headers = copilot_headers("tok", "1.100.0", DEFAULT_INTEGRATION_ID, false, None);
+		assert!(!headers.contains_key("x-forwarded-for"));
+		assert!(!headers.contains_key("x-forwarded-user-agent"));
+	}
+
+	#[test]
+	fn integration_id_configurable() {
+		let headers = copilot_headers("tok", "1.100.0", DEFAULT_INTEGRATION_ID, false, None);
+		assert_eq!(headers["copilot-integration-id"], "vscode-chat");
+		let headers = copilot_headers("tok", "1.100.0", "copilot-developer-cli", false, None);
+		assert_eq!(headers["copilot-integration-id"], "copilot-developer-cli");
+	}
+
+	#[test]
+	fn peer_appended_to_forwarded_chain() {
+		let info = ClientInfo::from_request(
+			&client_headers(&[("x-forwarded-for", "203.0.113.1, 10.0.0.2")]),
+			"[::1]:4141".parse().unwrap(),
+		);
+		assert_eq!(
+			info.forwarded_for.as_deref(),
+			Some("203.0.113.1, 10.0.0.2, ::1")
+		);
+		assert_eq!(info.user_agent, None);
+	}
+}
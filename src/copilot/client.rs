@@ -1,20 +1,73 @@
+use std::path::Path;
+use std::time::Duration;
+
 use anyhow::Context;
-use reqwest::Client;
+use reqwest::{Certificate, Client, StatusCode, tls};
+use thiserror::Error;
 use tracing::debug;
 
-use super::api::{GITHUB_API_BASE_URL, copilot_base_url, copilot_headers, github_headers};
+use super::api::{
+	ClientInfo, GITHUB_API_BASE_URL, copilot_base_url, copilot_headers, github_headers,
+};
 use super::types::{CopilotTokenResponse, ModelsResponse};
 
+/// Non-success status returned by the Copilot API.
+#[derive(Debug, Error)]
+#[error("HTTP {status}: {body}")]
+pub struct UpstreamStatusError {
+	pub status: StatusCode,
+	pub body: String,
+}
+
+impl UpstreamStatusError {
+	/// Upstream is out of capacity rather than limiting us in particular: a
+	/// 503, or a 429 that says it's overloaded.
+	pub fn is_overloaded(&self) -> bool {
+		self.status == StatusCode::SERVICE_UNAVAILABLE
+			|| (self.status == StatusCode::TOO_MANY_REQUESTS
+				&& self.body.to_ascii_lowercase().contains("overload"))
+	}
+}
+
+/// Build the HTTP client used for upstream calls: TLS 1.2 or later, and
+/// optionally trusting the root CAs in a PEM file (e.g. a corporate proxy's).
+///
+/// With `ca_only`, those roots replace the built-in ones instead of adding to them.
+pub fn build_http_client(ca_cert: Option<&Path>, ca_only: bool) -> anyhow::Result<Client> {
+	let mut builder = Client::builder().tls_version_min(tls::Version::TLS_1_2);
+
+	if let Some(path) = ca_cert {
+		let pem = std::fs::read(path)
+			.with_context(|| format!("failed to read CA certificate {}", path.display()))?;
+		let certs = Certificate::from_pem_bundle(&pem)
+			.with_context(|| format!("invalid CA certificate {}", path.display()))?;
+		if certs.is_empty() {
+			anyhow::bail!("no certificates found in {}", path.display());
+		}
+		debug!(path = %path.display(), count = certs.len(), ca_only, "loaded custom CA certificates");
+		builder = if ca_only {
+			builder.tls_certs_only(certs)
+		} else {
+			builder.tls_certs_merge(certs)
+		};
+	}
+
+	builder.build().context("failed to build HTTP client")
+}
+
+/// Exchange a GitHub token for a Copilot token, giving up after `timeout`.
 pub async fn fetch_copilot_token(
 	client: &Client,
 	gh_token: &str,
 	vscode_version: &str,
+	timeout: Duration,
 ) -> Result<CopilotTokenResponse, reqwest::Error> {
 	debug!("fetching copilot token from GitHub API");
 	let headers = github_headers(gh_token, vscode_version);
 	let resp = client
 		.get(format!("{GITHUB_API_BASE_URL}/copilot_internal/v2/token"))
 		.headers(headers)
+		.timeout(timeout)
 		.send()
 		.await?
 		.error_for_status()?
@@ -24,18 +77,42 @@ pub async fn fetch_copilot_token(
 	Ok(resp)
 }
 
+/// Fetch the model list, giving up after `timeout`. Unlike chat completions,
+/// this should be quick.
 pub async fn fetch_models(
 	client: &Client,
 	copilot_token: &str,
 	account_type: &str,
 	vscode_version: &str,
+	integration_id: &str,
+	timeout: Duration,
 ) -> Result<ModelsResponse, anyhow::Error> {
 	let base = copilot_base_url(account_type);
+	fetch_models_from(
+		client,
+		&base,
+		copilot_token,
+		vscode_version,
+		integration_id,
+		timeout,
+	)
+	.await
+}
+
+async fn fetch_models_from(
+	client: &Client,
+	base: &str,
+	copilot_token: &str,
+	vscode_version: &str,
+	integration_id: &str,
+	timeout: Duration,
+) -> Result<ModelsResponse, anyhow::Error> {
 	debug!(url = %format!("{base}/models"), "fetching models from Copilot API");
-	let headers = copilot_headers(copilot_token, vscode_version, false);
+	let headers = copilot_headers(copilot_token, vscode_version, integration_id, false, None);
 	let resp = client
 		.get(format!("{base}/models"))
 		.headers(headers)
+		.timeout(timeout)
 		.send()
 		.await
 		.context("failed to send models request")?;
@@ -67,14 +144,20 @@ pub async fn fetch_models(
 	Ok(models)
 }
 
+#[expect(
+	clippy::too_many_arguments,
+	reason = "thin wrapper over the upstream request parameters"
+)]
 pub async fn chat_completions_raw(
 	client: &Client,
 	copilot_token: &str,
 	account_type: &str,
 	vscode_version: &str,
+	integration_id: &str,
 	body: &[u8],
 	vision: bool,
 	is_agent: bool,
+	client_info: Option<&ClientInfo>,
 ) -> Result<reqwest::Response, anyhow::Error> {
 	let base = copilot_base_url(account_type);
 	debug!(
@@ -84,7 +167,13 @@ pub async fn chat_completions_raw(
 		agent = is_agent,
 		"sending chat completions request to Copilot API"
 	);
-	let mut headers = copilot_headers(copilot_token, vscode_version, vision);
+	let mut headers = copilot_headers(
+		copilot_token,
+		vscode_version,
+		integration_id,
+		vision,
+		client_info,
+	);
 	headers.insert(
 		"x-initiator",
 		if is_agent { "agent" } else { "user" }.parse().unwrap(),
@@ -106,9 +195,88 @@ pub async fn chat_completions_raw(
 			body = %error_text,
 			"Copilot API returned error status"
 		);
-		anyhow::bail!("HTTP {status}: {error_text}");
+		return Err(UpstreamStatusError {
+			status,
+			body: error_text.into_owned(),
+		}
+		.into());
 	}
 
 	debug!(status = %status, "received chat completions response");
 	Ok(resp)
 }
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::*;
+
+	const TEST_CA: &str = "-----BEGIN CERTIFICATE-----\nMIIBmDCCAT2gAwIBAgIUYtqVPuWLlOeRn+VbfDcCFWpa5RQwCgYIKoZIzj0EAwIw\nIDEeMBwGA1UEAwwVZmlyc3Qtb2ZmaWNlciB0ZXN0IENBMCAXDTI2MTAxNjEwNDMy\nMloYDzIxMjYwOTIyMTA0MzIyWjAgMR4wHAYDVQQDDBVmaXJzdC1vZmZpY2VyIHRl\nc3QgQ0EwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAASKGDp2nRdQjrNSl5mM9uC9\n6mnX9MokJ1OEkbcZJA4xw4nOoqfr0hEFZ5cIOvPvi2aEaShxl/DFJpKGcaxDSQr1\no1MwUTAdBgNVHQ4EFgQUsEXjsUY6EURJCb/8sA4tL6WPKUwwHwYDVR0jBBgwFoAU\nsEXjsUY6EURJCb/8sA4tL6WPKUwwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQD\nAgNJADBGAiEAvUWaDpmbsKP2aKH4xClFYTWEHdY7BHucFYjN+9JSczwCIQDNHFjp\nj9QOC955A0x5Vc4AsCfIJA1hHnPxbPo2zBjhsA==\n-----END CERTIFICATE-----\n";
+
+	fn write_temp(name: &str, contents: &str) -> PathBuf {
+		let path =
+			std::env::temp_dir().join(format!("first-officer-{}-{name}.pem", std::process::id()));
+		std::fs::write(&path, contents).unwrap();
+		path
+	}
+
+	#[test]
+	fn client_builds_with_custom_ca() {
+		let path = write_temp("ca", TEST_CA);
+		assert!(build_http_client(Some(&path), false).is_ok());
+		assert!(build_http_client(Some(&path), true).is_ok());
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn slow_models_endpoint_times_out_without_limiting_chat() {
+		// Accepts connections but never answers
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let base = format!("http://{}", listener.local_addr().unwrap());
+		let _server = tokio::spawn(async move {
+			let mut held = Vec::new();
+			while let Ok((socket, _)) = listener.accept().await {
+				held.push(socket);
+			}
+		});
+		let client = build_http_client(None, false).unwrap();
+
+		let started = std::time::Instant::now();
+		let err = fetch_models_from(
+			&client,
+			&base,
+			"tok",
+			"1.100.0",
+			"vscode-chat",
+			Duration::from_millis(200),
+		)
+		.await
+		.unwrap_err();
+		assert!(started.elapsed() < Duration::from_secs(2));
+		let err = err.downcast_ref::<reqwest::Error>().unwrap();
+		assert!(err.is_timeout(), "{err}");
+
+		// The shared client has no overall timeout, so a long completion
+		// on it is still waiting well past the models timeout
+		let chat = client
+			.post(format!("{base}/chat/completions"))
+			.body("{}")
+			.send();
+		assert!(
+			tokio::time::timeout(Duration::from_millis(600), chat)
+				.await
+				.is_err()
+		);
+	}
+
+	#[test]
+	fn client_rejects_missing_or_empty_ca() {
+		let missing = std::env::temp_dir().join("first-officer-does-not-exist.pem");
+		assert!(build_http_client(Some(&missing), false).is_err());
+
+		let path = write_temp("empty", "not a certificate\n");
+		assert!(build_http_client(Some(&path), false).is_err());
+		std::fs::remove_file(path).unwrap();
+	}
+}
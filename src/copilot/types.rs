@@ -23,10 +23,17 @@ pub struct ChatCompletionsRequest {
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub presence_penalty: Option<f64>,
 	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seed: Option<i64>,
+	/// JSON mode or structured output, passed through as-is.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub response_format: Option<serde_json::Value>,
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub tools: Option<Vec<Tool>>,
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub tool_choice: Option<ToolChoice>,
 	#[serde(skip_serializing_if = "Option::is_none")]
+	pub parallel_tool_calls: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub user: Option<String>,
 }
 
@@ -64,6 +71,16 @@ pub enum ContentPart {
 	Text { text: String },
 	#[serde(rename = "image_url")]
 	ImageUrl { image_url: ImageUrl },
+	#[serde(rename = "file")]
+	File { file: FileData },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileData {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub filename: Option<String>,
+	/// A base64 data URL.
+	pub file_data: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,6 +102,8 @@ pub struct FunctionDef {
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub description: Option<String>,
 	pub parameters: serde_json::Value,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub strict: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -170,10 +189,16 @@ pub struct Usage {
 	pub prompt_tokens_details: Option<PromptTokensDetails>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Breakdown of the prompt tokens. Only `cached_tokens` has an Anthropic
+/// equivalent (`cache_read_input_tokens`); the others are kept for logging.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PromptTokensDetails {
 	#[serde(default)]
 	pub cached_tokens: u64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub audio_tokens: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub text_tokens: Option<u64>,
 }
 
 // --- Chat Completions Streaming ---
@@ -299,6 +324,27 @@ pub struct ModelCapabilities {
 	pub r#type: Option<String>,
 }
 
+impl ModelCapabilities {
+	/// Whether the model reads PDFs sent as `file` parts. Models that don't
+	/// say are assumed not to.
+	pub fn supports_documents(&self) -> bool {
+		self.supports
+			.as_ref()
+			.and_then(|s| s.get("pdf"))
+			.and_then(serde_json::Value::as_bool)
+			.unwrap_or(false)
+	}
+
+	/// Whether the model can stream. Models that don't say are assumed to.
+	pub fn supports_streaming(&self) -> bool {
+		self.supports
+			.as_ref()
+			.and_then(|s| s.get("streaming"))
+			.and_then(serde_json::Value::as_bool)
+			.unwrap_or(true)
+	}
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelLimits {
 	#[serde(skip_serializing_if = "Option::is_none")]
@@ -0,0 +1,448 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::{Body, Bytes};
+use axum::http::response::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::sync::watch;
+use tracing::{debug, error, warn};
+
+use crate::coalesce::CoalesceKey;
+use crate::routes::error::error_response;
+
+const DEFAULT_TTL: Duration = Duration::from_secs(600);
+const DEFAULT_CAPACITY: usize = 256;
+
+/// Successful responses to requests that carried an idempotency key, so that
+/// a client retrying with the same key gets the first answer back instead of
+/// a second upstream call. A retry that comes while the first attempt is still
+/// running waits for it, and reusing a key for a different request is refused.
+///
+/// Entries expire after `IDEMPOTENCY_TTL_SECS`, and the least recently used
+/// are dropped once there are more than `IDEMPOTENCY_CACHE_SIZE`.
+pub struct IdempotencyCache {
+	ttl: Duration,
+	capacity: usize,
+	entries: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+	entries: HashMap<CoalesceKey, Slot>,
+	/// Keys from least to most recently used.
+	order: VecDeque<CoalesceKey>,
+}
+
+/// The request a key was first used for, and where its response is at.
+struct Slot {
+	request: CoalesceKey,
+	state: SlotState,
+}
+
+enum SlotState {
+	/// Still being computed: the receiver gets the response once it's stored,
+	/// or is closed if the attempt failed or was abandoned.
+	Pending(watch::Receiver<Option<Arc<Stored>>>),
+	Done(Arc<Stored>),
+}
+
+struct Stored {
+	parts: Parts,
+	body: Bytes,
+	stored_at: Instant,
+}
+
+impl Stored {
+	fn response(&self) -> Response {
+		Response::from_parts(self.parts.clone(), Body::from(self.body.clone()))
+	}
+}
+
+/// What identifies a request for idempotency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdempotencyKey {
+	/// The client's key, scoped to their credentials.
+	pub key: CoalesceKey,
+	/// The request itself, to catch a key reused for a different one.
+	pub request: CoalesceKey,
+}
+
+enum Lookup {
+	Replay(Response),
+	Wait(watch::Receiver<Option<Arc<Stored>>>),
+	Lead(watch::Sender<Option<Arc<Stored>>>),
+	/// The key was already used for a different request.
+	Conflict,
+}
+
+/// Forgets a pending slot if its attempt ends without storing a response,
+/// including by being dropped, so that retries don't wait on it forever.
+struct PendingGuard<'a> {
+	cache: &'a IdempotencyCache,
+	key: CoalesceKey,
+	tx: watch::Sender<Option<Arc<Stored>>>,
+}
+
+impl Drop for PendingGuard<'_> {
+	fn drop(&mut self) {
+		if self.tx.borrow().is_some() {
+			return;
+		}
+		let mut lru = self.cache.entries.lock().unwrap();
+		let ours = matches!(
+			lru.entries.get(&self.key),
+			Some(Slot { state: SlotState::Pending(rx), .. }) if rx.same_channel(&self.tx.subscribe())
+		);
+		if ours {
+			lru.remove(self.key);
+		}
+	}
+}
+
+impl IdempotencyCache {
+	pub fn new(ttl: Duration, capacity: usize) -> Self {
+		Self {
+			ttl,
+			capacity,
+			entries: Mutex::default(),
+		}
+	}
+
+	pub fn from_env() -> Self {
+		let ttl = env::var("IDEMPOTENCY_TTL_SECS")
+			.ok()
+			.and_then(|v| v.parse().ok())
+			.map_or(DEFAULT_TTL, Duration::from_secs);
+		let capacity = env::var("IDEMPOTENCY_CACHE_SIZE")
+			.ok()
+			.and_then(|v| v.parse().ok())
+			.unwrap_or(DEFAULT_CAPACITY);
+		Self::new(ttl, capacity)
+	}
+
+	pub fn ttl(&self) -> Duration {
+		self.ttl
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Replay the stored response for `key` if there is one, wait for it if
+	/// it's being computed, or run `compute` and store its response if it
+	/// succeeded. Without a key, or with the cache disabled, this is just
+	/// `compute`.
+	pub async fn respond(
+		&self,
+		key: Option<IdempotencyKey>,
+		compute: impl Future<Output = Response>,
+	) -> Response {
+		let Some(IdempotencyKey { key, request }) =
+			key.filter(|_| self.capacity > 0 && !self.ttl.is_zero())
+		else {
+			return compute.await;
+		};
+
+		let tx = loop {
+			match self.lookup(key, request) {
+				Lookup::Replay(resp) => {
+					debug!("replaying response for idempotency key");
+					return resp;
+				}
+				Lookup::Wait(mut rx) => {
+					debug!("waiting on in-flight request with the same idempotency key");
+					if let Ok(stored) = rx.wait_for(Option::is_some).await
+						&& let Some(stored) = stored.as_ref()
+					{
+						return stored.response();
+					}
+					// The first attempt failed or was abandoned: take over
+				}
+				Lookup::Lead(tx) => break tx,
+				Lookup::Conflict => {
+					warn!("idempotency key reused for a different request");
+					return error_response(
+						StatusCode::UNPROCESSABLE_ENTITY,
+						"invalid_request_error",
+						"idempotency key was already used for a different request",
+					);
+				}
+			}
+		};
+		let guard = PendingGuard {
+			cache: self,
+			key,
+			tx,
+		};
+
+		let resp = compute.await;
+		if !resp.status().is_success() {
+			return resp;
+		}
+
+		let (parts, body) = resp.into_parts();
+		let body = match axum::body::to_bytes(body, usize::MAX).await {
+			Ok(body) => body,
+			Err(e) => {
+				error!(error = %e, "failed to buffer response for idempotency key");
+				return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+			}
+		};
+		let stored = Arc::new(Stored {
+			parts,
+			body,
+			stored_at: Instant::now(),
+		});
+		let resp = stored.response();
+		self.insert(key, request, Arc::clone(&stored));
+		guard.tx.send_replace(Some(stored));
+		resp
+	}
+
+	/// Find what to do for `key`, claiming it if it's free.
+	fn lookup(&self, key: CoalesceKey, request: CoalesceKey) -> Lookup {
+		let mut lru = self.entries.lock().unwrap();
+		let live = lru.entries.get(&key).filter(|slot| match &slot.state {
+			SlotState::Done(stored) => stored.stored_at.elapsed() < self.ttl,
+			SlotState::Pending(rx) => rx.has_changed().is_ok(),
+		});
+		let found = live.map(|slot| match &slot.state {
+			_ if slot.request != request => Lookup::Conflict,
+			SlotState::Done(stored) => Lookup::Replay(stored.response()),
+			SlotState::Pending(rx) => Lookup::Wait(rx.clone()),
+		});
+		if let Some(found) = found {
+			lru.touch(key);
+			return found;
+		}
+
+		let (tx, rx) = watch::channel(None);
+		self.insert_locked(
+			&mut lru,
+			key,
+			Slot {
+				request,
+				state: SlotState::Pending(rx),
+			},
+		);
+		Lookup::Lead(tx)
+	}
+
+	fn insert(&self, key: CoalesceKey, request: CoalesceKey, stored: Arc<Stored>) {
+		let mut lru = self.entries.lock().unwrap();
+		self.insert_locked(
+			&mut lru,
+			key,
+			Slot {
+				request,
+				state: SlotState::Done(stored),
+			},
+		);
+	}
+
+	fn insert_locked(&self, lru: &mut Lru, key: CoalesceKey, slot: Slot) {
+		lru.entries.insert(key, slot);
+		lru.touch(key);
+		while lru.entries.len() > self.capacity {
+			let Some(oldest) = lru.order.pop_front() else {
+				break;
+			};
+			lru.entries.remove(&oldest);
+		}
+	}
+}
+
+impl Lru {
+	fn touch(&mut self, key: CoalesceKey) {
+		self.order.retain(|&k| k != key);
+		self.order.push_back(key);
+	}
+
+	fn remove(&mut self, key: CoalesceKey) {
+		if self.entries.remove(&key).is_some() {
+			self.order.retain(|&k| k != key);
+		}
+	}
+}
+
+/// The client's idempotency key, from `x-idempotency-key` or
+/// `idempotency-key`.
+pub fn idempotency_key(headers: &HeaderMap) -> Option<&str> {
+	["x-idempotency-key", "idempotency-key"]
+		.into_iter()
+		.find_map(|name| headers.get(name))
+		.and_then(|v| v.to_str().ok())
+		.map(str::trim)
+		.filter(|k| !k.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use tokio::sync::oneshot;
+
+	use super::*;
+
+	fn key(n: u8) -> Option<IdempotencyKey> {
+		Some(IdempotencyKey {
+			key: [n; 32],
+			request: [0; 32],
+		})
+	}
+
+	async fn call(
+		cache: &IdempotencyCache,
+		key: Option<IdempotencyKey>,
+		calls: &AtomicU32,
+	) -> String {
+		let resp = cache
+			.respond(key, async {
+				let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+				format!("response {n}").into_response()
+			})
+			.await;
+		let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		String::from_utf8(body.to_vec()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn same_key_hits_upstream_once() {
+		let cache = IdempotencyCache::new(DEFAULT_TTL, DEFAULT_CAPACITY);
+		let calls = AtomicU32::new(0);
+
+		assert_eq!(call(&cache, key(1), &calls).await, "response 1");
+		assert_eq!(call(&cache, key(1), &calls).await, "response 1");
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+		assert_eq!(call(&cache, key(2), &calls).await, "response 2");
+		assert_eq!(call(&cache, None, &calls).await, "response 3");
+		assert_eq!(call(&cache, None, &calls).await, "response 4");
+	}
+
+	#[tokio::test]
+	async fn failures_are_not_stored() {
+		let cache = IdempotencyCache::new(DEFAULT_TTL, DEFAULT_CAPACITY);
+		let calls = AtomicU32::new(0);
+		for _ in 0..2 {
+			cache
+				.respond(key(1), async {
+					calls.fetch_add(1, Ordering::SeqCst);
+					StatusCode::BAD_GATEWAY.into_response()
+				})
+				.await;
+		}
+		assert_eq!(calls.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn least_recently_used_evicted() {
+		let cache = IdempotencyCache::new(DEFAULT_TTL, 2);
+		let calls = AtomicU32::new(0);
+
+		call(&cache, key(1), &calls).await;
+		call(&cache, key(2), &calls).await;
+		// Using 1 again makes 2 the oldest
+		call(&cache, key(1), &calls).await;
+		call(&cache, key(3), &calls).await;
+		assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+		assert_eq!(call(&cache, key(1), &calls).await, "response 1");
+		assert_eq!(call(&cache, key(2), &calls).await, "response 4");
+	}
+
+	#[tokio::test]
+	async fn expired_entries_recomputed() {
+		let cache = IdempotencyCache::new(Duration::from_millis(20), DEFAULT_CAPACITY);
+		let calls = AtomicU32::new(0);
+
+		call(&cache, key(1), &calls).await;
+		tokio::time::sleep(Duration::from_millis(40)).await;
+		assert_eq!(call(&cache, key(1), &calls).await, "response 2");
+	}
+
+	#[tokio::test]
+	async fn concurrent_retry_waits_for_first_attempt() {
+		let cache = IdempotencyCache::new(DEFAULT_TTL, DEFAULT_CAPACITY);
+		let calls = AtomicU32::new(0);
+		let (release, gate) = oneshot::channel::<()>();
+
+		let first = cache.respond(key(1), async {
+			calls.fetch_add(1, Ordering::SeqCst);
+			gate.await.unwrap();
+			"first".into_response()
+		});
+		let retry = async {
+			tokio::task::yield_now().await;
+			let resp = call(&cache, key(1), &calls).await;
+			assert_eq!(resp, "first");
+		};
+		let release = async {
+			tokio::task::yield_now().await;
+			tokio::task::yield_now().await;
+			release.send(()).unwrap();
+		};
+		let (first, (), ()) = tokio::join!(first, retry, release);
+
+		assert!(first.status().is_success());
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn retry_takes_over_after_failed_attempt() {
+		let cache = IdempotencyCache::new(DEFAULT_TTL, DEFAULT_CAPACITY);
+		let calls = AtomicU32::new(0);
+		let (release, gate) = oneshot::channel::<()>();
+
+		let first = cache.respond(key(1), async {
+			calls.fetch_add(1, Ordering::SeqCst);
+			gate.await.unwrap();
+			StatusCode::BAD_GATEWAY.into_response()
+		});
+		let retry = async {
+			tokio::task::yield_now().await;
+			call(&cache, key(1), &calls).await
+		};
+		let release = async {
+			tokio::task::yield_now().await;
+			tokio::task::yield_now().await;
+			release.send(()).unwrap();
+		};
+		let (first, retry, ()) = tokio::join!(first, retry, release);
+
+		assert_eq!(first.status(), StatusCode::BAD_GATEWAY);
+		assert_eq!(retry, "response 2");
+	}
+
+	#[tokio::test]
+	async fn key_reused_for_different_request_rejected() {
+		let cache = IdempotencyCache::new(DEFAULT_TTL, DEFAULT_CAPACITY);
+		let calls = AtomicU32::new(0);
+		call(&cache, key(1), &calls).await;
+
+		let other = Some(IdempotencyKey {
+			key: [1; 32],
+			request: [9; 32],
+		});
+		let resp = cache
+			.respond(other, async { "other".into_response() })
+			.await;
+		assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn key_from_either_header() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(idempotency_key(&headers), None);
+		headers.insert("idempotency-key", "abc".parse().unwrap());
+		assert_eq!(idempotency_key(&headers), Some("abc"));
+		headers.insert("x-idempotency-key", "def".parse().unwrap());
+		assert_eq!(idempotency_key(&headers), Some("def"));
+	}
+}
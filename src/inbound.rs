@@ -0,0 +1,149 @@
+use std::env;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderName};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::{debug, warn};
+
+use crate::state::AppState;
+
+/// The only inbound headers handlers get to see. Anything else (cookies,
+/// proprietary auth, tracing baggage...) is dropped before routing, so it
+/// can't influence behaviour or leak upstream by accident.
+const INBOUND_HEADER_ALLOWLIST: &[&str] = &[
+	"host",
+	"content-type",
+	"content-length",
+	"accept",
+	// credentials
+	"x-api-key",
+	"authorization",
+	"api-key",
+	"x-admin-token",
+	// behaviour
+	"anthropic-version",
+	"x-emulate-thinking",
+	"x-openai-params",
+	"x-disable-tools",
+	"x-error-format",
+	"x-return-upstream-model",
+	"x-request-timeout-secs",
+	"x-idempotency-key",
+	"idempotency-key",
+	// forwarded with FORWARD_CLIENT_INFO
+	"x-forwarded-for",
+	"user-agent",
+];
+
+/// Which inbound headers are dropped before requests reach the handlers.
+#[derive(Debug, Clone, Default)]
+pub struct InboundHeaderFilter {
+	/// Allowlisted headers that are stripped anyway.
+	strip: Vec<HeaderName>,
+}
+
+impl InboundHeaderFilter {
+	/// Read `STRIP_INBOUND_HEADERS`, a comma-separated list of header names.
+	pub fn from_env() -> Self {
+		Self::new(env::var("STRIP_INBOUND_HEADERS").ok().as_deref())
+	}
+
+	fn new(raw: Option<&str>) -> Self {
+		let strip = raw
+			.unwrap_or_default()
+			.split(',')
+			.map(str::trim)
+			.filter(|name| !name.is_empty())
+			.filter_map(|name| match HeaderName::try_from(name) {
+				Ok(name) => Some(name),
+				Err(e) => {
+					warn!(header = name, error = %e, "ignoring invalid STRIP_INBOUND_HEADERS entry");
+					None
+				}
+			})
+			.collect();
+		Self { strip }
+	}
+
+	fn allows(&self, name: &HeaderName) -> bool {
+		INBOUND_HEADER_ALLOWLIST.contains(&name.as_str()) && !self.strip.contains(name)
+	}
+
+	pub fn apply(&self, headers: &mut HeaderMap) {
+		let dropped: Vec<HeaderName> = headers
+			.keys()
+			.filter(|name| !self.allows(name))
+			.cloned()
+			.collect();
+		for name in dropped {
+			debug!(header = %name, "dropping inbound header");
+			headers.remove(name);
+		}
+	}
+}
+
+pub async fn filter_inbound_headers(
+	State(state): State<Arc<AppState>>,
+	mut req: Request,
+	next: Next,
+) -> Response {
+	state.inbound_headers.apply(req.headers_mut());
+	next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use axum::http::HeaderValue;
+
+	use super::*;
+	use crate::copilot::api::{ClientInfo, copilot_headers};
+
+	fn inbound() -> HeaderMap {
+		let mut h = HeaderMap::new();
+		h.insert("cookie", HeaderValue::from_static("session=secret"));
+		h.insert("x-internal-auth", HeaderValue::from_static("hunter2"));
+		h.insert("x-api-key", HeaderValue::from_static("ghu_token"));
+		h.insert("user-agent", HeaderValue::from_static("claude-cli/1.0"));
+		h
+	}
+
+	fn upstream_for(headers: &HeaderMap) -> HeaderMap {
+		let peer: SocketAddr = "192.0.2.7:51000".parse().unwrap();
+		let info = ClientInfo::from_request(headers, peer);
+		copilot_headers("tok", "1.100.0", "vscode-chat", false, Some(&info))
+	}
+
+	fn contains_value(headers: &HeaderMap, needle: &str) -> bool {
+		headers
+			.values()
+			.any(|v| v.to_str().is_ok_and(|v| v.contains(needle)))
+	}
+
+	#[test]
+	fn sensitive_headers_never_reach_upstream() {
+		let mut headers = inbound();
+		InboundHeaderFilter::new(None).apply(&mut headers);
+
+		assert!(!headers.contains_key("cookie"));
+		assert!(!headers.contains_key("x-internal-auth"));
+		assert_eq!(headers["x-api-key"], "ghu_token");
+
+		let upstream = upstream_for(&headers);
+		assert!(!contains_value(&upstream, "session=secret"));
+		assert!(!contains_value(&upstream, "hunter2"));
+		assert_eq!(upstream["x-forwarded-user-agent"], "claude-cli/1.0");
+	}
+
+	#[test]
+	fn configured_headers_stripped_even_if_allowed() {
+		let mut headers = inbound();
+		InboundHeaderFilter::new(Some("User-Agent, not a header")).apply(&mut headers);
+
+		assert!(!headers.contains_key("user-agent"));
+		assert!(!upstream_for(&headers).contains_key("x-forwarded-user-agent"));
+	}
+}
@@ -1,23 +1,34 @@
 use std::env;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::Router;
 use axum::routing::{get, post};
+use axum::{Router, middleware};
 use tower_http::cors::CorsLayer;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
 use tracing::{Level, error, info, warn};
 
 mod auth;
+mod batches;
+mod cancel;
+mod coalesce;
 mod copilot;
+mod idempotency;
+mod inbound;
 mod rename;
 mod routes;
+mod shadow;
 mod state;
+mod stats;
 mod translate;
+mod warmup;
 
-use auth::token::{initial_token_exchange, spawn_refresh_loop};
+use auth::token::{initial_token_exchange, retry_with_backoff, spawn_refresh_loop};
 use copilot::client::fetch_models;
 use rename::ModelRenamer;
 use state::AppState;
+use warmup::spawn_warmup_loop;
 
 const DEFAULT_VSCODE_VERSION: &str = "1.100.0";
 
@@ -39,21 +50,34 @@ async fn main() {
 		.ok()
 		.and_then(|p| p.parse().ok())
 		.unwrap_or(4141);
+	let bind_addr = match parse_bind_addr(env::var("BIND_ADDR").ok().as_deref()) {
+		Ok(addr) => addr,
+		Err(e) => {
+			error!(error = %e, "invalid BIND_ADDR");
+			std::process::exit(1);
+		}
+	};
 	let account_type = env::var("ACCOUNT_TYPE").unwrap_or_else(|_| "individual".to_string());
 	let vscode_version =
 		env::var("VSCODE_VERSION").unwrap_or_else(|_| DEFAULT_VSCODE_VERSION.to_string());
 
 	let renamer = ModelRenamer::from_env();
-	let state = Arc::new(AppState::new(
-		github_token,
-		account_type,
-		vscode_version,
-		renamer,
-	));
+	let state = match AppState::new(github_token, account_type, vscode_version, renamer) {
+		Ok(state) => Arc::new(state),
+		Err(e) => {
+			error!(error = %format!("{e:#}"), "invalid configuration");
+			std::process::exit(1);
+		}
+	};
 
 	if state.default_github_token.is_some() {
-		if let Err(e) = initial_token_exchange(&state).await {
-			error!(error = %e, "failed to acquire initial copilot token");
+		let retries = state.token_exchange_retries;
+		if let Err(e) = retry_with_backoff(retries, Duration::from_secs(1), || {
+			initial_token_exchange(&state)
+		})
+		.await
+		{
+			error!(error = %e, retries, "failed to acquire initial copilot token");
 			std::process::exit(1);
 		}
 
@@ -70,8 +94,10 @@ async fn main() {
 			match fetch_models(
 				&state.client,
 				&token,
-				&state.account_type,
+				&state.account_type(),
 				&state.vscode_version,
+				&state.copilot_integration_id,
+				state.models_timeout,
 			)
 			.await
 			{
@@ -93,10 +119,7 @@ async fn main() {
 						info!(display = %display_name, upstream = %upstream_name, "mapping");
 					}
 
-					*state.models.write().await = Some(state::CachedModels {
-						response: models,
-						cached_at: std::time::SystemTime::now(),
-					});
+					state.store_models(models).await;
 				}
 				Err(e) => {
 					error!(error = %e, "failed to fetch models (continuing without cache)");
@@ -106,6 +129,7 @@ async fn main() {
 	}
 
 	spawn_refresh_loop(Arc::clone(&state));
+	spawn_warmup_loop(Arc::clone(&state));
 
 	let app = Router::new()
 		.route("/", get(routes::health::health))
@@ -120,6 +144,39 @@ async fn main() {
 		.route("/v1/models", get(routes::models::get_models))
 		.route("/models", get(routes::models::get_models))
 		.route("/v1/messages", post(routes::messages::post_messages))
+		.route("/v1/messages/batches", post(routes::batches::post_batches))
+		.route(
+			"/v1/messages/batches/{batch_id}",
+			get(routes::batches::get_batch),
+		)
+		.route(
+			"/v1/messages/batches/{batch_id}/results",
+			get(routes::batches::get_batch_results),
+		)
+		.route(
+			"/v1/messages/{request_id}/cancel",
+			post(routes::messages::cancel_message),
+		)
+		.route(
+			"/admin/account-type",
+			get(routes::admin::get_account_type).post(routes::admin::post_account_type),
+		)
+		.route(
+			"/admin/renames/reload",
+			post(routes::admin::post_reload_renames),
+		)
+		.route(
+			"/admin/tokens/evict",
+			post(routes::admin::post_evict_tokens),
+		)
+		.route("/debug/stats", get(routes::debug::get_stats))
+		.route("/debug/config", get(routes::debug::get_config))
+		.fallback(routes::health::not_found)
+		.layer(middleware::from_fn_with_state(
+			Arc::clone(&state),
+			inbound::filter_inbound_headers,
+		))
+		.layer(middleware::from_fn(routes::health::version_header))
 		.layer(
 			TraceLayer::new_for_http()
 				.make_span_with(DefaultMakeSpan::new().level(Level::INFO))
@@ -128,15 +185,82 @@ async fn main() {
 		.layer(CorsLayer::permissive())
 		.with_state(state);
 
-	let listener = tokio::net::TcpListener::bind(("::", port))
+	let listener = tokio::net::TcpListener::bind((bind_addr, port))
 		.await
 		.expect("failed to bind");
 
 	info!(
+		address = %bind_addr,
 		port,
 		version = env!("CARGO_PKG_VERSION"),
 		"first-officer listening"
 	);
 
-	axum::serve(listener, app).await.expect("server error");
+	axum::serve(
+		listener,
+		app.into_make_service_with_connect_info::<SocketAddr>(),
+	)
+	.await
+	.expect("server error");
+}
+
+/// Parse `BIND_ADDR`, defaulting to `::` (dual-stack where the OS allows it).
+fn parse_bind_addr(raw: Option<&str>) -> Result<IpAddr, std::net::AddrParseError> {
+	match raw.map(str::trim) {
+		None | Some("") => Ok(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+		Some(addr) => addr
+			.strip_prefix('[')
+			.and_then(|a| a.strip_suffix(']'))
+			.unwrap_or(addr)
+			.parse(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use super::*;
+
+	#[test]
+	fn bind_addr_default_is_dual_stack() {
+		assert_eq!(
+			parse_bind_addr(None).unwrap(),
+			IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+		);
+		assert_eq!(
+			parse_bind_addr(Some("")).unwrap(),
+			IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+		);
+	}
+
+	#[test]
+	fn bind_addr_ipv4() {
+		assert_eq!(
+			parse_bind_addr(Some("0.0.0.0")).unwrap(),
+			IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+		);
+		assert_eq!(
+			parse_bind_addr(Some("127.0.0.1")).unwrap(),
+			IpAddr::V4(Ipv4Addr::LOCALHOST)
+		);
+	}
+
+	#[test]
+	fn bind_addr_ipv6() {
+		assert_eq!(
+			parse_bind_addr(Some("::1")).unwrap(),
+			IpAddr::V6(Ipv6Addr::LOCALHOST)
+		);
+		assert_eq!(
+			parse_bind_addr(Some("[::]")).unwrap(),
+			IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+		);
+	}
+
+	#[test]
+	fn bind_addr_invalid() {
+		assert!(parse_bind_addr(Some("localhost")).is_err());
+		assert!(parse_bind_addr(Some("0.0.0.0:4141")).is_err());
+	}
 }
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::env;
 use std::sync::RwLock;
 
+use serde::Serialize;
 use tracing::info;
 
 /// Bidirectional model name renamer.
@@ -13,11 +14,30 @@ use tracing::info;
 ///
 /// Reverse (resolve): uses a learned map built from the actual model list at startup.
 /// Custom mappings from `MODEL_RENAME_MAP` take priority in both directions.
+///
+/// Aliases (`MODEL_ALIASES`) name a pattern instead of a model, and expand to
+/// the newest matching model in the current list; see [`ModelRenamer::expand_alias`].
+///
+/// The configured rules can be swapped at runtime with [`ModelRenamer::reload`].
 pub struct ModelRenamer {
+	rules: RwLock<RenameRules>,
+	learned_reverse: RwLock<HashMap<String, String>>,
+}
+
+struct RenameRules {
 	auto_enabled: bool,
 	custom_forward: HashMap<String, String>,
 	custom_reverse: HashMap<String, String>,
-	learned_reverse: RwLock<HashMap<String, String>>,
+	aliases: HashMap<String, String>,
+}
+
+/// How many renaming rules of each kind are in effect.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleCounts {
+	pub auto: bool,
+	pub custom: usize,
+	pub aliases: usize,
+	pub learned: usize,
 }
 
 /// Strip date suffix in the format `-YYYYMMDD` from model names.
@@ -115,6 +135,33 @@ fn auto_rename(name: &str) -> Option<String> {
 	}
 }
 
+/// Match a model name against a pattern where `*` stands for any run of characters.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+	let Some((prefix, rest)) = pattern.split_once('*') else {
+		return pattern == name;
+	};
+	let Some(mut remaining) = name.strip_prefix(prefix) else {
+		return false;
+	};
+	let mut pieces: Vec<&str> = rest.split('*').collect();
+	let suffix = pieces.pop().unwrap_or_default();
+	for piece in pieces {
+		match remaining.find(piece) {
+			Some(at) => remaining = &remaining[at + piece.len()..],
+			None => return false,
+		}
+	}
+	remaining.len() >= suffix.len() && remaining.ends_with(suffix)
+}
+
+/// The numbers in a model name, in order, for comparing versions:
+/// `claude-sonnet-4-5` → `[4, 5]`, `claude-sonnet-4.6` → `[4, 6]`.
+fn version_key(name: &str) -> Vec<u64> {
+	name.split(|c: char| !c.is_ascii_digit())
+		.filter_map(|n| n.parse().ok())
+		.collect()
+}
+
 impl ModelRenamer {
 	/// Build from environment variables:
 	///
@@ -122,6 +169,8 @@ impl ModelRenamer {
 	///   Default: enabled.
 	/// - `MODEL_RENAME_MAP` — JSON object `{"upstream-name": "display-name", ...}`
 	///   applied on top of auto rules (custom entries take priority).
+	/// - `MODEL_ALIASES` — JSON object `{"alias": "pattern", ...}` where patterns
+	///   match display names, with `*` as a wildcard.
 	pub fn from_env() -> Self {
 		let auto_enabled = env::var("MODEL_RENAME_AUTO")
 			.map(|v| v != "false")
@@ -138,9 +187,26 @@ impl ModelRenamer {
 			})
 			.unwrap_or_default();
 
-		let custom_reverse: HashMap<String, String> =
-			custom.iter().map(|(k, v)| (v.clone(), k.clone())).collect();
+		let aliases: HashMap<String, String> = env::var("MODEL_ALIASES")
+			.ok()
+			.and_then(|raw| match serde_json::from_str(&raw) {
+				Ok(m) => Some(m),
+				Err(e) => {
+					tracing::warn!(error = %e, "MODEL_ALIASES is not valid JSON, ignoring");
+					None
+				}
+			})
+			.unwrap_or_default();
 
+		Self::new(auto_enabled, custom, aliases)
+	}
+
+	/// Build from rules given directly, as `from_env` reads them.
+	pub fn new(
+		auto_enabled: bool,
+		custom: HashMap<String, String>,
+		aliases: HashMap<String, String>,
+	) -> Self {
 		if auto_enabled || !custom.is_empty() {
 			info!(
 				auto = auto_enabled,
@@ -148,23 +214,62 @@ impl ModelRenamer {
 				"model renaming active"
 			);
 		}
+		if !aliases.is_empty() {
+			info!(aliases = ?aliases, "model aliases active");
+		}
 
 		Self {
-			auto_enabled,
-			custom_forward: custom,
-			custom_reverse,
+			rules: RwLock::new(RenameRules {
+				auto_enabled,
+				custom_reverse: custom.iter().map(|(k, v)| (v.clone(), k.clone())).collect(),
+				custom_forward: custom,
+				aliases,
+			}),
 			learned_reverse: RwLock::new(HashMap::new()),
 		}
 	}
 
+	/// Switch to the rules of `other`, keeping the mappings learned so far.
+	pub fn reload(&self, other: ModelRenamer) {
+		*self.rules.write().unwrap() = other.rules.into_inner().unwrap();
+	}
+
+	pub fn is_alias(&self, name: &str) -> bool {
+		self.rules.read().unwrap().aliases.contains_key(name)
+	}
+
+	/// Expand an alias to the newest of `models` (display names) matching its
+	/// pattern: the one with the highest version numbers, and among equal
+	/// versions the shortest name, so `claude-opus-4-6` wins over `claude-opus-4-6-fast`.
+	///
+	/// Returns None if `name` isn't an alias or nothing matches.
+	pub fn expand_alias<'m>(
+		&self,
+		name: &str,
+		models: impl IntoIterator<Item = &'m str>,
+	) -> Option<String> {
+		let rules = self.rules.read().unwrap();
+		let pattern = rules.aliases.get(name)?;
+		models
+			.into_iter()
+			.filter(|model| glob_match(pattern, model))
+			.max_by(|a, b| {
+				version_key(a)
+					.cmp(&version_key(b))
+					.then_with(|| b.len().cmp(&a.len()))
+			})
+			.map(str::to_string)
+	}
+
 	/// Map an upstream (Copilot) model ID to its display name.
 	/// Custom mappings take priority over auto rules.
 	/// Returns the original name unchanged if nothing matches.
 	pub fn rename(&self, upstream_name: &str) -> String {
-		if let Some(custom) = self.custom_forward.get(upstream_name) {
+		let rules = self.rules.read().unwrap();
+		if let Some(custom) = rules.custom_forward.get(upstream_name) {
 			return custom.clone();
 		}
-		if self.auto_enabled
+		if rules.auto_enabled
 			&& let Some(renamed) = auto_rename(upstream_name)
 		{
 			return renamed;
@@ -186,7 +291,7 @@ impl ModelRenamer {
 	/// Map a display name back to the upstream (Copilot) model ID.
 	/// Priority: custom → learned (from model list) → strip date suffix → pass through.
 	pub fn resolve(&self, display_name: &str) -> String {
-		if let Some(custom) = self.custom_reverse.get(display_name) {
+		if let Some(custom) = self.rules.read().unwrap().custom_reverse.get(display_name) {
 			return custom.clone();
 		}
 		if let Some(learned) = self.learned_reverse.read().unwrap().get(display_name) {
@@ -205,17 +310,23 @@ impl ModelRenamer {
 	}
 
 	pub fn has_rules(&self) -> bool {
-		self.auto_enabled || !self.custom_forward.is_empty()
+		let rules = self.rules.read().unwrap();
+		rules.auto_enabled || !rules.custom_forward.is_empty() || !rules.aliases.is_empty()
 	}
 
-	/// Debug method to inspect learned reverse mappings.
-	pub fn dump_learned(&self) -> Vec<(String, String)> {
-		self.learned_reverse
-			.read()
-			.unwrap()
-			.iter()
-			.map(|(k, v)| (k.clone(), v.clone()))
-			.collect()
+	pub fn rule_counts(&self) -> RuleCounts {
+		let rules = self.rules.read().unwrap();
+		RuleCounts {
+			auto: rules.auto_enabled,
+			custom: rules.custom_forward.len(),
+			aliases: rules.aliases.len(),
+			learned: self.learned_reverse.read().unwrap().len(),
+		}
+	}
+
+	/// Snapshot of the learned display → upstream mappings.
+	pub fn dump_learned(&self) -> HashMap<String, String> {
+		self.learned_reverse.read().unwrap().clone()
 	}
 }
 
@@ -223,21 +334,15 @@ impl ModelRenamer {
 mod tests {
 	use super::*;
 
-	fn renamer(auto: bool, custom: &[(&str, &str)]) -> ModelRenamer {
-		let custom_forward: HashMap<String, String> = custom
+	fn custom_map(custom: &[(&str, &str)]) -> HashMap<String, String> {
+		custom
 			.iter()
 			.map(|(k, v)| (k.to_string(), v.to_string()))
-			.collect();
-		let custom_reverse = custom_forward
-			.iter()
-			.map(|(k, v)| (v.clone(), k.clone()))
-			.collect();
-		ModelRenamer {
-			auto_enabled: auto,
-			custom_forward,
-			custom_reverse,
-			learned_reverse: RwLock::new(HashMap::new()),
-		}
+			.collect()
+	}
+
+	fn renamer(auto: bool, custom: &[(&str, &str)]) -> ModelRenamer {
+		ModelRenamer::new(auto, custom_map(custom), HashMap::new())
 	}
 
 	/// Simulate what main.rs does: rename each model + register.
@@ -494,4 +599,113 @@ mod tests {
 		assert_eq!(replace_version_dots(".5"), ".5");
 		assert_eq!(replace_version_dots("4."), "4.");
 	}
+
+	#[test]
+	fn dump_learned_after_model_list() {
+		let r = renamer(true, &[("gpt-4o", "my-gpt")]);
+		assert!(r.dump_learned().is_empty());
+
+		apply_model_list(
+			&r,
+			&[
+				"claude-3.5-sonnet",
+				"claude-sonnet-4.5",
+				"gpt-4o",
+				"o3-mini",
+			],
+		);
+
+		let learned = r.dump_learned();
+		assert_eq!(
+			learned,
+			HashMap::from([
+				(
+					"claude-sonnet-3-5".to_string(),
+					"claude-3.5-sonnet".to_string()
+				),
+				(
+					"claude-sonnet-4-5".to_string(),
+					"claude-sonnet-4.5".to_string()
+				),
+				("my-gpt".to_string(), "gpt-4o".to_string()),
+			])
+		);
+		for (display, upstream) in &learned {
+			assert_eq!(&r.resolve(display), upstream);
+		}
+	}
+
+	#[test]
+	fn reload_swaps_rules_and_keeps_learned() {
+		let r = renamer(true, &[]);
+		apply_model_list(&r, &["claude-sonnet-4.5", "gpt-4.1"]);
+		assert_eq!(r.resolve("claude-sonnet-4-5"), "claude-sonnet-4.5");
+
+		r.reload(renamer(true, &[("gpt-4.1", "house-model")]));
+		assert_eq!(r.rename("gpt-4.1"), "house-model");
+		assert_eq!(r.resolve("house-model"), "gpt-4.1");
+		assert_eq!(r.resolve("claude-sonnet-4-5"), "claude-sonnet-4.5");
+		assert_eq!(r.rule_counts().custom, 1);
+	}
+
+	// --- aliases ---
+
+	#[test]
+	fn latest_alias_resolves_to_highest_version() {
+		let r = ModelRenamer::new(
+			true,
+			HashMap::new(),
+			custom_map(&[
+				("sonnet-latest", "claude-sonnet-*"),
+				("opus-latest", "claude-opus-*"),
+			]),
+		);
+		let display: Vec<String> = apply_model_list(
+			&r,
+			&[
+				"claude-sonnet-4",
+				"claude-sonnet-4.6",
+				"claude-3.5-sonnet",
+				"claude-sonnet-4.5",
+				"claude-opus-4.6-fast",
+				"claude-opus-4.6",
+				"gpt-5",
+			],
+		)
+		.into_iter()
+		.map(|(_, display)| display)
+		.collect();
+		let models = display.iter().map(String::as_str);
+
+		let sonnet = r.expand_alias("sonnet-latest", models.clone()).unwrap();
+		assert_eq!(sonnet, "claude-sonnet-4-6");
+		assert_eq!(r.resolve(&sonnet), "claude-sonnet-4.6");
+		assert_eq!(
+			r.expand_alias("opus-latest", models.clone()).as_deref(),
+			Some("claude-opus-4-6")
+		);
+		assert_eq!(r.expand_alias("claude-sonnet-4", models), None);
+	}
+
+	#[test]
+	fn alias_without_match_expands_to_nothing() {
+		let r = ModelRenamer::new(
+			true,
+			HashMap::new(),
+			custom_map(&[("haiku-latest", "claude-haiku-*")]),
+		);
+		assert!(r.is_alias("haiku-latest"));
+		assert_eq!(r.expand_alias("haiku-latest", ["claude-sonnet-4-5"]), None);
+	}
+
+	#[test]
+	fn glob_patterns() {
+		assert!(glob_match("claude-sonnet-*", "claude-sonnet-4-5"));
+		assert!(!glob_match("claude-sonnet-*", "claude-opus-4-5"));
+		assert!(glob_match("gpt-*-codex", "gpt-5.1-codex"));
+		assert!(!glob_match("gpt-*-codex", "gpt-5.1-codex-mini"));
+		assert!(glob_match("*mini*", "gpt-5.1-codex-mini"));
+		assert!(glob_match("gpt-5", "gpt-5"));
+		assert!(!glob_match("a*a", "a"));
+	}
 }
@@ -1,4 +1,8 @@
+pub mod admin;
+pub mod batches;
 pub mod completions;
+pub mod debug;
+pub mod error;
 pub mod health;
 pub mod messages;
 pub mod models;
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::Json;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::copilot::api::copilot_base_url;
+use crate::rename::ModelRenamer;
+use crate::routes::error::error_response;
+use crate::state::AppState;
+
+/// Check the `x-admin-token` header against `ADMIN_TOKEN`, returning the
+/// rejection to send if it doesn't match.
+///
+/// Admin endpoints are disabled entirely when `ADMIN_TOKEN` isn't set.
+pub fn reject_unauthorized(state: &AppState, headers: &HeaderMap) -> Option<Response> {
+	let Some(expected) = state.admin_token.as_deref() else {
+		return Some(admin_error(
+			StatusCode::FORBIDDEN,
+			"admin endpoints are disabled — set ADMIN_TOKEN to enable them",
+		));
+	};
+
+	let provided = headers
+		.get("x-admin-token")
+		.and_then(|v| v.to_str().ok())
+		.unwrap_or_default();
+
+	if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+		None
+	} else {
+		warn!("rejected admin request with missing or wrong token");
+		Some(admin_error(StatusCode::FORBIDDEN, "invalid admin token"))
+	}
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn admin_error(status: StatusCode, message: &str) -> Response {
+	error_response(status, "permission_error", message)
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountTypeInfo {
+	pub account_type: String,
+	pub base_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAccountType {
+	pub account_type: String,
+}
+
+pub async fn get_account_type(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+	if let Some(resp) = reject_unauthorized(&state, &headers) {
+		return resp;
+	}
+	Json(account_type_info(&state)).into_response()
+}
+
+pub async fn post_account_type(
+	State(state): State<Arc<AppState>>,
+	headers: HeaderMap,
+	Json(body): Json<SetAccountType>,
+) -> Response {
+	if let Some(resp) = reject_unauthorized(&state, &headers) {
+		return resp;
+	}
+
+	let account_type = body.account_type.trim().to_string();
+	if account_type.is_empty() {
+		return error_response(
+			StatusCode::BAD_REQUEST,
+			"invalid_request_error",
+			"account_type must not be empty",
+		);
+	}
+
+	let previous = state.account_type();
+	state.set_account_type(account_type).await;
+	let info = account_type_info(&state);
+	info!(
+		from = %previous,
+		to = %info.account_type,
+		base_url = %info.base_url,
+		"account type switched, models cache cleared"
+	);
+
+	Json(info).into_response()
+}
+
+/// Renaming rules to switch to, in place of the `MODEL_RENAME_*` and
+/// `MODEL_ALIASES` environment variables.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenameConfig {
+	#[serde(default = "default_auto")]
+	pub auto: bool,
+	#[serde(default)]
+	pub rename_map: HashMap<String, String>,
+	#[serde(default)]
+	pub aliases: HashMap<String, String>,
+}
+
+fn default_auto() -> bool {
+	true
+}
+
+/// Rebuild the model renaming rules from the posted [`RenameConfig`], or from
+/// the environment if the body is empty. Learned mappings are kept, and the
+/// model list cache is cleared so listings pick up the new names.
+pub async fn post_reload_renames(
+	State(state): State<Arc<AppState>>,
+	headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	if let Some(resp) = reject_unauthorized(&state, &headers) {
+		return resp;
+	}
+
+	let renamer = if body.iter().all(u8::is_ascii_whitespace) {
+		ModelRenamer::from_env()
+	} else {
+		match serde_json::from_slice::<RenameConfig>(&body) {
+			Ok(config) => ModelRenamer::new(config.auto, config.rename_map, config.aliases),
+			Err(e) => {
+				return error_response(
+					StatusCode::BAD_REQUEST,
+					"invalid_request_error",
+					format!("invalid rename config: {e}"),
+				);
+			}
+		}
+	};
+
+	state.renamer.reload(renamer);
+	state.clear_models_cache().await;
+	let counts = state.renamer.rule_counts();
+	info!(
+		?counts,
+		"model renaming rules reloaded, models cache cleared"
+	);
+	Json(counts).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvictTokens {
+	/// Only evict the Copilot token exchanged for this GH token.
+	#[serde(default)]
+	pub github_token: Option<String>,
+}
+
+/// Drop cached Copilot tokens, all of them or those for one GH token, so
+/// they're exchanged again on next use, e.g. after rotating credentials.
+pub async fn post_evict_tokens(
+	State(state): State<Arc<AppState>>,
+	headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	if let Some(resp) = reject_unauthorized(&state, &headers) {
+		return resp;
+	}
+
+	let request = if body.iter().all(u8::is_ascii_whitespace) {
+		EvictTokens::default()
+	} else {
+		match serde_json::from_slice::<EvictTokens>(&body) {
+			Ok(request) => request,
+			Err(e) => {
+				return error_response(
+					StatusCode::BAD_REQUEST,
+					"invalid_request_error",
+					format!("invalid eviction request: {e}"),
+				);
+			}
+		}
+	};
+
+	let evicted = state
+		.token_cache
+		.evict(request.github_token.as_deref())
+		.await;
+	info!(
+		evicted,
+		all = request.github_token.is_none(),
+		"copilot tokens evicted"
+	);
+	Json(serde_json::json!({ "evicted": evicted })).into_response()
+}
+
+fn account_type_info(state: &AppState) -> AccountTypeInfo {
+	let account_type = state.account_type();
+	AccountTypeInfo {
+		base_url: copilot_base_url(&account_type),
+		account_type,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::HeaderValue;
+
+	use super::*;
+	use crate::copilot::types::ModelsResponse;
+	use crate::state::CachedModels;
+
+	fn state(admin_token: Option<&str>) -> Arc<AppState> {
+		let mut state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		state.admin_token = admin_token.map(str::to_string);
+		Arc::new(state)
+	}
+
+	fn admin_headers(token: &'static str) -> HeaderMap {
+		let mut h = HeaderMap::new();
+		h.insert("x-admin-token", HeaderValue::from_static(token));
+		h
+	}
+
+	#[tokio::test]
+	async fn swapping_account_type_changes_base_url() {
+		let state = state(Some("secret"));
+		*state.models.write().await = Some(CachedModels {
+			response: ModelsResponse {
+				data: Vec::new(),
+				object: "list".to_string(),
+			},
+			cached_at: std::time::SystemTime::now(),
+		});
+		assert_eq!(
+			account_type_info(&state).base_url,
+			"https://api.githubcopilot.com"
+		);
+
+		let resp = post_account_type(
+			State(Arc::clone(&state)),
+			admin_headers("secret"),
+			Json(SetAccountType {
+				account_type: "enterprise".to_string(),
+			}),
+		)
+		.await;
+
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(state.account_type(), "enterprise");
+		assert_eq!(
+			account_type_info(&state).base_url,
+			"https://api.enterprise.githubcopilot.com"
+		);
+		assert!(state.models.read().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn wrong_admin_token_rejected() {
+		let state = state(Some("secret"));
+		let resp = post_account_type(
+			State(Arc::clone(&state)),
+			admin_headers("guess"),
+			Json(SetAccountType {
+				account_type: "enterprise".to_string(),
+			}),
+		)
+		.await;
+
+		assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+		assert_eq!(state.account_type(), "individual");
+	}
+
+	#[tokio::test]
+	async fn reloading_renames_changes_resolution() {
+		let state = state(Some("secret"));
+		state
+			.renamer
+			.register("claude-sonnet-4.5", "claude-sonnet-4-5");
+
+		let body = serde_json::json!({"rename_map": {"gpt-4.1": "house-model"}});
+		let resp = post_reload_renames(
+			State(Arc::clone(&state)),
+			admin_headers("secret"),
+			Bytes::from(body.to_string()),
+		)
+		.await;
+
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(state.renamer.resolve("house-model"), "gpt-4.1");
+		assert_eq!(state.renamer.rename("gpt-4.1"), "house-model");
+		assert_eq!(
+			state.renamer.resolve("claude-sonnet-4-5"),
+			"claude-sonnet-4.5"
+		);
+
+		let resp = post_reload_renames(
+			State(Arc::clone(&state)),
+			admin_headers("secret"),
+			Bytes::from_static(br#"{"rename_map": {"a": 1}}"#),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+		assert_eq!(state.renamer.resolve("house-model"), "gpt-4.1");
+
+		let resp = post_reload_renames(
+			State(Arc::clone(&state)),
+			admin_headers("guess"),
+			Bytes::new(),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+	}
+
+	#[tokio::test]
+	async fn evicting_tokens() {
+		let state = state(Some("secret"));
+		let evict = |token, body: &'static str| {
+			post_evict_tokens(
+				State(Arc::clone(&state)),
+				admin_headers(token),
+				Bytes::from_static(body.as_bytes()),
+			)
+		};
+
+		let resp = evict("secret", "").await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		assert_eq!(&body[..], br#"{"evicted":0}"#);
+
+		let resp = evict("secret", r#"{"github_token": "gho_abc"}"#).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let resp = evict("secret", r#"{"token": "gho_abc"}"#).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+		let resp = evict("guess", "").await;
+		assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+	}
+
+	#[tokio::test]
+	async fn admin_disabled_without_token() {
+		let state = state(None);
+		let resp = get_account_type(State(state), admin_headers("")).await;
+		assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+	}
+}
@@ -0,0 +1,366 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::Json;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::auth::resolve::{request_credentials, resolve_copilot_token};
+use crate::batches::{BatchOwner, BatchResult};
+use crate::routes::error::error_response;
+use crate::routes::messages::{create_message, deserialize_with_path};
+use crate::state::AppState;
+use crate::translate::types::MessagesRequest;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBatch {
+	pub requests: Vec<BatchRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+	pub custom_id: String,
+	/// Kept as JSON so each request can be shadowed with its own body.
+	pub params: serde_json::Value,
+}
+
+/// A request in a batch, parsed and as it was sent.
+type Params = (MessagesRequest, Bytes);
+
+/// Check every request in the batch up front, as Anthropic does, so a bad one
+/// fails the whole submission rather than just its result.
+fn validate(batch: CreateBatch) -> Result<Vec<(String, Params)>, String> {
+	if batch.requests.is_empty() {
+		return Err("requests: must not be empty".to_string());
+	}
+
+	let mut seen = HashSet::new();
+	let mut requests = Vec::with_capacity(batch.requests.len());
+	for (i, request) in batch.requests.into_iter().enumerate() {
+		if !seen.insert(request.custom_id.clone()) {
+			return Err(format!(
+				"requests[{i}].custom_id: duplicate custom_id {}",
+				request.custom_id
+			));
+		}
+		let raw_body = Bytes::from(request.params.to_string());
+		let params = deserialize_with_path::<MessagesRequest>(&raw_body)
+			.map_err(|e| format!("requests[{i}].params.{e}"))?;
+		if params.stream == Some(true) {
+			return Err(format!(
+				"requests[{i}].params.stream: streaming is not supported in batches"
+			));
+		}
+		requests.push((request.custom_id, (params, raw_body)));
+	}
+	Ok(requests)
+}
+
+/// `POST /v1/messages/batches`: answer each request in the background, as
+/// `POST /v1/messages` would, for clients of Anthropic's Message Batches API.
+pub async fn post_batches(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	mut headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	let requests = match deserialize_with_path::<CreateBatch>(&body)
+		.map_err(|e| e.to_string())
+		.and_then(validate)
+	{
+		Ok(requests) => requests,
+		Err(message) => {
+			warn!(error = %message, "rejecting message batch");
+			return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message);
+		}
+	};
+
+	// Fail fast on bad credentials, rather than with every result
+	if let Err(resp) = resolve_copilot_token(&state, &headers).await {
+		return resp;
+	}
+	let owner = batch_owner(&state, &headers);
+
+	// A batch-wide key would make every request replay the first answer
+	headers.remove("x-idempotency-key");
+	headers.remove("idempotency-key");
+
+	let processing = Arc::clone(&state);
+	let batch = state
+		.batches
+		.submit(owner, requests, move |(req, raw_body): Params| {
+			let (state, headers) = (Arc::clone(&processing), headers.clone());
+			async move { batch_result(create_message(state, peer, headers, req, raw_body).await).await }
+		});
+	match batch {
+		Ok(batch) => Json(batch).into_response(),
+		Err(e) => {
+			warn!(error = %e, "rejecting message batch");
+			error_response(
+				StatusCode::TOO_MANY_REQUESTS,
+				"rate_limit_error",
+				e.to_string(),
+			)
+		}
+	}
+}
+
+/// Who a batch belongs to: a hash of the credentials it was submitted with,
+/// so that only they can read it back.
+fn batch_owner(state: &AppState, headers: &HeaderMap) -> BatchOwner {
+	let credentials = request_credentials(state, headers).unwrap_or_default();
+	Sha256::digest(credentials.as_bytes()).into()
+}
+
+async fn batch_result(resp: Response) -> BatchResult {
+	let status = resp.status();
+	let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+		.await
+		.unwrap_or_default();
+	let json = serde_json::from_slice(&body).ok();
+	match json {
+		Some(message) if status.is_success() => BatchResult::Succeeded { message },
+		Some(error) => BatchResult::Errored { error },
+		None => BatchResult::Errored {
+			error: serde_json::json!({
+				"type": "error",
+				"error": {
+					"type": "api_error",
+					"message": format!("request failed with status {status}")
+				}
+			}),
+		},
+	}
+}
+
+fn unknown_batch(batch_id: &str) -> Response {
+	error_response(
+		StatusCode::NOT_FOUND,
+		"not_found_error",
+		format!("no message batch with id {batch_id}"),
+	)
+}
+
+/// Batches submitted with other credentials are reported as unknown.
+pub async fn get_batch(
+	State(state): State<Arc<AppState>>,
+	headers: HeaderMap,
+	Path(batch_id): Path<String>,
+) -> Response {
+	match state.batches.get(&batch_owner(&state, &headers), &batch_id) {
+		Some(batch) => Json(batch).into_response(),
+		None => unknown_batch(&batch_id),
+	}
+}
+
+/// The results of an ended batch, one JSON object per line.
+pub async fn get_batch_results(
+	State(state): State<Arc<AppState>>,
+	headers: HeaderMap,
+	Path(batch_id): Path<String>,
+) -> Response {
+	let owner = batch_owner(&state, &headers);
+	let Some(results) = state.batches.results(&owner, &batch_id) else {
+		return match state.batches.get(&owner, &batch_id) {
+			Some(_) => error_response(
+				StatusCode::BAD_REQUEST,
+				"invalid_request_error",
+				format!("message batch {batch_id} is still in progress"),
+			),
+			None => unknown_batch(&batch_id),
+		};
+	};
+
+	let mut body = String::new();
+	for line in results {
+		body.push_str(&serde_json::to_string(&line).expect("results serialize"));
+		body.push('\n');
+	}
+	([(header::CONTENT_TYPE, "application/x-jsonl")], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::rename::ModelRenamer;
+
+	fn state() -> Arc<AppState> {
+		Arc::new(
+			AppState::new(
+				None,
+				"individual".to_string(),
+				"1.100.0".to_string(),
+				ModelRenamer::from_env(),
+			)
+			.unwrap(),
+		)
+	}
+
+	async fn body_json(resp: Response) -> serde_json::Value {
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		serde_json::from_slice(&bytes).unwrap()
+	}
+
+	fn batch(requests: serde_json::Value) -> Result<Vec<String>, String> {
+		let batch = serde_json::from_value(serde_json::json!({ "requests": requests })).unwrap();
+		validate(batch).map(|requests| requests.into_iter().map(|(id, _)| id).collect())
+	}
+
+	#[test]
+	fn batches_validated_up_front() {
+		let params = serde_json::json!({
+			"model": "claude-sonnet-4",
+			"max_tokens": 10,
+			"messages": [{"role": "user", "content": "hi"}]
+		});
+		let mut streaming = params.clone();
+		streaming["stream"] = true.into();
+
+		assert_eq!(
+			batch(serde_json::json!([
+				{"custom_id": "a", "params": params},
+				{"custom_id": "b", "params": params}
+			])),
+			Ok(vec!["a".to_string(), "b".to_string()])
+		);
+		assert_eq!(
+			batch(serde_json::json!([])),
+			Err("requests: must not be empty".to_string())
+		);
+		let err = batch(serde_json::json!([
+			{"custom_id": "a", "params": params},
+			{"custom_id": "a", "params": params}
+		]))
+		.unwrap_err();
+		assert!(err.starts_with("requests[1].custom_id: duplicate"), "{err}");
+		let err = batch(serde_json::json!([
+			{"custom_id": "a", "params": {"model": "claude-sonnet-4", "messages": []}}
+		]))
+		.unwrap_err();
+		assert!(err.starts_with("requests[0].params."), "{err}");
+		assert!(err.contains("max_tokens"), "{err}");
+		let err = batch(serde_json::json!([{"custom_id": "a", "params": streaming}])).unwrap_err();
+		assert!(err.starts_with("requests[0].params.stream"), "{err}");
+	}
+
+	#[tokio::test]
+	async fn polling_a_batch_to_completion() {
+		let state = state();
+		let requests = vec![("first".to_string(), "hi"), ("second".to_string(), "")];
+		let owner = batch_owner(&state, &HeaderMap::new());
+		let batch = state
+			.batches
+			.submit(owner, requests, |text: &str| async move {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+				let status = if text.is_empty() {
+					StatusCode::BAD_REQUEST
+				} else {
+					StatusCode::OK
+				};
+				let resp = (status, Json(serde_json::json!({ "text": text }))).into_response();
+				batch_result(resp).await
+			});
+		let batch = batch.unwrap();
+
+		let resp = get_batch_results(
+			State(Arc::clone(&state)),
+			HeaderMap::new(),
+			Path(batch.id.clone()),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+		let mut polled = serde_json::Value::Null;
+		for _ in 0..200 {
+			polled = body_json(
+				get_batch(
+					State(Arc::clone(&state)),
+					HeaderMap::new(),
+					Path(batch.id.clone()),
+				)
+				.await,
+			)
+			.await;
+			if polled["processing_status"] == "ended" {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(5)).await;
+		}
+		assert_eq!(polled["processing_status"], "ended", "{polled}");
+		assert_eq!(polled["type"], "message_batch");
+		assert_eq!(polled["request_counts"]["succeeded"], 1);
+		assert_eq!(polled["request_counts"]["errored"], 1);
+		assert_eq!(polled["request_counts"]["processing"], 0);
+
+		let mut other = HeaderMap::new();
+		other.insert("x-api-key", "ghp_someone_else".parse().unwrap());
+		let resp = get_batch(
+			State(Arc::clone(&state)),
+			other.clone(),
+			Path(batch.id.clone()),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+		let resp =
+			get_batch_results(State(Arc::clone(&state)), other, Path(batch.id.clone())).await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+		let resp = get_batch_results(
+			State(Arc::clone(&state)),
+			HeaderMap::new(),
+			Path(batch.id.clone()),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let lines: Vec<serde_json::Value> = String::from_utf8(bytes.to_vec())
+			.unwrap()
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		assert_eq!(
+			lines,
+			[
+				serde_json::json!({
+					"custom_id": "first",
+					"result": {"type": "succeeded", "message": {"text": "hi"}}
+				}),
+				serde_json::json!({
+					"custom_id": "second",
+					"result": {"type": "errored", "error": {"text": ""}}
+				}),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn unknown_batch_is_404() {
+		let state = state();
+		let resp = get_batch(
+			State(Arc::clone(&state)),
+			HeaderMap::new(),
+			Path("msgbatch_nope".to_string()),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+		let resp = get_batch_results(
+			State(state),
+			HeaderMap::new(),
+			Path("msgbatch_nope".to_string()),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+	}
+}
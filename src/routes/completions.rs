@@ -1,19 +1,23 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use axum::body::Body;
-use axum::extract::State;
+use axum::extract::{ConnectInfo, State};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use futures::StreamExt;
 use tracing::{debug, error, info};
 
 use crate::auth::resolve::resolve_copilot_token;
+use crate::copilot::api::ClientInfo;
 use crate::copilot::client::chat_completions_raw;
 use crate::copilot::types::ChatCompletionsRequest;
+use crate::routes::messages::tools_disabled;
 use crate::state::AppState;
 
 pub async fn post_completions(
 	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
 	headers: HeaderMap,
 	body: axum::body::Bytes,
 ) -> Response {
@@ -22,12 +26,17 @@ pub async fn post_completions(
 		Err(resp) => return resp,
 	};
 
-	let body = resolve_model_name(&state, &body);
+	let mut body = resolve_model_name(&state, &body).await;
+	if tools_disabled(&headers) {
+		debug!("tools disabled for this request");
+		body = strip_tools(&body);
+	}
 	let vision = detect_vision(&body);
 	let is_agent = detect_agent(&body);
 
 	// Log the incoming request
-	if let Ok(req) = serde_json::from_slice::<ChatCompletionsRequest>(&body) {
+	let req = serde_json::from_slice::<ChatCompletionsRequest>(&body).ok();
+	if let Some(ref req) = req {
 		let is_streaming = req.stream.unwrap_or(false);
 		info!(
 			model = %req.model,
@@ -44,20 +53,28 @@ pub async fn post_completions(
 		);
 	}
 
+	let model = req.map(|r| r.model).unwrap_or_default();
+	state.model_stats.record_request(&model);
+	let client_info = state
+		.forward_client_info
+		.then(|| ClientInfo::from_request(&headers, peer));
 	let resp = chat_completions_raw(
 		&state.client,
 		&copilot_token,
-		&state.account_type,
-		&state.vscode_version,
+		&state.account_type(),
+		state.editor_version(&model),
+		&state.copilot_integration_id,
 		&body,
 		vision,
 		is_agent,
+		client_info.as_ref(),
 	)
 	.await;
 
 	let upstream = match resp {
 		Ok(r) => r,
 		Err(e) => {
+			state.model_stats.record_failure(&model, &e);
 			error!(error = %e, "copilot chat completions request failed");
 			return StatusCode::BAD_GATEWAY.into_response();
 		}
@@ -131,17 +148,83 @@ fn detect_agent(body: &[u8]) -> bool {
 
 /// If the request's model name is a renamed display name, swap it back to the
 /// upstream Copilot model ID before forwarding.
-fn resolve_model_name(state: &AppState, body: &[u8]) -> Vec<u8> {
+async fn resolve_model_name(state: &AppState, body: &[u8]) -> Vec<u8> {
 	if !state.renamer.has_rules() {
 		return body.to_vec();
 	}
 	let Ok(mut req) = serde_json::from_slice::<ChatCompletionsRequest>(body) else {
 		return body.to_vec();
 	};
-	let resolved = state.renamer.resolve(&req.model);
+	let resolved = state.resolve_model(&req.model).await;
 	if resolved == req.model {
 		return body.to_vec();
 	}
 	req.model = resolved;
 	serde_json::to_vec(&req).unwrap_or_else(|_| body.to_vec())
 }
+
+/// Remove the request's tools, and tell upstream not to call any.
+fn strip_tools(body: &[u8]) -> Vec<u8> {
+	let Ok(serde_json::Value::Object(mut req)) = serde_json::from_slice(body) else {
+		return body.to_vec();
+	};
+	req.remove("tools");
+	req.insert("tool_choice".to_string(), "none".into());
+	// Upstream rejects it without tools
+	req.remove("parallel_tool_calls");
+	serde_json::to_vec(&req).unwrap_or_else(|_| body.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rename::ModelRenamer;
+
+	#[test]
+	fn tools_stripped_when_disabled() {
+		let body = serde_json::to_vec(&serde_json::json!({
+			"model": "gpt-4.1",
+			"messages": [{"role": "user", "content": "hi"}],
+			"tools": [{"type": "function", "function": {"name": "ls", "parameters": {}}}],
+			"tool_choice": "required",
+			"parallel_tool_calls": true,
+			"seed": 7
+		}))
+		.unwrap();
+
+		let stripped: serde_json::Value = serde_json::from_slice(&strip_tools(&body)).unwrap();
+		assert!(stripped.get("tools").is_none());
+		assert_eq!(stripped["tool_choice"], "none");
+		assert!(stripped.get("parallel_tool_calls").is_none());
+		assert_eq!(stripped["seed"], 7);
+	}
+
+	#[tokio::test]
+	async fn response_format_survives_model_resolution() {
+		let state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		state
+			.renamer
+			.register("claude-sonnet-4.5", "claude-sonnet-4-5");
+		let response_format = serde_json::json!({
+			"type": "json_schema",
+			"json_schema": {"name": "answer", "schema": {"type": "object"}}
+		});
+		let body = serde_json::to_vec(&serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"messages": [{"role": "user", "content": "hi"}],
+			"response_format": response_format,
+		}))
+		.unwrap();
+
+		let resolved: serde_json::Value =
+			serde_json::from_slice(&resolve_model_name(&state, &body).await).unwrap();
+		assert_eq!(resolved["model"], "claude-sonnet-4.5");
+		assert_eq!(resolved["response_format"], response_format);
+	}
+}
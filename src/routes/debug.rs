@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+
+use crate::state::AppState;
+
+/// The settings in effect, as resolved from the environment. Secrets are only
+/// reported as set or not.
+pub async fn get_config(State(state): State<Arc<AppState>>) -> Response {
+	if !state.debug_endpoints {
+		return StatusCode::NOT_FOUND.into_response();
+	}
+
+	let redacted = |secret: Option<&String>| secret.map(|_| "[redacted]");
+	Json(serde_json::json!({
+		"account_type": state.account_type(),
+		"vscode_version": state.vscode_version,
+		"editor_versions": state.editor_versions,
+		"copilot_integration_id": state.copilot_integration_id,
+		"github_token": redacted(state.default_github_token.as_ref()),
+		"admin_token": redacted(state.admin_token.as_ref()),
+		"copilot_token_prefix": state.copilot_token_prefix,
+		"accept_base64_keys": state.accept_base64_keys,
+		"models_cache_ttl_secs": state.models_cache_ttl.as_secs(),
+		"models_timeout_secs": state.models_timeout.as_secs(),
+		"messages_soft_timeout_secs": state.messages_soft_timeout.map(|t| t.as_secs()),
+		"request_timeout_min_secs": state.request_timeout_min.as_secs(),
+		"request_timeout_max_secs": state.request_timeout_max.as_secs(),
+		"token_refresh_floor_secs": state.token_refresh_floor.as_secs(),
+		"token_exchange_retries": state.token_exchange_retries,
+		"warmup_interval_secs": state.warmup_interval.map(|t| t.as_secs()),
+		"emulate_thinking": state.emulate_thinking,
+		"strip_tool_preamble": state.strip_tool_preamble,
+		"use_pause_turn": state.use_pause_turn,
+		"drop_thinking": state.drop_thinking,
+		"usage_headers": state.usage_headers,
+		"missing_usage": state.missing_usage,
+		"unstreamable_models": state.unstreamable_models,
+		"derive_user_from_token": state.derive_user_from_token,
+		"forward_client_info": state.forward_client_info,
+		"stream_coalesce": state.stream_coalesce,
+		"idempotency_ttl_secs": state.idempotency.ttl().as_secs(),
+		"idempotency_cache_size": state.idempotency.capacity(),
+		"batch_ttl_secs": state.batches.ttl().as_secs(),
+		"batch_concurrency": state.batches.concurrency(),
+		"batch_max_stored": state.batches.capacity(),
+		"shadow_backend": state.shadow_backend.is_some(),
+		"hidden_models": state.hidden_models,
+		"sort_models": state.sort_models,
+		"model_display_template": state.model_display_template,
+		"renames": state.renamer.rule_counts(),
+		"translate": state.translate_options,
+	}))
+	.into_response()
+}
+
+/// Per-model upstream request and rate-limit counts, and how fresh the model
+/// list cache is.
+pub async fn get_stats(State(state): State<Arc<AppState>>) -> Response {
+	if !state.debug_endpoints {
+		return StatusCode::NOT_FOUND.into_response();
+	}
+
+	let cache_age = state.models_cache_age().await;
+	Json(serde_json::json!({
+		"models": state.model_stats.snapshot(),
+		"models_cache": {
+			"age_secs": cache_age.map(|age| age.as_secs()),
+			"fresh": cache_age.is_some_and(|age| age < state.models_cache_ttl),
+		},
+	}))
+	.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::{Duration, SystemTime};
+
+	use crate::copilot::client::UpstreamStatusError;
+	use crate::copilot::types::ModelsResponse;
+	use crate::rename::ModelRenamer;
+
+	fn state(debug_endpoints: bool) -> Arc<AppState> {
+		let mut state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		state.debug_endpoints = debug_endpoints;
+		Arc::new(state)
+	}
+
+	async fn body_json(resp: Response) -> serde_json::Value {
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		serde_json::from_slice(&bytes).unwrap()
+	}
+
+	#[tokio::test]
+	async fn stats_reflect_rate_limited_requests() {
+		let state = state(true);
+		for _ in 0..2 {
+			state.model_stats.record_request("gpt-4o");
+			state.model_stats.record_failure(
+				"gpt-4o",
+				&UpstreamStatusError {
+					status: StatusCode::TOO_MANY_REQUESTS,
+					body: "slow down".to_string(),
+				}
+				.into(),
+			);
+		}
+		state.model_stats.record_request("gpt-4o");
+
+		let resp = get_stats(State(state)).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let json = body_json(resp).await;
+		assert_eq!(json["models"]["gpt-4o"]["requests"], 3);
+		assert_eq!(json["models"]["gpt-4o"]["rate_limited"], 2);
+	}
+
+	#[tokio::test]
+	async fn stats_report_models_cache_freshness() {
+		let state = state(true);
+		let cache = || async {
+			body_json(get_stats(State(Arc::clone(&state))).await).await["models_cache"].clone()
+		};
+		assert_eq!(
+			cache().await,
+			serde_json::json!({"age_secs": null, "fresh": false})
+		);
+
+		state
+			.store_models(ModelsResponse {
+				data: Vec::new(),
+				object: "list".to_string(),
+			})
+			.await;
+		assert_eq!(
+			cache().await,
+			serde_json::json!({"age_secs": 0, "fresh": true})
+		);
+
+		let expired = state.models_cache_ttl + Duration::from_secs(5);
+		state.models.write().await.as_mut().unwrap().cached_at = SystemTime::now() - expired;
+		assert_eq!(
+			cache().await,
+			serde_json::json!({"age_secs": expired.as_secs(), "fresh": false})
+		);
+	}
+
+	#[tokio::test]
+	async fn stats_hidden_unless_enabled() {
+		let resp = get_stats(State(state(false))).await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+		let resp = get_config(State(state(false))).await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn config_redacts_secrets() {
+		let mut state = AppState::new(
+			Some("ghu_supersecret".to_string()),
+			"business".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		state.debug_endpoints = true;
+		state.admin_token = Some("admin-supersecret".to_string());
+
+		let resp = get_config(State(Arc::new(state))).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let json = body_json(resp).await;
+		assert_eq!(json["account_type"], "business");
+		assert_eq!(json["github_token"], "[redacted]");
+		assert_eq!(json["admin_token"], "[redacted]");
+		assert_eq!(json["renames"]["auto"], true);
+		assert_eq!(json["translate"]["tools_over_limit"], "reject");
+		for key in [
+			"vscode_version",
+			"models_cache_ttl_secs",
+			"emulate_thinking",
+			"stream_coalesce",
+		] {
+			assert!(json.get(key).is_some(), "missing {key}");
+		}
+		assert!(!json.to_string().contains("supersecret"), "{json}");
+	}
+}
@@ -0,0 +1,138 @@
+use axum::Json;
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use tracing::warn;
+
+/// An error response in Anthropic's envelope.
+pub fn error_response(status: StatusCode, r#type: &str, message: impl Into<String>) -> Response {
+	(
+		status,
+		Json(serde_json::json!({
+			"type": "error",
+			"error": {
+				"type": r#type,
+				"message": message.into()
+			}
+		})),
+	)
+		.into_response()
+}
+
+/// The error envelope a client wants, from `x-error-format`, for bridges
+/// that speak Anthropic's API but parse errors as OpenAI's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+	#[default]
+	Anthropic,
+	OpenAi,
+}
+
+impl ErrorFormat {
+	pub fn from_headers(headers: &HeaderMap) -> Self {
+		match headers
+			.get("x-error-format")
+			.and_then(|v| v.to_str().ok())
+			.map(str::trim)
+		{
+			None | Some("anthropic") => Self::Anthropic,
+			Some("openai") => Self::OpenAi,
+			Some(other) => {
+				warn!(value = other, "unknown x-error-format, using anthropic");
+				Self::Anthropic
+			}
+		}
+	}
+
+	/// Re-render an error response in this format. Successful responses,
+	/// including streams, pass through untouched.
+	pub async fn render(self, resp: Response) -> Response {
+		if self == Self::Anthropic || resp.status().is_success() {
+			return resp;
+		}
+
+		let (mut parts, body) = resp.into_parts();
+		let body = axum::body::to_bytes(body, usize::MAX)
+			.await
+			.unwrap_or_default();
+		let error = serde_json::from_slice::<serde_json::Value>(&body)
+			.ok()
+			.and_then(|json| json.get("error").cloned())
+			.unwrap_or_default();
+		let r#type = error["type"].as_str().unwrap_or("api_error");
+		let message = error["message"]
+			.as_str()
+			.or(parts.status.canonical_reason())
+			.unwrap_or("error");
+
+		let body = serde_json::json!({
+			"error": {
+				"message": message,
+				"type": r#type,
+				"param": null,
+				"code": null
+			}
+		});
+
+		parts.headers.remove(header::CONTENT_LENGTH);
+		parts.headers.insert(
+			header::CONTENT_TYPE,
+			HeaderValue::from_static("application/json"),
+		);
+		Response::from_parts(parts, Body::from(body.to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	async fn json(resp: Response) -> serde_json::Value {
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		serde_json::from_slice(&bytes).unwrap()
+	}
+
+	#[tokio::test]
+	async fn openai_envelope() {
+		let resp = error_response(
+			StatusCode::UNAUTHORIZED,
+			"authentication_error",
+			"bad token",
+		);
+		let resp = ErrorFormat::OpenAi.render(resp).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+		let body = json(resp).await;
+		assert!(body.get("type").is_none(), "{body}");
+		assert_eq!(body["error"]["type"], "authentication_error");
+		assert_eq!(body["error"]["message"], "bad token");
+		assert!(body["error"]["code"].is_null());
+
+		// Bare statuses get a body too
+		let resp = ErrorFormat::OpenAi
+			.render(StatusCode::BAD_GATEWAY.into_response())
+			.await;
+		assert_eq!(json(resp).await["error"]["message"], "Bad Gateway");
+	}
+
+	#[tokio::test]
+	async fn success_passes_through() {
+		let resp = ErrorFormat::OpenAi.render("ok".into_response()).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		assert_eq!(&body[..], b"ok");
+	}
+
+	#[test]
+	fn format_from_header() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(ErrorFormat::from_headers(&headers), ErrorFormat::Anthropic);
+		headers.insert("x-error-format", "openai".parse().unwrap());
+		assert_eq!(ErrorFormat::from_headers(&headers), ErrorFormat::OpenAi);
+		headers.insert("x-error-format", "anthropic".parse().unwrap());
+		assert_eq!(ErrorFormat::from_headers(&headers), ErrorFormat::Anthropic);
+	}
+}
@@ -1,5 +1,79 @@
-use axum::http::StatusCode;
+use axum::extract::Request;
+use axum::http::{HeaderValue, Method, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::routes::error::error_response;
+
+/// The crate version, with the commit it was built from when
+/// `FIRST_OFFICER_GIT_HASH` was set at build time.
+fn build_version() -> String {
+	let version = env!("CARGO_PKG_VERSION");
+	match option_env!("FIRST_OFFICER_GIT_HASH") {
+		Some(hash) if !hash.is_empty() => format!("{version}+{}", &hash[..hash.len().min(12)]),
+		_ => version.to_string(),
+	}
+}
 
 pub async fn health() -> StatusCode {
 	StatusCode::OK
 }
+
+/// Fallback for unknown routes, with an error body Anthropic SDKs can parse.
+pub async fn not_found(method: Method, uri: Uri) -> Response {
+	error_response(
+		StatusCode::NOT_FOUND,
+		"not_found_error",
+		format!("Unknown endpoint: {method} {}", uri.path()),
+	)
+}
+
+/// Tag every response with the build that served it, as
+/// `x-first-officer-version`.
+pub async fn version_header(req: Request, next: Next) -> Response {
+	let mut resp = next.run(req).await;
+	if let Ok(version) = HeaderValue::from_str(&build_version()) {
+		resp.headers_mut()
+			.insert("x-first-officer-version", version);
+	}
+	resp
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::Router;
+	use axum::middleware;
+	use axum::routing::get;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn health_carries_version_header() {
+		let app = Router::new()
+			.route("/", get(health))
+			.layer(middleware::from_fn(version_header));
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+		let resp = reqwest::get(url).await.unwrap();
+		assert_eq!(resp.status(), StatusCode::OK);
+		let version = resp.headers()["x-first-officer-version"].to_str().unwrap();
+		assert!(version.starts_with(env!("CARGO_PKG_VERSION")), "{version}");
+	}
+
+	#[tokio::test]
+	async fn unknown_route_is_anthropic_404() {
+		let app = Router::new().route("/", get(health)).fallback(not_found);
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/v1/nope?x=1", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+		let resp = reqwest::Client::new().post(url).send().await.unwrap();
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+		let json: serde_json::Value = resp.json().await.unwrap();
+		assert_eq!(json["type"], "error");
+		assert_eq!(json["error"]["type"], "not_found_error");
+		assert_eq!(json["error"]["message"], "Unknown endpoint: POST /v1/nope");
+	}
+}
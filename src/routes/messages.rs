@@ -1,25 +1,52 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::Json;
-use axum::extract::{FromRequest, Request, State};
-use axum::http::{HeaderMap, StatusCode};
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, FromRequest, Path, Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use futures::StreamExt;
 use futures::stream::Stream;
+use serde::Deserialize;
 use tracing::{debug, error, info, warn};
 
-use crate::auth::resolve::resolve_copilot_token;
-use crate::copilot::client::chat_completions_raw;
-use crate::copilot::types::ChatCompletionChunk;
+use crate::auth::extract::{extract_copilot_token, extract_gh_token};
+use crate::auth::resolve::{request_credentials, resolve_copilot_token};
+use crate::cancel::StreamOwner;
+use crate::coalesce::coalesce_key;
+use crate::copilot::api::ClientInfo;
+use crate::copilot::client::{UpstreamStatusError, chat_completions_raw};
+use crate::copilot::types::{
+	ChatCompletionChunk, ChatCompletionResponse, ChatCompletionsRequest, ModelCapabilities,
+	ModelsResponse, ToolChoice,
+};
+use crate::idempotency::{IdempotencyKey, idempotency_key};
+use crate::routes::error::{ErrorFormat, error_response};
+use crate::shadow::MessageSummary;
 use crate::state::AppState;
-use crate::translate::request::{has_vision_content, is_agent_call, translate_request};
-use crate::translate::response::translate_response;
-use crate::translate::stream::translate_chunk;
-use crate::translate::types::{MessagesRequest, StreamState};
+use crate::translate::models::{ModelFamily, model_family};
+use crate::translate::request::{
+	derive_user, documents_as_notes, estimate_prompt_tokens, has_vision_content, is_agent_call,
+	renamed_tools, translate_request,
+};
+use crate::translate::response::{
+	MissingUsage, drop_thinking_blocks, estimate_output_tokens, restore_tool_names,
+	translate_response,
+};
+use crate::translate::stream::{
+	UnstreamableModels, error_event, finish_stream, response_events, translate_chunk,
+	upstream_error_event,
+};
+use crate::translate::types::{MessagesRequest, StreamEvent, StreamState};
 
-pub struct JsonWithLogging<T>(T);
+/// A JSON body, along with its raw bytes.
+pub struct JsonWithLogging<T>(T, Bytes);
 
 impl<T> FromRequest<Arc<AppState>> for JsonWithLogging<T>
 where
@@ -33,48 +60,87 @@ where
 			Ok(b) => b,
 			Err(e) => {
 				error!(error = %e, "failed to read request body");
-				return Err((
+				return Err(error_response(
 					StatusCode::BAD_REQUEST,
-					Json(serde_json::json!({
-						"type": "error",
-						"error": {
-							"type": "invalid_request_error",
-							"message": format!("failed to read request body: {e}")
-						}
-					})),
-				)
-					.into_response());
+					"invalid_request_error",
+					format!("failed to read request body: {e}"),
+				));
 			}
 		};
 
-		match serde_json::from_slice::<T>(&bytes) {
-			Ok(value) => Ok(JsonWithLogging(value)),
+		match deserialize_with_path::<T>(&bytes) {
+			Ok(value) => Ok(JsonWithLogging(value, bytes)),
 			Err(e) => {
 				error!(
 					error = %e,
 					body = %String::from_utf8_lossy(&bytes),
 					"failed to deserialize request body"
 				);
-				Err((
+				Err(error_response(
 					StatusCode::UNPROCESSABLE_ENTITY,
-					Json(serde_json::json!({
-						"type": "error",
-						"error": {
-							"type": "invalid_request_error",
-							"message": format!("Failed to deserialize the JSON body into the target type: {e}")
-						}
-					})),
-				)
-					.into_response())
+					"invalid_request_error",
+					format!("Failed to deserialize the JSON body into the target type: {e}"),
+				))
 			}
 		}
 	}
 }
 
+/// Anthropic requires at least one token, and there's no point asking for
+/// more than any of the cached models can write.
+fn check_max_tokens(
+	max_tokens: u64,
+	capabilities: &HashMap<String, ModelCapabilities>,
+) -> Result<(), String> {
+	if max_tokens == 0 {
+		return Err("max_tokens: must be greater than or equal to 1".to_string());
+	}
+	let largest = capabilities
+		.values()
+		.filter_map(|caps| caps.limits.as_ref()?.max_output_tokens)
+		.max();
+	if let Some(largest) = largest
+		&& max_tokens > largest
+	{
+		return Err(format!(
+			"max_tokens: {max_tokens} is more than any available model can produce (at most {largest})"
+		));
+	}
+	Ok(())
+}
+
+/// Deserialize a JSON body, prefixing errors with the path of the offending
+/// field (e.g. `tools[1].name: invalid type: ...`).
+pub fn deserialize_with_path<T: serde::de::DeserializeOwned>(
+	bytes: &[u8],
+) -> Result<T, serde_path_to_error::Error<serde_json::Error>> {
+	let de = &mut serde_json::Deserializer::from_slice(bytes);
+	serde_path_to_error::deserialize(de)
+}
+
 pub async fn post_messages(
 	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	body: Result<JsonWithLogging<MessagesRequest>, Response>,
+) -> Response {
+	let format = ErrorFormat::from_headers(&headers);
+	let resp = match body {
+		Ok(JsonWithLogging(req, raw_body)) => {
+			create_message(state, peer, headers, req, raw_body).await
+		}
+		Err(rejection) => rejection,
+	};
+	format.render(resp).await
+}
+
+/// Answer a single Messages API request, in Anthropic's error format.
+pub async fn create_message(
+	state: Arc<AppState>,
+	peer: SocketAddr,
 	headers: HeaderMap,
-	JsonWithLogging(mut req): JsonWithLogging<MessagesRequest>,
+	mut req: MessagesRequest,
+	raw_body: Bytes,
 ) -> Response {
 	let copilot_token = match resolve_copilot_token(&state, &headers).await {
 		Ok(t) => t,
@@ -84,24 +150,57 @@ pub async fn post_messages(
 	let display_model = req.model.clone();
 
 	// Ensure models are learned for resolution
-	if state.renamer.dump_learned().is_empty() {
+	if state.renamer.dump_learned().is_empty() || state.renamer.is_alias(&req.model) {
 		debug!("no learned model mappings, fetching models on-demand");
 		if let Err(e) = ensure_models_cached(&state, &copilot_token).await {
 			warn!(error = %e, "failed to fetch models for resolution, proceeding anyway");
 		}
 	}
 
-	let resolved_model = state.renamer.resolve(&req.model);
+	let resolved_model = state.resolve_model(&req.model).await;
 	info!(
 		display = %display_model,
 		resolved = %resolved_model,
+		family = ?model_family(&resolved_model),
 		"model resolution"
 	);
+	let display_model = reported_model(&headers, display_model, &resolved_model);
 	req.model = resolved_model;
+	let capabilities = state.capabilities();
+	if let Err(message) = check_max_tokens(req.max_tokens, &capabilities) {
+		warn!(error = %message, model = %display_model, "rejecting max_tokens");
+		return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message);
+	}
+	let limits = capabilities
+		.get(&req.model)
+		.and_then(|caps| caps.limits.clone());
 
 	let is_streaming = req.stream.unwrap_or(false);
+	let stream_upstream = match stream_upstream(
+		is_streaming,
+		&req.model,
+		capabilities.get(&req.model),
+		state.unstreamable_models,
+	) {
+		Ok(stream) => stream,
+		Err(message) => {
+			warn!(error = %message, model = %display_model, "rejecting streaming request");
+			return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message);
+		}
+	};
+	let emulate_thinking = emulate_thinking_for(&headers, state.emulate_thinking);
+	let openai_params = match OpenAiParams::from_headers(&headers) {
+		Ok(p) => p,
+		Err(message) => {
+			warn!(error = %message, model = %display_model, "rejecting x-openai-params");
+			return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message);
+		}
+	};
 	let vision = has_vision_content(&req);
 	let agent = is_agent_call(&req);
+	let tool_names = renamed_tools(&req);
+	let pause_turn = pause_turn_for(&req.model, state.use_pause_turn);
+	let timeout = request_timeout(&headers, &state);
 
 	info!(
 		model = %display_model,
@@ -110,10 +209,51 @@ pub async fn post_messages(
 		vision = vision,
 		agent = agent,
 		thinking = req.thinking.is_some(),
+		emulate_thinking,
 		"incoming /v1/messages request"
 	);
 
-	let openai_req = translate_request(&req, state.emulate_thinking);
+	let mut openai_req = match translate_request(&req, emulate_thinking, &state.translate_options) {
+		Ok(r) => r,
+		Err(e) => {
+			warn!(error = %e, model = %display_model, "rejecting untranslatable request");
+			return error_response(
+				StatusCode::BAD_REQUEST,
+				"invalid_request_error",
+				e.to_string(),
+			);
+		}
+	};
+	openai_params.apply(&mut openai_req);
+	if !capabilities
+		.get(&openai_req.model)
+		.is_some_and(ModelCapabilities::supports_documents)
+	{
+		documents_as_notes(&mut openai_req);
+	}
+	if is_streaming && !stream_upstream {
+		info!(model = %display_model, "model can't stream, buffering its response");
+		openai_req.stream = Some(false);
+	}
+	if tools_disabled(&headers) {
+		debug!(model = %display_model, "tools disabled for this request");
+		disable_tools(&mut openai_req);
+	}
+	if let Some(max_output) = limits.as_ref().and_then(|l| l.max_output_tokens)
+		&& openai_req.max_tokens.is_some_and(|t| t > max_output)
+	{
+		warn!(
+			model = %display_model,
+			max_tokens = ?openai_req.max_tokens,
+			max_output,
+			"max_tokens is over the model's output limit"
+		);
+	}
+	if state.derive_user_from_token
+		&& let Some(gh_token) = user_gh_token(&headers, &state)
+	{
+		derive_user(&mut openai_req, &gh_token);
+	}
 	let body = match serde_json::to_vec(&openai_req) {
 		Ok(b) => b,
 		Err(e) => {
@@ -126,34 +266,159 @@ pub async fn post_messages(
 		upstream_model = %openai_req.model,
 		upstream_messages = openai_req.messages.len(),
 		max_tokens = ?openai_req.max_tokens,
+		context_window = ?limits.and_then(|l| l.max_context_window_tokens),
 		"sending request to Copilot API"
 	);
 
-	let upstream = match chat_completions_raw(
+	state.model_stats.record_request(&openai_req.model);
+	let client_info = state
+		.forward_client_info
+		.then(|| ClientInfo::from_request(&headers, peer));
+	let account_type = state.account_type();
+
+	if is_streaming && state.stream_coalesce && openai_req.temperature == Some(0.0) {
+		let key = coalesce_key(&[
+			copilot_token.as_bytes(),
+			account_type.as_bytes(),
+			&body,
+			display_model.as_bytes(),
+			&[u8::from(emulate_thinking)],
+		]);
+		let estimate = estimate_prompt_tokens(&openai_req);
+		let upstream_model = openai_req.model;
+		let flight_state = Arc::clone(&state);
+		let (leader, events) = state.stream_coalescer.join(key, move || {
+			async_stream::stream! {
+				let state = flight_state;
+				let sent = chat_completions_raw(
+					&state.client,
+					&copilot_token,
+					&account_type,
+					state.editor_version(&upstream_model),
+					&state.copilot_integration_id,
+					&body,
+					vision,
+					agent,
+					client_info.as_ref(),
+				)
+				.await;
+				match sent {
+					Ok(upstream) => {
+						let events = translate_stream(
+							upstream,
+							display_model,
+							emulate_thinking,
+							estimate,
+							tool_names,
+							pause_turn,
+							state.drop_thinking,
+						);
+						for await event in events {
+							yield event;
+						}
+					}
+					Err(e) => {
+						// Past the point of answering with an HTTP error, as the
+						// stream may already be shared
+						state.model_stats.record_failure(&upstream_model, &e);
+						error!(error = %e, model = %display_model, "copilot request failed");
+						let (_, kind) = failure_kind(&e);
+						let failure = error_event(kind, format!("upstream request failed: {e}"));
+						if let Some(event) = sse_event(&failure) {
+							yield event;
+						}
+					}
+				}
+			}
+		});
+		debug!(leader, "serving coalesced stream");
+		return cancellable_sse(&state, &headers, with_idle_timeout(events, timeout));
+	}
+
+	let send = chat_completions_raw(
 		&state.client,
 		&copilot_token,
-		&state.account_type,
-		&state.vscode_version,
+		&account_type,
+		state.editor_version(&openai_req.model),
+		&state.copilot_integration_id,
 		&body,
 		vision,
 		agent,
-	)
-	.await
-	{
-		Ok(r) => r,
-		Err(e) => {
-			error!(error = %e, model = %display_model, "copilot request failed");
-			return (
-				StatusCode::BAD_GATEWAY,
-				Json(serde_json::json!({
-					"type": "error",
-					"error": {
-						"type": "api_error",
-						"message": format!("upstream request failed: {e}")
-					}
-				})),
-			)
-				.into_response();
+		client_info.as_ref(),
+	);
+
+	if !is_streaming {
+		let shadow = state
+			.shadow_backend
+			.as_ref()
+			.map(|shadow| shadow.mirror(&state.client, raw_body, &headers));
+		let exchange = async {
+			match send.await {
+				Ok(upstream) => {
+					debug!(status = %upstream.status(), "received response from Copilot API");
+					handle_non_streaming(
+						upstream,
+						display_model.clone(),
+						emulate_thinking,
+						state.strip_tool_preamble,
+						&tool_names,
+						state.missing_usage,
+						estimate_prompt_tokens(&openai_req),
+						pause_turn,
+						state.drop_thinking,
+						state.usage_headers,
+					)
+					.await
+				}
+				Err(e) => {
+					state.model_stats.record_failure(&openai_req.model, &e);
+					upstream_failure(e, &display_model)
+				}
+			}
+		};
+
+		// Dropping the exchange on timeout aborts the upstream request
+		let deadline = async {
+			match with_soft_deadline(timeout.or(state.messages_soft_timeout), exchange).await {
+				Ok(resp) => resp,
+				Err(limit) => {
+					warn!(model = %display_model, ?limit, "non-streaming request hit soft timeout");
+					error_response(
+						StatusCode::GATEWAY_TIMEOUT,
+						"timeout_error",
+						format!("upstream did not respond within {}s", limit.as_secs()),
+					)
+				}
+			}
+		};
+		// Scoped to the caller's token, so keys can't collide across users
+		let key = idempotency_key(&headers).map(|key| IdempotencyKey {
+			key: coalesce_key(&[copilot_token.as_bytes(), key.as_bytes()]),
+			request: coalesce_key(&[&body]),
+		});
+		let resp = state.idempotency.respond(key, deadline).await;
+		if let Some(shadow) = shadow
+			&& let Some(summary) = resp.extensions().get::<MessageSummary>()
+		{
+			// The shadow task may have given up already
+			let _ = shadow.send(summary.clone());
+		}
+		return resp;
+	}
+
+	let upstream = match with_soft_deadline(timeout, send).await {
+		Ok(Ok(r)) => r,
+		Ok(Err(e)) => {
+			state.model_stats.record_failure(&openai_req.model, &e);
+			return upstream_failure(e, &display_model);
+		}
+		Err(limit) => {
+			warn!(model = %display_model, ?limit, "streaming request hit timeout before responding");
+			return error_response(
+				StatusCode::GATEWAY_TIMEOUT,
+				"timeout_error",
+				format!("upstream did not respond within {}s", limit.as_secs()),
+			);
 		}
 	};
 
@@ -163,17 +428,290 @@ pub async fn post_messages(
 		"received response from Copilot API"
 	);
 
-	if !is_streaming {
-		return handle_non_streaming(upstream, display_model, state.emulate_thinking).await;
+	let events = translate_stream(
+		upstream,
+		display_model,
+		emulate_thinking,
+		estimate_prompt_tokens(&openai_req),
+		tool_names,
+		pause_turn,
+		state.drop_thinking,
+	);
+	cancellable_sse(&state, &headers, with_idle_timeout(events, timeout))
+}
+
+/// Stream `events`, registered so `POST /v1/messages/{request_id}/cancel`
+/// with the same credentials can stop them. The ID is returned in the
+/// `request-id` header, as Anthropic does.
+fn cancellable_sse(
+	state: &AppState,
+	headers: &HeaderMap,
+	events: impl Stream<Item = Event> + Send + 'static,
+) -> Response {
+	let owner = stream_owner(state, headers);
+	let (request_id, events) = state.streams.register(owner, events);
+	debug!(%request_id, "registered cancellable stream");
+	let mut resp = ([("request-id", request_id)], sse(events)).into_response();
+	// So nginx, envoy and the like pass events on as they come
+	let headers = resp.headers_mut();
+	headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+	headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive"));
+	headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+	resp
+}
+
+/// Who a stream belongs to: a hash of the credentials it was requested with.
+fn stream_owner(state: &AppState, headers: &HeaderMap) -> StreamOwner {
+	let credentials = request_credentials(state, headers).unwrap_or_default();
+	coalesce_key(&[credentials.as_bytes()])
+}
+
+/// Streams requested with other credentials are reported as unknown.
+pub async fn cancel_message(
+	State(state): State<Arc<AppState>>,
+	headers: HeaderMap,
+	Path(request_id): Path<String>,
+) -> Response {
+	if state
+		.streams
+		.cancel(&stream_owner(&state, &headers), &request_id)
+	{
+		info!(%request_id, "cancelled stream on client request");
+		Json(serde_json::json!({ "id": request_id, "cancelled": true })).into_response()
+	} else {
+		error_response(
+			StatusCode::NOT_FOUND,
+			"not_found_error",
+			format!("no in-flight stream with id {request_id}"),
+		)
+	}
+}
+
+/// Per-request override of `EMULATE_THINKING` via `x-emulate-thinking: true|false`.
+fn emulate_thinking_for(headers: &HeaderMap, default: bool) -> bool {
+	match headers
+		.get("x-emulate-thinking")
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.trim().to_ascii_lowercase())
+		.as_deref()
+	{
+		Some("true") => true,
+		Some("false") => false,
+		Some(other) => {
+			warn!(value = other, "ignoring invalid x-emulate-thinking header");
+			default
+		}
+		None => default,
+	}
+}
+
+/// The model name to answer with: the one the client asked for, or with
+/// `x-return-upstream-model: true`, the upstream model that served it.
+fn reported_model(headers: &HeaderMap, requested: String, upstream: &str) -> String {
+	let wanted = headers
+		.get("x-return-upstream-model")
+		.and_then(|v| v.to_str().ok())
+		.is_some_and(|v| matches!(v.trim(), "true" | "1"));
+	if wanted {
+		upstream.to_string()
+	} else {
+		requested
+	}
+}
+
+/// Whether to ask upstream for a stream. Clients asking to stream from a
+/// model the list says can't get its single response replayed as a stream
+/// instead, or are refused, depending on `UNSTREAMABLE_MODELS`.
+fn stream_upstream(
+	is_streaming: bool,
+	model: &str,
+	capabilities: Option<&ModelCapabilities>,
+	unstreamable: UnstreamableModels,
+) -> Result<bool, String> {
+	if !is_streaming || capabilities.is_none_or(ModelCapabilities::supports_streaming) {
+		return Ok(is_streaming);
+	}
+	match unstreamable {
+		UnstreamableModels::Buffer => Ok(false),
+		UnstreamableModels::Reject => Err(format!(
+			"model {model} does not support streaming, set stream: false"
+		)),
+	}
+}
+
+/// Whether tool use should be reported as `pause_turn`: only with
+/// `USE_PAUSE_TURN`, and only for Claude models, whose clients expect it.
+fn pause_turn_for(model: &str, use_pause_turn: bool) -> bool {
+	use_pause_turn
+		&& matches!(
+			model_family(model),
+			ModelFamily::ClaudeSonnet | ModelFamily::ClaudeOpus | ModelFamily::ClaudeHaiku
+		)
+}
+
+/// Whether the client asked to run this request without tools, with
+/// `x-disable-tools: true`.
+pub fn tools_disabled(headers: &HeaderMap) -> bool {
+	headers
+		.get("x-disable-tools")
+		.and_then(|v| v.to_str().ok())
+		.is_some_and(|v| v.trim().eq_ignore_ascii_case("true") || v.trim() == "1")
+}
+
+fn disable_tools(req: &mut ChatCompletionsRequest) {
+	req.tools = None;
+	req.tool_choice = Some(ToolChoice::String("none".to_string()));
+	// Upstream rejects it without tools
+	req.parallel_tool_calls = None;
+}
+
+/// OpenAI-only parameters Anthropic has no equivalent for, which clients can
+/// set on `/v1/messages` with an `x-openai-params` JSON header.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OpenAiParams {
+	frequency_penalty: Option<f64>,
+	presence_penalty: Option<f64>,
+	seed: Option<i64>,
+	parallel_tool_calls: Option<bool>,
+}
+
+impl OpenAiParams {
+	fn from_headers(headers: &HeaderMap) -> Result<Self, String> {
+		let Some(raw) = headers.get("x-openai-params") else {
+			return Ok(Self::default());
+		};
+		let raw = raw
+			.to_str()
+			.map_err(|_| "x-openai-params header is not valid UTF-8".to_string())?;
+		serde_json::from_str(raw).map_err(|e| format!("invalid x-openai-params header: {e}"))
+	}
+
+	fn apply(self, req: &mut ChatCompletionsRequest) {
+		if self.frequency_penalty.is_some() {
+			req.frequency_penalty = self.frequency_penalty;
+		}
+		if self.presence_penalty.is_some() {
+			req.presence_penalty = self.presence_penalty;
+		}
+		if self.seed.is_some() {
+			req.seed = self.seed;
+		}
+		// Upstream rejects it without tools
+		if self.parallel_tool_calls.is_some() && req.tools.is_some() {
+			req.parallel_tool_calls = self.parallel_tool_calls;
+		}
+	}
+}
+
+/// The status and Anthropic error type to report an upstream failure as.
+/// Overload gets Anthropic's 529, so SDKs back off and retry.
+fn failure_kind(e: &anyhow::Error) -> (StatusCode, &'static str) {
+	match e.downcast_ref::<UpstreamStatusError>() {
+		Some(upstream) if upstream.is_overloaded() => (
+			StatusCode::from_u16(529).expect("529 is a valid status"),
+			"overloaded_error",
+		),
+		_ => (StatusCode::BAD_GATEWAY, "api_error"),
+	}
+}
+
+fn upstream_failure(e: anyhow::Error, display_model: &str) -> Response {
+	error!(error = %e, model = %display_model, "copilot request failed");
+	let (status, kind) = failure_kind(&e);
+	error_response(status, kind, format!("upstream request failed: {e}"))
+}
+
+/// The GitHub token to derive the OpenAI `user` from: none when the request
+/// came with a raw Copilot token, as the server's `GH_TOKEN` isn't theirs.
+fn user_gh_token<'a>(headers: &'a HeaderMap, state: &'a AppState) -> Option<Cow<'a, str>> {
+	if extract_copilot_token(headers, &state.copilot_token_prefix).is_some() {
+		return None;
+	}
+	extract_gh_token(headers, state.accept_base64_keys)
+		.or(state.default_github_token.as_deref().map(Cow::Borrowed))
+}
+
+/// The client's `x-request-timeout-secs`, kept within
+/// `REQUEST_TIMEOUT_MIN_SECS` and `REQUEST_TIMEOUT_MAX_SECS`.
+fn request_timeout(headers: &HeaderMap, state: &AppState) -> Option<Duration> {
+	let raw = headers.get("x-request-timeout-secs")?;
+	let Some(secs) = raw.to_str().ok().and_then(|v| v.trim().parse::<u64>().ok()) else {
+		warn!(value = ?raw, "ignoring invalid x-request-timeout-secs");
+		return None;
+	};
+	let requested = Duration::from_secs(secs);
+	let timeout = requested.clamp(
+		state.request_timeout_min,
+		state.request_timeout_max.max(state.request_timeout_min),
+	);
+	if timeout != requested {
+		warn!(
+			requested = secs,
+			timeout = timeout.as_secs(),
+			"x-request-timeout-secs out of bounds, clamping"
+		);
 	}
+	Some(timeout)
+}
+
+/// End `events` with a `timeout_error` once none has come for `limit`.
+fn with_idle_timeout(
+	events: impl Stream<Item = Event> + Send + 'static,
+	limit: Option<Duration>,
+) -> impl Stream<Item = Event> + Send + 'static {
+	async_stream::stream! {
+		let mut events = Box::pin(events);
+		loop {
+			let next = match with_soft_deadline(limit, events.next()).await {
+				Ok(next) => next,
+				Err(limit) => {
+					warn!(?limit, "stream went idle, ending it");
+					let timeout = error_event(
+						"timeout_error",
+						format!("upstream sent nothing for {}s", limit.as_secs()),
+					);
+					if let Some(event) = sse_event(&timeout) {
+						yield event;
+					}
+					break;
+				}
+			};
+			let Some(event) = next else {
+				break;
+			};
+			yield event;
+		}
+	}
+}
 
-	handle_streaming(upstream, display_model, state.emulate_thinking).into_response()
+/// Await `fut`, giving up after `limit` if one is set.
+/// On timeout the future is dropped, and the limit is returned as the error.
+async fn with_soft_deadline<F: Future>(
+	limit: Option<Duration>,
+	fut: F,
+) -> Result<F::Output, Duration> {
+	match limit {
+		Some(limit) => tokio::time::timeout(limit, fut).await.map_err(|_| limit),
+		None => Ok(fut.await),
+	}
 }
 
+#[expect(
+	clippy::too_many_arguments,
+	reason = "the per-request translation settings"
+)]
 async fn handle_non_streaming(
 	upstream: reqwest::Response,
 	display_model: String,
 	emulate_thinking: bool,
+	strip_tool_preamble: bool,
+	tool_names: &HashMap<String, String>,
+	missing_usage: MissingUsage,
+	estimated_input_tokens: u64,
+	pause_turn: bool,
+	drop_thinking: bool,
+	usage_headers: bool,
 ) -> Response {
 	let bytes = match upstream.bytes().await {
 		Ok(b) => b,
@@ -183,7 +721,7 @@ async fn handle_non_streaming(
 		}
 	};
 
-	let openai_resp = match serde_json::from_slice(&bytes) {
+	let openai_resp: ChatCompletionResponse = match serde_json::from_slice(&bytes) {
 		Ok(r) => r,
 		Err(e) => {
 			error!(
@@ -195,8 +733,19 @@ async fn handle_non_streaming(
 		}
 	};
 
-	let mut anthropic_resp = translate_response(&openai_resp, emulate_thinking);
+	let mut anthropic_resp =
+		translate_response(&openai_resp, emulate_thinking, strip_tool_preamble);
 	anthropic_resp.model = display_model.clone();
+	anthropic_resp.stop_reason = anthropic_resp.stop_reason.map(|r| r.pausing(pause_turn));
+	restore_tool_names(&mut anthropic_resp, tool_names);
+	if drop_thinking {
+		drop_thinking_blocks(&mut anthropic_resp);
+	}
+	let usage_missing = openai_resp.usage.is_none();
+	if usage_missing && missing_usage == MissingUsage::Estimate {
+		anthropic_resp.usage.input_tokens = estimated_input_tokens;
+		anthropic_resp.usage.output_tokens = estimate_output_tokens(&anthropic_resp);
+	}
 
 	info!(
 		model = %display_model,
@@ -207,38 +756,131 @@ async fn handle_non_streaming(
 		"non-streaming response complete"
 	);
 
-	Json(anthropic_resp).into_response()
+	let summary = MessageSummary::from_response(&anthropic_resp);
+	let mut resp = if usage_missing && missing_usage == MissingUsage::Omit {
+		let mut body = serde_json::json!(anthropic_resp);
+		if let Some(body) = body.as_object_mut() {
+			body.remove("usage");
+		}
+		Json(body).into_response()
+	} else {
+		let mut resp = Json(&anthropic_resp).into_response();
+		if usage_headers {
+			let usage = &anthropic_resp.usage;
+			let headers = resp.headers_mut();
+			headers.insert("x-usage-input-tokens", usage.input_tokens.into());
+			headers.insert("x-usage-output-tokens", usage.output_tokens.into());
+		}
+		resp
+	};
+	resp.extensions_mut().insert(summary);
+	resp
 }
 
-fn handle_streaming(
+fn sse(
+	events: impl Stream<Item = Event> + Send + 'static,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+	Sse::new(events.map(Ok)).keep_alive(KeepAlive::default())
+}
+
+fn sse_event(ev: &StreamEvent) -> Option<Event> {
+	match serde_json::to_string(ev) {
+		Ok(data) => Some(Event::default().event(ev.event_type()).data(data)),
+		Err(e) => {
+			error!(error = %e, "failed to serialize stream event");
+			None
+		}
+	}
+}
+
+/// Translate an upstream chat completions stream into Anthropic SSE events.
+fn translate_stream(
 	upstream: reqwest::Response,
 	display_model: String,
 	emulate_thinking: bool,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-	let stream = async_stream::stream! {
+	estimated_input_tokens: u64,
+	tool_names: HashMap<String, String>,
+	pause_turn: bool,
+	drop_thinking: bool,
+) -> impl Stream<Item = Event> + Send + 'static {
+	let content_type = upstream
+		.headers()
+		.get("content-type")
+		.and_then(|v| v.to_str().ok())
+		.unwrap_or_default();
+	let is_event_stream = content_type.contains("text/event-stream");
+	let mut framing = (content_type.contains("ndjson") || content_type.contains("jsonl"))
+		.then_some(Framing::Ndjson);
+
+	async_stream::stream! {
+		if !is_event_stream && framing.is_none() {
+			let events =
+				buffered_response_events(upstream, &display_model, emulate_thinking, &tool_names, pause_turn, drop_thinking);
+			for ev in events.await {
+				if let Some(event) = sse_event(&ev) {
+					yield event;
+				}
+			}
+			return;
+		}
+
 		let mut state = StreamState::new(emulate_thinking);
+		state.estimated_input_tokens = estimated_input_tokens;
+		state.display_model = Some(display_model.clone());
+		state.tool_names = tool_names;
+		state.pause_turn = pause_turn;
+		state.drop_thinking = drop_thinking;
 		let mut bytes_stream = upstream.bytes_stream();
 		let mut buffer = String::new();
 
-		while let Some(chunk_result) = bytes_stream.next().await {
-			let chunk_bytes = match chunk_result {
-				Ok(b) => b,
-				Err(e) => {
+		let mut ended = false;
+
+		while !ended {
+			match bytes_stream.next().await {
+				Some(Ok(chunk_bytes)) => buffer.push_str(&String::from_utf8_lossy(&chunk_bytes)),
+				Some(Err(e)) => {
 					error!(error = %e, "error reading upstream stream");
 					break;
 				}
-			};
+				None => {
+					// Terminate the last line or event if upstream didn't, so
+					// its content isn't lost
+					ended = true;
+					buffer.push_str("\n\n");
+				}
+			}
 
-			buffer.push_str(&String::from_utf8_lossy(&chunk_bytes));
+			if framing.is_none() {
+				framing = detect_framing(&buffer);
+				if framing == Some(Framing::Ndjson) {
+					warn!("upstream sent newline-delimited JSON instead of SSE");
+				}
+			}
 
-			// Process complete SSE lines from the buffer
-			while let Some(event_data) = extract_next_sse_data(&mut buffer) {
+			// Process complete events from the buffer
+			while let Some(event_data) = match framing {
+				Some(Framing::Sse) => extract_next_sse_data(&mut buffer),
+				Some(Framing::Ndjson) => extract_next_ndjson_line(&mut buffer),
+				None => None,
+			} {
 				if event_data == "[DONE]" {
 					debug!("upstream SSE stream done");
 					break;
 				}
 
-				let mut chunk: ChatCompletionChunk = match serde_json::from_str(&event_data) {
+				// Every chunk field is optional, so an error would parse as an
+				// empty chunk and leave the client with a truncated stream
+				if event_data.contains("\"error\"")
+					&& let Some(failure) = upstream_error_event(&event_data)
+				{
+					error!(data = %event_data, model = %display_model, "upstream sent an error mid-stream");
+					if let Some(event) = sse_event(&failure) {
+						yield event;
+					}
+					return;
+				}
+
+				let chunk: ChatCompletionChunk = match serde_json::from_str(&event_data) {
 					Ok(c) => c,
 					Err(e) => {
 						debug!(error = %e, data = %event_data, "skipping unparsable chunk");
@@ -246,64 +888,104 @@ fn handle_streaming(
 					}
 				};
 
-				chunk.model = display_model.clone();
 				let events = translate_chunk(&chunk, &mut state);
 				for ev in events {
-					let data = match serde_json::to_string(&ev) {
-						Ok(d) => d,
-						Err(e) => {
-							error!(error = %e, "failed to serialize stream event");
-							continue;
-						}
-					};
-
-					let sse_event = Event::default()
-						.event(ev.event_type())
-						.data(data);
-
-					yield Ok(sse_event);
+					if let Some(event) = sse_event(&ev) {
+						yield event;
+					}
 				}
 			}
 		}
 
 		info!(model = %display_model, "streaming response complete");
 
-		// Flush any buffered content from the thinking parser
-		if let Some(parser) = state.thinking_parser.take()
-			&& let Some(final_event) = parser.finish() {
-				match final_event {
-					crate::translate::thinking::ThinkingEvent::ThinkingDelta(thinking_text) => {
-						let ev = crate::translate::types::StreamEvent::ContentBlockDelta {
-							index: state.content_block_index,
-							delta: crate::translate::types::ContentDelta::Thinking {
-								thinking: thinking_text,
-							},
-						};
-						if let Ok(data) = serde_json::to_string(&ev) {
-							let sse_event = Event::default()
-								.event(ev.event_type())
-								.data(data);
-							yield Ok(sse_event);
-						}
-					}
-					crate::translate::thinking::ThinkingEvent::TextDelta(text_chunk) => {
-						let ev = crate::translate::types::StreamEvent::ContentBlockDelta {
-							index: state.content_block_index,
-							delta: crate::translate::types::ContentDelta::Text { text: text_chunk },
-						};
-						if let Ok(data) = serde_json::to_string(&ev) {
-							let sse_event = Event::default()
-								.event(ev.event_type())
-								.data(data);
-							yield Ok(sse_event);
-						}
-					}
-					_ => {} // ThinkingStart/End shouldn't happen in finish
-				}
+		for ev in finish_stream(&mut state) {
+			if let Some(event) = sse_event(&ev) {
+				yield event;
 			}
+		}
+	}
+}
+
+/// Translate a plain JSON reply to a streaming request into the SSE sequence
+/// the client expects, or an error event if it isn't a chat completion.
+async fn buffered_response_events(
+	upstream: reqwest::Response,
+	display_model: &str,
+	emulate_thinking: bool,
+	tool_names: &HashMap<String, String>,
+	pause_turn: bool,
+	drop_thinking: bool,
+) -> Vec<StreamEvent> {
+	warn!(
+		content_type = ?upstream.headers().get("content-type"),
+		"upstream answered a streaming request without an event stream"
+	);
+
+	let bytes = match upstream.bytes().await {
+		Ok(b) => b,
+		Err(e) => {
+			error!(error = %e, "failed to read upstream response");
+			return vec![error_event(
+				"api_error",
+				format!("failed to read upstream response: {e}"),
+			)];
+		}
 	};
 
-	Sse::new(stream).keep_alive(KeepAlive::default())
+	match serde_json::from_slice::<ChatCompletionResponse>(&bytes) {
+		Ok(openai_resp) => {
+			let mut anthropic_resp = translate_response(&openai_resp, emulate_thinking, false);
+			anthropic_resp.model = display_model.to_string();
+			anthropic_resp.stop_reason = anthropic_resp.stop_reason.map(|r| r.pausing(pause_turn));
+			restore_tool_names(&mut anthropic_resp, tool_names);
+			if drop_thinking {
+				drop_thinking_blocks(&mut anthropic_resp);
+			}
+			info!(model = %display_model, "replayed non-streaming upstream response as a stream");
+			response_events(&anthropic_resp)
+		}
+		Err(e) => {
+			let body = String::from_utf8_lossy(&bytes);
+			error!(error = %e, body = %body, "failed to parse upstream response");
+			vec![error_event(
+				"api_error",
+				format!("unexpected upstream response: {body}"),
+			)]
+		}
+	}
+}
+
+/// How chunks are separated in an upstream stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+	Sse,
+	/// One bare JSON chunk per line, which some upstreams send despite
+	/// claiming to be an event stream.
+	Ndjson,
+}
+
+/// Tell the framing from the first complete line, or None until there is one.
+fn detect_framing(buffer: &str) -> Option<Framing> {
+	let (line, _) = buffer.trim_start().split_once('\n')?;
+	Some(
+		if serde_json::from_str::<ChatCompletionChunk>(line.trim()).is_ok() {
+			Framing::Ndjson
+		} else {
+			Framing::Sse
+		},
+	)
+}
+
+fn extract_next_ndjson_line(buffer: &mut String) -> Option<String> {
+	while let Some(pos) = buffer.find('\n') {
+		let line = buffer[..pos].trim().to_string();
+		buffer.drain(..=pos);
+		if !line.is_empty() {
+			return Some(line);
+		}
+	}
+	None
 }
 
 /// Extract the next complete SSE data field from the buffer.
@@ -353,26 +1035,50 @@ fn parse_sse_data(block: &str) -> Option<String> {
 }
 
 async fn ensure_models_cached(state: &AppState, copilot_token: &str) -> Result<(), anyhow::Error> {
-	// Check if cache is valid
-	{
-		let models = state.models.read().await;
-		if let Some(cached) = models.as_ref()
-			&& state.is_models_cache_valid(cached)
-		{
-			debug!("models cache is valid, using cached mappings");
-			return Ok(());
-		}
+	ensure_models_cached_with(state, || async {
+		crate::copilot::client::fetch_models(
+			&state.client,
+			copilot_token,
+			&state.account_type(),
+			&state.vscode_version,
+			&state.copilot_integration_id,
+			state.models_timeout,
+		)
+		.await
+	})
+	.await
+}
+
+async fn models_cache_valid(state: &AppState) -> bool {
+	let models = state.models.read().await;
+	models
+		.as_ref()
+		.is_some_and(|cached| state.is_models_cache_valid(cached))
+}
+
+async fn ensure_models_cached_with<F, Fut>(state: &AppState, fetch: F) -> Result<(), anyhow::Error>
+where
+	F: FnOnce() -> Fut,
+	Fut: Future<Output = Result<ModelsResponse, anyhow::Error>>,
+{
+	if models_cache_valid(state).await {
+		debug!("models cache is valid, using cached mappings");
+		return Ok(());
 	}
 
-	// Fetch and cache models
-	let mut models = crate::copilot::client::fetch_models(
-		&state.client,
-		copilot_token,
-		&state.account_type,
-		&state.vscode_version,
-	)
-	.await?;
+	// One fetch at a time: requests that queued up behind it find the cache filled
+	let _fetching = state.models_fetch.lock().await;
+	if models_cache_valid(state).await {
+		debug!("models fetched by a concurrent request, using cached mappings");
+		return Ok(());
+	}
 
+	cache_models(state, fetch().await?).await;
+	Ok(())
+}
+
+/// Rename a freshly fetched models list, learn its mappings, and cache it.
+pub async fn cache_models(state: &AppState, mut models: ModelsResponse) {
 	// Apply model renaming and register mappings
 	for model in &mut models.data {
 		let renamed = state.renamer.rename(&model.id);
@@ -392,18 +1098,907 @@ async fn ensure_models_cached(state: &AppState, copilot_token: &str) -> Result<(
 		info!(display = %display_name, upstream = %upstream_name, "mapping");
 	}
 
-	*state.models.write().await = Some(crate::state::CachedModels {
-		response: models,
-		cached_at: std::time::SystemTime::now(),
-	});
-
-	Ok(())
+	state.store_models(models).await;
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
 
+	async fn reject_body(body: &'static str) -> String {
+		let state = Arc::new(
+			AppState::new(
+				None,
+				"individual".to_string(),
+				"1.100.0".to_string(),
+				crate::rename::ModelRenamer::from_env(),
+			)
+			.unwrap(),
+		);
+		let req = Request::builder()
+			.uri("/v1/messages")
+			.body(axum::body::Body::from(body))
+			.unwrap();
+		let Err(resp) = JsonWithLogging::<MessagesRequest>::from_request(req, &state).await else {
+			panic!("expected the body to be rejected");
+		};
+		assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+		assert_eq!(json["type"], "error");
+		assert_eq!(json["error"]["type"], "invalid_request_error");
+		json["error"]["message"].as_str().unwrap().to_string()
+	}
+
+	#[tokio::test]
+	async fn deserialize_error_includes_json_path() {
+		let message = reject_body(
+			r#"{
+				"model": "claude-sonnet-4",
+				"max_tokens": 10,
+				"messages": [{"role": "user", "content": "hi"}],
+				"tools": [
+					{"name": "ok", "input_schema": {}},
+					{"name": 5, "input_schema": {}}
+				]
+			}"#,
+		)
+		.await;
+		assert!(message.contains("tools[1].name: invalid type"), "{message}");
+	}
+
+	#[tokio::test]
+	async fn deserialize_error_points_at_message_index() {
+		let message = reject_body(
+			r#"{
+				"model": "claude-sonnet-4",
+				"max_tokens": 10,
+				"messages": [
+					{"role": "user", "content": "hi"},
+					{"role": "user", "content": 42}
+				]
+			}"#,
+		)
+		.await;
+		assert!(message.contains("messages[1]"), "{message}");
+	}
+
+	async fn sse_from_upstream(content_type: &str, body: &str) -> String {
+		let upstream = reqwest::Response::from(
+			axum::http::Response::builder()
+				.header("content-type", content_type)
+				.body(body.to_string())
+				.unwrap(),
+		);
+		let state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			crate::rename::ModelRenamer::from_env(),
+		)
+		.unwrap();
+		let events = translate_stream(
+			upstream,
+			"claude-sonnet-4".to_string(),
+			false,
+			10,
+			HashMap::new(),
+			false,
+			false,
+		);
+		let resp = cancellable_sse(&state, &HeaderMap::new(), events);
+		let request_id = resp.headers()["request-id"].to_str().unwrap().to_string();
+		assert!(request_id.starts_with("req_"), "{request_id}");
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		String::from_utf8(bytes.to_vec()).unwrap()
+	}
+
+	async fn stream_from_upstream(content_type: &str, body: &str) -> Vec<String> {
+		sse_from_upstream(content_type, body)
+			.await
+			.lines()
+			.filter_map(|l| l.strip_prefix("event: "))
+			.map(str::to_string)
+			.collect()
+	}
+
+	#[test]
+	fn upstream_model_reported_on_request() {
+		let report = |value: Option<&'static str>| {
+			let mut headers = HeaderMap::new();
+			if let Some(value) = value {
+				headers.insert("x-return-upstream-model", value.parse().unwrap());
+			}
+			reported_model(&headers, "sonnet-latest".to_string(), "claude-sonnet-4.5")
+		};
+		assert_eq!(report(None), "sonnet-latest");
+		assert_eq!(report(Some("false")), "sonnet-latest");
+		assert_eq!(report(Some("true")), "claude-sonnet-4.5");
+		assert_eq!(report(Some("1")), "claude-sonnet-4.5");
+	}
+
+	#[tokio::test]
+	async fn stream_events_carry_display_model() {
+		let chunk = |delta: &str, finish: &str| {
+			format!(
+				r#"data: {{"id":"c1","model":"claude-sonnet-4.5","choices":[{{"index":0,"delta":{delta},"finish_reason":{finish}}}]}}"#
+			)
+		};
+		let body = [
+			chunk(r#"{"role":"assistant"}"#, "null"),
+			chunk(r#"{"content":"hi"}"#, "null"),
+			chunk("{}", r#""stop""#),
+			"data: [DONE]".to_string(),
+		]
+		.join("\n\n");
+
+		let sse = sse_from_upstream("text/event-stream", &body).await;
+		let data: Vec<serde_json::Value> = sse
+			.lines()
+			.filter_map(|l| l.strip_prefix("data: "))
+			.map(|d| serde_json::from_str(d).unwrap())
+			.collect();
+		assert_eq!(data[0]["type"], "message_start");
+		assert_eq!(data[0]["message"]["model"], "claude-sonnet-4");
+		assert!(data.len() > 3);
+		assert!(!sse.contains("claude-sonnet-4.5"), "{sse}");
+	}
+
+	#[test]
+	fn user_not_derived_for_copilot_tokens() {
+		let mut state = AppState::new(
+			Some("ghu_server".to_string()),
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			crate::rename::ModelRenamer::from_env(),
+		)
+		.unwrap();
+		state.copilot_token_prefix = "tid=".to_string();
+		let mut headers = HeaderMap::new();
+		assert_eq!(
+			user_gh_token(&headers, &state).as_deref(),
+			Some("ghu_server")
+		);
+		headers.insert("x-api-key", HeaderValue::from_static("ghp_client"));
+		assert_eq!(
+			user_gh_token(&headers, &state).as_deref(),
+			Some("ghp_client")
+		);
+		headers.insert("x-api-key", HeaderValue::from_static("tid=abc;exp=1"));
+		assert_eq!(user_gh_token(&headers, &state), None);
+	}
+
+	#[test]
+	fn request_timeout_clamped_to_bounds() {
+		let state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			crate::rename::ModelRenamer::from_env(),
+		)
+		.unwrap();
+		let timeout = |value: Option<&'static str>| {
+			let mut headers = HeaderMap::new();
+			if let Some(value) = value {
+				headers.insert("x-request-timeout-secs", HeaderValue::from_static(value));
+			}
+			request_timeout(&headers, &state).map(|t| t.as_secs())
+		};
+		assert_eq!(timeout(None), None);
+		assert_eq!(timeout(Some("30")), Some(30));
+		assert_eq!(timeout(Some("0")), Some(1));
+		assert_eq!(timeout(Some("86400")), Some(600));
+		assert_eq!(timeout(Some("soon")), None);
+	}
+
+	#[tokio::test]
+	async fn idle_stream_ends_with_timeout_error() {
+		let first = Event::default().event("ping").data("{}");
+		let stalled = futures::stream::iter([first]).chain(futures::stream::pending());
+		let events: Vec<Event> = with_idle_timeout(stalled, Some(Duration::from_millis(20)))
+			.collect()
+			.await;
+		assert_eq!(events.len(), 2);
+		let resp = sse(futures::stream::iter(events)).into_response();
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let body = String::from_utf8(bytes.to_vec()).unwrap();
+		assert!(body.contains("event: error"), "{body}");
+		assert!(body.contains("timeout_error"), "{body}");
+
+		// Without a limit, it's left alone
+		let events: Vec<Event> = with_idle_timeout(futures::stream::iter([Event::default()]), None)
+			.collect()
+			.await;
+		assert_eq!(events.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn streams_ask_proxies_not_to_buffer() {
+		let state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			crate::rename::ModelRenamer::from_env(),
+		)
+		.unwrap();
+		let resp = cancellable_sse(&state, &HeaderMap::new(), futures::stream::empty());
+		let headers = resp.headers();
+		assert_eq!(headers[header::CONTENT_TYPE], "text/event-stream");
+		assert_eq!(headers[header::CACHE_CONTROL], "no-cache");
+		assert_eq!(headers[header::CONNECTION], "keep-alive");
+		assert_eq!(headers["x-accel-buffering"], "no");
+	}
+
+	#[tokio::test]
+	async fn error_in_stream_reported_as_error_event() {
+		let body = concat!(
+			r#"data: {"id":"c1","model":"gpt-4.1","choices":[{"index":0,"delta":{"role":"assistant","content":"hel"},"finish_reason":null}]}"#,
+			"\n\n",
+			r#"data: {"error":{"message":"The model produced invalid content","code":"invalid_content"}}"#,
+			"\n\n",
+			r#"data: {"id":"c1","model":"gpt-4.1","choices":[{"index":0,"delta":{"content":"lo"},"finish_reason":"stop"}]}"#,
+			"\n\ndata: [DONE]\n\n",
+		);
+		let sse = sse_from_upstream("text/event-stream", body).await;
+		let events: Vec<&str> = sse
+			.lines()
+			.filter_map(|l| l.strip_prefix("event: "))
+			.collect();
+		assert_eq!(events.last(), Some(&"error"), "{sse}");
+		assert!(!events.contains(&"message_stop"), "{sse}");
+		assert!(
+			sse.contains("upstream error: The model produced invalid content"),
+			"{sse}"
+		);
+		assert!(!sse.contains(r#""text":"lo""#), "{sse}");
+	}
+
+	#[tokio::test]
+	async fn unterminated_last_event_is_translated() {
+		let body = concat!(
+			r#"data: {"id":"c1","model":"gpt-4.1","choices":[{"index":0,"delta":{"role":"assistant","content":"hello"},"finish_reason":null}]}"#,
+			"\n\n",
+			r#"data: {"id":"c1","model":"gpt-4.1","choices":[{"index":0,"delta":{"content":" world"},"finish_reason":"stop"}]}"#,
+		);
+		let sse = sse_from_upstream("text/event-stream", body).await;
+		assert!(sse.contains(r#""text":" world""#), "{sse}");
+		assert!(sse.contains(r#""stop_reason":"end_turn""#), "{sse}");
+	}
+
+	#[tokio::test]
+	async fn ndjson_upstream_is_translated() {
+		let chunk = |delta: &str, finish: &str| {
+			format!(
+				r#"{{"id":"c1","model":"claude-sonnet-4.5","choices":[{{"index":0,"delta":{delta},"finish_reason":{finish}}}]}}"#
+			)
+		};
+		// No trailing newline after the last chunk
+		let body = [
+			chunk(r#"{"role":"assistant"}"#, "null"),
+			chunk(r#"{"content":"hi"}"#, "null"),
+			chunk("{}", r#""stop""#),
+		]
+		.join("\n");
+		let expected = [
+			"message_start",
+			"content_block_start",
+			"content_block_delta",
+			"content_block_stop",
+			"message_delta",
+			"message_stop",
+		];
+
+		for content_type in ["text/event-stream", "application/x-ndjson"] {
+			let sse = sse_from_upstream(content_type, &body).await;
+			let events: Vec<_> = sse
+				.lines()
+				.filter_map(|l| l.strip_prefix("event: "))
+				.collect();
+			assert_eq!(events, expected, "{content_type}");
+			assert!(sse.contains(r#""text":"hi""#), "{sse}");
+		}
+	}
+
+	#[tokio::test]
+	async fn streaming_request_with_json_upstream_is_replayed() {
+		let body = serde_json::json!({
+			"id": "chatcmpl-1",
+			"object": "chat.completion",
+			"created": 0,
+			"model": "gpt-4",
+			"choices": [{
+				"index": 0,
+				"message": {"role": "assistant", "content": "hello"},
+				"finish_reason": "stop"
+			}],
+			"usage": {"prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6}
+		});
+		let events = stream_from_upstream("application/json", &body.to_string()).await;
+		assert_eq!(
+			events,
+			[
+				"message_start",
+				"content_block_start",
+				"content_block_delta",
+				"content_block_stop",
+				"message_delta",
+				"message_stop",
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn streaming_request_with_unparsable_upstream_sends_error_event() {
+		let events = stream_from_upstream("application/json", r#"{"message":"overloaded"}"#).await;
+		assert_eq!(events, ["error"]);
+	}
+
+	#[tokio::test]
+	async fn stream_closed_without_finish_reason_terminates() {
+		let chunk = serde_json::json!({
+			"id": "chatcmpl-1",
+			"model": "gpt-4",
+			"choices": [{"index": 0, "delta": {"content": "hello"}}]
+		});
+		let events = stream_from_upstream("text/event-stream", &format!("data: {chunk}\n\n")).await;
+		assert_eq!(
+			events,
+			[
+				"message_start",
+				"content_block_start",
+				"content_block_delta",
+				"content_block_stop",
+				"message_delta",
+				"message_stop",
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn concurrent_cold_requests_fetch_models_once() {
+		let state = Arc::new(
+			AppState::new(
+				None,
+				"individual".to_string(),
+				"1.100.0".to_string(),
+				crate::rename::ModelRenamer::from_env(),
+			)
+			.unwrap(),
+		);
+		let fetches = Arc::new(std::sync::atomic::AtomicU32::new(0));
+
+		let requests = (0..8).map(|_| {
+			let state = Arc::clone(&state);
+			let fetches = Arc::clone(&fetches);
+			tokio::spawn(async move {
+				ensure_models_cached_with(&state, || async {
+					fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+					tokio::time::sleep(Duration::from_millis(50)).await;
+					Ok(serde_json::from_value(serde_json::json!({
+						"object": "list",
+						"data": [{"id": "claude-sonnet-4.5", "name": "Claude Sonnet 4.5"}]
+					}))?)
+				})
+				.await
+			})
+		});
+		for request in futures::future::join_all(requests).await {
+			request.unwrap().unwrap();
+		}
+
+		assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+		assert_eq!(
+			state.renamer.resolve("claude-sonnet-4-5"),
+			"claude-sonnet-4.5"
+		);
+	}
+
+	#[tokio::test]
+	async fn cancel_endpoint_stops_registered_stream() {
+		let state = Arc::new(
+			AppState::new(
+				None,
+				"individual".to_string(),
+				"1.100.0".to_string(),
+				crate::rename::ModelRenamer::from_env(),
+			)
+			.unwrap(),
+		);
+		let mut owner = HeaderMap::new();
+		owner.insert("x-api-key", HeaderValue::from_static("ghp_owner"));
+		let mut other = HeaderMap::new();
+		other.insert("x-api-key", HeaderValue::from_static("ghp_other"));
+		let (request_id, events) = state.streams.register(
+			stream_owner(&state, &owner),
+			futures::stream::pending::<Event>(),
+		);
+
+		let resp = cancel_message(State(Arc::clone(&state)), other, Path(request_id.clone())).await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+		let resp = cancel_message(
+			State(Arc::clone(&state)),
+			owner.clone(),
+			Path(request_id.clone()),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(events.collect::<Vec<_>>().await.len(), 0);
+
+		let resp = cancel_message(State(state), owner, Path(request_id)).await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+	}
+
+	fn openai_params_headers(value: &'static str) -> HeaderMap {
+		let mut h = HeaderMap::new();
+		h.insert("x-openai-params", value.parse().unwrap());
+		h
+	}
+
+	#[tokio::test]
+	async fn missing_usage_policies() {
+		let reply = |missing_usage| async move {
+			let upstream = reqwest::Response::from(
+				axum::http::Response::builder()
+					.header("content-type", "application/json")
+					.body(
+						serde_json::json!({
+							"id": "chatcmpl-1",
+							"model": "gpt-4.1",
+							"choices": [{
+								"index": 0,
+								"message": {"role": "assistant", "content": "twelve chars"},
+								"finish_reason": "stop"
+							}]
+						})
+						.to_string(),
+					)
+					.unwrap(),
+			);
+			let resp = handle_non_streaming(
+				upstream,
+				"gpt-4.1".to_string(),
+				false,
+				false,
+				&HashMap::new(),
+				missing_usage,
+				42,
+				false,
+				false,
+				false,
+			)
+			.await;
+			let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+				.await
+				.unwrap();
+			serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+		};
+
+		let zeros = reply(MissingUsage::Zeros).await;
+		assert_eq!(zeros["usage"]["input_tokens"], 0);
+		assert_eq!(zeros["usage"]["output_tokens"], 0);
+
+		let omitted = reply(MissingUsage::Omit).await;
+		assert!(omitted.get("usage").is_none(), "{omitted}");
+		assert_eq!(omitted["content"][0]["text"], "twelve chars");
+
+		let estimated = reply(MissingUsage::Estimate).await;
+		assert_eq!(estimated["usage"]["input_tokens"], 42);
+		assert_eq!(estimated["usage"]["output_tokens"], 3);
+	}
+
+	#[tokio::test]
+	async fn usage_headers_match_body() {
+		let reply = |usage_headers| async move {
+			let upstream = reqwest::Response::from(
+				axum::http::Response::builder()
+					.header("content-type", "application/json")
+					.body(
+						serde_json::json!({
+							"id": "chatcmpl-1",
+							"model": "gpt-4.1",
+							"choices": [{
+								"index": 0,
+								"message": {"role": "assistant", "content": "hi"},
+								"finish_reason": "stop"
+							}],
+							"usage": {"prompt_tokens": 120, "completion_tokens": 7, "total_tokens": 127}
+						})
+						.to_string(),
+					)
+					.unwrap(),
+			);
+			handle_non_streaming(
+				upstream,
+				"gpt-4.1".to_string(),
+				false,
+				false,
+				&HashMap::new(),
+				MissingUsage::Zeros,
+				0,
+				false,
+				false,
+				usage_headers,
+			)
+			.await
+		};
+
+		let resp = reply(true).await;
+		let header = |name| resp.headers()[name].to_str().unwrap().to_string();
+		let (input, output) = (
+			header("x-usage-input-tokens"),
+			header("x-usage-output-tokens"),
+		);
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+		assert_eq!(input, body["usage"]["input_tokens"].to_string());
+		assert_eq!(output, body["usage"]["output_tokens"].to_string());
+		assert_eq!(output, "7");
+
+		let resp = reply(false).await;
+		assert!(resp.headers().get("x-usage-input-tokens").is_none());
+	}
+
+	#[tokio::test]
+	async fn pause_turn_replaces_tool_use_when_enabled() {
+		let reply = |pause_turn| async move {
+			let upstream = reqwest::Response::from(
+				axum::http::Response::builder()
+					.header("content-type", "application/json")
+					.body(
+						serde_json::json!({
+							"id": "chatcmpl-1",
+							"model": "claude-sonnet-4",
+							"choices": [{
+								"index": 0,
+								"message": {"role": "assistant", "tool_calls": [{
+									"id": "call_1",
+									"type": "function",
+									"function": {"name": "ls", "arguments": "{}"}
+								}]},
+								"finish_reason": "tool_calls"
+							}]
+						})
+						.to_string(),
+					)
+					.unwrap(),
+			);
+			let resp = handle_non_streaming(
+				upstream,
+				"claude-sonnet-4".to_string(),
+				false,
+				false,
+				&HashMap::new(),
+				MissingUsage::Zeros,
+				0,
+				pause_turn,
+				false,
+				false,
+			)
+			.await;
+			let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+				.await
+				.unwrap();
+			serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+		};
+
+		assert_eq!(reply(false).await["stop_reason"], "tool_use");
+		assert_eq!(reply(true).await["stop_reason"], "pause_turn");
+
+		assert!(pause_turn_for("claude-sonnet-4", true));
+		assert!(!pause_turn_for("claude-sonnet-4", false));
+		assert!(!pause_turn_for("gpt-4.1", true));
+	}
+
+	#[tokio::test]
+	async fn overloaded_upstream_is_529() {
+		let failure = |status: StatusCode, body: &str| {
+			upstream_failure(
+				UpstreamStatusError {
+					status,
+					body: body.to_string(),
+				}
+				.into(),
+				"claude-sonnet-4",
+			)
+		};
+
+		let resp = failure(StatusCode::SERVICE_UNAVAILABLE, "");
+		assert_eq!(resp.status().as_u16(), 529);
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+		assert_eq!(json["type"], "error");
+		assert_eq!(json["error"]["type"], "overloaded_error");
+
+		let resp = failure(StatusCode::TOO_MANY_REQUESTS, "Model is overloaded");
+		assert_eq!(resp.status().as_u16(), 529);
+		let resp = failure(StatusCode::TOO_MANY_REQUESTS, "quota exceeded");
+		assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+		let resp = failure(StatusCode::INTERNAL_SERVER_ERROR, "");
+		assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+	}
+
+	#[tokio::test]
+	async fn upstream_failure_in_either_format() {
+		let failure = || upstream_failure(anyhow::anyhow!("connection reset"), "claude-sonnet-4");
+		let json = |resp: Response| async {
+			let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+				.await
+				.unwrap();
+			serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+		};
+
+		let resp = ErrorFormat::Anthropic.render(failure()).await;
+		assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+		let body = json(resp).await;
+		assert_eq!(body["type"], "error");
+		assert_eq!(body["error"]["type"], "api_error");
+		assert_eq!(
+			body["error"]["message"],
+			"upstream request failed: connection reset"
+		);
+
+		let resp = ErrorFormat::OpenAi.render(failure()).await;
+		assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+		let body = json(resp).await;
+		assert_eq!(
+			body,
+			serde_json::json!({
+				"error": {
+					"message": "upstream request failed: connection reset",
+					"type": "api_error",
+					"param": null,
+					"code": null
+				}
+			})
+		);
+	}
+
+	#[test]
+	fn unstreamable_model_buffered_or_rejected() {
+		let capabilities: HashMap<String, ModelCapabilities> =
+			serde_json::from_value(serde_json::json!({
+				"o1-pro": {"supports": {"streaming": false}},
+				"gpt-4.1": {"supports": {"streaming": true}},
+				"claude-sonnet-4": {}
+			}))
+			.unwrap();
+		let decide = |streaming: bool, model: &str, policy| {
+			stream_upstream(streaming, model, capabilities.get(model), policy)
+		};
+
+		assert_eq!(
+			decide(true, "o1-pro", UnstreamableModels::Buffer),
+			Ok(false)
+		);
+		let err = decide(true, "o1-pro", UnstreamableModels::Reject).unwrap_err();
+		assert!(err.contains("does not support streaming"), "{err}");
+		assert_eq!(
+			decide(false, "o1-pro", UnstreamableModels::Reject),
+			Ok(false)
+		);
+
+		for model in ["gpt-4.1", "claude-sonnet-4", "unknown-model"] {
+			assert_eq!(decide(true, model, UnstreamableModels::Reject), Ok(true));
+		}
+	}
+
+	#[test]
+	fn max_tokens_bounds() {
+		let capabilities: HashMap<String, ModelCapabilities> =
+			serde_json::from_value(serde_json::json!({
+				"claude-sonnet-4": {"limits": {"max_output_tokens": 16000}},
+				"gpt-5": {"limits": {"max_output_tokens": 128000}},
+				"text-embedding-3-small": {}
+			}))
+			.unwrap();
+
+		let zero = check_max_tokens(0, &capabilities).unwrap_err();
+		assert!(zero.contains("greater than or equal to 1"), "{zero}");
+		assert!(check_max_tokens(1, &capabilities).is_ok());
+		assert!(check_max_tokens(128000, &capabilities).is_ok());
+		let over = check_max_tokens(1_000_000_000, &capabilities).unwrap_err();
+		assert!(over.contains("at most 128000"), "{over}");
+		// Without the model list, only the lower bound is known
+		assert!(check_max_tokens(1_000_000_000, &HashMap::new()).is_ok());
+	}
+
+	#[test]
+	fn openai_params_header_applied_to_forwarded_request() {
+		let req: MessagesRequest = serde_json::from_str(
+			r#"{"model":"gpt-4o","max_tokens":100,"messages":[{"role":"user","content":"hi"}]}"#,
+		)
+		.unwrap();
+		let mut openai_req = translate_request(&req, false, &Default::default()).unwrap();
+
+		let params = OpenAiParams::from_headers(&openai_params_headers(
+			r#"{"frequency_penalty":0.5,"presence_penalty":-0.25,"seed":42}"#,
+		))
+		.unwrap();
+		params.apply(&mut openai_req);
+
+		let body: serde_json::Value =
+			serde_json::from_slice(&serde_json::to_vec(&openai_req).unwrap()).unwrap();
+		assert_eq!(body["frequency_penalty"], 0.5);
+		assert_eq!(body["presence_penalty"], -0.25);
+		assert_eq!(body["seed"], 42);
+	}
+
+	#[test]
+	fn openai_params_header_absent_or_invalid() {
+		let mut openai_req = translate_request(
+			&serde_json::from_str(
+				r#"{"model":"gpt-4o","max_tokens":100,"messages":[{"role":"user","content":"hi"}]}"#,
+			)
+			.unwrap(),
+			false,
+			&Default::default(),
+		)
+		.unwrap();
+		OpenAiParams::from_headers(&HeaderMap::new())
+			.unwrap()
+			.apply(&mut openai_req);
+		let body = serde_json::to_value(&openai_req).unwrap();
+		assert!(body.get("frequency_penalty").is_none());
+		assert!(body.get("seed").is_none());
+
+		let unknown =
+			OpenAiParams::from_headers(&openai_params_headers(r#"{"logit_bias":{}}"#)).unwrap_err();
+		assert!(unknown.contains("unknown field `logit_bias`"), "{unknown}");
+		assert!(OpenAiParams::from_headers(&openai_params_headers("not json")).is_err());
+	}
+
+	fn thinking_headers(value: &'static str) -> HeaderMap {
+		let mut h = HeaderMap::new();
+		h.insert("x-emulate-thinking", value.parse().unwrap());
+		h
+	}
+
+	#[test]
+	fn disable_tools_header_strips_tools() {
+		let req: crate::translate::types::MessagesRequest =
+			serde_json::from_value(serde_json::json!({
+				"model": "claude-sonnet-4-5",
+				"max_tokens": 100,
+				"tools": [{"name": "ls", "input_schema": {"type": "object"}}],
+				"tool_choice": {"type": "any"},
+				"messages": [{"role": "user", "content": "hi"}]
+			}))
+			.unwrap();
+		let translated = translate_request(&req, false, &Default::default()).unwrap();
+
+		let mut headers = HeaderMap::new();
+		assert!(!tools_disabled(&headers));
+		headers.insert("x-disable-tools", "false".parse().unwrap());
+		assert!(!tools_disabled(&headers));
+		assert!(translated.tools.is_some());
+
+		headers.insert("x-disable-tools", "true".parse().unwrap());
+		assert!(tools_disabled(&headers));
+		let mut stripped = translated;
+		disable_tools(&mut stripped);
+		assert!(stripped.tools.is_none());
+		assert!(matches!(stripped.tool_choice, Some(ToolChoice::String(c)) if c == "none"));
+	}
+
+	#[test]
+	fn parallel_tool_calls_dropped_with_tools() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"tools": [{"name": "ls", "input_schema": {"type": "object"}}],
+			"messages": [{"role": "user", "content": "hi"}]
+		}))
+		.unwrap();
+		let options = crate::translate::request::TranslateOptions {
+			parallel_tool_calls_default: Some(true),
+			..Default::default()
+		};
+		let params = || {
+			OpenAiParams::from_headers(&openai_params_headers(r#"{"parallel_tool_calls":false}"#))
+				.unwrap()
+		};
+
+		let mut openai_req = translate_request(&req, false, &options).unwrap();
+		params().apply(&mut openai_req);
+		assert_eq!(openai_req.parallel_tool_calls, Some(false));
+		disable_tools(&mut openai_req);
+		assert_eq!(openai_req.parallel_tool_calls, None);
+
+		let mut without_tools = req;
+		without_tools.tools = None;
+		let mut openai_req = translate_request(&without_tools, false, &options).unwrap();
+		params().apply(&mut openai_req);
+		assert_eq!(openai_req.parallel_tool_calls, None);
+	}
+
+	#[test]
+	fn emulate_thinking_header_overrides_default() {
+		assert!(emulate_thinking_for(&thinking_headers("true"), false));
+		assert!(!emulate_thinking_for(&thinking_headers("false"), true));
+		assert!(emulate_thinking_for(&thinking_headers("TRUE"), false));
+		assert!(emulate_thinking_for(&thinking_headers("maybe"), true));
+		assert!(!emulate_thinking_for(&HeaderMap::new(), false));
+	}
+
+	#[test]
+	fn emulate_thinking_override_controls_parsing() {
+		use crate::copilot::types::{ChatCompletionResponse, Choice, ResponseMessage};
+		use crate::translate::types::AssistantContentBlock;
+
+		let resp = ChatCompletionResponse {
+			id: "chatcmpl-1".to_string(),
+			object: "chat.completion".to_string(),
+			created: 0,
+			model: "gpt-4".to_string(),
+			choices: vec![Choice {
+				index: 0,
+				message: ResponseMessage {
+					role: "assistant".to_string(),
+					content: Some("<thinking>hmm</thinking>42".to_string()),
+					tool_calls: None,
+				},
+				finish_reason: Some("stop".to_string()),
+				logprobs: None,
+			}],
+			system_fingerprint: None,
+			usage: None,
+		};
+
+		let on = emulate_thinking_for(&thinking_headers("true"), false);
+		let parsed = translate_response(&resp, on, false);
+		assert!(matches!(
+			parsed.content[0],
+			AssistantContentBlock::Thinking(_)
+		));
+
+		let off = emulate_thinking_for(&thinking_headers("false"), true);
+		let raw = translate_response(&resp, off, false);
+		assert!(
+			matches!(&raw.content[0], AssistantContentBlock::Text(t) if t.text == "<thinking>hmm</thinking>42")
+		);
+	}
+
+	struct DropFlag(Arc<std::sync::atomic::AtomicBool>);
+
+	impl Drop for DropFlag {
+		fn drop(&mut self) {
+			self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+		}
+	}
+
+	#[tokio::test]
+	async fn soft_deadline_aborts_slow_upstream() {
+		let dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+		let guard = DropFlag(Arc::clone(&dropped));
+		let slow_upstream = async move {
+			let _guard = guard;
+			tokio::time::sleep(Duration::from_secs(60)).await;
+			"response"
+		};
+
+		let result = with_soft_deadline(Some(Duration::from_millis(10)), slow_upstream).await;
+		assert_eq!(result, Err(Duration::from_millis(10)));
+		assert!(dropped.load(std::sync::atomic::Ordering::SeqCst));
+	}
+
+	#[tokio::test]
+	async fn soft_deadline_passes_fast_response() {
+		let result = with_soft_deadline(Some(Duration::from_secs(5)), async { "response" }).await;
+		assert_eq!(result, Ok("response"));
+		assert_eq!(with_soft_deadline(None, async { 1 }).await, Ok(1));
+	}
+
 	#[test]
 	fn extract_sse_data_simple() {
 		let mut buf = "data: hello\n\n".to_string();
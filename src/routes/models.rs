@@ -1,18 +1,33 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use axum::Json;
-use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
 use tracing::{info, warn};
 
 use crate::auth::extract::extract_gh_token;
 use crate::copilot::client::fetch_models;
-use crate::copilot::types::{AnthropicModelInfo, AnthropicModelsResponse};
+use crate::copilot::types::{AnthropicModelInfo, AnthropicModelsResponse, Model, ModelsResponse};
+use crate::rename::glob_match;
 use crate::state::AppState;
 
-pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
-	let is_anthropic = headers.get("anthropic-version").is_some();
+#[derive(Debug, Default, Deserialize)]
+pub struct ModelsQuery {
+	/// Return the full Copilot model objects (with capabilities) even to
+	/// Anthropic clients.
+	#[serde(default)]
+	pub raw: bool,
+}
+
+pub async fn get_models(
+	State(state): State<Arc<AppState>>,
+	Query(query): Query<ModelsQuery>,
+	headers: HeaderMap,
+) -> Response {
+	let is_anthropic = headers.get("anthropic-version").is_some() && !query.raw;
 
 	// Try to serve from cache first if valid
 	{
@@ -23,29 +38,65 @@ pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap)
 					count = cached.response.data.len(),
 					"serving models list from cache"
 				);
-				if is_anthropic {
-					return Json(to_anthropic_format(&cached.response)).into_response();
-				} else {
-					return Json(cached.response.clone()).into_response();
-				}
+				return models_response(&cached.response, is_anthropic, &state);
 			}
 			info!("models cache expired, refetching");
 		}
 	}
 
+	match fetch_models_on_demand(&state, &headers).await {
+		Ok(models) => models_response(&models, is_anthropic, &state),
+		Err(error) => {
+			// An expired list beats none at all
+			let models = state.models.read().await;
+			let Some(stale) = models.as_ref() else {
+				return error;
+			};
+			warn!(
+				status = %error.status(),
+				count = stale.response.data.len(),
+				"serving stale models list after failed refetch"
+			);
+			let mut resp = models_response(&stale.response, is_anthropic, &state);
+			resp.headers_mut()
+				.insert("x-cache", HeaderValue::from_static("stale"));
+			resp
+		}
+	}
+}
+
+/// Fetch the models list from Copilot and cache it, or the error response to
+/// give when that fails.
+async fn fetch_models_on_demand(
+	state: &AppState,
+	headers: &HeaderMap,
+) -> Result<ModelsResponse, Response> {
+	// One fetch at a time, shared with `/v1/messages` and warmup: requests that
+	// queued up behind it find the cache filled
+	let _fetching = state.models_fetch.lock().await;
+	{
+		let models = state.models.read().await;
+		if let Some(cached) = models.as_ref()
+			&& state.is_models_cache_valid(cached)
+		{
+			info!("models fetched by a concurrent request, serving from cache");
+			return Ok(cached.response.clone());
+		}
+	}
+
 	// Cache is empty or expired - fetch on-demand
 	info!("fetching models on-demand");
 
 	// Get a GitHub token from request or default
-	let gh_token = extract_gh_token(&headers)
-		.map(|s| s.to_string())
+	let gh_token = extract_gh_token(headers, state.accept_base64_keys)
+		.map(|s| s.into_owned())
 		.or_else(|| state.default_github_token.clone());
 
 	let gh_token = match gh_token {
 		Some(t) => t,
 		None => {
 			warn!("no GitHub token available for on-demand model fetch");
-			return (
+			return Err((
 				StatusCode::SERVICE_UNAVAILABLE,
 				Json(serde_json::json!({
 					"error": {
@@ -54,7 +105,7 @@ pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap)
 					}
 				})),
 			)
-				.into_response();
+				.into_response());
 		}
 	};
 
@@ -67,7 +118,7 @@ pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap)
 		Ok(t) => t,
 		Err(e) => {
 			warn!(error = %e, "failed to exchange token for on-demand model fetch");
-			return (
+			return Err((
 				StatusCode::UNAUTHORIZED,
 				Json(serde_json::json!({
 					"error": {
@@ -76,7 +127,7 @@ pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap)
 					}
 				})),
 			)
-				.into_response();
+				.into_response());
 		}
 	};
 
@@ -84,15 +135,17 @@ pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap)
 	let mut models = match fetch_models(
 		&state.client,
 		&copilot_token,
-		&state.account_type,
+		&state.account_type(),
 		&state.vscode_version,
+		&state.copilot_integration_id,
+		state.models_timeout,
 	)
 	.await
 	{
 		Ok(m) => m,
 		Err(e) => {
 			warn!(error = %e, "failed to fetch models on-demand");
-			return (
+			return Err((
 				StatusCode::BAD_GATEWAY,
 				Json(serde_json::json!({
 					"error": {
@@ -101,7 +154,7 @@ pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap)
 					}
 				})),
 			)
-				.into_response();
+				.into_response());
 		}
 	};
 
@@ -125,26 +178,66 @@ pub async fn get_models(State(state): State<Arc<AppState>>, headers: HeaderMap)
 	}
 
 	// Update cache with timestamp
-	*state.models.write().await = Some(crate::state::CachedModels {
-		response: models.clone(),
-		cached_at: std::time::SystemTime::now(),
-	});
+	state.store_models(models.clone()).await;
 
-	if is_anthropic {
-		Json(to_anthropic_format(&models)).into_response()
+	Ok(models)
+}
+
+fn models_response(models: &ModelsResponse, anthropic: bool, state: &AppState) -> Response {
+	let mut seen = HashSet::new();
+	let mut data: Vec<Model> = models
+		.data
+		.iter()
+		.filter(|m| !is_hidden(&state.hidden_models, &m.id))
+		.filter(|m| seen.insert(m.id.as_str()))
+		.cloned()
+		.collect();
+	if state.sort_models {
+		data.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+	}
+	let listed = ModelsResponse {
+		data,
+		object: models.object.clone(),
+	};
+	if anthropic {
+		Json(to_anthropic_format(&listed, &state.model_display_template)).into_response()
 	} else {
-		Json(models).into_response()
+		Json(listed).into_response()
 	}
 }
 
-fn to_anthropic_format(models: &crate::copilot::types::ModelsResponse) -> AnthropicModelsResponse {
+/// Family then version, for `SORT_MODELS`, falling back to the ID for models
+/// that don't say.
+fn sort_key(model: &Model) -> (&str, &str, &str) {
+	let family = model
+		.capabilities
+		.as_ref()
+		.map(|c| c.family.as_str())
+		.filter(|f| !f.is_empty())
+		.unwrap_or(&model.id);
+	(family, &model.version, &model.id)
+}
+
+/// Whether a model is left out of listings by `HIDE_MODELS`. Hidden models
+/// can still be requested by name.
+fn is_hidden(patterns: &[String], id: &str) -> bool {
+	patterns.iter().any(|pattern| glob_match(pattern, id))
+}
+
+/// Fill in a `MODEL_DISPLAY_TEMPLATE`: `{id}` is the (renamed) model ID and
+/// `{name}` Copilot's human-readable name.
+fn display_name(template: &str, id: &str, name: &str) -> String {
+	template.replace("{id}", id).replace("{name}", name)
+}
+
+fn to_anthropic_format(models: &ModelsResponse, display_template: &str) -> AnthropicModelsResponse {
 	let data: Vec<AnthropicModelInfo> = models
 		.data
 		.iter()
 		.map(|m| AnthropicModelInfo {
 			id: m.id.clone(),
 			created_at: "1970-01-01T00:00:00Z".to_string(),
-			display_name: m.name.clone(),
+			display_name: display_name(display_template, &m.id, &m.name),
 			r#type: "model".to_string(),
 		})
 		.collect();
@@ -159,3 +252,206 @@ fn to_anthropic_format(models: &crate::copilot::types::ModelsResponse) -> Anthro
 		last_id,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rename::ModelRenamer;
+	use crate::state::CachedModels;
+
+	async fn cached_state() -> Arc<AppState> {
+		let state = Arc::new(
+			AppState::new(
+				None,
+				"individual".to_string(),
+				"1.100.0".to_string(),
+				ModelRenamer::from_env(),
+			)
+			.unwrap(),
+		);
+		let response: ModelsResponse = serde_json::from_value(serde_json::json!({
+			"object": "list",
+			"data": [{
+				"id": "claude-sonnet-4",
+				"name": "Claude Sonnet 4",
+				"capabilities": {
+					"family": "claude-sonnet-4",
+					"object": "model_capabilities",
+					"supports": {"vision": true, "tool_calls": true}
+				}
+			}, {
+				"id": "oswe-vscode-prime",
+				"name": "Raptor mini (Preview)"
+			}, {
+				"id": "gemini-3-pro-preview",
+				"name": "Gemini 3 Pro (Preview)"
+			}]
+		}))
+		.unwrap();
+		*state.models.write().await = Some(CachedModels {
+			response,
+			cached_at: std::time::SystemTime::now(),
+		});
+		state
+	}
+
+	async fn list(raw: bool) -> serde_json::Value {
+		list_from(cached_state().await, raw).await
+	}
+
+	async fn list_from(state: Arc<AppState>, raw: bool) -> serde_json::Value {
+		let mut headers = HeaderMap::new();
+		headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
+		let resp = get_models(State(state), Query(ModelsQuery { raw }), headers).await;
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		serde_json::from_slice(&bytes).unwrap()
+	}
+
+	#[tokio::test]
+	async fn raw_query_includes_capabilities_for_anthropic_clients() {
+		let json = list(true).await;
+		assert_eq!(json["data"][0]["capabilities"]["supports"]["vision"], true);
+	}
+
+	#[tokio::test]
+	async fn anthropic_format_by_default() {
+		let json = list(false).await;
+		assert_eq!(json["data"][0]["type"], "model");
+		assert!(json["data"][0].get("capabilities").is_none());
+	}
+
+	#[tokio::test]
+	async fn display_name_matches_id_by_default() {
+		let json = list(false).await;
+		assert_eq!(json["data"][0]["id"], "claude-sonnet-4");
+		assert_eq!(json["data"][0]["display_name"], "claude-sonnet-4");
+	}
+
+	#[tokio::test]
+	async fn display_name_from_template() {
+		let mut state = cached_state().await;
+		Arc::get_mut(&mut state).unwrap().model_display_template = "{name} ({id})".to_string();
+		let json = list_from(state, false).await;
+		assert_eq!(
+			json["data"][0]["display_name"],
+			"Claude Sonnet 4 (claude-sonnet-4)"
+		);
+	}
+
+	#[tokio::test]
+	async fn hidden_models_left_out_of_listings_only() {
+		let mut state = cached_state().await;
+		Arc::get_mut(&mut state).unwrap().hidden_models =
+			vec!["oswe-vscode-*".to_string(), "*-preview".to_string()];
+
+		for raw in [false, true] {
+			let json = list_from(Arc::clone(&state), raw).await;
+			let ids: Vec<&str> = json["data"]
+				.as_array()
+				.unwrap()
+				.iter()
+				.map(|m| m["id"].as_str().unwrap())
+				.collect();
+			assert_eq!(ids, ["claude-sonnet-4"]);
+		}
+
+		let cached = state.models.read().await;
+		assert_eq!(cached.as_ref().unwrap().response.data.len(), 3);
+		drop(cached);
+		assert_eq!(
+			state.resolve_model("oswe-vscode-prime").await,
+			"oswe-vscode-prime"
+		);
+	}
+
+	#[tokio::test]
+	async fn duplicates_listed_once_and_optionally_sorted() {
+		let mut state = cached_state().await;
+		let model = |id: &str, family: &str, version: &str| {
+			serde_json::json!({
+				"id": id,
+				"version": version,
+				"capabilities": {"family": family}
+			})
+		};
+		let response: ModelsResponse = serde_json::from_value(serde_json::json!({
+			"object": "list",
+			"data": [
+				model("gpt-4-o-preview", "gpt-4o", "gpt-4o-2024-05-13"),
+				model("claude-sonnet-4", "claude-sonnet-4", "claude-sonnet-4"),
+				model("gpt-4o", "gpt-4o", "gpt-4o-2024-11-20"),
+				model("gpt-4-o-preview", "gpt-4o", "gpt-4o-2024-08-06"),
+				model("claude-opus-4", "claude-opus-4", "claude-opus-4"),
+			]
+		}))
+		.unwrap();
+		state.models.write().await.as_mut().unwrap().response = response;
+
+		let ids = |json: serde_json::Value| -> Vec<String> {
+			json["data"]
+				.as_array()
+				.unwrap()
+				.iter()
+				.map(|m| m["id"].as_str().unwrap().to_string())
+				.collect()
+		};
+		assert_eq!(
+			ids(list_from(Arc::clone(&state), true).await),
+			[
+				"gpt-4-o-preview",
+				"claude-sonnet-4",
+				"gpt-4o",
+				"claude-opus-4"
+			]
+		);
+		let raw = list_from(Arc::clone(&state), true).await;
+		assert_eq!(raw["data"][0]["version"], "gpt-4o-2024-05-13");
+
+		Arc::get_mut(&mut state).unwrap().sort_models = true;
+		for _ in 0..2 {
+			assert_eq!(
+				ids(list_from(Arc::clone(&state), false).await),
+				[
+					"claude-opus-4",
+					"claude-sonnet-4",
+					"gpt-4-o-preview",
+					"gpt-4o"
+				]
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn stale_cache_served_when_refetch_fails() {
+		let state = cached_state().await;
+		state.models.write().await.as_mut().unwrap().cached_at =
+			std::time::SystemTime::now() - state.models_cache_ttl * 2;
+
+		// No GitHub token, so the refetch fails without reaching out
+		let resp = get_models(
+			State(Arc::clone(&state)),
+			Query(ModelsQuery::default()),
+			HeaderMap::new(),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(resp.headers()["x-cache"], "stale");
+		let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+		assert_eq!(json["data"][0]["id"], "claude-sonnet-4");
+
+		*state.models.write().await = None;
+		let resp = get_models(
+			State(state),
+			Query(ModelsQuery::default()),
+			HeaderMap::new(),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+		assert!(resp.headers().get("x-cache").is_none());
+	}
+}
@@ -0,0 +1,215 @@
+use std::env;
+
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use reqwest::Client;
+use serde_json::Value;
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use crate::translate::types::MessagesResponse;
+
+const DEFAULT_ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// A second Anthropic-compatible backend that non-streaming `/v1/messages`
+/// requests are mirrored to, to compare its answers with ours.
+#[derive(Debug, Clone)]
+pub struct ShadowBackend {
+	url: String,
+	api_key: Option<String>,
+}
+
+/// The parts of a message response compared between backends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageSummary {
+	pub stop_reason: Option<String>,
+	pub input_tokens: u64,
+	pub output_tokens: u64,
+}
+
+impl MessageSummary {
+	pub fn from_json(message: &Value) -> Self {
+		let tokens = |field: &str| message["usage"][field].as_u64().unwrap_or_default();
+		Self {
+			stop_reason: message["stop_reason"].as_str().map(str::to_string),
+			input_tokens: tokens("input_tokens"),
+			output_tokens: tokens("output_tokens"),
+		}
+	}
+
+	pub fn from_response(message: &MessagesResponse) -> Self {
+		Self {
+			stop_reason: message
+				.stop_reason
+				.and_then(|r| serde_json::to_value(r).ok())
+				.and_then(|r| r.as_str().map(str::to_string)),
+			input_tokens: message.usage.input_tokens,
+			output_tokens: message.usage.output_tokens,
+		}
+	}
+}
+
+impl ShadowBackend {
+	/// Read `SHADOW_BACKEND_URL`, and the optional `SHADOW_API_KEY` to
+	/// authenticate there (the client's key is never forwarded).
+	pub fn from_env() -> Option<Self> {
+		let url = env::var("SHADOW_BACKEND_URL")
+			.ok()
+			.filter(|u| !u.is_empty())?;
+		info!(url, "shadowing non-streaming messages requests");
+		Some(Self {
+			url: url.trim_end_matches('/').to_string(),
+			api_key: env::var("SHADOW_API_KEY").ok().filter(|k| !k.is_empty()),
+		})
+	}
+
+	/// Send the original request `body` to the shadow backend in the
+	/// background. Once the primary summary is sent on the returned channel,
+	/// the two are compared and differences logged. Never fails or blocks.
+	pub fn mirror(
+		&self,
+		client: &Client,
+		body: Bytes,
+		headers: &HeaderMap,
+	) -> oneshot::Sender<MessageSummary> {
+		let (tx, primary) = oneshot::channel();
+		let shadow = self.clone();
+		let client = client.clone();
+		let version = headers
+			.get("anthropic-version")
+			.and_then(|v| v.to_str().ok())
+			.unwrap_or(DEFAULT_ANTHROPIC_VERSION)
+			.to_string();
+		tokio::spawn(async move {
+			let shadowed = match shadow.send(&client, body, &version).await {
+				Ok(summary) => summary,
+				Err(e) => {
+					warn!(error = %format!("{e:#}"), "shadow request failed");
+					return;
+				}
+			};
+			let Ok(primary) = primary.await else {
+				debug!(
+					?shadowed,
+					"no primary response to compare the shadow's with"
+				);
+				return;
+			};
+			if primary == shadowed {
+				debug!(?primary, "shadow response matches");
+			} else {
+				info!(?primary, ?shadowed, "shadow response differs");
+			}
+		});
+		tx
+	}
+
+	async fn send(
+		&self,
+		client: &Client,
+		body: Bytes,
+		version: &str,
+	) -> anyhow::Result<MessageSummary> {
+		let mut req = client
+			.post(format!("{}/v1/messages", self.url))
+			.header("content-type", "application/json")
+			.header("anthropic-version", version)
+			.body(body);
+		if let Some(key) = &self.api_key {
+			req = req.header("x-api-key", key);
+		}
+		let message: Value = req.send().await?.error_for_status()?.json().await?;
+		Ok(MessageSummary::from_json(&message))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use axum::Router;
+	use axum::routing::post;
+	use tokio::sync::mpsc;
+
+	use super::*;
+
+	async fn serve(app: Router) -> String {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+		url
+	}
+
+	#[tokio::test]
+	async fn shadow_receives_original_request() {
+		let (seen_tx, mut seen) = mpsc::unbounded_channel();
+		let app = Router::new().route(
+			"/v1/messages",
+			post(move |headers: HeaderMap, body: Bytes| {
+				let seen_tx = seen_tx.clone();
+				async move {
+					seen_tx.send((headers, body)).unwrap();
+					axum::Json(serde_json::json!({
+						"stop_reason": "end_turn",
+						"usage": {"input_tokens": 12, "output_tokens": 3}
+					}))
+				}
+			}),
+		);
+		let shadow = ShadowBackend {
+			url: serve(app).await,
+			api_key: Some("sk-ant-shadow".to_string()),
+		};
+
+		let body = Bytes::from_static(br#"{"model":"claude-sonnet-4-5","max_tokens":10}"#);
+		let mut headers = HeaderMap::new();
+		headers.insert("x-api-key", "ghu_client".parse().unwrap());
+		let primary = shadow.mirror(&Client::new(), body.clone(), &headers);
+		primary
+			.send(MessageSummary {
+				stop_reason: Some("end_turn".to_string()),
+				input_tokens: 12,
+				output_tokens: 3,
+			})
+			.unwrap();
+
+		let (headers, received) = seen.recv().await.unwrap();
+		assert_eq!(received, body);
+		assert_eq!(headers["x-api-key"], "sk-ant-shadow");
+		assert_eq!(headers["anthropic-version"], DEFAULT_ANTHROPIC_VERSION);
+
+		let summary = shadow
+			.send(&Client::new(), body, DEFAULT_ANTHROPIC_VERSION)
+			.await
+			.unwrap();
+		assert_eq!(summary.input_tokens, 12);
+		assert_eq!(summary.stop_reason.as_deref(), Some("end_turn"));
+	}
+
+	#[tokio::test]
+	async fn shadow_failure_stays_in_background() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		drop(listener);
+		let shadow = ShadowBackend { url, api_key: None };
+
+		let primary = tokio::time::timeout(Duration::from_millis(50), async {
+			shadow.mirror(&Client::new(), Bytes::from_static(b"{}"), &HeaderMap::new())
+		})
+		.await
+		.expect("mirroring must not wait on the shadow backend");
+		// The primary side can still report, whether or not the shadow task is around
+		let _ = primary.send(MessageSummary {
+			stop_reason: None,
+			input_tokens: 0,
+			output_tokens: 0,
+		});
+
+		assert!(
+			shadow
+				.send(&Client::new(), Bytes::from_static(b"{}"), "2023-06-01")
+				.await
+				.is_err()
+		);
+	}
+}
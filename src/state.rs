@@ -1,10 +1,48 @@
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock as SyncRwLock};
 use std::time::{Duration, SystemTime};
 
+use axum::response::sse::Event;
+
 use crate::auth::cache::TokenCache;
-use crate::copilot::types::ModelsResponse;
+use crate::batches::BatchStore;
+use crate::cancel::StreamRegistry;
+use crate::coalesce::Coalescer;
+use crate::copilot::api::DEFAULT_INTEGRATION_ID;
+use crate::copilot::client::build_http_client;
+use crate::copilot::types::{ModelCapabilities, ModelsResponse};
+use crate::idempotency::IdempotencyCache;
+use crate::inbound::InboundHeaderFilter;
 use crate::rename::ModelRenamer;
-use tokio::sync::RwLock;
+use crate::shadow::ShadowBackend;
+use crate::stats::ModelStats;
+use crate::translate::models::{ModelFamily, model_family};
+use crate::translate::request::TranslateOptions;
+use crate::translate::response::MissingUsage;
+use crate::translate::stream::UnstreamableModels;
+use tokio::sync::{Mutex, RwLock};
+
+/// Default for the quick upstream calls (model list and token exchange).
+const DEFAULT_SHORT_TIMEOUT_SECS: u64 = 10;
+
+const DEFAULT_WARMUP_INTERVAL: Duration = Duration::from_secs(300);
+
+const DEFAULT_TOKEN_EXCHANGE_RETRIES: u32 = 3;
+
+/// Default bounds for `x-request-timeout-secs`.
+const DEFAULT_REQUEST_TIMEOUT_MIN: Duration = Duration::from_secs(1);
+const DEFAULT_REQUEST_TIMEOUT_MAX: Duration = Duration::from_secs(600);
+
+fn timeout_from_env(var: &str) -> Duration {
+	let secs = env::var(var)
+		.ok()
+		.and_then(|v| v.parse::<u64>().ok())
+		.filter(|&secs| secs > 0)
+		.unwrap_or(DEFAULT_SHORT_TIMEOUT_SECS);
+	Duration::from_secs(secs)
+}
 
 pub struct CachedModels {
 	pub response: ModelsResponse,
@@ -13,23 +51,72 @@ pub struct CachedModels {
 
 pub struct AppState {
 	pub default_github_token: Option<String>,
-	pub account_type: String,
+	pub copilot_token_prefix: String,
+	account_type: SyncRwLock<String>,
 	pub vscode_version: String,
+	/// Editor versions to claim for chat requests to some model families,
+	/// in place of `vscode_version`.
+	pub editor_versions: HashMap<ModelFamily, String>,
+	pub copilot_integration_id: String,
 	pub models: RwLock<Option<CachedModels>>,
+	/// Capabilities of the cached models by upstream name, rebuilt with the
+	/// cache so requests can take a snapshot without holding its lock.
+	capabilities: SyncRwLock<Arc<HashMap<String, ModelCapabilities>>>,
+	/// Held while fetching the model list, so concurrent misses wait for one fetch.
+	pub models_fetch: Mutex<()>,
 	pub models_cache_ttl: Duration,
+	pub model_display_template: String,
+	pub hidden_models: Vec<String>,
+	/// List models by family and version, rather than in Copilot's order.
+	pub sort_models: bool,
+	pub models_timeout: Duration,
 	pub client: reqwest::Client,
 	pub renamer: ModelRenamer,
 	pub token_cache: TokenCache,
+	/// The shortest wait between refreshes of the default Copilot token.
+	pub token_refresh_floor: Duration,
+	/// How many times to retry the startup token exchange for `GH_TOKEN`.
+	pub token_exchange_retries: u32,
 	pub emulate_thinking: bool,
+	pub strip_tool_preamble: bool,
+	pub use_pause_turn: bool,
+	pub drop_thinking: bool,
+	/// Report usage in `x-usage-*-tokens` headers on non-streaming responses.
+	pub usage_headers: bool,
+	pub missing_usage: MissingUsage,
+	pub unstreamable_models: UnstreamableModels,
+	pub derive_user_from_token: bool,
+	pub admin_token: Option<String>,
+	pub accept_base64_keys: bool,
+	pub messages_soft_timeout: Option<Duration>,
+	/// How often to make sure the default token and models list are fresh,
+	/// with `WARMUP`.
+	pub warmup_interval: Option<Duration>,
+	/// Bounds for a client's own `x-request-timeout-secs`.
+	pub request_timeout_min: Duration,
+	pub request_timeout_max: Duration,
+	pub translate_options: TranslateOptions,
+	pub debug_endpoints: bool,
+	pub forward_client_info: bool,
+	pub model_stats: ModelStats,
+	pub stream_coalesce: bool,
+	pub stream_coalescer: Coalescer<Event>,
+	pub streams: StreamRegistry,
+	pub idempotency: IdempotencyCache,
+	pub batches: BatchStore,
+	pub shadow_backend: Option<ShadowBackend>,
+	pub inbound_headers: InboundHeaderFilter,
 }
 
 impl AppState {
+	/// Fails if the HTTP client can't be built, e.g. with a bad
+	/// `COPILOT_CA_CERT`.
 	pub fn new(
 		default_github_token: Option<String>,
 		account_type: String,
 		vscode_version: String,
 		renamer: ModelRenamer,
-	) -> Self {
+	) -> anyhow::Result<Self> {
 		let emulate_thinking = env::var("EMULATE_THINKING")
 			.map(|v| v != "false")
 			.unwrap_or(true);
@@ -39,17 +126,180 @@ impl AppState {
 			.and_then(|v| v.parse::<u64>().ok())
 			.unwrap_or(3600); // Default: 1 hour
 
-		Self {
+		let ca_cert = env::var_os("COPILOT_CA_CERT").map(PathBuf::from);
+		let ca_only = env::var("COPILOT_CA_CERT_ONLY").is_ok_and(|v| v == "true" || v == "1");
+		let client = build_http_client(ca_cert.as_deref(), ca_only)?;
+
+		Ok(Self {
 			default_github_token,
-			account_type,
+			copilot_token_prefix: env::var("COPILOT_TOKEN_PREFIX")
+				.ok()
+				.filter(|p| !p.is_empty())
+				.unwrap_or_else(|| "tid=".to_string()),
+			account_type: SyncRwLock::new(account_type),
 			vscode_version,
+			editor_versions: env::var("EDITOR_VERSION_MAP")
+				.ok()
+				.and_then(|raw| match serde_json::from_str(&raw) {
+					Ok(m) => Some(m),
+					Err(e) => {
+						tracing::warn!(error = %e, "EDITOR_VERSION_MAP is not valid JSON, ignoring");
+						None
+					}
+				})
+				.unwrap_or_default(),
+			copilot_integration_id: env::var("COPILOT_INTEGRATION_ID")
+				.ok()
+				.filter(|id| !id.is_empty())
+				.unwrap_or_else(|| DEFAULT_INTEGRATION_ID.to_string()),
 			models: RwLock::new(None),
-			client: reqwest::Client::new(),
+			capabilities: SyncRwLock::default(),
+			models_fetch: Mutex::new(()),
+			client,
 			renamer,
-			token_cache: TokenCache::new(),
+			token_cache: TokenCache::new(timeout_from_env("TOKEN_TIMEOUT_SECS")),
+			token_refresh_floor: env::var("TOKEN_REFRESH_FLOOR_SECS")
+				.ok()
+				.and_then(|v| v.parse::<u64>().ok())
+				.map_or(Duration::from_secs(30), Duration::from_secs),
+			token_exchange_retries: env::var("TOKEN_EXCHANGE_RETRIES")
+				.ok()
+				.and_then(|v| v.parse().ok())
+				.unwrap_or(DEFAULT_TOKEN_EXCHANGE_RETRIES),
 			emulate_thinking,
+			strip_tool_preamble: env::var("STRIP_TOOL_PREAMBLE")
+				.is_ok_and(|v| v == "true" || v == "1"),
+			use_pause_turn: env::var("USE_PAUSE_TURN").is_ok_and(|v| v == "true" || v == "1"),
+			drop_thinking: env::var("DROP_THINKING").is_ok_and(|v| v == "true" || v == "1"),
+			usage_headers: env::var("USAGE_HEADERS").is_ok_and(|v| v == "true" || v == "1"),
+			missing_usage: MissingUsage::from_env(),
+			unstreamable_models: UnstreamableModels::from_env(),
+			derive_user_from_token: env::var("DERIVE_USER_FROM_TOKEN")
+				.is_ok_and(|v| v == "true" || v == "1"),
+			admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+			accept_base64_keys: env::var("ACCEPT_BASE64_KEYS")
+				.is_ok_and(|v| v == "true" || v == "1"),
+			messages_soft_timeout: env::var("MESSAGES_SOFT_TIMEOUT_SECS")
+				.ok()
+				.and_then(|v| v.parse::<u64>().ok())
+				.filter(|&secs| secs > 0)
+				.map(Duration::from_secs),
+			warmup_interval: env::var("WARMUP")
+				.is_ok_and(|v| v == "true" || v == "1")
+				.then(|| {
+					env::var("WARMUP_INTERVAL_SECS")
+						.ok()
+						.and_then(|v| v.parse::<u64>().ok())
+						.filter(|&secs| secs > 0)
+						.map_or(DEFAULT_WARMUP_INTERVAL, Duration::from_secs)
+				}),
+			request_timeout_min: env::var("REQUEST_TIMEOUT_MIN_SECS")
+				.ok()
+				.and_then(|v| v.parse::<u64>().ok())
+				.map_or(DEFAULT_REQUEST_TIMEOUT_MIN, Duration::from_secs),
+			request_timeout_max: env::var("REQUEST_TIMEOUT_MAX_SECS")
+				.ok()
+				.and_then(|v| v.parse::<u64>().ok())
+				.map_or(DEFAULT_REQUEST_TIMEOUT_MAX, Duration::from_secs),
+			translate_options: TranslateOptions::from_env(),
+			debug_endpoints: env::var("DEBUG_ENDPOINTS").is_ok_and(|v| v == "true" || v == "1"),
+			model_stats: ModelStats::default(),
+			stream_coalesce: env::var("STREAM_COALESCE").is_ok_and(|v| v == "true" || v == "1"),
+			stream_coalescer: Coalescer::default(),
+			streams: StreamRegistry::default(),
+			idempotency: IdempotencyCache::from_env(),
+			batches: BatchStore::from_env(),
+			shadow_backend: ShadowBackend::from_env(),
+			inbound_headers: InboundHeaderFilter::from_env(),
+			forward_client_info: env::var("FORWARD_CLIENT_INFO")
+				.is_ok_and(|v| v == "true" || v == "1"),
 			models_cache_ttl: Duration::from_secs(models_cache_ttl_secs),
+			models_timeout: timeout_from_env("MODELS_TIMEOUT_SECS"),
+			hidden_models: env::var("HIDE_MODELS")
+				.unwrap_or_default()
+				.split(',')
+				.map(str::trim)
+				.filter(|p| !p.is_empty())
+				.map(str::to_string)
+				.collect(),
+			sort_models: env::var("SORT_MODELS").is_ok_and(|v| v == "true" || v == "1"),
+			model_display_template: env::var("MODEL_DISPLAY_TEMPLATE")
+				.ok()
+				.filter(|t| !t.is_empty())
+				.unwrap_or_else(|| "{id}".to_string()),
+		})
+	}
+
+	/// The editor version to claim for a chat request to `model` (upstream).
+	pub fn editor_version(&self, model: &str) -> &str {
+		self.editor_versions
+			.get(&model_family(model))
+			.unwrap_or(&self.vscode_version)
+	}
+
+	pub fn account_type(&self) -> String {
+		self.account_type.read().unwrap().clone()
+	}
+
+	/// Switch the account type for subsequent upstream calls, dropping the
+	/// models cache since it was fetched from the previous base URL.
+	pub async fn set_account_type(&self, account_type: String) {
+		*self.account_type.write().unwrap() = account_type;
+		self.clear_models_cache().await;
+	}
+
+	pub async fn clear_models_cache(&self) {
+		*self.models.write().await = None;
+		*self.capabilities.write().unwrap() = Arc::default();
+	}
+
+	/// Cache a (renamed) model list, and the capabilities of its models.
+	pub async fn store_models(&self, models: ModelsResponse) {
+		let capabilities = models
+			.data
+			.iter()
+			.filter_map(|m| {
+				let caps = m.capabilities.clone()?;
+				Some((self.renamer.resolve(&m.id), caps))
+			})
+			.collect();
+		let mut cached = self.models.write().await;
+		*self.capabilities.write().unwrap() = Arc::new(capabilities);
+		*cached = Some(CachedModels {
+			response: models,
+			cached_at: SystemTime::now(),
+		});
+	}
+
+	/// The capabilities of all cached models, as of now.
+	pub fn capabilities(&self) -> Arc<HashMap<String, ModelCapabilities>> {
+		Arc::clone(&self.capabilities.read().unwrap())
+	}
+
+	/// Map a requested model name to the upstream model ID, expanding aliases
+	/// against the cached model list first.
+	pub async fn resolve_model(&self, name: &str) -> String {
+		if self.renamer.is_alias(name) {
+			let models = self.models.read().await;
+			let ids = models
+				.iter()
+				.flat_map(|cached| cached.response.data.iter().map(|m| m.id.as_str()));
+			match self.renamer.expand_alias(name, ids) {
+				Some(target) => {
+					tracing::debug!(alias = name, target = %target, "expanded model alias");
+					return self.renamer.resolve(&target);
+				}
+				None => tracing::warn!(alias = name, "no cached model matches alias"),
+			}
 		}
+		self.renamer.resolve(name)
+	}
+
+	/// How long ago the model list was cached, if it is.
+	pub async fn models_cache_age(&self) -> Option<Duration> {
+		let models = self.models.read().await;
+		let cached_at = models.as_ref()?.cached_at;
+		Some(cached_at.elapsed().unwrap_or_default())
 	}
 
 	pub fn is_models_cache_valid(&self, cached: &CachedModels) -> bool {
@@ -60,3 +310,73 @@ impl AppState {
 			.unwrap_or(false)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::copilot::api::copilot_headers;
+
+	#[tokio::test]
+	async fn capabilities_snapshot_outlives_models_lock() {
+		let state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		let models: ModelsResponse = serde_json::from_value(serde_json::json!({
+			"object": "list",
+			"data": [{
+				"id": "gpt-4.1",
+				"capabilities": {
+					"family": "gpt-4.1",
+					"limits": {"max_context_window_tokens": 128000, "max_output_tokens": 16384}
+				}
+			}, {
+				"id": "no-capabilities"
+			}]
+		}))
+		.unwrap();
+		state.store_models(models).await;
+
+		let snapshot = state.capabilities();
+		// Hold the models cache as a refresh would, and still read capabilities
+		let refreshing = state.models.write().await;
+		let limits = state.capabilities()["gpt-4.1"].limits.clone().unwrap();
+		assert_eq!(limits.max_output_tokens, Some(16384));
+		assert_eq!(snapshot.len(), 1);
+
+		drop(refreshing);
+		state.set_account_type("business".to_string()).await;
+		assert!(state.capabilities().is_empty());
+		assert_eq!(snapshot.len(), 1);
+	}
+
+	#[test]
+	fn editor_version_per_family() {
+		let mut state = AppState::new(
+			None,
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		state.editor_versions =
+			serde_json::from_str(r#"{"claude-opus": "1.104.0", "gemini": "1.99.0"}"#).unwrap();
+
+		let editor_version = |model| {
+			copilot_headers(
+				"tok",
+				state.editor_version(model),
+				"vscode-chat",
+				false,
+				None,
+			)["editor-version"]
+				.clone()
+		};
+		assert_eq!(editor_version("claude-opus-4.1"), "vscode/1.104.0");
+		assert_eq!(editor_version("gemini-2.5-pro"), "vscode/1.99.0");
+		assert_eq!(editor_version("claude-sonnet-4"), "vscode/1.100.0");
+	}
+}
@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::copilot::client::UpstreamStatusError;
+
+/// How many models get their own counters. Model names come from clients, so
+/// past this, new ones are counted together under [`OTHER_MODELS`].
+const MAX_TRACKED_MODELS: usize = 256;
+const OTHER_MODELS: &str = "other";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ModelCounters {
+	pub requests: u64,
+	pub rate_limited: u64,
+}
+
+/// Upstream request accounting, keyed by resolved (Copilot) model name.
+#[derive(Debug, Default)]
+pub struct ModelStats {
+	counters: Mutex<HashMap<String, ModelCounters>>,
+}
+
+impl ModelStats {
+	pub fn record_request(&self, model: &str) {
+		self.update(model, |c| c.requests += 1);
+	}
+
+	/// Count a failed upstream call, if it was a 429.
+	pub fn record_failure(&self, model: &str, error: &anyhow::Error) {
+		if error
+			.downcast_ref::<UpstreamStatusError>()
+			.is_some_and(|e| e.status == StatusCode::TOO_MANY_REQUESTS)
+		{
+			self.update(model, |c| c.rate_limited += 1);
+		}
+	}
+
+	pub fn snapshot(&self) -> BTreeMap<String, ModelCounters> {
+		self.counters
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(model, counters)| (model.clone(), *counters))
+			.collect()
+	}
+
+	fn update(&self, model: &str, f: impl FnOnce(&mut ModelCounters)) {
+		let mut counters = self.counters.lock().unwrap();
+		// One slot is kept for the overflow
+		let model = if counters.contains_key(model) || counters.len() < MAX_TRACKED_MODELS - 1 {
+			model
+		} else {
+			OTHER_MODELS
+		};
+		f(counters.entry(model.to_string()).or_default());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn upstream_error(status: StatusCode) -> anyhow::Error {
+		UpstreamStatusError {
+			status,
+			body: String::new(),
+		}
+		.into()
+	}
+
+	#[test]
+	fn only_429s_count_as_rate_limited() {
+		let stats = ModelStats::default();
+		for _ in 0..3 {
+			stats.record_request("gpt-4o");
+		}
+		stats.record_failure("gpt-4o", &upstream_error(StatusCode::TOO_MANY_REQUESTS));
+		stats.record_failure("gpt-4o", &upstream_error(StatusCode::TOO_MANY_REQUESTS));
+		stats.record_failure("gpt-4o", &upstream_error(StatusCode::BAD_GATEWAY));
+		stats.record_failure("gpt-4o", &anyhow::anyhow!("connection reset"));
+		stats.record_request("claude-sonnet-4");
+
+		let snapshot = stats.snapshot();
+		assert_eq!(
+			snapshot["gpt-4o"],
+			ModelCounters {
+				requests: 3,
+				rate_limited: 2
+			}
+		);
+		assert_eq!(
+			snapshot["claude-sonnet-4"],
+			ModelCounters {
+				requests: 1,
+				rate_limited: 0
+			}
+		);
+	}
+
+	#[test]
+	fn untracked_models_counted_together() {
+		let stats = ModelStats::default();
+		stats.record_request("gpt-4o");
+		for n in 0..MAX_TRACKED_MODELS * 2 {
+			stats.record_request(&format!("random-{n}"));
+		}
+		stats.record_request("gpt-4o");
+
+		let snapshot = stats.snapshot();
+		assert_eq!(snapshot.len(), MAX_TRACKED_MODELS);
+		assert_eq!(snapshot["gpt-4o"].requests, 2);
+		assert_eq!(
+			snapshot[OTHER_MODELS].requests,
+			(MAX_TRACKED_MODELS * 2 - (MAX_TRACKED_MODELS - 2)) as u64
+		);
+	}
+}
@@ -1,5 +1,11 @@
+pub mod models;
+pub mod partial_json;
 pub mod request;
 pub mod response;
 pub mod stream;
 pub mod thinking;
 pub mod types;
+
+/// Where translation decisions are logged, so they can be singled out with
+/// `RUST_LOG=first_officer::translate=debug`.
+pub const LOG_TARGET: &str = "first_officer::translate";
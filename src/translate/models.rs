@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+
+/// Broad model families, for behaviour that depends on the kind of model
+/// without needing its capabilities from the model list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModelFamily {
+	ClaudeSonnet,
+	ClaudeOpus,
+	ClaudeHaiku,
+	/// The o-series and GPT-5 onwards, which think before answering.
+	GptReasoning,
+	GptStandard,
+	Gemini,
+	Embedding,
+	Unknown,
+}
+
+/// Classify a resolved (upstream) model name.
+pub fn model_family(model: &str) -> ModelFamily {
+	let model = model.to_ascii_lowercase();
+	if model.contains("embedding") {
+		return ModelFamily::Embedding;
+	}
+
+	if let Some(claude) = model.strip_prefix("claude-") {
+		return if claude.contains("opus") {
+			ModelFamily::ClaudeOpus
+		} else if claude.contains("sonnet") {
+			ModelFamily::ClaudeSonnet
+		} else if claude.contains("haiku") {
+			ModelFamily::ClaudeHaiku
+		} else {
+			ModelFamily::Unknown
+		};
+	}
+
+	if model.starts_with("gemini-") {
+		return ModelFamily::Gemini;
+	}
+
+	// o1, o3-mini, o4-mini...
+	let mut chars = model.chars();
+	if chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit()) {
+		return ModelFamily::GptReasoning;
+	}
+
+	if let Some(gpt) = model.strip_prefix("gpt-") {
+		let major: String = gpt.chars().take_while(|c| c.is_ascii_digit()).collect();
+		return match major.parse::<u32>() {
+			Ok(5..=9) => ModelFamily::GptReasoning,
+			// Includes gpt-41-copilot, a GPT-4.1 build
+			Ok(_) => ModelFamily::GptStandard,
+			Err(_) => ModelFamily::Unknown,
+		};
+	}
+
+	ModelFamily::Unknown
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn real_copilot_model_list() {
+		use ModelFamily::*;
+
+		let cases = [
+			("claude-opus-4.6-fast", ClaudeOpus),
+			("claude-opus-4.5", ClaudeOpus),
+			("claude-sonnet-4.6", ClaudeSonnet),
+			("claude-sonnet-4", ClaudeSonnet),
+			("claude-3.5-sonnet", ClaudeSonnet),
+			("claude-haiku-4.5", ClaudeHaiku),
+			("gpt-5.2-codex", GptReasoning),
+			("gpt-5-mini", GptReasoning),
+			("gpt-5.1-codex-max", GptReasoning),
+			("o3-mini", GptReasoning),
+			("o1", GptReasoning),
+			("gpt-4o-2024-11-20", GptStandard),
+			("gpt-4.1", GptStandard),
+			("gpt-41-copilot", GptStandard),
+			("gpt-3.5-turbo-0613", GptStandard),
+			("gpt-4-o-preview", GptStandard),
+			("gemini-2.5-pro", Gemini),
+			("gemini-3-flash-preview", Gemini),
+			("text-embedding-3-small", Embedding),
+			("text-embedding-ada-002", Embedding),
+			("grok-code-fast-1", Unknown),
+			("oswe-vscode-prime", Unknown),
+		];
+		for (model, family) in cases {
+			assert_eq!(model_family(model), family, "{model}");
+		}
+	}
+}
@@ -0,0 +1,151 @@
+enum Last {
+	Open,
+	Key,
+	Colon,
+	Comma,
+	Value,
+}
+
+/// The text to append to a JSON document that was cut off, such as tool call
+/// arguments when the model ran out of tokens, so it parses:
+/// `{"path": "src/ma` needs `"}`, `[1, tr` needs `ue]`.
+///
+/// Returns None if the input isn't a prefix of valid JSON as far as this can tell.
+pub fn close_partial_json(partial: &str) -> Option<String> {
+	let mut stack = Vec::new();
+	let mut last = None;
+	let mut in_string = false;
+	let mut is_key = false;
+	let mut escape = false;
+	let mut unicode_left = 0;
+	let mut scalar = String::new();
+
+	for c in partial.chars() {
+		if in_string {
+			if unicode_left > 0 {
+				unicode_left -= 1;
+			} else if escape {
+				escape = false;
+				if c == 'u' {
+					unicode_left = 4;
+				}
+			} else if c == '\\' {
+				escape = true;
+			} else if c == '"' {
+				in_string = false;
+				last = Some(if is_key { Last::Key } else { Last::Value });
+			}
+			continue;
+		}
+
+		if !scalar.is_empty() && (c.is_whitespace() || "{}[]:,\"".contains(c)) {
+			scalar.clear();
+			last = Some(Last::Value);
+		}
+		match c {
+			'{' | '[' => {
+				stack.push(c);
+				last = Some(Last::Open);
+			}
+			'}' | ']' => {
+				let open = stack.pop()?;
+				if (open == '{') != (c == '}') {
+					return None;
+				}
+				last = Some(Last::Value);
+			}
+			':' => last = Some(Last::Colon),
+			',' => last = Some(Last::Comma),
+			'"' => {
+				in_string = true;
+				is_key =
+					stack.last() == Some(&'{') && matches!(last, Some(Last::Open | Last::Comma));
+			}
+			c if c.is_whitespace() => {}
+			c => scalar.push(c),
+		}
+	}
+
+	let mut suffix = String::new();
+	if in_string {
+		if unicode_left > 0 {
+			suffix.push_str(&"0".repeat(unicode_left));
+		} else if escape {
+			// Makes the dangling backslash an escaped one
+			suffix.push('\\');
+		}
+		suffix.push('"');
+		last = Some(if is_key { Last::Key } else { Last::Value });
+	} else if !scalar.is_empty() {
+		suffix.push_str(complete_scalar(&scalar)?);
+		last = Some(Last::Value);
+	}
+
+	match last {
+		Some(Last::Key) => suffix.push_str(":null"),
+		Some(Last::Colon) => suffix.push_str("null"),
+		Some(Last::Comma) if stack.last() == Some(&'{') => suffix.push_str("\"\":null"),
+		Some(Last::Comma) => suffix.push_str("null"),
+		_ => {}
+	}
+	for open in stack.iter().rev() {
+		suffix.push(if *open == '{' { '}' } else { ']' });
+	}
+	Some(suffix)
+}
+
+/// What's missing from a literal or number that was cut off.
+fn complete_scalar(scalar: &str) -> Option<&'static str> {
+	for literal in ["true", "false", "null"] {
+		if let Some(rest) = literal.strip_prefix(scalar) {
+			return Some(&literal[literal.len() - rest.len()..]);
+		}
+	}
+	if !scalar
+		.chars()
+		.all(|c| c.is_ascii_digit() || "-+.eE".contains(c))
+	{
+		return None;
+	}
+	if scalar.ends_with(['-', '+', '.', 'e', 'E']) {
+		Some("0")
+	} else {
+		Some("")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn repaired(partial: &str) -> serde_json::Value {
+		let suffix = close_partial_json(partial).unwrap();
+		serde_json::from_str(&format!("{partial}{suffix}"))
+			.unwrap_or_else(|e| panic!("{partial}{suffix} still invalid: {e}"))
+	}
+
+	#[test]
+	fn truncated_documents_are_closed() {
+		use serde_json::json;
+
+		assert_eq!(repaired(r#"{"path": "src/ma"#), json!({"path": "src/ma"}));
+		assert_eq!(repaired(r#"{"path""#), json!({"path": null}));
+		assert_eq!(repaired(r#"{"path":"#), json!({"path": null}));
+		assert_eq!(repaired(r#"{"a": [1, tr"#), json!({"a": [1, true]}));
+		assert_eq!(repaired(r#"{"a": [1,"#), json!({"a": [1, null]}));
+		assert_eq!(repaired(r#"{"n": -1.5e"#), json!({"n": -1.5}));
+		assert_eq!(repaired(r#"{"s": "a\"#), json!({"s": "a\\"}));
+		assert_eq!(repaired(r#"{"s": "\u00e"#), json!({"s": "\u{e0}"}));
+		assert_eq!(
+			repaired(r#"{"a": {"b": "c"},"#),
+			json!({"a": {"b": "c"}, "": null})
+		);
+		assert_eq!(repaired(r#"{"done": true}"#), json!({"done": true}));
+	}
+
+	#[test]
+	fn nonsense_is_rejected() {
+		assert_eq!(close_partial_json(r#"{"a": ]"#), None);
+		assert_eq!(close_partial_json(r#"{"a": nope"#), None);
+	}
+}
@@ -1,17 +1,425 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
 use crate::copilot::types::{
-	ChatCompletionsRequest, Content, ContentPart, FunctionDef, ImageUrl, Message, NamedToolChoice,
-	NamedToolChoiceFunction, Stop, Tool, ToolCall, ToolCallFunction, ToolChoice,
+	ChatCompletionsRequest, Content, ContentPart, FileData, FunctionDef, ImageUrl, Message,
+	NamedToolChoice, NamedToolChoiceFunction, Stop, Tool, ToolCall, ToolCallFunction, ToolChoice,
 };
+use crate::translate::LOG_TARGET;
+use crate::translate::models::{ModelFamily, model_family};
 use crate::translate::types::{
 	AnthropicMessage, AnthropicTool, AnthropicToolChoice, AssistantContent, AssistantContentBlock,
-	MessagesRequest, SystemPrompt, UserContent, UserContentBlock,
+	DocumentBlock, ImageSource, MessagesRequest, SystemPrompt, ToolResultContent,
+	ToolResultContentBlock, UserContent, UserContentBlock,
 };
 
-pub fn translate_request(req: &MessagesRequest, emulate_thinking: bool) -> ChatCompletionsRequest {
+/// Upstream rejects longer tool names, and any with characters outside of
+/// `[a-zA-Z0-9_-]`.
+const MAX_TOOL_NAME_LEN: usize = 64;
+/// Hex digits of the hash ending tool names that had to be cut short.
+const TOOL_NAME_HASH_LEN: usize = 8;
+
+/// Anthropic rejects requests with more `cache_control` breakpoints than this.
+const MAX_CACHE_BREAKPOINTS: usize = 4;
+
+/// What to do when a request carries more tools than `MAX_TOOLS` allows, or
+/// more messages than `MAX_MESSAGES`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverLimit {
+	#[default]
+	Reject,
+	Truncate,
+}
+
+impl OverLimit {
+	fn from_env(var: &str) -> Self {
+		match env::var(var).as_deref() {
+			Ok("truncate") => Self::Truncate,
+			Ok("reject") | Err(_) => Self::Reject,
+			Ok(other) => {
+				warn!(value = other, "unknown {var}, using reject");
+				Self::Reject
+			}
+		}
+	}
+}
+
+/// What to do with a `tool_result` whose `tool_use_id` isn't in any earlier
+/// assistant turn, which strict upstreams reject.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrphanToolResults {
+	/// Keep the result's content as a plain user message.
+	#[default]
+	User,
+	Drop,
+}
+
+/// The role the system prompt is sent as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemRole {
+	System,
+	/// What newer OpenAI models expect instead of `system`.
+	Developer,
+}
+
+impl SystemRole {
+	/// The role `model` prefers: `developer` for reasoning GPTs.
+	pub fn for_model(model: &str) -> Self {
+		match model_family(model) {
+			ModelFamily::GptReasoning => Self::Developer,
+			_ => Self::System,
+		}
+	}
+
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::System => "system",
+			Self::Developer => "developer",
+		}
+	}
+}
+
+/// The `detail` level images are sent upstream at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VisionDetail {
+	Low,
+	High,
+	Auto,
+}
+
+impl VisionDetail {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Low => "low",
+			Self::High => "high",
+			Self::Auto => "auto",
+		}
+	}
+}
+
+/// A `REDACT_PATTERNS` rule, rewriting matches of `pattern` in user and
+/// system text before it's sent upstream.
+#[derive(Debug, Clone, Serialize)]
+pub struct Redaction {
+	#[serde(serialize_with = "serialize_regex")]
+	pub pattern: Regex,
+	/// May refer to capture groups, as `$1` or `${name}`.
+	pub replacement: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RedactionConfig {
+	pattern: String,
+	#[serde(default = "default_replacement")]
+	replacement: String,
+}
+
+fn default_replacement() -> String {
+	"[redacted]".to_string()
+}
+
+fn serialize_regex<S: Serializer>(regex: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.serialize_str(regex.as_str())
+}
+
+/// Parse `REDACT_PATTERNS`, a JSON array of `{"pattern", "replacement"}`.
+/// Invalid patterns are skipped with a warning, rather than failing startup.
+fn parse_redactions(raw: &str) -> Vec<Redaction> {
+	let configs: Vec<RedactionConfig> = match serde_json::from_str(raw) {
+		Ok(configs) => configs,
+		Err(e) => {
+			warn!(error = %e, "invalid REDACT_PATTERNS, not redacting");
+			return Vec::new();
+		}
+	};
+	configs
+		.into_iter()
+		.filter_map(|config| match Regex::new(&config.pattern) {
+			Ok(pattern) => Some(Redaction {
+				pattern,
+				replacement: config.replacement,
+			}),
+			Err(e) => {
+				warn!(pattern = %config.pattern, error = %e, "skipping invalid redaction pattern");
+				None
+			}
+		})
+		.collect()
+}
+
+const DEFAULT_IMAGE_MEDIA_TYPE: &str = "image/png";
+
+/// Copilot's limit on `stop`, like OpenAI's.
+const DEFAULT_MAX_STOP_SEQUENCES: usize = 4;
+
+/// Request translation settings, read from the environment at startup.
+#[derive(Debug, Clone, Serialize)]
+pub struct TranslateOptions {
+	pub max_tools: Option<usize>,
+	pub tools_over_limit: OverLimit,
+	pub max_messages: Option<usize>,
+	pub messages_over_limit: OverLimit,
+	pub default_image_media_type: String,
+	/// Keep system blocks marked with `cache_control` as separate system
+	/// messages, so upstream cache boundaries line up with the client's.
+	pub split_cached_system: bool,
+	pub orphan_tool_results: OrphanToolResults,
+	/// How many stop sequences Copilot accepts; the rest are dropped.
+	pub max_stop_sequences: usize,
+	/// Send the system prompt with this role, rather than by model family.
+	pub system_role: Option<SystemRole>,
+	/// Ask for strict schema adherence on tools that don't say otherwise.
+	pub tools_strict: bool,
+	/// Image detail level, left to upstream when unset.
+	pub vision_detail: Option<VisionDetail>,
+	/// Images larger than this (decoded) are sent at low detail.
+	pub vision_auto_low_bytes: Option<usize>,
+	/// Requests with images larger than this (decoded) are rejected.
+	pub max_image_bytes: Option<usize>,
+	/// Applied in order to user, tool result and system text.
+	pub redactions: Vec<Redaction>,
+	/// Move system-like text leading the first user message into the system
+	/// prompt, rather than sending system context twice over.
+	pub merge_system_like: bool,
+	/// `parallel_tool_calls` for Claude models whose client didn't say.
+	pub parallel_tool_calls_default: Option<bool>,
+}
+
+impl Default for TranslateOptions {
+	fn default() -> Self {
+		Self {
+			max_tools: None,
+			tools_over_limit: OverLimit::default(),
+			max_messages: None,
+			messages_over_limit: OverLimit::default(),
+			default_image_media_type: DEFAULT_IMAGE_MEDIA_TYPE.to_string(),
+			split_cached_system: false,
+			orphan_tool_results: OrphanToolResults::default(),
+			max_stop_sequences: DEFAULT_MAX_STOP_SEQUENCES,
+			system_role: None,
+			tools_strict: false,
+			vision_detail: None,
+			vision_auto_low_bytes: None,
+			max_image_bytes: None,
+			redactions: Vec::new(),
+			merge_system_like: false,
+			parallel_tool_calls_default: None,
+		}
+	}
+}
+
+impl TranslateOptions {
+	/// Build from environment variables:
+	///
+	/// - `MAX_TOOLS` — maximum number of tools forwarded upstream. Default: unlimited.
+	/// - `TOOLS_OVER_LIMIT` — `reject` (default) or `truncate` when over `MAX_TOOLS`.
+	/// - `MAX_MESSAGES` — maximum number of messages in a conversation. Default: unlimited.
+	/// - `MESSAGES_OVER_LIMIT` — `reject` (default) or `truncate` the oldest
+	///   when over `MAX_MESSAGES`.
+	/// - `DEFAULT_IMAGE_MEDIA_TYPE` — media type assumed for images that don't
+	///   declare one. Default: `image/png`.
+	/// - `SPLIT_CACHED_SYSTEM` — `true` to split the system prompt at
+	///   `cache_control` breakpoints. Default: joined into one message.
+	/// - `ORPHAN_TOOL_RESULTS` — `user` (default) to turn tool results without
+	///   a matching tool call into user messages, or `drop` to remove them.
+	/// - `MAX_STOP_SEQUENCES` — how many stop sequences to forward upstream.
+	///   Default: 4.
+	/// - `SYSTEM_ROLE` — `system` or `developer` to send the system prompt as
+	///   for all models. Default: `developer` for reasoning GPTs only.
+	/// - `TOOLS_STRICT` — `true` to forward tools with `strict: true` unless
+	///   they set it themselves. Default: not sent.
+	/// - `VISION_DETAIL` — `low`, `high` or `auto` detail for images. Default:
+	///   not sent.
+	/// - `VISION_AUTO_LOW_BYTES` — send images over this many bytes at `low`
+	///   detail. Default: never.
+	/// - `MAX_IMAGE_BYTES` — reject requests with images over this many bytes.
+	///   Default: unlimited.
+	/// - `REDACT_PATTERNS` — JSON array of `{"pattern", "replacement"}` regex
+	///   rewrites for user and system text. Default: none.
+	/// - `MERGE_SYSTEM_LIKE` — `true` to move `<system...>` text that leads the
+	///   first user message into the system prompt. Default: left in place.
+	/// - `PARALLEL_TOOL_CALLS_DEFAULT` — `true` or `false` to send as
+	///   `parallel_tool_calls` for Claude models with tools. Default: not sent.
+	pub fn from_env() -> Self {
+		let max_tools = env::var("MAX_TOOLS")
+			.ok()
+			.and_then(|v| v.parse::<usize>().ok());
+
+		let orphan_tool_results = match env::var("ORPHAN_TOOL_RESULTS").as_deref() {
+			Ok("drop") => OrphanToolResults::Drop,
+			Ok("user") | Err(_) => OrphanToolResults::User,
+			Ok(other) => {
+				warn!(value = other, "unknown ORPHAN_TOOL_RESULTS, using user");
+				OrphanToolResults::User
+			}
+		};
+
+		let system_role = match env::var("SYSTEM_ROLE").as_deref() {
+			Ok("system") => Some(SystemRole::System),
+			Ok("developer") => Some(SystemRole::Developer),
+			Ok("auto" | "") | Err(_) => None,
+			Ok(other) => {
+				warn!(value = other, "unknown SYSTEM_ROLE, choosing by model");
+				None
+			}
+		};
+
+		let vision_detail = match env::var("VISION_DETAIL").as_deref() {
+			Ok("low") => Some(VisionDetail::Low),
+			Ok("high") => Some(VisionDetail::High),
+			Ok("auto") => Some(VisionDetail::Auto),
+			Ok("") | Err(_) => None,
+			Ok(other) => {
+				warn!(
+					value = other,
+					"unknown VISION_DETAIL, leaving it to upstream"
+				);
+				None
+			}
+		};
+
+		let parallel_tool_calls_default = match env::var("PARALLEL_TOOL_CALLS_DEFAULT").as_deref() {
+			Ok("true" | "1") => Some(true),
+			Ok("false" | "0") => Some(false),
+			Ok("") | Err(_) => None,
+			Ok(other) => {
+				warn!(
+					value = other,
+					"unknown PARALLEL_TOOL_CALLS_DEFAULT, leaving it to upstream"
+				);
+				None
+			}
+		};
+
+		let default_image_media_type = env::var("DEFAULT_IMAGE_MEDIA_TYPE")
+			.ok()
+			.filter(|v| !v.is_empty())
+			.unwrap_or_else(|| DEFAULT_IMAGE_MEDIA_TYPE.to_string());
+
+		Self {
+			max_tools,
+			tools_over_limit: OverLimit::from_env("TOOLS_OVER_LIMIT"),
+			max_messages: env::var("MAX_MESSAGES")
+				.ok()
+				.and_then(|v| v.parse::<usize>().ok())
+				.filter(|&n| n > 0),
+			messages_over_limit: OverLimit::from_env("MESSAGES_OVER_LIMIT"),
+			default_image_media_type,
+			split_cached_system: env::var("SPLIT_CACHED_SYSTEM")
+				.is_ok_and(|v| v == "true" || v == "1"),
+			orphan_tool_results,
+			max_stop_sequences: env::var("MAX_STOP_SEQUENCES")
+				.ok()
+				.and_then(|v| v.parse::<usize>().ok())
+				.filter(|&n| n > 0)
+				.unwrap_or(DEFAULT_MAX_STOP_SEQUENCES),
+			system_role,
+			tools_strict: env::var("TOOLS_STRICT").is_ok_and(|v| v == "true" || v == "1"),
+			vision_detail,
+			vision_auto_low_bytes: env::var("VISION_AUTO_LOW_BYTES")
+				.ok()
+				.and_then(|v| v.parse::<usize>().ok())
+				.filter(|&n| n > 0),
+			max_image_bytes: env::var("MAX_IMAGE_BYTES")
+				.ok()
+				.and_then(|v| v.parse::<usize>().ok())
+				.filter(|&n| n > 0),
+			redactions: env::var("REDACT_PATTERNS")
+				.ok()
+				.filter(|v| !v.trim().is_empty())
+				.map(|v| parse_redactions(&v))
+				.unwrap_or_default(),
+			merge_system_like: env::var("MERGE_SYSTEM_LIKE").is_ok_and(|v| v == "true" || v == "1"),
+			parallel_tool_calls_default,
+		}
+	}
+}
+
+/// A request that can't be translated, reported to the client as an
+/// `invalid_request_error`.
+#[derive(Debug, Error)]
+pub enum TranslateError {
+	#[error("too many tools: {count} provided, the limit is {limit}")]
+	TooManyTools { count: usize, limit: usize },
+	#[error(
+		"too many messages: {count} provided, the limit is {limit}; summarise the conversation or start a new one"
+	)]
+	ConversationTooLong { count: usize, limit: usize },
+	#[error(
+		"A maximum of {MAX_CACHE_BREAKPOINTS} blocks with cache_control may be provided. Found {0}."
+	)]
+	TooManyCacheBreakpoints(usize),
+	#[error("tool_choice: tool {0} is not in tools")]
+	UnknownToolChoice(String),
+	#[error("tools: {first} and {second} would both be sent as {upstream}; rename one of them")]
+	ToolNameCollision {
+		first: String,
+		second: String,
+		upstream: String,
+	},
+	#[error("messages.{message}: image is {bytes} bytes, the limit is {limit}")]
+	ImageTooLarge {
+		message: usize,
+		bytes: usize,
+		limit: usize,
+	},
+}
+
+pub fn translate_request(
+	req: &MessagesRequest,
+	emulate_thinking: bool,
+	options: &TranslateOptions,
+) -> Result<ChatCompletionsRequest, TranslateError> {
+	let breakpoints = count_cache_breakpoints(req);
+	if breakpoints > MAX_CACHE_BREAKPOINTS {
+		return Err(TranslateError::TooManyCacheBreakpoints(breakpoints));
+	}
+	if let Some(limit) = options.max_image_bytes {
+		check_image_sizes(&req.messages, limit)?;
+	}
+
+	let tools = req
+		.tools
+		.as_deref()
+		.map(|t| limit_tools(t, options))
+		.transpose()?;
+	if let Some(tc) = &req.tool_choice
+		&& tc.r#type == "tool"
+		&& let Some(name) = &tc.name
+		&& !tools.into_iter().flatten().any(|t| &t.name == name)
+	{
+		return Err(TranslateError::UnknownToolChoice(name.clone()));
+	}
+	if let Some(tools) = tools {
+		check_tool_names(tools)?;
+	}
+	let history = limit_messages(&req.messages, options)?;
+	let (system, history) = merge_system_like(&req.system, history, options.merge_system_like);
+
+	if let Some(container) = &req.container {
+		debug!(
+			target: LOG_TARGET,
+			%container,
+			"ignoring code execution container, which Copilot has no use for"
+		);
+	}
+
 	let thinking_enabled =
 		emulate_thinking && req.thinking.as_ref().is_some_and(|t| t.r#type == "enabled");
 
-	let (messages, max_tokens) = if thinking_enabled {
+	let (mut messages, max_tokens) = if thinking_enabled {
 		let thinking_budget = req
 			.thinking
 			.as_ref()
@@ -22,13 +430,14 @@ pub fn translate_request(req: &MessagesRequest, emulate_thinking: bool) -> ChatC
 
 		let thinking_system = "When thinking through a problem, wrap your reasoning in <thinking></thinking> XML tags. Put your final answer outside the tags.";
 
-		let combined_system = match &req.system {
+		let combined_system = match &*system {
 			Some(SystemPrompt::Text(s)) => {
 				SystemPrompt::Text(format!("{}\n\n{}", thinking_system, s))
 			}
 			Some(SystemPrompt::Blocks(blocks)) => {
 				let mut new_blocks = vec![crate::translate::types::TextBlock {
 					text: thinking_system.to_string(),
+					cache_control: None,
 				}];
 				new_blocks.extend(blocks.clone());
 				SystemPrompt::Blocks(new_blocks)
@@ -37,62 +446,337 @@ pub fn translate_request(req: &MessagesRequest, emulate_thinking: bool) -> ChatC
 		};
 
 		(
-			translate_messages(&req.messages, &Some(combined_system)),
+			translate_messages(&history, &Some(combined_system), &req.model, options),
 			adjusted_max_tokens,
 		)
 	} else {
 		(
-			translate_messages(&req.messages, &req.system),
+			translate_messages(&history, &system, &req.model, options),
 			req.max_tokens,
 		)
 	};
 
-	ChatCompletionsRequest {
+	redact(&mut messages, &options.redactions);
+
+	debug!(
+		target: LOG_TARGET,
+		model = %req.model,
+		messages = req.messages.len(),
+		kept_messages = history.len(),
+		upstream_messages = messages.len(),
+		tools = tools.map_or(0, <[_]>::len),
+		vision = has_vision_content(req),
+		agent = is_agent_call(req),
+		thinking = ?req.thinking.as_ref().map(|t| t.r#type.as_str()),
+		emulate_thinking = thinking_enabled,
+		max_tokens,
+		"translated request"
+	);
+
+	Ok(ChatCompletionsRequest {
 		model: req.model.clone(),
 		messages,
 		max_tokens: Some(max_tokens),
 		temperature: req.temperature,
 		top_p: req.top_p,
-		stop: req.stop_sequences.as_ref().map(|s| {
-			if s.len() == 1 {
-				Stop::Single(s[0].clone())
-			} else {
-				Stop::Multiple(s.clone())
-			}
-		}),
+		stop: req
+			.stop_sequences
+			.as_deref()
+			.map(|s| translate_stop(s, options.max_stop_sequences)),
 		stream: req.stream,
 		n: None,
 		frequency_penalty: None,
 		presence_penalty: None,
-		tools: req.tools.as_ref().map(|t| translate_tools(t)),
+		seed: None,
+		response_format: None,
+		tools: tools.map(|t| translate_tools(t, options)),
 		tool_choice: req.tool_choice.as_ref().and_then(translate_tool_choice),
+		parallel_tool_calls: tools.and_then(|_| parallel_tool_calls(req, options)),
 		user: req.metadata.as_ref().and_then(|m| m.user_id.clone()),
+	})
+}
+
+/// Copilot rejects requests with more stop sequences than it supports, so
+/// only the first `limit` are kept.
+fn translate_stop(sequences: &[String], limit: usize) -> Stop {
+	if sequences.len() > limit {
+		warn!(
+			count = sequences.len(),
+			limit,
+			dropped = ?&sequences[limit..],
+			"too many stop sequences, forwarding the first ones only"
+		);
+	}
+	let kept = &sequences[..sequences.len().min(limit)];
+	if let [single] = kept {
+		Stop::Single(single.clone())
+	} else {
+		Stop::Multiple(kept.to_vec())
+	}
+}
+
+fn count_cache_breakpoints(req: &MessagesRequest) -> usize {
+	let system = match &req.system {
+		Some(SystemPrompt::Blocks(blocks)) => {
+			blocks.iter().filter(|b| b.cache_control.is_some()).count()
+		}
+		_ => 0,
+	};
+
+	let messages: usize = req
+		.messages
+		.iter()
+		.map(|msg| match msg {
+			AnthropicMessage::User {
+				content: UserContent::Blocks(blocks),
+			} => blocks
+				.iter()
+				.map(|b| match b {
+					UserContentBlock::Text(t) => usize::from(t.cache_control.is_some()),
+					UserContentBlock::Image(i) => usize::from(i.cache_control.is_some()),
+					UserContentBlock::Document(d) => usize::from(d.cache_control.is_some()),
+					UserContentBlock::ToolResult(tr) => {
+						let nested = match &tr.content {
+							ToolResultContent::Blocks(blocks) => blocks
+								.iter()
+								.filter(|b| match b {
+									ToolResultContentBlock::Text(t) => t.cache_control.is_some(),
+								})
+								.count(),
+							ToolResultContent::Text(_) => 0,
+						};
+						usize::from(tr.cache_control.is_some()) + nested
+					}
+					UserContentBlock::ServerTool => 0,
+				})
+				.sum(),
+			AnthropicMessage::Assistant {
+				content: AssistantContent::Blocks(blocks),
+			} => blocks
+				.iter()
+				.filter(|b| match b {
+					AssistantContentBlock::Text(t) => t.cache_control.is_some(),
+					AssistantContentBlock::ToolUse(tu) => tu.cache_control.is_some(),
+					AssistantContentBlock::Image(i) => i.cache_control.is_some(),
+					AssistantContentBlock::Thinking(_) | AssistantContentBlock::ServerTool => false,
+				})
+				.count(),
+			_ => 0,
+		})
+		.sum();
+
+	let tools = req
+		.tools
+		.iter()
+		.flatten()
+		.filter(|t| t.cache_control.is_some())
+		.count();
+
+	tools + system + messages
+}
+
+fn limit_tools<'a>(
+	tools: &'a [AnthropicTool],
+	options: &TranslateOptions,
+) -> Result<&'a [AnthropicTool], TranslateError> {
+	let Some(limit) = options.max_tools else {
+		return Ok(tools);
+	};
+	if tools.len() <= limit {
+		return Ok(tools);
+	}
+
+	match options.tools_over_limit {
+		OverLimit::Reject => Err(TranslateError::TooManyTools {
+			count: tools.len(),
+			limit,
+		}),
+		OverLimit::Truncate => {
+			warn!(count = tools.len(), limit, "too many tools, truncating");
+			Ok(&tools[..limit])
+		}
+	}
+}
+
+/// Whether text reads as system context wrapped in a tag, like the
+/// `<system-reminder>`s some harnesses put in the first user turn.
+fn is_system_like(text: &str) -> bool {
+	let text = text.trim();
+	text.starts_with("<system") && text.ends_with('>')
+}
+
+/// With a system prompt, system-like text leading the first user message is
+/// system context sent twice over. With `merge`, it's moved into the system
+/// prompt, as long as the message keeps some content of its own so the
+/// conversation still opens with a user turn.
+fn merge_system_like<'a>(
+	system: &'a Option<SystemPrompt>,
+	messages: &'a [AnthropicMessage],
+	merge: bool,
+) -> (Cow<'a, Option<SystemPrompt>>, Cow<'a, [AnthropicMessage]>) {
+	let unchanged = (Cow::Borrowed(system), Cow::Borrowed(messages));
+	let (Some(prompt), Some(AnthropicMessage::User { content })) = (system, messages.first())
+	else {
+		return unchanged;
+	};
+	let blocks = match content {
+		UserContent::Blocks(blocks) => blocks.as_slice(),
+		UserContent::Text(text) => {
+			if is_system_like(text) {
+				debug!(target: LOG_TARGET, "first user message is system-like, keeping it as the opening user turn");
+			}
+			return unchanged;
+		}
+	};
+	let leading = blocks
+		.iter()
+		.take_while(|b| matches!(b, UserContentBlock::Text(t) if is_system_like(&t.text)))
+		.count();
+	if leading == 0 {
+		return unchanged;
+	}
+	if !merge || leading == blocks.len() {
+		debug!(
+			target: LOG_TARGET,
+			blocks = leading,
+			"first user message opens with system-like text alongside the system prompt, leaving it in place"
+		);
+		return unchanged;
+	}
+
+	let (moved, rest) = blocks.split_at(leading);
+	let prompt = match prompt {
+		SystemPrompt::Text(text) => {
+			let mut texts = vec![text.as_str()];
+			texts.extend(moved.iter().filter_map(|b| match b {
+				UserContentBlock::Text(t) => Some(t.text.as_str()),
+				_ => None,
+			}));
+			SystemPrompt::Text(texts.join("\n\n"))
+		}
+		SystemPrompt::Blocks(system_blocks) => {
+			let mut system_blocks = system_blocks.clone();
+			system_blocks.extend(moved.iter().filter_map(|b| match b {
+				UserContentBlock::Text(t) => Some(t.clone()),
+				_ => None,
+			}));
+			SystemPrompt::Blocks(system_blocks)
+		}
+	};
+	let mut messages = messages.to_vec();
+	messages[0] = AnthropicMessage::User {
+		content: UserContent::Blocks(rest.to_vec()),
+	};
+	debug!(
+		target: LOG_TARGET,
+		blocks = leading,
+		"merged system-like text from the first user message into the system prompt"
+	);
+	(Cow::Owned(Some(prompt)), Cow::Owned(messages))
+}
+
+/// Apply `MAX_MESSAGES`. Truncating keeps the most recent messages, starting
+/// from a user turn that isn't answering tool calls which were dropped, or
+/// failing that, just the last message; the system prompt is separate, and
+/// always kept.
+fn limit_messages<'a>(
+	messages: &'a [AnthropicMessage],
+	options: &TranslateOptions,
+) -> Result<&'a [AnthropicMessage], TranslateError> {
+	let Some(limit) = options.max_messages else {
+		return Ok(messages);
+	};
+	if messages.len() <= limit {
+		return Ok(messages);
+	}
+
+	match options.messages_over_limit {
+		OverLimit::Reject => Err(TranslateError::ConversationTooLong {
+			count: messages.len(),
+			limit,
+		}),
+		OverLimit::Truncate => {
+			let start = (messages.len() - limit..messages.len())
+				.find(|&i| is_fresh_user_turn(&messages[i]))
+				.unwrap_or(messages.len() - 1);
+			warn!(
+				count = messages.len(),
+				limit,
+				dropped = start,
+				"too many messages, dropping the oldest"
+			);
+			Ok(&messages[start..])
+		}
+	}
+}
+
+fn is_fresh_user_turn(message: &AnthropicMessage) -> bool {
+	match message {
+		AnthropicMessage::User {
+			content: UserContent::Blocks(blocks),
+		} => !blocks
+			.iter()
+			.any(|b| matches!(b, UserContentBlock::ToolResult(_))),
+		AnthropicMessage::User { .. } => true,
+		AnthropicMessage::Assistant { .. } => false,
 	}
 }
 
 fn translate_messages(
 	messages: &[AnthropicMessage],
 	system: &Option<SystemPrompt>,
+	model: &str,
+	options: &TranslateOptions,
 ) -> Vec<Message> {
 	let mut out = Vec::new();
 
 	if let Some(sys) = system {
-		out.push(Message {
-			role: "system".to_string(),
-			content: Some(Content::Text(system_prompt_to_string(sys))),
-			name: None,
-			tool_calls: None,
-			tool_call_id: None,
-		});
+		let role = options
+			.system_role
+			.unwrap_or_else(|| SystemRole::for_model(model));
+		out.extend(
+			system_texts(sys, options.split_cached_system)
+				.into_iter()
+				.map(|text| Message {
+					role: role.as_str().to_string(),
+					content: Some(Content::Text(text)),
+					name: None,
+					tool_calls: None,
+					tool_call_id: None,
+				}),
+		);
 	}
 
+	let mut tool_call_ids = HashSet::new();
 	for msg in messages {
 		match msg {
 			AnthropicMessage::User { content } => {
-				out.extend(translate_user_message(content));
+				// Orphans are held back until the turn's tool messages are out,
+				// as those must directly follow the assistant's tool calls
+				let mut orphans = Vec::new();
+				for message in translate_user_message(content, options) {
+					match message.tool_call_id.as_deref() {
+						Some(id) if !tool_call_ids.contains(id) => {
+							orphans.extend(handle_orphan_tool_result(message, options));
+						}
+						Some(_) => out.push(message),
+						None => {
+							out.append(&mut orphans);
+							out.push(message);
+						}
+					}
+				}
+				out.append(&mut orphans);
 			}
 			AnthropicMessage::Assistant { content } => {
-				out.extend(translate_assistant_message(content));
+				let assistant = translate_assistant_message(content, options);
+				tool_call_ids.extend(
+					assistant
+						.iter()
+						.flat_map(|m| m.tool_calls.iter().flatten())
+						.map(|tc| tc.id.clone()),
+				);
+				out.extend(assistant);
 			}
 		}
 	}
@@ -100,6 +784,66 @@ fn translate_messages(
 	out
 }
 
+fn handle_orphan_tool_result(message: Message, options: &TranslateOptions) -> Option<Message> {
+	let id = message.tool_call_id.unwrap_or_default();
+	match options.orphan_tool_results {
+		OrphanToolResults::Drop => {
+			warn!(tool_use_id = %id, "dropping tool_result without a matching tool_use");
+			None
+		}
+		OrphanToolResults::User => {
+			warn!(tool_use_id = %id, "sending tool_result without a matching tool_use as user text");
+			let result = match message.content {
+				Some(Content::Text(text)) => text,
+				Some(Content::Parts(parts)) => parts
+					.into_iter()
+					.filter_map(|part| match part {
+						ContentPart::Text { text } => Some(text),
+						_ => None,
+					})
+					.collect::<Vec<_>>()
+					.join("\n\n"),
+				None => String::new(),
+			};
+			Some(Message {
+				role: "user".to_string(),
+				content: Some(Content::Text(format!(
+					"Result of tool call {id}:\n{result}"
+				))),
+				name: None,
+				tool_calls: None,
+				tool_call_id: None,
+			})
+		}
+	}
+}
+
+/// The system prompt as one text per upstream system message: all joined, or
+/// with `split_cached`, one per run of blocks ending at a cache breakpoint
+/// (and one for whatever follows the last breakpoint).
+fn system_texts(sys: &SystemPrompt, split_cached: bool) -> Vec<String> {
+	let SystemPrompt::Blocks(blocks) = sys else {
+		return vec![system_prompt_to_string(sys)];
+	};
+	if !split_cached || blocks.iter().all(|b| b.cache_control.is_none()) {
+		return vec![system_prompt_to_string(sys)];
+	}
+
+	let mut texts = Vec::new();
+	let mut segment: Vec<&str> = Vec::new();
+	for block in blocks {
+		segment.push(&block.text);
+		if block.cache_control.is_some() {
+			texts.push(segment.join("\n\n"));
+			segment.clear();
+		}
+	}
+	if !segment.is_empty() {
+		texts.push(segment.join("\n\n"));
+	}
+	texts
+}
+
 fn system_prompt_to_string(sys: &SystemPrompt) -> String {
 	match sys {
 		SystemPrompt::Text(s) => s.clone(),
@@ -111,7 +855,7 @@ fn system_prompt_to_string(sys: &SystemPrompt) -> String {
 	}
 }
 
-fn translate_user_message(content: &UserContent) -> Vec<Message> {
+fn translate_user_message(content: &UserContent, options: &TranslateOptions) -> Vec<Message> {
 	match content {
 		UserContent::Text(s) => vec![Message {
 			role: "user".to_string(),
@@ -122,6 +866,12 @@ fn translate_user_message(content: &UserContent) -> Vec<Message> {
 		}],
 		UserContent::Blocks(blocks) => {
 			let mut out = Vec::new();
+			log_skipped_server_tools(
+				blocks
+					.iter()
+					.filter(|b| matches!(b, UserContentBlock::ServerTool))
+					.count(),
+			);
 
 			// Tool results must come first
 			for block in blocks {
@@ -148,33 +898,36 @@ fn translate_user_message(content: &UserContent) -> Vec<Message> {
 				}
 			}
 
+			// Kept in their original order, as text often refers to the image
+			// just before or after it
 			let other_blocks: Vec<&UserContentBlock> = blocks
 				.iter()
-				.filter(|b| !matches!(b, UserContentBlock::ToolResult(_)))
+				.filter(|b| {
+					!matches!(
+						b,
+						UserContentBlock::ToolResult(_) | UserContentBlock::ServerTool
+					)
+				})
 				.collect();
 
 			if !other_blocks.is_empty() {
-				let has_image = other_blocks
-					.iter()
-					.any(|b| matches!(b, UserContentBlock::Image(_)));
+				let has_parts = other_blocks.iter().any(|b| {
+					matches!(
+						b,
+						UserContentBlock::Image(_) | UserContentBlock::Document(_)
+					)
+				});
 
-				if has_image {
+				if has_parts {
 					let parts: Vec<ContentPart> = other_blocks
 						.iter()
 						.filter_map(|b| match b {
 							UserContentBlock::Text(t) => Some(ContentPart::Text {
 								text: t.text.clone(),
 							}),
-							UserContentBlock::Image(img) => Some(ContentPart::ImageUrl {
-								image_url: ImageUrl {
-									url: format!(
-										"data:{};base64,{}",
-										img.source.media_type, img.source.data
-									),
-									detail: None,
-								},
-							}),
-							UserContentBlock::ToolResult(_) => None,
+							UserContentBlock::Image(img) => Some(image_part(&img.source, options)),
+							UserContentBlock::Document(doc) => Some(document_part(doc)),
+							UserContentBlock::ToolResult(_) | UserContentBlock::ServerTool => None,
 						})
 						.collect();
 					out.push(Message {
@@ -208,7 +961,19 @@ fn translate_user_message(content: &UserContent) -> Vec<Message> {
 	}
 }
 
-fn translate_assistant_message(content: &AssistantContent) -> Vec<Message> {
+fn log_skipped_server_tools(count: usize) {
+	if count > 0 {
+		debug!(
+			count,
+			"skipping server tool blocks with no chat completions equivalent"
+		);
+	}
+}
+
+fn translate_assistant_message(
+	content: &AssistantContent,
+	options: &TranslateOptions,
+) -> Vec<Message> {
 	match content {
 		AssistantContent::Text(s) => vec![Message {
 			role: "assistant".to_string(),
@@ -218,109 +983,1956 @@ fn translate_assistant_message(content: &AssistantContent) -> Vec<Message> {
 			tool_call_id: None,
 		}],
 		AssistantContent::Blocks(blocks) => {
-			let tool_use_blocks: Vec<&AssistantContentBlock> = blocks
-				.iter()
-				.filter(|b| matches!(b, AssistantContentBlock::ToolUse(_)))
-				.collect();
+			log_skipped_server_tools(
+				blocks
+					.iter()
+					.filter(|b| matches!(b, AssistantContentBlock::ServerTool))
+					.count(),
+			);
 
-			let text_content: String = blocks
+			let tool_calls: Vec<ToolCall> = blocks
 				.iter()
 				.filter_map(|b| match b {
-					AssistantContentBlock::Text(t) => Some(t.text.as_str()),
-					AssistantContentBlock::Thinking(t) => Some(t.thinking.as_str()),
+					AssistantContentBlock::ToolUse(tu) => Some(ToolCall {
+						id: tu.id.clone(),
+						r#type: "function".to_string(),
+						function: ToolCallFunction {
+							name: sanitize_tool_name(&tu.name).into_owned(),
+							arguments: tool_arguments(&tu.input),
+						},
+					}),
 					_ => None,
 				})
-				.collect::<Vec<_>>()
-				.join("\n\n");
-
-			if tool_use_blocks.is_empty() {
-				vec![Message {
-					role: "assistant".to_string(),
-					content: if text_content.is_empty() {
-						None
-					} else {
-						Some(Content::Text(text_content))
-					},
-					name: None,
-					tool_calls: None,
-					tool_call_id: None,
-				}]
-			} else {
-				let tool_calls: Vec<ToolCall> = tool_use_blocks
-					.iter()
-					.filter_map(|b| match b {
-						AssistantContentBlock::ToolUse(tu) => Some(ToolCall {
-							id: tu.id.clone(),
-							r#type: "function".to_string(),
-							function: ToolCallFunction {
-								name: tu.name.clone(),
-								arguments: serde_json::to_string(&tu.input).unwrap_or_default(),
-							},
-						}),
-						_ => None,
-					})
-					.collect();
-
-				vec![Message {
-					role: "assistant".to_string(),
-					content: if text_content.is_empty() {
-						None
-					} else {
-						Some(Content::Text(text_content))
-					},
-					name: None,
-					tool_calls: Some(tool_calls),
-					tool_call_id: None,
-				}]
-			}
+				.collect();
+
+			vec![Message {
+				role: "assistant".to_string(),
+				content: assistant_content(blocks, options),
+				name: None,
+				tool_calls: if tool_calls.is_empty() {
+					None
+				} else {
+					Some(tool_calls)
+				},
+				tool_call_id: None,
+			}]
 		}
 	}
 }
 
-fn translate_tools(tools: &[AnthropicTool]) -> Vec<Tool> {
-	tools
+/// Text and thinking are joined into plain text, unless there are images in
+/// the turn, in which case everything goes in as parts, in order.
+fn assistant_content(
+	blocks: &[AssistantContentBlock],
+	options: &TranslateOptions,
+) -> Option<Content> {
+	let has_image = blocks
 		.iter()
-		.map(|t| Tool {
-			r#type: "function".to_string(),
-			function: FunctionDef {
-				name: t.name.clone(),
-				description: t.description.clone(),
-				parameters: t.input_schema.clone(),
-			},
+		.any(|b| matches!(b, AssistantContentBlock::Image(_)));
+
+	if has_image {
+		let parts = blocks
+			.iter()
+			.filter_map(|b| match b {
+				AssistantContentBlock::Text(t) => Some(ContentPart::Text {
+					text: t.text.clone(),
+				}),
+				AssistantContentBlock::Thinking(t) => Some(ContentPart::Text {
+					text: t.thinking.clone(),
+				}),
+				AssistantContentBlock::Image(img) => Some(image_part(&img.source, options)),
+				AssistantContentBlock::ToolUse(_) | AssistantContentBlock::ServerTool => None,
+			})
+			.collect();
+		return Some(Content::Parts(parts));
+	}
+
+	let text: String = blocks
+		.iter()
+		.filter_map(|b| match b {
+			AssistantContentBlock::Text(t) => Some(t.text.as_str()),
+			AssistantContentBlock::Thinking(t) => Some(t.thinking.as_str()),
+			_ => None,
 		})
-		.collect()
+		.collect::<Vec<_>>()
+		.join("\n\n");
+
+	if text.is_empty() {
+		None
+	} else {
+		Some(Content::Text(text))
+	}
 }
 
-fn translate_tool_choice(tc: &AnthropicToolChoice) -> Option<ToolChoice> {
-	match tc.r#type.as_str() {
-		"auto" => Some(ToolChoice::String("auto".to_string())),
-		"any" => Some(ToolChoice::String("required".to_string())),
-		"none" => Some(ToolChoice::String("none".to_string())),
-		"tool" => tc.name.as_ref().map(|name| {
-			ToolChoice::Named(NamedToolChoice {
-				r#type: "function".to_string(),
-				function: NamedToolChoiceFunction { name: name.clone() },
-			})
-		}),
-		_ => None,
+/// Apply `REDACT_PATTERNS` to everything but the assistant's own turns.
+fn redact(messages: &mut [Message], redactions: &[Redaction]) {
+	if redactions.is_empty() {
+		return;
+	}
+
+	let apply = |text: &mut String| {
+		for redaction in redactions {
+			if let Cow::Owned(redacted) = redaction
+				.pattern
+				.replace_all(text, redaction.replacement.as_str())
+			{
+				*text = redacted;
+			}
+		}
+	};
+	for message in messages.iter_mut().filter(|m| m.role != "assistant") {
+		match &mut message.content {
+			Some(Content::Text(text)) => apply(text),
+			Some(Content::Parts(parts)) => {
+				for part in parts {
+					if let ContentPart::Text { text } = part {
+						apply(text);
+					}
+				}
+			}
+			None => {}
+		}
 	}
 }
 
-/// Detect if any message in the Anthropic request contains image content.
-pub fn has_vision_content(req: &MessagesRequest) -> bool {
-	req.messages.iter().any(|msg| match msg {
-		AnthropicMessage::User {
-			content: UserContent::Blocks(blocks),
-		} => blocks
-			.iter()
-			.any(|b| matches!(b, UserContentBlock::Image(_))),
-		_ => false,
-	})
+fn image_part(source: &ImageSource, options: &TranslateOptions) -> ContentPart {
+	ContentPart::ImageUrl {
+		image_url: ImageUrl {
+			url: source_url(source, options),
+			detail: image_detail(source, options).map(|d| d.as_str().to_string()),
+		},
+	}
 }
 
-/// Detect if the conversation includes agent-like messages (assistant or tool).
-pub fn is_agent_call(req: &MessagesRequest) -> bool {
-	req.messages
-		.iter()
-		.any(|msg| matches!(msg, AnthropicMessage::Assistant { .. }))
+/// The image as a URL: passed through for `url` sources, otherwise a data
+/// URL built in one allocation, as images can be megabytes each.
+fn source_url(source: &ImageSource, options: &TranslateOptions) -> String {
+	if let Some(url) = &source.url {
+		return url.clone();
+	}
+	let (embedded, data) = split_data_url(&source.data);
+	let media_type = match embedded {
+		Some(media_type) => media_type,
+		None => image_media_type(source, options),
+	};
+	data_url(media_type, data)
+}
+
+fn data_url(media_type: &str, data: &str) -> String {
+	let mut url =
+		String::with_capacity("data:".len() + media_type.len() + ";base64,".len() + data.len());
+	url.push_str("data:");
+	url.push_str(media_type);
+	url.push_str(";base64,");
+	url.push_str(data);
+	url
+}
+
+/// A document as a `file` part, or as text where it already is text.
+fn document_part(doc: &DocumentBlock) -> ContentPart {
+	let source = &doc.source;
+	match source.r#type.as_str() {
+		"text" => ContentPart::Text {
+			text: source.data.clone(),
+		},
+		"base64" => {
+			let (embedded, data) = split_data_url(&source.data);
+			let media_type = embedded
+				.or(source.media_type.as_deref())
+				.unwrap_or("application/pdf");
+			ContentPart::File {
+				file: FileData {
+					filename: doc.title.clone(),
+					file_data: data_url(media_type, data),
+				},
+			}
+		}
+		other => {
+			debug!(
+				target: LOG_TARGET,
+				source = other,
+				"document source can't be forwarded, sending a note instead"
+			);
+			ContentPart::Text {
+				text: format!(
+					"[document: {}]",
+					source.url.as_deref().unwrap_or("unavailable")
+				),
+			}
+		}
+	}
+}
+
+/// Replace documents with a `[document: N bytes]` note, for models that
+/// can't read them.
+pub fn documents_as_notes(req: &mut ChatCompletionsRequest) {
+	let mut replaced = 0;
+	for message in &mut req.messages {
+		let Some(Content::Parts(parts)) = &mut message.content else {
+			continue;
+		};
+		for part in parts.iter_mut() {
+			if let ContentPart::File { file } = part {
+				let bytes = decoded_len(&file.file_data);
+				*part = ContentPart::Text {
+					text: format!("[document: {bytes} bytes]"),
+				};
+				replaced += 1;
+			}
+		}
+	}
+	if replaced > 0 {
+		info!(
+			documents = replaced,
+			model = %req.model,
+			"model can't read documents, sending notes instead"
+		);
+	}
+}
+
+/// Split the data URL some SDKs send as `data` into its media type, if it
+/// has one, and base64 payload. Plain base64 comes back as it is.
+fn split_data_url(data: &str) -> (Option<&str>, &str) {
+	let Some((header, payload)) = data
+		.strip_prefix("data:")
+		.and_then(|rest| rest.split_once(','))
+	else {
+		return (None, data);
+	};
+	let media_type = header.strip_suffix(";base64").unwrap_or(header);
+	(Some(media_type).filter(|m| !m.is_empty()), payload)
+}
+
+/// `VISION_DETAIL`, unless the image is over `VISION_AUTO_LOW_BYTES`: at high
+/// detail, large images are tiled and can cost thousands of tokens each.
+fn image_detail(source: &ImageSource, options: &TranslateOptions) -> Option<VisionDetail> {
+	let bytes = decoded_len(&source.data);
+	match options.vision_auto_low_bytes {
+		Some(limit) if bytes > limit && options.vision_detail != Some(VisionDetail::Low) => {
+			info!(
+				bytes,
+				limit,
+				detail = ?options.vision_detail,
+				"sending large image at low detail"
+			);
+			Some(VisionDetail::Low)
+		}
+		_ => options.vision_detail,
+	}
+}
+
+/// Reject images over `limit` bytes, which can time out upstream, going by
+/// the base64 length rather than decoding them.
+fn check_image_sizes(messages: &[AnthropicMessage], limit: usize) -> Result<(), TranslateError> {
+	for (i, msg) in messages.iter().enumerate() {
+		let images: Vec<&ImageSource> = match msg {
+			AnthropicMessage::User {
+				content: UserContent::Blocks(blocks),
+			} => blocks
+				.iter()
+				.filter_map(|b| match b {
+					UserContentBlock::Image(img) => Some(&img.source),
+					_ => None,
+				})
+				.collect(),
+			AnthropicMessage::Assistant {
+				content: AssistantContent::Blocks(blocks),
+			} => blocks
+				.iter()
+				.filter_map(|b| match b {
+					AssistantContentBlock::Image(img) => Some(&img.source),
+					_ => None,
+				})
+				.collect(),
+			_ => continue,
+		};
+		for source in images {
+			let bytes = decoded_len(&source.data);
+			if bytes > limit {
+				warn!(message = i, bytes, limit, "rejecting oversized image");
+				return Err(TranslateError::ImageTooLarge {
+					message: i,
+					bytes,
+					limit,
+				});
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Size of base64 `data` once decoded, without decoding it.
+fn decoded_len(data: &str) -> usize {
+	let data = split_data_url(data).1.trim_end();
+	let padding = data.len() - data.trim_end_matches('=').len();
+	(data.len() / 4 * 3).saturating_sub(padding)
+}
+
+fn image_media_type<'a>(source: &'a ImageSource, options: &'a TranslateOptions) -> &'a str {
+	match source.media_type.as_deref() {
+		Some(media_type) => media_type,
+		None => {
+			warn!(
+				default = %options.default_image_media_type,
+				"image source has no media_type, assuming default"
+			);
+			&options.default_image_media_type
+		}
+	}
+}
+
+/// Serialize a `tool_use` input as OpenAI function arguments.
+///
+/// Some clients send `input` as a string holding JSON rather than as an
+/// object; that's unwrapped instead of being encoded a second time. Such
+/// hand-built JSON sometimes has raw control characters in it, which are
+/// escaped so it still parses (and is forwarded as strict JSON).
+fn tool_arguments(input: &serde_json::Value) -> String {
+	if let serde_json::Value::String(s) = input
+		&& let Ok(inner) = serde_json::from_str::<serde_json::Value>(s)
+			.or_else(|_| serde_json::from_str(&escape_control_chars(s)))
+		&& !inner.is_string()
+	{
+		return serde_json::to_string(&inner).unwrap_or_default();
+	}
+	serde_json::to_string(input).unwrap_or_default()
+}
+
+/// Escape control characters inside the string literals of a JSON document,
+/// and drop those outside of them that aren't whitespace.
+fn escape_control_chars(json: &str) -> String {
+	let mut out = String::with_capacity(json.len());
+	let mut in_string = false;
+	let mut escape = false;
+	for c in json.chars() {
+		if c >= ' ' {
+			if in_string {
+				if escape {
+					escape = false;
+				} else if c == '\\' {
+					escape = true;
+				} else if c == '"' {
+					in_string = false;
+				}
+			} else if c == '"' {
+				in_string = true;
+			}
+			out.push(c);
+		} else if in_string {
+			escape = false;
+			match c {
+				'\n' => out.push_str("\\n"),
+				'\r' => out.push_str("\\r"),
+				'\t' => out.push_str("\\t"),
+				c => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+			}
+		} else if matches!(c, '\n' | '\r' | '\t') {
+			out.push(c);
+		} else {
+			warn!(char = ?c, "dropping control character from tool arguments");
+		}
+	}
+	out
+}
+
+fn translate_tools(tools: &[AnthropicTool], options: &TranslateOptions) -> Vec<Tool> {
+	// Copilot's tools have nowhere to carry a breakpoint; it caches prefixes
+	// on its own, and the tools come first either way.
+	let cached = tools.iter().filter(|t| t.cache_control.is_some()).count();
+	if cached > 0 {
+		debug!(
+			target: LOG_TARGET,
+			tools = cached,
+			"dropping cache_control from tools, which Copilot can't mark"
+		);
+	}
+
+	tools
+		.iter()
+		.map(|t| Tool {
+			r#type: "function".to_string(),
+			function: FunctionDef {
+				name: sanitize_tool_name(&t.name).into_owned(),
+				description: t.description.clone(),
+				parameters: t.input_schema.clone(),
+				strict: t.strict.or(options.tools_strict.then_some(true)),
+			},
+		})
+		.collect()
+}
+
+/// A tool name upstream accepts: other characters become underscores, and
+/// if it's over 64 characters, it's cut short and ends with a hash of the
+/// whole name instead, so that long names sharing a prefix stay distinct.
+pub fn sanitize_tool_name(name: &str) -> Cow<'_, str> {
+	let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+	if !name.is_empty() && name.len() <= MAX_TOOL_NAME_LEN && name.chars().all(valid) {
+		return Cow::Borrowed(name);
+	}
+	if name.is_empty() {
+		return Cow::Borrowed("_");
+	}
+	let mut sanitized: String = name
+		.chars()
+		.map(|c| if valid(c) { c } else { '_' })
+		.collect();
+	if sanitized.len() > MAX_TOOL_NAME_LEN {
+		let digest = format!("{:x}", Sha256::digest(name.as_bytes()));
+		let suffix = &digest[..TOOL_NAME_HASH_LEN];
+		sanitized.truncate(MAX_TOOL_NAME_LEN - suffix.len() - 1);
+		sanitized.push('_');
+		sanitized.push_str(suffix);
+	}
+	Cow::Owned(sanitized)
+}
+
+/// Refuse tools that would go upstream under the same name, as the calls
+/// couldn't be told apart.
+fn check_tool_names(tools: &[AnthropicTool]) -> Result<(), TranslateError> {
+	let mut seen: HashMap<Cow<'_, str>, &str> = HashMap::with_capacity(tools.len());
+	for tool in tools {
+		let upstream = sanitize_tool_name(&tool.name);
+		match seen.get(&upstream) {
+			Some(&first) if first != tool.name => {
+				return Err(TranslateError::ToolNameCollision {
+					first: first.to_string(),
+					second: tool.name.clone(),
+					upstream: upstream.into_owned(),
+				});
+			}
+			_ => {
+				seen.insert(upstream, &tool.name);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// The request's tools that get renamed upstream, as upstream name to
+/// original name, to put the original names back in the response.
+pub fn renamed_tools(req: &MessagesRequest) -> HashMap<String, String> {
+	req.tools
+		.iter()
+		.flatten()
+		.filter_map(|t| match sanitize_tool_name(&t.name) {
+			Cow::Borrowed(_) => None,
+			Cow::Owned(upstream) => {
+				debug!(tool = %t.name, upstream = %upstream, "renaming tool for upstream");
+				Some((upstream, t.name.clone()))
+			}
+		})
+		.collect()
+}
+
+fn translate_tool_choice(tc: &AnthropicToolChoice) -> Option<ToolChoice> {
+	match tc.r#type.as_str() {
+		"auto" => Some(ToolChoice::String("auto".to_string())),
+		"any" => Some(ToolChoice::String("required".to_string())),
+		"none" => Some(ToolChoice::String("none".to_string())),
+		"tool" => tc.name.as_ref().map(|name| {
+			ToolChoice::Named(NamedToolChoice {
+				r#type: "function".to_string(),
+				function: NamedToolChoiceFunction {
+					name: sanitize_tool_name(name).into_owned(),
+				},
+			})
+		}),
+		_ => None,
+	}
+}
+
+/// Whether upstream may call tools in parallel: as the client asked with
+/// `disable_parallel_tool_use`, or else the configured default for Claude.
+fn parallel_tool_calls(req: &MessagesRequest, options: &TranslateOptions) -> Option<bool> {
+	if let Some(disable) = req
+		.tool_choice
+		.as_ref()
+		.and_then(|tc| tc.disable_parallel_tool_use)
+	{
+		return Some(!disable);
+	}
+	options.parallel_tool_calls_default.filter(|_| {
+		matches!(
+			model_family(&req.model),
+			ModelFamily::ClaudeSonnet | ModelFamily::ClaudeOpus | ModelFamily::ClaudeHaiku
+		)
+	})
+}
+
+/// Rough prompt size in tokens (about four characters per token), for when
+/// upstream doesn't report usage up front.
+pub fn estimate_prompt_tokens(req: &ChatCompletionsRequest) -> u64 {
+	let mut chars = 0;
+	for msg in &req.messages {
+		match &msg.content {
+			Some(Content::Text(s)) => chars += s.len(),
+			Some(Content::Parts(parts)) => {
+				for part in parts {
+					if let ContentPart::Text { text } = part {
+						chars += text.len();
+					}
+				}
+			}
+			None => {}
+		}
+		for tc in msg.tool_calls.iter().flatten() {
+			chars += tc.function.name.len() + tc.function.arguments.len();
+		}
+	}
+	for tool in req.tools.iter().flatten() {
+		chars += serde_json::to_string(&tool.function)
+			.map(|s| s.len())
+			.unwrap_or(0);
+	}
+	chars.div_ceil(4) as u64
+}
+
+/// Detect if any message in the Anthropic request contains image content.
+pub fn has_vision_content(req: &MessagesRequest) -> bool {
+	req.messages.iter().any(|msg| match msg {
+		AnthropicMessage::User {
+			content: UserContent::Blocks(blocks),
+		} => blocks
+			.iter()
+			.any(|b| matches!(b, UserContentBlock::Image(_))),
+		AnthropicMessage::Assistant {
+			content: AssistantContent::Blocks(blocks),
+		} => blocks
+			.iter()
+			.any(|b| matches!(b, AssistantContentBlock::Image(_))),
+		_ => false,
+	})
+}
+
+/// Detect if the conversation includes agent-like messages (assistant or tool).
+pub fn is_agent_call(req: &MessagesRequest) -> bool {
+	req.messages
+		.iter()
+		.any(|msg| matches!(msg, AnthropicMessage::Assistant { .. }))
+}
+
+/// Set the OpenAI `user` to a hash of the GitHub token when the client didn't
+/// provide a `metadata.user_id`, so Copilot gets a stable per-user signal
+/// without ever seeing the token itself.
+pub fn derive_user(req: &mut ChatCompletionsRequest, gh_token: &str) {
+	if req.user.is_none() {
+		let digest = format!("{:x}", Sha256::digest(gh_token.as_bytes()));
+		req.user = Some(format!("user_{}", &digest[..32]));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use base64::Engine;
+	use base64::engine::general_purpose::STANDARD;
+
+	use super::*;
+
+	fn request_with_tools(count: usize) -> MessagesRequest {
+		let tools: Vec<serde_json::Value> = (0..count)
+			.map(|i| {
+				serde_json::json!({
+					"name": format!("tool_{i}"),
+					"input_schema": {"type": "object"}
+				})
+			})
+			.collect();
+		serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [{"role": "user", "content": "hi"}],
+			"tools": tools
+		}))
+		.unwrap()
+	}
+
+	fn limited(limit: usize, over: OverLimit) -> TranslateOptions {
+		TranslateOptions {
+			max_tools: Some(limit),
+			tools_over_limit: over,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn invalid_tool_names_sanitized() {
+		let long = "x".repeat(70);
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"tools": [
+				{"name": "mcp.fs.read", "input_schema": {"type": "object"}},
+				{"name": long, "input_schema": {"type": "object"}},
+				{"name": "fine_name-2", "input_schema": {"type": "object"}}
+			],
+			"tool_choice": {"type": "tool", "name": "mcp.fs.read"},
+			"messages": [
+				{"role": "user", "content": "read it"},
+				{"role": "assistant", "content": [
+					{"type": "tool_use", "id": "toolu_1", "name": "mcp.fs.read", "input": {}}
+				]}
+			]
+		}))
+		.unwrap();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let names: Vec<&str> = out
+			.tools
+			.as_ref()
+			.unwrap()
+			.iter()
+			.map(|t| t.function.name.as_str())
+			.collect();
+		let short = names[1];
+		assert_eq!(short.len(), 64);
+		assert!(short.starts_with(&"x".repeat(55)));
+		assert_eq!(names[0], "mcp_fs_read");
+		assert_eq!(names[2], "fine_name-2");
+		assert_eq!(
+			out.messages[1].tool_calls.as_ref().unwrap()[0]
+				.function
+				.name,
+			"mcp_fs_read"
+		);
+		assert!(matches!(
+			out.tool_choice,
+			Some(ToolChoice::Named(NamedToolChoice { function, .. })) if function.name == "mcp_fs_read"
+		));
+
+		let renamed = renamed_tools(&req);
+		assert_eq!(renamed.len(), 2);
+		assert_eq!(renamed["mcp_fs_read"], "mcp.fs.read");
+		assert_eq!(renamed[short], long);
+	}
+
+	#[test]
+	fn colliding_tool_names() {
+		let request = |names: [&str; 2]| -> MessagesRequest {
+			serde_json::from_value(serde_json::json!({
+				"model": "claude-sonnet-4-5",
+				"max_tokens": 100,
+				"tools": [
+					{"name": names[0], "input_schema": {"type": "object"}},
+					{"name": names[1], "input_schema": {"type": "object"}}
+				],
+				"messages": [{"role": "user", "content": "hi"}]
+			}))
+			.unwrap()
+		};
+
+		let err = translate_request(
+			&request(["mcp.read", "mcp_read"]),
+			false,
+			&TranslateOptions::default(),
+		)
+		.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"tools: mcp.read and mcp_read would both be sent as mcp_read; rename one of them"
+		);
+
+		let prefix = "mcp__server__".repeat(6);
+		let long = [format!("{prefix}read_file"), format!("{prefix}write_file")];
+		let req = request([&long[0], &long[1]]);
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let tools = out.tools.unwrap();
+		let (first, second) = (&tools[0].function.name, &tools[1].function.name);
+		assert_ne!(first, second);
+		let renamed = renamed_tools(&req);
+		assert_eq!(renamed[first], long[0]);
+		assert_eq!(renamed[second], long[1]);
+	}
+
+	#[test]
+	fn tools_strict_per_tool_and_global() {
+		let mut req = request_with_tools(3);
+		let tools = req.tools.as_mut().unwrap();
+		tools[0].strict = Some(true);
+		tools[1].strict = Some(false);
+		let strict = |options: &TranslateOptions| -> Vec<Option<bool>> {
+			translate_request(&req, false, options)
+				.unwrap()
+				.tools
+				.unwrap()
+				.into_iter()
+				.map(|t| t.function.strict)
+				.collect()
+		};
+
+		assert_eq!(
+			strict(&TranslateOptions::default()),
+			[Some(true), Some(false), None]
+		);
+		let global = TranslateOptions {
+			tools_strict: true,
+			..Default::default()
+		};
+		assert_eq!(strict(&global), [Some(true), Some(false), Some(true)]);
+	}
+
+	#[test]
+	fn tools_at_limit_pass_through() {
+		let req = request_with_tools(3);
+		for over in [OverLimit::Reject, OverLimit::Truncate] {
+			let out = translate_request(&req, false, &limited(3, over)).unwrap();
+			assert_eq!(out.tools.unwrap().len(), 3);
+		}
+	}
+
+	#[test]
+	fn tools_over_limit_rejected() {
+		let req = request_with_tools(4);
+		let err = translate_request(&req, false, &limited(3, OverLimit::Reject)).unwrap_err();
+		assert!(matches!(
+			err,
+			TranslateError::TooManyTools { count: 4, limit: 3 }
+		));
+		assert!(err.to_string().contains("limit is 3"));
+	}
+
+	fn conversation(turns: usize) -> MessagesRequest {
+		let messages: Vec<serde_json::Value> = (0..turns)
+			.map(|i| {
+				let role = if i % 2 == 0 { "user" } else { "assistant" };
+				serde_json::json!({"role": role, "content": format!("m{i}")})
+			})
+			.collect();
+		serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"system": "be brief",
+			"messages": messages
+		}))
+		.unwrap()
+	}
+
+	fn message_limit(limit: usize, over: OverLimit) -> TranslateOptions {
+		TranslateOptions {
+			max_messages: Some(limit),
+			messages_over_limit: over,
+			..Default::default()
+		}
+	}
+
+	fn texts(out: &ChatCompletionsRequest) -> Vec<&str> {
+		out.messages
+			.iter()
+			.map(|m| match &m.content {
+				Some(Content::Text(t)) => t.as_str(),
+				other => panic!("expected text, got {other:?}"),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn messages_at_limit_pass_through() {
+		let req = conversation(5);
+		for over in [OverLimit::Reject, OverLimit::Truncate] {
+			let out = translate_request(&req, false, &message_limit(5, over)).unwrap();
+			assert_eq!(texts(&out), ["be brief", "m0", "m1", "m2", "m3", "m4"]);
+		}
+	}
+
+	#[test]
+	fn messages_over_limit_rejected() {
+		let err = translate_request(
+			&conversation(6),
+			false,
+			&message_limit(5, OverLimit::Reject),
+		)
+		.unwrap_err();
+		assert!(matches!(
+			err,
+			TranslateError::ConversationTooLong { count: 6, limit: 5 }
+		));
+		assert!(err.to_string().contains("limit is 5"), "{err}");
+	}
+
+	#[test]
+	fn messages_over_limit_truncated() {
+		let options = message_limit(5, OverLimit::Truncate);
+
+		// Dropping just m0 would start on an assistant turn
+		let out = translate_request(&conversation(6), false, &options).unwrap();
+		assert_eq!(texts(&out), ["be brief", "m2", "m3", "m4", "m5"]);
+
+		let out = translate_request(&conversation(7), false, &options).unwrap();
+		assert_eq!(texts(&out), ["be brief", "m2", "m3", "m4", "m5", "m6"]);
+	}
+
+	fn tool_loop(turns: usize) -> MessagesRequest {
+		let mut messages = vec![serde_json::json!({"role": "user", "content": "start"})];
+		for i in 0..turns {
+			messages.push(serde_json::json!({"role": "assistant", "content": [
+				{"type": "tool_use", "id": format!("toolu_{i}"), "name": "ls", "input": {}}
+			]}));
+			messages.push(serde_json::json!({"role": "user", "content": [
+				{"type": "tool_result", "tool_use_id": format!("toolu_{i}"), "content": format!("r{i}")}
+			]}));
+		}
+		serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": messages
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn truncation_skips_tool_results_for_dropped_calls() {
+		let mut req = tool_loop(3);
+		req.messages.push(
+			serde_json::from_value(serde_json::json!({"role": "user", "content": "next"})).unwrap(),
+		);
+		req.messages.push(
+			serde_json::from_value(serde_json::json!({"role": "assistant", "content": "ok"}))
+				.unwrap(),
+		);
+		// The window starts on r1's tool_result, whose tool_use is cut
+		let history =
+			limit_messages(&req.messages, &message_limit(5, OverLimit::Truncate)).unwrap();
+		assert_eq!(history.len(), 2);
+		assert!(matches!(
+			&history[0],
+			AnthropicMessage::User { content: UserContent::Text(t) } if t == "next"
+		));
+	}
+
+	#[test]
+	fn truncation_never_empties_the_conversation() {
+		let req = tool_loop(3);
+		let history =
+			limit_messages(&req.messages, &message_limit(2, OverLimit::Truncate)).unwrap();
+		assert_eq!(history.len(), 1);
+		assert!(std::ptr::eq(&history[0], req.messages.last().unwrap()));
+
+		let req = conversation(4);
+		let history =
+			limit_messages(&req.messages[..2], &message_limit(1, OverLimit::Truncate)).unwrap();
+		assert_eq!(history.len(), 1);
+	}
+
+	#[test]
+	fn tools_over_limit_truncated() {
+		let req = request_with_tools(4);
+		let out = translate_request(&req, false, &limited(3, OverLimit::Truncate)).unwrap();
+		let names: Vec<String> = out
+			.tools
+			.unwrap()
+			.into_iter()
+			.map(|t| t.function.name)
+			.collect();
+		assert_eq!(names, ["tool_0", "tool_1", "tool_2"]);
+	}
+
+	#[test]
+	fn prompt_token_estimate() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"system": "abcd",
+			"messages": [{"role": "user", "content": "efghijkl"}]
+		}))
+		.unwrap();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert_eq!(estimate_prompt_tokens(&out), 3);
+	}
+
+	fn request_with_breakpoints(count: usize) -> MessagesRequest {
+		let mut req = serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"system": [{"type": "text", "text": "sys"}],
+			"messages": [
+				{"role": "user", "content": [{"type": "text", "text": "hi"}]},
+				{"role": "assistant", "content": [{"type": "text", "text": "hello"}]},
+				{"role": "user", "content": [{
+					"type": "tool_result",
+					"tool_use_id": "call_1",
+					"content": [{"type": "text", "text": "ok"}]
+				}]}
+			]
+		});
+		let targets = [
+			"/system/0",
+			"/messages/0/content/0",
+			"/messages/1/content/0",
+			"/messages/2/content/0",
+			"/messages/2/content/0/content/0",
+		];
+		for target in targets.into_iter().take(count) {
+			req.pointer_mut(target).unwrap()["cache_control"] =
+				serde_json::json!({"type": "ephemeral"});
+		}
+		serde_json::from_value(req).unwrap()
+	}
+
+	#[test]
+	fn four_cache_breakpoints_accepted() {
+		let req = request_with_breakpoints(4);
+		assert_eq!(count_cache_breakpoints(&req), 4);
+		assert!(translate_request(&req, false, &TranslateOptions::default()).is_ok());
+	}
+
+	#[test]
+	fn five_cache_breakpoints_rejected() {
+		let req = request_with_breakpoints(5);
+		let err = translate_request(&req, false, &TranslateOptions::default()).unwrap_err();
+		assert!(matches!(err, TranslateError::TooManyCacheBreakpoints(5)));
+		assert!(err.to_string().contains("A maximum of 4 blocks"));
+	}
+
+	#[test]
+	fn zero_sampling_parameters_forwarded() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"temperature": 0.0,
+			"top_p": 0.0,
+			"messages": [{"role": "user", "content": "hi"}]
+		}))
+		.unwrap();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert_eq!(out.temperature, Some(0.0));
+		assert_eq!(out.top_p, Some(0.0));
+
+		let json = serde_json::to_value(&out).unwrap();
+		assert_eq!(json["temperature"], 0.0);
+		assert_eq!(json["top_p"], 0.0);
+	}
+
+	#[test]
+	fn stop_sequences_capped_at_limit() {
+		let stops: Vec<String> = (1..=6).map(|i| format!("STOP{i}")).collect();
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"stop_sequences": stops,
+			"messages": [{"role": "user", "content": "count"}]
+		}))
+		.unwrap();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let Some(Stop::Multiple(forwarded)) = out.stop else {
+			panic!("expected several stop sequences: {:?}", out.stop);
+		};
+		assert_eq!(forwarded, &stops[..4]);
+
+		let options = TranslateOptions {
+			max_stop_sequences: 1,
+			..Default::default()
+		};
+		let out = translate_request(&req, false, &options).unwrap();
+		assert!(matches!(out.stop, Some(Stop::Single(s)) if s == "STOP1"));
+	}
+
+	#[test]
+	fn tool_arguments_from_object() {
+		let input = serde_json::json!({"location": "London"});
+		assert_eq!(tool_arguments(&input), r#"{"location":"London"}"#);
+	}
+
+	#[test]
+	fn tool_arguments_from_json_string() {
+		let input = serde_json::json!(r#"{"location": "London"}"#);
+		assert_eq!(tool_arguments(&input), r#"{"location":"London"}"#);
+	}
+
+	#[test]
+	fn tool_arguments_from_plain_string() {
+		let input = serde_json::json!("London");
+		assert_eq!(tool_arguments(&input), r#""London""#);
+		// A string that is itself a JSON string literal isn't unwrapped
+		let input = serde_json::json!(r#""London""#);
+		assert_eq!(tool_arguments(&input), r#""\"London\"""#);
+	}
+
+	#[test]
+	fn tool_arguments_escape_control_characters() {
+		let input = serde_json::json!("{\"cmd\": \"printf 'a\tb\nc\u{1}'\"}\u{0}");
+		let args = tool_arguments(&input);
+		assert_eq!(args, r#"{"cmd":"printf 'a\tb\nc\u0001'"}"#);
+		let parsed: serde_json::Value = serde_json::from_str(&args).unwrap();
+		assert_eq!(parsed["cmd"], "printf 'a\tb\nc\u{1}'");
+
+		// Already escaped sequences are left alone
+		let input = serde_json::json!(r#"{"cmd": "a\\nb"}"#);
+		assert_eq!(tool_arguments(&input), r#"{"cmd":"a\\nb"}"#);
+	}
+
+	#[test]
+	fn assistant_tool_use_with_string_input() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "user", "content": "weather?"},
+				{"role": "assistant", "content": [{
+					"type": "tool_use",
+					"id": "call_1",
+					"name": "get_weather",
+					"input": "{\"location\":\"London\"}"
+				}]}
+			]
+		}))
+		.unwrap();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let calls = out.messages[1].tool_calls.as_ref().unwrap();
+		assert_eq!(calls[0].function.arguments, r#"{"location":"London"}"#);
+	}
+
+	fn image_url(out: &ChatCompletionsRequest) -> &str {
+		let Some(Content::Parts(parts)) = &out.messages[0].content else {
+			panic!("expected content parts");
+		};
+		parts
+			.iter()
+			.find_map(|p| match p {
+				ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+				_ => None,
+			})
+			.unwrap()
+	}
+
+	#[test]
+	fn image_without_media_type_uses_default() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [{"role": "user", "content": [
+				{"type": "image", "source": {"type": "base64", "data": "AAAA"}}
+			]}]
+		}))
+		.unwrap();
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert_eq!(image_url(&out), "data:image/png;base64,AAAA");
+
+		let options = TranslateOptions {
+			default_image_media_type: "image/jpeg".to_string(),
+			..Default::default()
+		};
+		let out = translate_request(&req, false, &options).unwrap();
+		assert_eq!(image_url(&out), "data:image/jpeg;base64,AAAA");
+	}
+
+	#[test]
+	fn bare_content_block_treated_as_one_element_array() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "user", "content": {"type": "text", "text": "what's this?"}},
+				{"role": "assistant", "content": {"type": "text", "text": "show me"}},
+				{"role": "user", "content": {
+					"type": "image",
+					"source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}
+				}}
+			]
+		}))
+		.unwrap();
+		assert!(matches!(
+			&req.messages[0],
+			AnthropicMessage::User { content: UserContent::Blocks(blocks) } if blocks.len() == 1
+		));
+		assert!(matches!(
+			&req.messages[1],
+			AnthropicMessage::Assistant { content: AssistantContent::Blocks(blocks) } if blocks.len() == 1
+		));
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert!(matches!(
+			&out.messages[0].content,
+			Some(Content::Text(text)) if text == "what's this?"
+		));
+		assert!(matches!(
+			&out.messages[1].content,
+			Some(Content::Text(text)) if text == "show me"
+		));
+		let Some(Content::Parts(parts)) = &out.messages[2].content else {
+			panic!("expected parts, got {:?}", out.messages[2].content);
+		};
+		assert!(matches!(
+			&parts[..],
+			[ContentPart::ImageUrl { image_url }] if image_url.url == "data:image/png;base64,AAAA"
+		));
+	}
+
+	#[test]
+	fn documents_forwarded_as_files_or_notes() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [{"role": "user", "content": [
+				{"type": "document", "title": "report.pdf", "source": {
+					"type": "base64", "media_type": "application/pdf", "data": "JVBERi0x"
+				}},
+				{"type": "document", "source": {
+					"type": "text", "media_type": "text/plain", "data": "plain notes"
+				}},
+				{"type": "text", "text": "Summarise these"}
+			]}]
+		}))
+		.unwrap();
+
+		let mut out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let parts = |out: &ChatCompletionsRequest| -> Vec<ContentPart> {
+			let Some(Content::Parts(parts)) = &out.messages[0].content else {
+				panic!("expected parts, got {:?}", out.messages[0].content);
+			};
+			parts.clone()
+		};
+		let json = serde_json::to_value(parts(&out)).unwrap();
+		assert_eq!(
+			json,
+			serde_json::json!([
+				{"type": "file", "file": {
+					"filename": "report.pdf",
+					"file_data": "data:application/pdf;base64,JVBERi0x"
+				}},
+				{"type": "text", "text": "plain notes"},
+				{"type": "text", "text": "Summarise these"}
+			])
+		);
+
+		documents_as_notes(&mut out);
+		assert!(matches!(
+			&parts(&out)[0],
+			ContentPart::Text { text } if text == "[document: 6 bytes]"
+		));
+		assert!(matches!(
+			&parts(&out)[2],
+			ContentPart::Text { text } if text == "Summarise these"
+		));
+	}
+
+	#[test]
+	fn data_url_in_image_data_not_prefixed_twice() {
+		let request = |source: serde_json::Value| -> MessagesRequest {
+			serde_json::from_value(serde_json::json!({
+				"model": "claude-sonnet-4-5",
+				"max_tokens": 100,
+				"messages": [{"role": "user", "content": [
+					{"type": "image", "source": source}
+				]}]
+			}))
+			.unwrap()
+		};
+		let url = |source| {
+			let out =
+				translate_request(&request(source), false, &TranslateOptions::default()).unwrap();
+			image_url(&out).to_string()
+		};
+
+		assert_eq!(
+			url(serde_json::json!({"type": "base64", "media_type": "image/gif", "data": "R0lG"})),
+			"data:image/gif;base64,R0lG"
+		);
+		assert_eq!(
+			url(serde_json::json!({"type": "base64", "data": "data:image/jpeg;base64,/9j/"})),
+			"data:image/jpeg;base64,/9j/"
+		);
+		// The data URL knows better than a mismatched media_type
+		assert_eq!(
+			url(serde_json::json!({
+				"type": "base64",
+				"media_type": "image/png",
+				"data": "data:image/webp;base64,UklG"
+			})),
+			"data:image/webp;base64,UklG"
+		);
+		assert_eq!(
+			url(
+				serde_json::json!({"type": "base64", "media_type": "image/gif", "data": "data:;base64,R0lG"})
+			),
+			"data:image/gif;base64,R0lG"
+		);
+		assert_eq!(decoded_len("data:image/png;base64,AAAA"), 3);
+	}
+
+	#[test]
+	fn large_images_downgraded_to_low_detail() {
+		let request = |data: &str| -> MessagesRequest {
+			serde_json::from_value(serde_json::json!({
+				"model": "claude-sonnet-4-5",
+				"max_tokens": 100,
+				"messages": [{"role": "user", "content": [
+					{"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": data}}
+				]}]
+			}))
+			.unwrap()
+		};
+		let detail = |req: &MessagesRequest, options: &TranslateOptions| {
+			let out = translate_request(req, false, options).unwrap();
+			let Some(Content::Parts(parts)) = &out.messages[0].content else {
+				panic!("expected content parts");
+			};
+			match &parts[0] {
+				ContentPart::ImageUrl { image_url } => image_url.detail.clone(),
+				other => panic!("expected an image, got {other:?}"),
+			}
+		};
+
+		// 3000 and 29 bytes decoded
+		let large = request(&"AAAA".repeat(1000));
+		let small = request("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+		assert_eq!(decoded_len("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="), 29);
+
+		let options = TranslateOptions {
+			vision_detail: Some(VisionDetail::High),
+			vision_auto_low_bytes: Some(1000),
+			..Default::default()
+		};
+		assert_eq!(detail(&large, &options).as_deref(), Some("low"));
+		assert_eq!(detail(&small, &options).as_deref(), Some("high"));
+
+		let options = TranslateOptions {
+			vision_auto_low_bytes: Some(1000),
+			..Default::default()
+		};
+		assert_eq!(detail(&large, &options).as_deref(), Some("low"));
+		assert_eq!(detail(&small, &options), None);
+
+		assert_eq!(detail(&large, &TranslateOptions::default()), None);
+	}
+
+	#[test]
+	fn oversized_images_rejected() {
+		let request = |data: &str| -> MessagesRequest {
+			serde_json::from_value(serde_json::json!({
+				"model": "claude-sonnet-4-5",
+				"max_tokens": 100,
+				"messages": [
+					{"role": "user", "content": "look"},
+					{"role": "user", "content": [
+						{"type": "text", "text": "this one"},
+						{"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": data}}
+					]}
+				]
+			}))
+			.unwrap()
+		};
+		let options = TranslateOptions {
+			max_image_bytes: Some(1000),
+			..Default::default()
+		};
+
+		// 3000 bytes decoded
+		let large = request(&"AAAA".repeat(1000));
+		let err = translate_request(&large, false, &options).unwrap_err();
+		assert!(matches!(
+			err,
+			TranslateError::ImageTooLarge {
+				message: 1,
+				bytes: 3000,
+				limit: 1000
+			}
+		));
+		assert_eq!(
+			err.to_string(),
+			"messages.1: image is 3000 bytes, the limit is 1000"
+		);
+		assert!(translate_request(&large, false, &TranslateOptions::default()).is_ok());
+
+		let small = request(&"AAAA".repeat(300));
+		let out = translate_request(&small, false, &options).unwrap();
+		let Some(Content::Parts(parts)) = &out.messages[1].content else {
+			panic!("expected content parts");
+		};
+		assert!(matches!(parts[1], ContentPart::ImageUrl { .. }));
+	}
+
+	#[test]
+	fn redact_patterns_applied_to_user_and_system_text() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"system": "Support agent for alice@example.com",
+			"messages": [
+				{"role": "user", "content": "Mail bob.smith@example.org about it"},
+				{"role": "assistant", "content": "I'll email you at alice@example.com"},
+				{"role": "user", "content": [
+					{"type": "text", "text": "Also carol@example.net, key sk-12345"},
+					{"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}}
+				]}
+			]
+		}))
+		.unwrap();
+		let options = TranslateOptions {
+			redactions: parse_redactions(
+				r#"[
+					{"pattern": "[\\w.+-]+@([\\w-]+\\.)+\\w+", "replacement": "<email>"},
+					{"pattern": "sk-\\d+"},
+					{"pattern": "("}
+				]"#,
+			),
+			..Default::default()
+		};
+		assert_eq!(options.redactions.len(), 2);
+
+		let out = translate_request(&req, false, &options).unwrap();
+		let text = |i: usize| match &out.messages[i].content {
+			Some(Content::Text(t)) => t.as_str(),
+			Some(Content::Parts(parts)) => match &parts[0] {
+				ContentPart::Text { text } => text.as_str(),
+				other => panic!("expected text, got {other:?}"),
+			},
+			None => panic!("no content"),
+		};
+		assert_eq!(text(0), "Support agent for <email>");
+		assert_eq!(text(1), "Mail <email> about it");
+		assert_eq!(text(2), "I'll email you at alice@example.com");
+		assert_eq!(text(3), "Also <email>, key [redacted]");
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert!(
+			matches!(&out.messages[1].content, Some(Content::Text(t)) if t.contains("bob.smith@example.org"))
+		);
+	}
+
+	#[test]
+	fn decisions_logged_at_translate_target() {
+		use std::sync::{Arc, Mutex};
+
+		use tracing::field::{Field, Visit};
+		use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+		#[derive(Clone, Default)]
+		struct Recorder(Arc<Mutex<Vec<(String, String)>>>);
+
+		struct Fields(String);
+
+		impl Visit for Fields {
+			fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+				self.0.push_str(&format!("{}={value:?} ", field.name()));
+			}
+		}
+
+		impl<S: tracing::Subscriber> Layer<S> for Recorder {
+			fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+				let mut fields = Fields(String::new());
+				event.record(&mut fields);
+				self.0
+					.lock()
+					.unwrap()
+					.push((event.metadata().target().to_string(), fields.0));
+			}
+		}
+
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"thinking": {"type": "enabled", "budget_tokens": 1000},
+			"tools": [{"name": "ls", "input_schema": {"type": "object"}}],
+			"messages": [{"role": "user", "content": "hi"}]
+		}))
+		.unwrap();
+
+		let recorder = Recorder::default();
+		let subscriber = tracing_subscriber::registry().with(recorder.clone());
+		tracing::subscriber::with_default(subscriber, || {
+			translate_request(&req, true, &TranslateOptions::default()).unwrap();
+		});
+
+		let events = recorder.0.lock().unwrap();
+		let (_, fields) = events
+			.iter()
+			.find(|(target, _)| target == LOG_TARGET)
+			.expect("no event at the translate target");
+		for expected in [
+			"messages=1 ",
+			"upstream_messages=2 ",
+			"tools=1 ",
+			"vision=false ",
+			"agent=",
+			"thinking=Some(\"enabled\") ",
+			"emulate_thinking=true ",
+			"max_tokens=1100 ",
+		] {
+			assert!(fields.contains(expected), "{expected} not in {fields}");
+		}
+	}
+
+	#[test]
+	fn no_limit_by_default() {
+		let req = request_with_tools(200);
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert_eq!(out.tools.unwrap().len(), 200);
+	}
+
+	#[test]
+	fn server_tool_blocks_skipped_alongside_tool_results() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "user", "content": "run it"},
+				{"role": "assistant", "content": [
+					{"type": "text", "text": "Running"},
+					{"type": "server_tool_use", "id": "srvtoolu_1", "name": "code_execution", "input": {"code": "1+1"}},
+					{"type": "code_execution_tool_result", "tool_use_id": "srvtoolu_1", "content": {"type": "code_execution_result", "stdout": "2", "stderr": "", "return_code": 0}},
+					{"type": "tool_use", "id": "call_1", "name": "lookup", "input": {}}
+				]},
+				{"role": "user", "content": [
+					{"type": "code_execution_tool_result", "tool_use_id": "srvtoolu_1", "content": {"type": "code_execution_result", "stdout": "2", "stderr": "", "return_code": 0}},
+					{"type": "tool_result", "tool_use_id": "call_1", "content": "found"},
+					{"type": "text", "text": "thanks"}
+				]}
+			]
+		}))
+		.unwrap();
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let roles: Vec<&str> = out.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["user", "assistant", "tool", "user"]);
+
+		let assistant = &out.messages[1];
+		assert!(matches!(&assistant.content, Some(Content::Text(t)) if t == "Running"));
+		assert_eq!(assistant.tool_calls.as_ref().unwrap().len(), 1);
+		assert_eq!(out.messages[2].tool_call_id.as_deref(), Some("call_1"));
+		assert!(matches!(&out.messages[3].content, Some(Content::Text(t)) if t == "thanks"));
+	}
+
+	#[test]
+	fn container_is_accepted_and_dropped() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"container": "container_011CQ8SC4DWW9iQ5hLwUazi7",
+			"messages": [{"role": "user", "content": "run it again"}]
+		}))
+		.unwrap();
+		assert_eq!(
+			req.container.as_ref().and_then(|c| c.as_str()),
+			Some("container_011CQ8SC4DWW9iQ5hLwUazi7")
+		);
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert_eq!(out.messages.len(), 1);
+		assert!(!serde_json::to_string(&out).unwrap().contains("container"));
+	}
+
+	#[test]
+	fn named_tool_choice_must_be_a_tool() {
+		let request = |name: &str| -> MessagesRequest {
+			serde_json::from_value(serde_json::json!({
+				"model": "claude-sonnet-4",
+				"max_tokens": 100,
+				"tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+				"tool_choice": {"type": "tool", "name": name},
+				"messages": [{"role": "user", "content": "hi"}]
+			}))
+			.unwrap()
+		};
+
+		let out = translate_request(&request("get_weather"), false, &TranslateOptions::default())
+			.unwrap();
+		assert!(matches!(
+			out.tool_choice,
+			Some(ToolChoice::Named(NamedToolChoice { ref function, .. })) if function.name == "get_weather"
+		));
+
+		let err = translate_request(&request("get_time"), false, &TranslateOptions::default())
+			.unwrap_err();
+		assert!(matches!(err, TranslateError::UnknownToolChoice(ref name) if name == "get_time"));
+		assert_eq!(
+			err.to_string(),
+			"tool_choice: tool get_time is not in tools"
+		);
+
+		let mut no_tools = request("get_weather");
+		no_tools.tools = None;
+		assert!(translate_request(&no_tools, false, &TranslateOptions::default()).is_err());
+	}
+
+	#[test]
+	fn tool_cache_control_accepted_and_dropped() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4",
+			"max_tokens": 100,
+			"tools": [
+				{"name": "read", "input_schema": {"type": "object"}},
+				{
+					"name": "write",
+					"input_schema": {"type": "object"},
+					"cache_control": {"type": "ephemeral", "ttl": "1h"}
+				}
+			],
+			"messages": [{"role": "user", "content": "hi"}]
+		}))
+		.unwrap();
+		let tools = req.tools.as_deref().unwrap();
+		assert!(tools[1].cache_control.is_some());
+		assert_eq!(count_cache_breakpoints(&req), 1);
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let names: Vec<&str> = out
+			.tools
+			.iter()
+			.flatten()
+			.map(|t| t.function.name.as_str())
+			.collect();
+		assert_eq!(names, ["read", "write"]);
+		assert!(
+			!serde_json::to_string(&out)
+				.unwrap()
+				.contains("cache_control")
+		);
+	}
+
+	#[test]
+	fn parallel_tool_calls_default_for_claude_only() {
+		let request = |model: &str, tool_choice: serde_json::Value| -> MessagesRequest {
+			let mut req = serde_json::json!({
+				"model": model,
+				"max_tokens": 100,
+				"tools": [{"name": "read", "input_schema": {"type": "object"}}],
+				"messages": [{"role": "user", "content": "hi"}]
+			});
+			if !tool_choice.is_null() {
+				req["tool_choice"] = tool_choice;
+			}
+			serde_json::from_value(req).unwrap()
+		};
+		let options = TranslateOptions {
+			parallel_tool_calls_default: Some(false),
+			..Default::default()
+		};
+		let parallel = |req: &MessagesRequest, options: &TranslateOptions| {
+			translate_request(req, false, options)
+				.unwrap()
+				.parallel_tool_calls
+		};
+
+		for model in ["claude-sonnet-4-5", "claude-opus-4.6", "claude-haiku-4.5"] {
+			let req = request(model, serde_json::Value::Null);
+			assert_eq!(parallel(&req, &options), Some(false), "{model}");
+			assert_eq!(parallel(&req, &TranslateOptions::default()), None);
+		}
+		for model in ["gpt-4.1", "gpt-5", "gemini-2.5-pro"] {
+			let req = request(model, serde_json::Value::Null);
+			assert_eq!(parallel(&req, &options), None, "{model}");
+		}
+
+		// The client's own choice wins, for any model
+		let req = request(
+			"claude-sonnet-4-5",
+			serde_json::json!({"type": "auto", "disable_parallel_tool_use": false}),
+		);
+		assert_eq!(parallel(&req, &options), Some(true));
+		let req = request(
+			"gpt-4.1",
+			serde_json::json!({"type": "auto", "disable_parallel_tool_use": true}),
+		);
+		assert_eq!(parallel(&req, &options), Some(false));
+
+		// Meaningless without tools, so never sent
+		let mut req = request("claude-sonnet-4-5", serde_json::Value::Null);
+		req.tools = None;
+		assert_eq!(parallel(&req, &options), None);
+		let json = serde_json::to_value(translate_request(&req, false, &options).unwrap()).unwrap();
+		assert!(json.get("parallel_tool_calls").is_none());
+	}
+
+	#[test]
+	fn system_like_first_message_merged_into_system() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"system": "You are a coding agent.",
+			"messages": [
+				{"role": "user", "content": [
+					{"type": "text", "text": "<system-reminder>The repo uses tabs.</system-reminder>"},
+					{"type": "text", "text": "Fix the build"}
+				]},
+				{"role": "assistant", "content": "Done"},
+				{"role": "user", "content": "<system-reminder>Be brief.</system-reminder>"}
+			]
+		}))
+		.unwrap();
+		let text = |m: &Message| match &m.content {
+			Some(Content::Text(t)) => t.clone(),
+			Some(Content::Parts(parts)) => parts
+				.iter()
+				.map(|p| match p {
+					ContentPart::Text { text } => text.as_str(),
+					_ => "",
+				})
+				.collect(),
+			None => String::new(),
+		};
+
+		let kept = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let roles: Vec<&str> = kept.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["system", "user", "assistant", "user"]);
+		assert_eq!(text(&kept.messages[0]), "You are a coding agent.");
+		assert!(text(&kept.messages[1]).contains("The repo uses tabs."));
+
+		let options = TranslateOptions {
+			merge_system_like: true,
+			..Default::default()
+		};
+		let merged = translate_request(&req, false, &options).unwrap();
+		let roles: Vec<&str> = merged.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["system", "user", "assistant", "user"]);
+		assert_eq!(
+			text(&merged.messages[0]),
+			"You are a coding agent.\n\n<system-reminder>The repo uses tabs.</system-reminder>"
+		);
+		assert_eq!(text(&merged.messages[1]), "Fix the build");
+		// Only the first message, so later reminders stay where they are
+		assert!(text(&merged.messages[3]).contains("Be brief."));
+
+		// Nothing would be left to open the conversation, so it stays
+		let mut whole = req.clone();
+		whole.messages[0] = serde_json::from_value(serde_json::json!({
+			"role": "user",
+			"content": "<system-reminder>The repo uses tabs.</system-reminder>"
+		}))
+		.unwrap();
+		let out = translate_request(&whole, false, &options).unwrap();
+		let roles: Vec<&str> = out.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["system", "user", "assistant", "user"]);
+		assert_eq!(text(&out.messages[0]), "You are a coding agent.");
+	}
+
+	#[test]
+	fn interleaved_images_and_text_keep_their_order() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "user", "content": "look"},
+				{"role": "assistant", "content": [
+					{"type": "tool_use", "id": "call_1", "name": "screenshot", "input": {}}
+				]},
+				{"role": "user", "content": [
+					{"type": "text", "text": "before"},
+					{"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}},
+					{"type": "tool_result", "tool_use_id": "call_1", "content": "done"},
+					{"type": "text", "text": "between"},
+					{"type": "image", "source": {"type": "base64", "media_type": "image/gif", "data": "R0lG"}},
+					{"type": "text", "text": "after"}
+				]}
+			]
+		}))
+		.unwrap();
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let roles: Vec<&str> = out.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["user", "assistant", "tool", "user"]);
+		assert_eq!(out.messages[2].tool_call_id.as_deref(), Some("call_1"));
+
+		let Some(Content::Parts(parts)) = &out.messages[3].content else {
+			panic!("expected parts, got {:?}", out.messages[3].content);
+		};
+		let order: Vec<&str> = parts
+			.iter()
+			.map(|p| match p {
+				ContentPart::Text { text } => text.as_str(),
+				ContentPart::ImageUrl { image_url } => image_url.url.as_str(),
+				ContentPart::File { file } => file.file_data.as_str(),
+			})
+			.collect();
+		assert_eq!(
+			order,
+			[
+				"before",
+				"data:image/png;base64,AAAA",
+				"between",
+				"data:image/gif;base64,R0lG",
+				"after"
+			]
+		);
+	}
+
+	#[test]
+	fn many_images_in_one_message() {
+		let data: Vec<String> = (0..8u8)
+			.map(|n| STANDARD.encode(vec![n; 3000 + usize::from(n)]))
+			.collect();
+		let mut content: Vec<serde_json::Value> = data
+			.iter()
+			.map(|data| {
+				serde_json::json!({
+					"type": "image",
+					"source": {"type": "base64", "media_type": "image/png", "data": data}
+				})
+			})
+			.collect();
+		content.insert(3, serde_json::json!({"type": "text", "text": "and these"}));
+		content.push(serde_json::json!({
+			"type": "image",
+			"source": {"type": "url", "url": "https://example.com/cat.jpg"}
+		}));
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [{"role": "user", "content": content}]
+		}))
+		.unwrap();
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let Some(Content::Parts(parts)) = &out.messages[0].content else {
+			panic!("expected parts, got {:?}", out.messages[0].content);
+		};
+		assert_eq!(parts.len(), 10);
+		assert!(matches!(&parts[3], ContentPart::Text { text } if text == "and these"));
+
+		let urls: Vec<&str> = parts
+			.iter()
+			.filter_map(|p| match p {
+				ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+				ContentPart::Text { .. } | ContentPart::File { .. } => None,
+			})
+			.collect();
+		assert_eq!(urls.len(), 9);
+		for (n, (url, data)) in urls.iter().zip(&data).enumerate() {
+			let encoded = url.strip_prefix("data:image/png;base64,").unwrap();
+			assert_eq!(encoded, data);
+			let decoded = STANDARD.decode(encoded).unwrap();
+			assert_eq!(decoded, vec![n as u8; 3000 + n]);
+		}
+		assert_eq!(urls[8], "https://example.com/cat.jpg");
+	}
+
+	#[test]
+	fn assistant_turn_with_image_becomes_parts() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "user", "content": "draw a dot"},
+				{"role": "assistant", "content": [
+					{"type": "text", "text": "Here it is:"},
+					{"type": "image", "source": {"type": "base64", "media_type": "image/gif", "data": "R0lG"}},
+					{"type": "tool_use", "id": "call_1", "name": "save", "input": {}}
+				]}
+			]
+		}))
+		.unwrap();
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let assistant = &out.messages[1];
+		let Some(Content::Parts(parts)) = &assistant.content else {
+			panic!("expected parts, got {:?}", assistant.content);
+		};
+		assert_eq!(parts.len(), 2);
+		assert!(matches!(&parts[0], ContentPart::Text { text } if text == "Here it is:"));
+		assert!(
+			matches!(&parts[1], ContentPart::ImageUrl { image_url } if image_url.url == "data:image/gif;base64,R0lG")
+		);
+		assert_eq!(assistant.tool_calls.as_ref().unwrap().len(), 1);
+		assert!(has_vision_content(&req));
+	}
+
+	#[test]
+	fn assistant_turn_without_image_stays_text() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4-5",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "user", "content": "hi"},
+				{"role": "assistant", "content": [
+					{"type": "text", "text": "one"},
+					{"type": "text", "text": "two"}
+				]}
+			]
+		}))
+		.unwrap();
+
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert!(matches!(&out.messages[1].content, Some(Content::Text(t)) if t == "one\n\ntwo"));
+		assert!(out.messages[1].tool_calls.is_none());
+	}
+
+	fn request_with_metadata(metadata: serde_json::Value) -> ChatCompletionsRequest {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4",
+			"max_tokens": 100,
+			"metadata": metadata,
+			"messages": [{"role": "user", "content": "hi"}]
+		}))
+		.unwrap();
+		translate_request(&req, false, &TranslateOptions::default()).unwrap()
+	}
+
+	#[test]
+	fn user_derived_from_token_hash() {
+		let mut a = request_with_metadata(serde_json::json!({}));
+		let mut b = a.clone();
+		let mut other = a.clone();
+		derive_user(&mut a, "ghu_alice");
+		derive_user(&mut b, "ghu_alice");
+		derive_user(&mut other, "ghu_bob");
+
+		let user = a.user.unwrap();
+		assert_eq!(user, "user_a8ea8e85ba17e9711db2e616fc929c3a");
+		assert!(!user.contains("alice"));
+		assert_eq!(b.user.unwrap(), user);
+		assert_ne!(other.user.unwrap(), user);
+	}
+
+	#[test]
+	fn explicit_user_id_takes_precedence() {
+		let mut req = request_with_metadata(serde_json::json!({"user_id": "session-123"}));
+		derive_user(&mut req, "ghu_alice");
+		assert_eq!(req.user.as_deref(), Some("session-123"));
+	}
+
+	fn system_request() -> MessagesRequest {
+		serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4",
+			"max_tokens": 100,
+			"system": [
+				{"type": "text", "text": "tools manual"},
+				{"type": "text", "text": "project rules", "cache_control": {"type": "ephemeral"}},
+				{"type": "text", "text": "repo map", "cache_control": {"type": "ephemeral"}},
+				{"type": "text", "text": "today's date"}
+			],
+			"messages": [{"role": "user", "content": "hi"}]
+		}))
+		.unwrap()
+	}
+
+	fn system_contents(out: &ChatCompletionsRequest) -> Vec<&str> {
+		out.messages
+			.iter()
+			.filter(|m| m.role == "system")
+			.map(|m| match &m.content {
+				Some(Content::Text(t)) => t.as_str(),
+				other => panic!("unexpected system content {other:?}"),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn cached_system_blocks_split_when_enabled() {
+		let options = TranslateOptions {
+			split_cached_system: true,
+			..Default::default()
+		};
+		let out = translate_request(&system_request(), false, &options).unwrap();
+		assert_eq!(
+			system_contents(&out),
+			["tools manual\n\nproject rules", "repo map", "today's date"]
+		);
+		assert_eq!(out.messages[3].role, "user");
+	}
+
+	#[test]
+	fn system_blocks_joined_by_default() {
+		let out =
+			translate_request(&system_request(), false, &TranslateOptions::default()).unwrap();
+		assert_eq!(
+			system_contents(&out),
+			["tools manual\n\nproject rules\n\nrepo map\n\ntoday's date"]
+		);
+	}
+
+	#[test]
+	fn system_role_follows_model_family() {
+		let mut req = system_request();
+		req.model = "gpt-5-mini".to_string();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert_eq!(out.messages[0].role, "developer");
+
+		req.model = "gpt-4.1".to_string();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		assert_eq!(out.messages[0].role, "system");
+
+		let options = TranslateOptions {
+			system_role: Some(SystemRole::System),
+			..Default::default()
+		};
+		req.model = "o3-mini".to_string();
+		let out = translate_request(&req, false, &options).unwrap();
+		assert_eq!(out.messages[0].role, "system");
+	}
+
+	fn orphan_request() -> MessagesRequest {
+		serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "assistant", "content": [
+					{"type": "tool_use", "id": "toolu_known", "name": "ls", "input": {}}
+				]},
+				{"role": "user", "content": [
+					{"type": "tool_result", "tool_use_id": "toolu_known", "content": "a.txt"},
+					{"type": "tool_result", "tool_use_id": "toolu_gone", "content": "b.txt"},
+					{"type": "text", "text": "carry on"}
+				]}
+			]
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn orphaned_tool_result_becomes_user_text() {
+		let out =
+			translate_request(&orphan_request(), false, &TranslateOptions::default()).unwrap();
+		let roles: Vec<&str> = out.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["assistant", "tool", "user", "user"]);
+		assert_eq!(out.messages[1].tool_call_id.as_deref(), Some("toolu_known"));
+		assert!(out.messages[2].tool_call_id.is_none());
+		assert!(
+			matches!(&out.messages[2].content, Some(Content::Text(t)) if t == "Result of tool call toolu_gone:\nb.txt")
+		);
+	}
+
+	#[test]
+	fn orphaned_tool_result_follows_valid_results() {
+		let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+			"model": "claude-sonnet-4",
+			"max_tokens": 100,
+			"messages": [
+				{"role": "assistant", "content": [
+					{"type": "tool_use", "id": "toolu_a", "name": "ls", "input": {}},
+					{"type": "tool_use", "id": "toolu_b", "name": "ls", "input": {}}
+				]},
+				{"role": "user", "content": [
+					{"type": "tool_result", "tool_use_id": "toolu_a", "content": "a.txt"},
+					{"type": "tool_result", "tool_use_id": "toolu_gone", "content": "b.txt"},
+					{"type": "tool_result", "tool_use_id": "toolu_b", "content": "c.txt"}
+				]}
+			]
+		}))
+		.unwrap();
+		let out = translate_request(&req, false, &TranslateOptions::default()).unwrap();
+		let roles: Vec<&str> = out.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["assistant", "tool", "tool", "user"]);
+		assert_eq!(out.messages[1].tool_call_id.as_deref(), Some("toolu_a"));
+		assert_eq!(out.messages[2].tool_call_id.as_deref(), Some("toolu_b"));
+		assert!(
+			matches!(&out.messages[3].content, Some(Content::Text(t)) if t == "Result of tool call toolu_gone:\nb.txt")
+		);
+	}
+
+	#[test]
+	fn orphaned_tool_result_keeps_text_of_parts() {
+		let message = Message {
+			role: "tool".to_string(),
+			content: Some(Content::Parts(vec![ContentPart::Text {
+				text: "b.txt".to_string(),
+			}])),
+			name: None,
+			tool_calls: None,
+			tool_call_id: Some("toolu_gone".to_string()),
+		};
+		let out = handle_orphan_tool_result(message, &TranslateOptions::default()).unwrap();
+		assert!(
+			matches!(&out.content, Some(Content::Text(t)) if t == "Result of tool call toolu_gone:\nb.txt")
+		);
+	}
+
+	#[test]
+	fn orphaned_tool_result_dropped() {
+		let options = TranslateOptions {
+			orphan_tool_results: OrphanToolResults::Drop,
+			..Default::default()
+		};
+		let out = translate_request(&orphan_request(), false, &options).unwrap();
+		let roles: Vec<&str> = out.messages.iter().map(|m| m.role.as_str()).collect();
+		assert_eq!(roles, ["assistant", "tool", "user"]);
+		assert!(matches!(&out.messages[2].content, Some(Content::Text(t)) if t == "carry on"));
+	}
 }
@@ -1,12 +1,51 @@
+use std::collections::HashMap;
+use std::env;
+
+use serde::Serialize;
+use tracing::{debug, warn};
+
 use crate::copilot::types::{ChatCompletionResponse, ToolCall};
 use crate::translate::thinking::parse_thinking_blocks;
 use crate::translate::types::{
 	AnthropicUsage, AssistantContentBlock, MessagesResponse, StopReason, TextBlock, ToolUseBlock,
 };
 
+/// Leading text longer than this is kept even with `strip_tool_preamble`, as
+/// it's more likely to be an actual answer than filler.
+const MAX_TOOL_PREAMBLE_CHARS: usize = 200;
+
+/// What to report as usage when upstream doesn't say.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingUsage {
+	/// Zero tokens, which some clients take as a failure and retry.
+	#[default]
+	Zeros,
+	/// Leave `usage` out of the response.
+	Omit,
+	/// Estimate from the request and response sizes.
+	Estimate,
+}
+
+impl MissingUsage {
+	/// Read `MISSING_USAGE`: `zeros` (default), `omit`, or `estimate`.
+	pub fn from_env() -> Self {
+		match env::var("MISSING_USAGE").as_deref() {
+			Ok("zeros") | Err(_) => Self::Zeros,
+			Ok("omit") => Self::Omit,
+			Ok("estimate") => Self::Estimate,
+			Ok(other) => {
+				warn!(value = other, "unknown MISSING_USAGE, using zeros");
+				Self::Zeros
+			}
+		}
+	}
+}
+
 pub fn translate_response(
 	resp: &ChatCompletionResponse,
 	emulate_thinking: bool,
+	strip_tool_preamble: bool,
 ) -> MessagesResponse {
 	let mut text_blocks: Vec<AssistantContentBlock> = Vec::new();
 	let mut tool_blocks: Vec<AssistantContentBlock> = Vec::new();
@@ -21,6 +60,7 @@ pub fn translate_response(
 			} else {
 				text_blocks.push(AssistantContentBlock::Text(TextBlock {
 					text: content.clone(),
+					cache_control: None,
 				}));
 			}
 		}
@@ -39,11 +79,30 @@ pub fn translate_response(
 		}
 	}
 
+	if strip_tool_preamble && !tool_blocks.is_empty() {
+		drop_tool_preamble(&mut text_blocks);
+	}
+
 	let mut content = text_blocks;
 	content.append(&mut tool_blocks);
+	if matches!(stop_reason, Some(StopReason::Refusal)) {
+		warn!(model = %resp.model, "response blocked by the content filter");
+		content.push(AssistantContentBlock::Text(TextBlock {
+			text: CONTENT_FILTERED.to_string(),
+			cache_control: None,
+		}));
+	}
 
 	let (input_tokens, output_tokens, cache_read) = match &resp.usage {
 		Some(u) => {
+			if let Some(details) = &u.prompt_tokens_details {
+				debug!(
+					cached = details.cached_tokens,
+					audio = ?details.audio_tokens,
+					text = ?details.text_tokens,
+					"prompt token details"
+				);
+			}
 			let cached = u
 				.prompt_tokens_details
 				.as_ref()
@@ -76,9 +135,47 @@ pub fn translate_response(
 				None
 			},
 		},
+		system_fingerprint: resp.system_fingerprint.clone(),
+	}
+}
+
+/// Explains a `refusal`, as Copilot's filtered responses are otherwise empty
+/// or cut off mid-sentence.
+pub const CONTENT_FILTERED: &str = "[The response was blocked by the upstream content filter.]";
+
+/// Discard thinking blocks, for deployments that keep reasoning from clients.
+pub fn drop_thinking_blocks(resp: &mut MessagesResponse) {
+	resp.content
+		.retain(|block| !matches!(block, AssistantContentBlock::Thinking(_)));
+}
+
+/// Put back the original names of tools that were renamed upstream, given
+/// as upstream name to original name.
+pub fn restore_tool_names(resp: &mut MessagesResponse, tool_names: &HashMap<String, String>) {
+	for block in &mut resp.content {
+		if let AssistantContentBlock::ToolUse(tu) = block
+			&& let Some(original) = tool_names.get(&tu.name)
+		{
+			tu.name = original.clone();
+		}
 	}
 }
 
+/// Rough size of a response in tokens, counted like `estimate_prompt_tokens`.
+pub fn estimate_output_tokens(resp: &MessagesResponse) -> u64 {
+	let chars: usize = resp
+		.content
+		.iter()
+		.map(|block| match block {
+			AssistantContentBlock::Text(t) => t.text.len(),
+			AssistantContentBlock::Thinking(t) => t.thinking.len(),
+			AssistantContentBlock::ToolUse(tu) => tu.name.len() + tu.input.to_string().len(),
+			_ => 0,
+		})
+		.sum();
+	chars.div_ceil(4) as u64
+}
+
 fn translate_tool_call(tc: &ToolCall) -> AssistantContentBlock {
 	let input: serde_json::Value = serde_json::from_str(&tc.function.arguments)
 		.unwrap_or(serde_json::Value::Object(Default::default()));
@@ -87,6 +184,7 @@ fn translate_tool_call(tc: &ToolCall) -> AssistantContentBlock {
 		id: tc.id.clone(),
 		name: tc.function.name.clone(),
 		input,
+		cache_control: None,
 	})
 }
 
@@ -95,16 +193,55 @@ fn map_stop_reason(reason: &str) -> StopReason {
 		"stop" => StopReason::EndTurn,
 		"length" => StopReason::MaxTokens,
 		"tool_calls" => StopReason::ToolUse,
-		"content_filter" => StopReason::EndTurn,
+		"content_filter" => StopReason::Refusal,
 		_ => StopReason::EndTurn,
 	}
 }
 
+/// Drop the first text block ("Let me check that.") if it's short, leaving
+/// any thinking blocks alone.
+fn drop_tool_preamble(blocks: &mut Vec<AssistantContentBlock>) {
+	let Some(index) = blocks
+		.iter()
+		.position(|b| matches!(b, AssistantContentBlock::Text(_)))
+	else {
+		return;
+	};
+	if let AssistantContentBlock::Text(text) = &blocks[index]
+		&& text.text.trim().chars().count() <= MAX_TOOL_PREAMBLE_CHARS
+	{
+		debug!(preamble = %text.text, "stripping text before tool call");
+		blocks.remove(index);
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use crate::copilot::types::*;
 
+	#[test]
+	fn content_filtered_response_is_refusal() {
+		let resp: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+			"id": "chatcmpl-123",
+			"model": "gpt-4.1",
+			"choices": [{
+				"index": 0,
+				"message": {"role": "assistant", "content": null},
+				"finish_reason": "content_filter",
+				"content_filter_results": {"hate": {"filtered": true, "severity": "high"}}
+			}]
+		}))
+		.unwrap();
+
+		let result = translate_response(&resp, false, false);
+		assert!(matches!(result.stop_reason, Some(StopReason::Refusal)));
+		let json = serde_json::to_value(&result).unwrap();
+		assert_eq!(json["stop_reason"], "refusal");
+		assert_eq!(json["content"][0]["type"], "text");
+		assert_eq!(json["content"][0]["text"], CONTENT_FILTERED);
+	}
+
 	#[test]
 	fn translate_simple_text_response() {
 		let resp = ChatCompletionResponse {
@@ -131,7 +268,7 @@ mod tests {
 			}),
 		};
 
-		let result = translate_response(&resp, false);
+		let result = translate_response(&resp, false, false);
 		assert_eq!(result.id, "chatcmpl-123");
 		assert_eq!(result.model, "gpt-4");
 		assert_eq!(result.content.len(), 1);
@@ -174,7 +311,7 @@ mod tests {
 			}),
 		};
 
-		let result = translate_response(&resp, false);
+		let result = translate_response(&resp, false, false);
 		assert!(matches!(result.stop_reason, Some(StopReason::ToolUse)));
 		assert_eq!(result.content.len(), 2);
 		assert!(
@@ -207,16 +344,56 @@ mod tests {
 				prompt_tokens: 100,
 				completion_tokens: 5,
 				total_tokens: 105,
-				prompt_tokens_details: Some(PromptTokensDetails { cached_tokens: 40 }),
+				prompt_tokens_details: Some(PromptTokensDetails {
+					cached_tokens: 40,
+					..Default::default()
+				}),
 			}),
 		};
 
-		let result = translate_response(&resp, false);
+		let result = translate_response(&resp, false, false);
 		assert_eq!(result.usage.input_tokens, 60);
 		assert_eq!(result.usage.output_tokens, 5);
 		assert_eq!(result.usage.cache_read_input_tokens, Some(40));
 	}
 
+	#[test]
+	fn prompt_token_details_with_extra_fields() {
+		let resp: ChatCompletionResponse = serde_json::from_str(
+			r#"{
+				"id": "chatcmpl-1",
+				"object": "chat.completion",
+				"created": 0,
+				"model": "gpt-4o",
+				"choices": [],
+				"usage": {
+					"prompt_tokens": 100,
+					"completion_tokens": 5,
+					"total_tokens": 105,
+					"prompt_tokens_details": {
+						"cached_tokens": 40,
+						"audio_tokens": 10,
+						"text_tokens": 50,
+						"image_tokens": 0
+					}
+				}
+			}"#,
+		)
+		.unwrap();
+
+		let details = resp
+			.usage
+			.as_ref()
+			.and_then(|u| u.prompt_tokens_details.as_ref())
+			.unwrap();
+		assert_eq!(details.audio_tokens, Some(10));
+		assert_eq!(details.text_tokens, Some(50));
+
+		let result = translate_response(&resp, false, false);
+		assert_eq!(result.usage.input_tokens, 60);
+		assert_eq!(result.usage.cache_read_input_tokens, Some(40));
+	}
+
 	#[test]
 	fn translate_with_thinking_emulation() {
 		let resp = ChatCompletionResponse {
@@ -245,7 +422,7 @@ mod tests {
 			}),
 		};
 
-		let result = translate_response(&resp, true);
+		let result = translate_response(&resp, true, false);
 		assert_eq!(result.content.len(), 2);
 		assert!(
 			matches!(&result.content[0], AssistantContentBlock::Thinking(t) if t.thinking == "Let me analyze this...")
@@ -253,5 +430,94 @@ mod tests {
 		assert!(
 			matches!(&result.content[1], AssistantContentBlock::Text(t) if t.text == "The answer is 42.")
 		);
+
+		let mut dropped = result.clone();
+		drop_thinking_blocks(&mut dropped);
+		assert_eq!(dropped.content.len(), 1);
+		assert!(
+			matches!(&dropped.content[0], AssistantContentBlock::Text(t) if t.text == "The answer is 42.")
+		);
+	}
+
+	#[test]
+	fn system_fingerprint_propagated_when_present() {
+		let mut resp = ChatCompletionResponse {
+			id: "chatcmpl-1".to_string(),
+			object: "chat.completion".to_string(),
+			created: 0,
+			model: "gpt-4".to_string(),
+			choices: Vec::new(),
+			system_fingerprint: Some("fp_abc123".to_string()),
+			usage: None,
+		};
+
+		let json = serde_json::to_value(translate_response(&resp, false, false)).unwrap();
+		assert_eq!(json["system_fingerprint"], "fp_abc123");
+
+		resp.system_fingerprint = None;
+		let json = serde_json::to_value(translate_response(&resp, false, false)).unwrap();
+		assert!(json.get("system_fingerprint").is_none());
+	}
+
+	fn preamble_response(content: &str) -> ChatCompletionResponse {
+		ChatCompletionResponse {
+			id: "chatcmpl-789".to_string(),
+			object: "chat.completion".to_string(),
+			created: 0,
+			model: "gpt-4".to_string(),
+			choices: vec![Choice {
+				index: 0,
+				message: ResponseMessage {
+					role: "assistant".to_string(),
+					content: Some(content.to_string()),
+					tool_calls: Some(vec![ToolCall {
+						id: "call_abc".to_string(),
+						r#type: "function".to_string(),
+						function: ToolCallFunction {
+							name: "read_file".to_string(),
+							arguments: r#"{"path":"src/main.rs"}"#.to_string(),
+						},
+					}]),
+				},
+				finish_reason: Some("tool_calls".to_string()),
+				logprobs: None,
+			}],
+			system_fingerprint: None,
+			usage: None,
+		}
+	}
+
+	#[test]
+	fn tool_preamble_stripped_when_enabled() {
+		let resp = preamble_response("<thinking>which file?</thinking>Let me check that.");
+
+		let stripped = translate_response(&resp, true, true);
+		assert_eq!(stripped.content.len(), 2);
+		assert!(matches!(
+			stripped.content[0],
+			AssistantContentBlock::Thinking(_)
+		));
+		assert!(
+			matches!(&stripped.content[1], AssistantContentBlock::ToolUse(tu) if tu.name == "read_file")
+		);
+
+		let kept = translate_response(&resp, true, false);
+		assert_eq!(kept.content.len(), 3);
+		assert!(
+			matches!(&kept.content[1], AssistantContentBlock::Text(t) if t.text == "Let me check that.")
+		);
+	}
+
+	#[test]
+	fn long_tool_preamble_kept() {
+		let long = "This is a real explanation. ".repeat(10);
+		let result = translate_response(&preamble_response(&long), false, true);
+		assert_eq!(result.content.len(), 2);
+		assert!(matches!(&result.content[0], AssistantContentBlock::Text(t) if t.text == long));
+
+		let mut no_tools = preamble_response("Hello!");
+		no_tools.choices[0].message.tool_calls = None;
+		let result = translate_response(&no_tools, false, true);
+		assert!(matches!(&result.content[0], AssistantContentBlock::Text(t) if t.text == "Hello!"));
 	}
 }
@@ -1,138 +1,96 @@
-use crate::copilot::types::ChatCompletionChunk;
+use std::env;
+
+use serde::Serialize;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::copilot::types::{ChatCompletionChunk, Usage};
+use crate::translate::partial_json::close_partial_json;
+use crate::translate::response::CONTENT_FILTERED;
 use crate::translate::thinking::ThinkingEvent;
 use crate::translate::types::{
-	AnthropicUsage, ContentBlockStartBody, ContentDelta, MessageDeltaBody, MessageStartBody,
-	StopReason, StreamEvent, StreamState,
+	AnthropicUsage, AssistantContentBlock, ContentBlockStartBody, ContentDelta, MessageDeltaBody,
+	MessageStartBody, MessagesResponse, StopReason, StreamError, StreamEvent, StreamState,
 };
 
+/// What to do when a client asks to stream from a model that can't.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnstreamableModels {
+	/// Ask upstream for a single response, and replay it as a stream.
+	#[default]
+	Buffer,
+	/// Refuse the request.
+	Reject,
+}
+
+impl UnstreamableModels {
+	/// Read `UNSTREAMABLE_MODELS`: `buffer` (default) or `reject`.
+	pub fn from_env() -> Self {
+		match env::var("UNSTREAMABLE_MODELS").as_deref() {
+			Ok("buffer") | Err(_) => Self::Buffer,
+			Ok("reject") => Self::Reject,
+			Ok(other) => {
+				warn!(value = other, "unknown UNSTREAMABLE_MODELS, using buffer");
+				Self::Buffer
+			}
+		}
+	}
+}
+
 pub fn translate_chunk(chunk: &ChatCompletionChunk, state: &mut StreamState) -> Vec<StreamEvent> {
 	let mut events = Vec::new();
 
+	if let Some(ref usage) = chunk.usage {
+		state.usage = Some(latest_usage(state.usage.take(), usage));
+	}
+
+	if state.first_chunk.is_none() {
+		state.first_chunk = Some((chunk.id.clone(), chunk.model.clone()));
+	}
+
 	if chunk.choices.is_empty() {
+		// Usage can arrive in a trailing chunk with no choices, after the finish
+		if state.usage.is_some()
+			&& let Some(stop_reason) = state.pending_stop.take()
+		{
+			push_message_end(stop_reason, state, &mut events);
+		}
 		return events;
 	}
 
 	let choice = &chunk.choices[0];
 	let delta = &choice.delta;
+	if state.role.is_none() {
+		state.role.clone_from(&delta.role);
+	}
+
+	// Heartbeat-like empty deltas carry nothing to translate, and shouldn't
+	// trigger message_start before the first real content
+	let has_content = delta.content.as_ref().is_some_and(|c| !c.is_empty());
+	let has_tool_calls = delta.tool_calls.as_ref().is_some_and(|t| !t.is_empty());
+	if !has_content && !has_tool_calls && choice.finish_reason.is_none() {
+		return events;
+	}
 
 	if !state.message_start_sent {
-		let (input_tokens, cache_read) = extract_input_usage(chunk);
-		events.push(StreamEvent::MessageStart {
-			message: MessageStartBody {
-				id: chunk.id.clone(),
-				r#type: "message",
-				role: "assistant",
-				content: Vec::new(),
-				model: chunk.model.clone(),
-				stop_reason: None,
-				stop_sequence: None,
-				usage: AnthropicUsage {
-					input_tokens,
-					output_tokens: 0,
-					cache_creation_input_tokens: None,
-					cache_read_input_tokens: if cache_read > 0 {
-						Some(cache_read)
-					} else {
-						None
-					},
-				},
-			},
-		});
-		state.message_start_sent = true;
+		push_message_start(
+			chunk.id.clone(),
+			&chunk.model,
+			chunk.system_fingerprint.clone(),
+			state,
+			&mut events,
+		);
 	}
 
 	if let Some(ref text) = delta.content {
 		// If thinking emulation is enabled, process through the parser
 		if let Some(ref mut parser) = state.thinking_parser {
-			let thinking_events = parser.push(text);
-			for thinking_event in thinking_events {
-				match thinking_event {
-					ThinkingEvent::ThinkingStart => {
-						// Close any open block before starting thinking
-						if state.content_block_open {
-							events.push(StreamEvent::ContentBlockStop {
-								index: state.content_block_index,
-							});
-							state.content_block_index += 1;
-						}
-
-						// Open a new thinking block
-						events.push(StreamEvent::ContentBlockStart {
-							index: state.content_block_index,
-							content_block: ContentBlockStartBody::Thinking {
-								thinking: String::new(),
-							},
-						});
-						state.content_block_open = true;
-					}
-					ThinkingEvent::ThinkingDelta(thinking_text) => {
-						events.push(StreamEvent::ContentBlockDelta {
-							index: state.content_block_index,
-							delta: ContentDelta::Thinking {
-								thinking: thinking_text,
-							},
-						});
-					}
-					ThinkingEvent::ThinkingEnd => {
-						// Close the thinking block
-						events.push(StreamEvent::ContentBlockStop {
-							index: state.content_block_index,
-						});
-						state.content_block_index += 1;
-						state.content_block_open = false;
-					}
-					ThinkingEvent::TextDelta(text_chunk) => {
-						// If a tool block is open, close it before starting a text block
-						if state.is_tool_block_open() {
-							events.push(StreamEvent::ContentBlockStop {
-								index: state.content_block_index,
-							});
-							state.content_block_index += 1;
-							state.content_block_open = false;
-						}
-
-						if !state.content_block_open {
-							events.push(StreamEvent::ContentBlockStart {
-								index: state.content_block_index,
-								content_block: ContentBlockStartBody::Text {
-									text: String::new(),
-								},
-							});
-							state.content_block_open = true;
-						}
-
-						events.push(StreamEvent::ContentBlockDelta {
-							index: state.content_block_index,
-							delta: ContentDelta::Text { text: text_chunk },
-						});
-					}
-				}
+			for thinking_event in parser.push(text) {
+				apply_thinking_event(thinking_event, state, &mut events);
 			}
 		} else {
-			// No thinking emulation - pass through as regular text
-			// If a tool block is open, close it before starting a text block
-			if state.is_tool_block_open() {
-				events.push(StreamEvent::ContentBlockStop {
-					index: state.content_block_index,
-				});
-				state.content_block_index += 1;
-				state.content_block_open = false;
-			}
-
-			if !state.content_block_open {
-				events.push(StreamEvent::ContentBlockStart {
-					index: state.content_block_index,
-					content_block: ContentBlockStartBody::Text {
-						text: String::new(),
-					},
-				});
-				state.content_block_open = true;
-			}
-
-			events.push(StreamEvent::ContentBlockDelta {
-				index: state.content_block_index,
-				delta: ContentDelta::Text { text: text.clone() },
-			});
+			push_text_delta(text.clone(), state, &mut events);
 		}
 	}
 
@@ -142,39 +100,31 @@ pub fn translate_chunk(chunk: &ChatCompletionChunk, state: &mut StreamState) ->
 			if let (Some(id), Some(func)) = (&tool_call.id, &tool_call.function)
 				&& let Some(ref name) = func.name
 			{
-				// Close any previously open block
-				if state.content_block_open {
-					events.push(StreamEvent::ContentBlockStop {
-						index: state.content_block_index,
-					});
-					state.content_block_index += 1;
-					state.content_block_open = false;
-				}
+				// Text held back by the thinking parser precedes this tool call
+				flush_thinking_parser(state, &mut events);
 
-				let anthropic_block_index = state.content_block_index;
+				stop_block(state, &mut events);
+				let content_block = ContentBlockStartBody::ToolUse {
+					id: id.clone(),
+					name: state.tool_names.get(name).unwrap_or(name).clone(),
+					input: serde_json::Value::Object(Default::default()),
+				};
+				let anthropic_block_index = start_block(content_block, state, &mut events);
 				state.tool_calls.insert(
 					tool_call.index,
 					crate::translate::types::ToolCallState {
 						anthropic_block_index,
+						arguments: String::new(),
 					},
 				);
-
-				events.push(StreamEvent::ContentBlockStart {
-					index: anthropic_block_index,
-					content_block: ContentBlockStartBody::ToolUse {
-						id: id.clone(),
-						name: name.clone(),
-						input: serde_json::Value::Object(Default::default()),
-					},
-				});
-				state.content_block_open = true;
 			}
 
 			// Tool call arguments delta
 			if let Some(ref func) = tool_call.function
 				&& let Some(ref arguments) = func.arguments
-				&& let Some(tc_state) = state.tool_calls.get(&tool_call.index)
+				&& let Some(tc_state) = state.tool_calls.get_mut(&tool_call.index)
 			{
+				tc_state.arguments.push_str(arguments);
 				events.push(StreamEvent::ContentBlockDelta {
 					index: tc_state.anthropic_block_index,
 					delta: ContentDelta::InputJson {
@@ -186,62 +136,406 @@ pub fn translate_chunk(chunk: &ChatCompletionChunk, state: &mut StreamState) ->
 	}
 
 	if let Some(ref finish_reason) = choice.finish_reason {
-		if state.content_block_open {
-			events.push(StreamEvent::ContentBlockStop {
+		state.finish_seen = true;
+		flush_thinking_parser(state, &mut events);
+		close_open_block(state, &mut events);
+
+		let stop_reason = map_stop_reason(finish_reason);
+		if matches!(stop_reason, StopReason::Refusal) {
+			warn!("stream blocked by the content filter");
+			push_text_delta(CONTENT_FILTERED.to_string(), state, &mut events);
+			close_open_block(state, &mut events);
+		}
+		if state.usage.is_some() {
+			push_message_end(stop_reason, state, &mut events);
+		} else {
+			// Hold the message end back until usage shows up or the stream ends
+			state.pending_stop = Some(stop_reason);
+		}
+	}
+
+	events
+}
+
+fn apply_thinking_event(
+	event: ThinkingEvent,
+	state: &mut StreamState,
+	events: &mut Vec<StreamEvent>,
+) {
+	match event {
+		ThinkingEvent::ThinkingStart
+		| ThinkingEvent::ThinkingDelta(_)
+		| ThinkingEvent::ThinkingEnd
+			if state.drop_thinking => {}
+		ThinkingEvent::ThinkingStart => {
+			stop_block(state, events);
+			start_block(
+				ContentBlockStartBody::Thinking {
+					thinking: String::new(),
+				},
+				state,
+				events,
+			);
+		}
+		ThinkingEvent::ThinkingDelta(thinking_text) => {
+			events.push(StreamEvent::ContentBlockDelta {
 				index: state.content_block_index,
+				delta: ContentDelta::Thinking {
+					thinking: thinking_text,
+				},
 			});
-			state.content_block_open = false;
 		}
+		ThinkingEvent::ThinkingEnd => stop_block(state, events),
+		ThinkingEvent::TextDelta(text_chunk) => push_text_delta(text_chunk, state, events),
+	}
+}
 
-		let (input_tokens, cache_read) = extract_input_usage(chunk);
+fn push_text_delta(text: String, state: &mut StreamState, events: &mut Vec<StreamEvent>) {
+	// If a tool block is open, close it before starting a text block
+	if state.is_tool_block_open() {
+		stop_block(state, events);
+	}
 
-		events.push(StreamEvent::MessageDelta {
-			delta: MessageDeltaBody {
-				stop_reason: Some(map_stop_reason(finish_reason)),
-				stop_sequence: None,
+	if !state.content_block_open {
+		start_block(
+			ContentBlockStartBody::Text {
+				text: String::new(),
 			},
-			usage: Some(AnthropicUsage {
+			state,
+			events,
+		);
+	}
+
+	events.push(StreamEvent::ContentBlockDelta {
+		index: state.content_block_index,
+		delta: ContentDelta::Text { text },
+	});
+}
+
+/// Emit whatever the thinking parser is holding back (its partial-tag reserve)
+/// so it lands in the block it belongs to, before a different block opens.
+fn flush_thinking_parser(state: &mut StreamState, events: &mut Vec<StreamEvent>) {
+	let Some(pending) = state.thinking_parser.as_mut().and_then(|p| p.flush()) else {
+		return;
+	};
+	apply_thinking_event(pending, state, events);
+}
+
+/// Open a block at the next index, returning it. Only one block is open at a
+/// time, and each gets a fresh index, counting up from 0.
+fn start_block(
+	content_block: ContentBlockStartBody,
+	state: &mut StreamState,
+	events: &mut Vec<StreamEvent>,
+) -> u32 {
+	debug_assert!(
+		!state.content_block_open,
+		"block {} is still open",
+		state.content_block_index
+	);
+	debug_assert_eq!(
+		state.content_block_index, state.blocks_started,
+		"block index reused or skipped"
+	);
+	let index = state.content_block_index;
+	events.push(StreamEvent::ContentBlockStart {
+		index,
+		content_block,
+	});
+	state.content_block_open = true;
+	state.blocks_started += 1;
+	index
+}
+
+/// Close the open block, if any, moving on to the next index.
+fn stop_block(state: &mut StreamState, events: &mut Vec<StreamEvent>) {
+	if state.content_block_open {
+		events.push(StreamEvent::ContentBlockStop {
+			index: state.content_block_index,
+		});
+		state.content_block_index += 1;
+		state.content_block_open = false;
+	}
+}
+
+fn close_open_block(state: &mut StreamState, events: &mut Vec<StreamEvent>) {
+	if state.content_block_open {
+		close_tool_arguments(state, events);
+		stop_block(state, events);
+	}
+}
+
+/// If the open block is a tool call whose arguments were cut off (e.g. by a
+/// `length` finish), stream whatever closes them so the input still parses.
+fn close_tool_arguments(state: &StreamState, events: &mut Vec<StreamEvent>) {
+	let Some(tool) = state
+		.tool_calls
+		.values()
+		.find(|tc| tc.anthropic_block_index == state.content_block_index)
+	else {
+		return;
+	};
+	if tool.arguments.trim().is_empty()
+		|| serde_json::from_str::<serde_json::Value>(&tool.arguments).is_ok()
+	{
+		return;
+	}
+
+	let closing = close_partial_json(&tool.arguments).filter(|suffix| {
+		serde_json::from_str::<serde_json::Value>(&format!("{}{suffix}", tool.arguments)).is_ok()
+	});
+	match closing {
+		Some(partial_json) => {
+			warn!(closing = %partial_json, "closing truncated tool call arguments");
+			events.push(StreamEvent::ContentBlockDelta {
+				index: tool.anthropic_block_index,
+				delta: ContentDelta::InputJson { partial_json },
+			});
+		}
+		None => warn!(
+			arguments = %tool.arguments,
+			"tool call arguments are invalid JSON and can't be closed"
+		),
+	}
+}
+
+/// Emit the events that end the message once the upstream stream is exhausted.
+///
+/// If the finish was held back waiting for usage that never came, it's
+/// emitted now with the estimated input tokens. If upstream closed without
+/// ever sending a finish, one is synthesized from what was streamed, so the
+/// client's stream always terminates: if nothing was streamed at all, that's
+/// an empty message, started here.
+pub fn finish_stream(state: &mut StreamState) -> Vec<StreamEvent> {
+	let mut events = Vec::new();
+	if !state.message_start_sent {
+		// Nothing but empty deltas came, if anything
+		let (id, model) = state.first_chunk.take().unwrap_or_default();
+		let id = if id.is_empty() {
+			format!("msg_{}", Uuid::new_v4().simple())
+		} else {
+			id
+		};
+		push_message_start(id, &model, None, state, &mut events);
+		push_message_end(StopReason::EndTurn, state, &mut events);
+	} else if let Some(stop_reason) = state.pending_stop.take() {
+		push_message_end(stop_reason, state, &mut events);
+	} else if !state.finish_seen {
+		if let Some(pending) = state.thinking_parser.take().and_then(|p| p.finish()) {
+			apply_thinking_event(pending, state, &mut events);
+		}
+		close_open_block(state, &mut events);
+		let stop_reason = if state.tool_calls.is_empty() {
+			StopReason::EndTurn
+		} else {
+			StopReason::ToolUse
+		};
+		push_message_end(stop_reason, state, &mut events);
+	}
+	events
+}
+
+fn push_message_start(
+	id: String,
+	upstream_model: &str,
+	system_fingerprint: Option<String>,
+	state: &mut StreamState,
+	events: &mut Vec<StreamEvent>,
+) {
+	let (input_tokens, cache_read) = match state.usage {
+		Some(ref u) => input_usage(u),
+		None => (state.estimated_input_tokens, 0),
+	};
+	events.push(StreamEvent::MessageStart {
+		message: MessageStartBody {
+			id,
+			r#type: "message",
+			role: state
+				.role
+				.clone()
+				.unwrap_or_else(|| "assistant".to_string()),
+			content: Vec::new(),
+			model: state
+				.display_model
+				.clone()
+				.unwrap_or_else(|| upstream_model.to_string()),
+			stop_reason: None,
+			stop_sequence: None,
+			usage: AnthropicUsage {
 				input_tokens,
-				output_tokens: chunk
-					.usage
-					.as_ref()
-					.map(|u| u.completion_tokens)
-					.unwrap_or(0),
+				output_tokens: 0,
 				cache_creation_input_tokens: None,
 				cache_read_input_tokens: if cache_read > 0 {
 					Some(cache_read)
 				} else {
 					None
 				},
-			}),
-		});
+			},
+			system_fingerprint,
+		},
+	});
+	state.message_start_sent = true;
+}
+
+/// Replay a complete (non-streaming) response as the equivalent SSE sequence,
+/// for when upstream answers a streaming request with plain JSON.
+pub fn response_events(resp: &MessagesResponse) -> Vec<StreamEvent> {
+	let mut events = vec![StreamEvent::MessageStart {
+		message: MessageStartBody {
+			id: resp.id.clone(),
+			r#type: "message",
+			role: resp.role.to_string(),
+			content: Vec::new(),
+			model: resp.model.clone(),
+			stop_reason: None,
+			stop_sequence: None,
+			usage: AnthropicUsage {
+				output_tokens: 0,
+				..resp.usage.clone()
+			},
+			system_fingerprint: resp.system_fingerprint.clone(),
+		},
+	}];
+
+	let blocks = resp.content.iter().filter_map(|block| match block {
+		AssistantContentBlock::Text(t) => Some((
+			ContentBlockStartBody::Text {
+				text: String::new(),
+			},
+			ContentDelta::Text {
+				text: t.text.clone(),
+			},
+		)),
+		AssistantContentBlock::ToolUse(t) => Some((
+			ContentBlockStartBody::ToolUse {
+				id: t.id.clone(),
+				name: t.name.clone(),
+				input: serde_json::Value::Object(Default::default()),
+			},
+			ContentDelta::InputJson {
+				partial_json: t.input.to_string(),
+			},
+		)),
+		AssistantContentBlock::Thinking(t) => Some((
+			ContentBlockStartBody::Thinking {
+				thinking: String::new(),
+			},
+			ContentDelta::Thinking {
+				thinking: t.thinking.clone(),
+			},
+		)),
+		AssistantContentBlock::Image(_) | AssistantContentBlock::ServerTool => None,
+	});
 
-		events.push(StreamEvent::MessageStop {});
+	for (index, (content_block, delta)) in (0u32..).zip(blocks) {
+		events.push(StreamEvent::ContentBlockStart {
+			index,
+			content_block,
+		});
+		events.push(StreamEvent::ContentBlockDelta { index, delta });
+		events.push(StreamEvent::ContentBlockStop { index });
 	}
 
+	events.push(StreamEvent::MessageDelta {
+		delta: MessageDeltaBody {
+			stop_reason: Some(resp.stop_reason.unwrap_or(StopReason::EndTurn)),
+			stop_sequence: resp.stop_sequence.clone(),
+		},
+		usage: Some(resp.usage.clone()),
+	});
+	events.push(StreamEvent::MessageStop {});
 	events
 }
 
-fn extract_input_usage(chunk: &ChatCompletionChunk) -> (u64, u64) {
-	match &chunk.usage {
-		Some(u) => {
-			let cached = u
-				.prompt_tokens_details
-				.as_ref()
-				.map(|d| d.cached_tokens)
-				.unwrap_or(0);
-			(u.prompt_tokens.saturating_sub(cached), cached)
+pub fn error_event(r#type: &str, message: String) -> StreamEvent {
+	StreamEvent::Error {
+		error: StreamError {
+			r#type: r#type.to_string(),
+			message,
+		},
+	}
+}
+
+/// The Anthropic error event for an error Copilot sent in place of a chunk,
+/// if `data` is one.
+pub fn upstream_error_event(data: &str) -> Option<StreamEvent> {
+	let json: serde_json::Value = serde_json::from_str(data).ok()?;
+	let error = json
+		.get("error")
+		.filter(|e| e.is_object() || e.is_string())?;
+	let message = error
+		.get("message")
+		.and_then(serde_json::Value::as_str)
+		.or(error.as_str())
+		.unwrap_or("no message");
+	Some(error_event(
+		"api_error",
+		format!("upstream error: {message}"),
+	))
+}
+
+fn push_message_end(stop_reason: StopReason, state: &StreamState, events: &mut Vec<StreamEvent>) {
+	let (input_tokens, output_tokens, cache_read) = match state.usage {
+		Some(ref u) => {
+			let (input_tokens, cache_read) = input_usage(u);
+			(input_tokens, u.completion_tokens, cache_read)
 		}
-		None => (0, 0),
+		None => (state.estimated_input_tokens, 0, 0),
+	};
+
+	events.push(StreamEvent::MessageDelta {
+		delta: MessageDeltaBody {
+			stop_reason: Some(stop_reason.pausing(state.pause_turn)),
+			stop_sequence: None,
+		},
+		usage: Some(AnthropicUsage {
+			input_tokens,
+			output_tokens,
+			cache_creation_input_tokens: None,
+			cache_read_input_tokens: if cache_read > 0 {
+				Some(cache_read)
+			} else {
+				None
+			},
+		}),
+	});
+
+	events.push(StreamEvent::MessageStop {});
+}
+
+/// Usage is cumulative, so the latest chunk's figures win. Any it leaves out
+/// (as zero) are kept from earlier chunks, so the final totals stay whole.
+fn latest_usage(previous: Option<Usage>, latest: &Usage) -> Usage {
+	let Some(previous) = previous else {
+		return latest.clone();
+	};
+	let or_previous = |latest: u64, previous: u64| if latest > 0 { latest } else { previous };
+	Usage {
+		prompt_tokens: or_previous(latest.prompt_tokens, previous.prompt_tokens),
+		completion_tokens: or_previous(latest.completion_tokens, previous.completion_tokens),
+		total_tokens: or_previous(latest.total_tokens, previous.total_tokens),
+		prompt_tokens_details: latest
+			.prompt_tokens_details
+			.clone()
+			.or(previous.prompt_tokens_details),
 	}
 }
 
+fn input_usage(usage: &Usage) -> (u64, u64) {
+	let cached = usage
+		.prompt_tokens_details
+		.as_ref()
+		.map(|d| d.cached_tokens)
+		.unwrap_or(0);
+	(usage.prompt_tokens.saturating_sub(cached), cached)
+}
+
 fn map_stop_reason(reason: &str) -> StopReason {
 	match reason {
 		"stop" => StopReason::EndTurn,
 		"length" => StopReason::MaxTokens,
 		"tool_calls" => StopReason::ToolUse,
-		"content_filter" => StopReason::EndTurn,
+		"content_filter" => StopReason::Refusal,
 		_ => StopReason::EndTurn,
 	}
 }
@@ -317,13 +611,72 @@ mod tests {
 		assert_eq!(events[0].event_type(), "content_block_delta");
 	}
 
+	#[test]
+	fn content_filter_finish_is_refusal() {
+		let mut state = StreamState::new(false);
+		translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Sure, here")]),
+			&mut state,
+		);
+		let mut chunk = make_chunk("c1", "gpt-4", vec![finish_choice("content_filter")]);
+		chunk.usage = Some(usage(10, 2));
+		let events = translate_chunk(&chunk, &mut state);
+
+		assert_eq!(
+			summarise(&events),
+			vec![
+				s("stop", Some(0)),
+				s("start:text", Some(1)),
+				s(&format!("text:{CONTENT_FILTERED}"), Some(1)),
+				s("stop", Some(1)),
+				s("message_delta", None),
+				s("message_stop", None),
+			]
+		);
+		let StreamEvent::MessageDelta { delta, .. } = &events[4] else {
+			panic!("expected message_delta");
+		};
+		assert!(matches!(delta.stop_reason, Some(StopReason::Refusal)));
+	}
+
+	#[test]
+	fn tool_use_stream_pauses_turn_when_enabled() {
+		for (pause_turn, expected) in [(false, "tool_use"), (true, "pause_turn")] {
+			let mut state = StreamState::new(false);
+			state.pause_turn = pause_turn;
+			translate_chunk(
+				&make_chunk(
+					"c1",
+					"claude-sonnet-4",
+					vec![content_and_tool(
+						None,
+						vec![tool_call_start(0, "call_1", "ls")],
+					)],
+				),
+				&mut state,
+			);
+			let mut chunk = make_chunk("c1", "claude-sonnet-4", vec![finish_choice("tool_calls")]);
+			chunk.usage = Some(usage(10, 2));
+			let events = translate_chunk(&chunk, &mut state);
+			let delta = events
+				.iter()
+				.find_map(|e| match e {
+					StreamEvent::MessageDelta { delta, .. } => Some(delta),
+					_ => None,
+				})
+				.unwrap();
+			assert_eq!(serde_json::to_value(delta.stop_reason).unwrap(), expected);
+		}
+	}
+
 	#[test]
 	fn finish_reason_closes_and_stops() {
 		let mut state = StreamState::new(false);
 		let chunk1 = make_chunk("c1", "gpt-4", vec![text_delta("Hi")]);
 		translate_chunk(&chunk1, &mut state);
 
-		let chunk2 = make_chunk("c1", "gpt-4", vec![finish_choice("stop")]);
+		let mut chunk2 = make_chunk("c1", "gpt-4", vec![finish_choice("stop")]);
+		chunk2.usage = Some(usage(10, 2));
 		let events = translate_chunk(&chunk2, &mut state);
 
 		assert_eq!(events.len(), 3);
@@ -333,6 +686,248 @@ mod tests {
 		assert!(!state.content_block_open);
 	}
 
+	#[test]
+	fn empty_delta_is_ignored_until_first_content() {
+		let mut state = StreamState::new(false);
+		let heartbeat = make_chunk(
+			"c1",
+			"gpt-4",
+			vec![ChunkChoice {
+				index: 0,
+				delta: Delta {
+					content: None,
+					role: None,
+					tool_calls: None,
+				},
+				finish_reason: None,
+				logprobs: None,
+			}],
+		);
+		assert!(translate_chunk(&heartbeat, &mut state).is_empty());
+		assert!(
+			translate_chunk(&make_chunk("c1", "gpt-4", vec![text_delta("")]), &mut state)
+				.is_empty()
+		);
+		assert!(!state.message_start_sent);
+
+		let events = translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Hi")]),
+			&mut state,
+		);
+		let types: Vec<&str> = events.iter().map(|e| e.event_type()).collect();
+		assert_eq!(
+			types,
+			[
+				"message_start",
+				"content_block_start",
+				"content_block_delta"
+			]
+		);
+
+		// message_start is never repeated
+		translate_chunk(&heartbeat, &mut state);
+		let events = translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("!")]),
+			&mut state,
+		);
+		assert!(events.iter().all(|e| e.event_type() != "message_start"));
+	}
+
+	#[test]
+	fn role_only_stream_still_terminates() {
+		let mut state = StreamState::new(false);
+		state.estimated_input_tokens = 42;
+		state.display_model = Some("claude-sonnet-4".to_string());
+		let role_only = make_chunk(
+			"c1",
+			"gpt-4",
+			vec![ChunkChoice {
+				index: 0,
+				delta: Delta {
+					content: Some(String::new()),
+					role: Some("assistant".to_string()),
+					tool_calls: None,
+				},
+				finish_reason: None,
+				logprobs: None,
+			}],
+		);
+		assert!(translate_chunk(&role_only, &mut state).is_empty());
+
+		let events = finish_stream(&mut state);
+		let types: Vec<&str> = events.iter().map(|e| e.event_type()).collect();
+		assert_eq!(types, ["message_start", "message_delta", "message_stop"]);
+		let StreamEvent::MessageStart { message } = &events[0] else {
+			panic!("expected message_start");
+		};
+		assert_eq!(message.id, "c1");
+		assert_eq!(message.model, "claude-sonnet-4");
+		assert_eq!(message.role, "assistant");
+		assert_eq!(message.usage.input_tokens, 42);
+		assert!(matches!(
+			&events[1],
+			StreamEvent::MessageDelta { delta, .. } if matches!(delta.stop_reason, Some(StopReason::EndTurn))
+		));
+	}
+
+	#[test]
+	fn finish_without_content_still_starts_message() {
+		let mut state = StreamState::new(false);
+		let events = translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![finish_choice("stop")]),
+			&mut state,
+		);
+		assert_eq!(events[0].event_type(), "message_start");
+	}
+
+	fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+		Usage {
+			prompt_tokens,
+			completion_tokens,
+			total_tokens: prompt_tokens + completion_tokens,
+			prompt_tokens_details: None,
+		}
+	}
+
+	fn final_usage(events: &[StreamEvent]) -> &AnthropicUsage {
+		events
+			.iter()
+			.find_map(|e| match e {
+				StreamEvent::MessageDelta {
+					usage: Some(usage), ..
+				} => Some(usage),
+				_ => None,
+			})
+			.expect("no message_delta with usage")
+	}
+
+	#[test]
+	fn message_start_uses_estimate_without_usage() {
+		let mut state = StreamState::new(false);
+		state.estimated_input_tokens = 42;
+		let events = translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Hi")]),
+			&mut state,
+		);
+
+		let StreamEvent::MessageStart { message } = &events[0] else {
+			panic!("expected message_start");
+		};
+		assert_eq!(message.usage.input_tokens, 42);
+	}
+
+	#[test]
+	fn late_usage_corrects_final_message_delta() {
+		let mut state = StreamState::new(false);
+		state.estimated_input_tokens = 42;
+		translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Hi")]),
+			&mut state,
+		);
+
+		// Finish without usage: the message end is held back
+		let events = translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![finish_choice("stop")]),
+			&mut state,
+		);
+		let types: Vec<&str> = events.iter().map(|e| e.event_type()).collect();
+		assert_eq!(types, ["content_block_stop"]);
+
+		// Usage arrives in a trailing chunk with no choices
+		let mut usage_chunk = make_chunk("c1", "gpt-4", vec![]);
+		usage_chunk.usage = Some(usage(120, 7));
+		let events = translate_chunk(&usage_chunk, &mut state);
+
+		let types: Vec<&str> = events.iter().map(|e| e.event_type()).collect();
+		assert_eq!(types, ["message_delta", "message_stop"]);
+		let usage = final_usage(&events);
+		assert_eq!(usage.input_tokens, 120);
+		assert_eq!(usage.output_tokens, 7);
+		assert!(finish_stream(&mut state).is_empty());
+	}
+
+	#[test]
+	fn usage_in_two_chunks_reports_the_last() {
+		let mut state = StreamState::new(false);
+		let mut first = make_chunk("c1", "gpt-4", vec![text_delta("Hi")]);
+		first.usage = Some(usage(100, 1));
+		translate_chunk(&first, &mut state);
+
+		let mut finish = make_chunk("c1", "gpt-4", vec![finish_choice("stop")]);
+		finish.usage = Some(usage(120, 9));
+		let events = translate_chunk(&finish, &mut state);
+		let totals = final_usage(&events);
+		assert_eq!(totals.input_tokens, 120);
+		assert_eq!(totals.output_tokens, 9);
+
+		// A later chunk leaving figures out doesn't zero them
+		let mut state = StreamState::new(false);
+		translate_chunk(&first, &mut state);
+		let mut finish = make_chunk("c1", "gpt-4", vec![finish_choice("stop")]);
+		finish.usage = Some(usage(0, 9));
+		let events = translate_chunk(&finish, &mut state);
+		let totals = final_usage(&events);
+		assert_eq!(totals.input_tokens, 100);
+		assert_eq!(totals.output_tokens, 9);
+	}
+
+	#[test]
+	fn missing_usage_falls_back_to_estimate_at_end() {
+		let mut state = StreamState::new(false);
+		state.estimated_input_tokens = 42;
+		translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Hi")]),
+			&mut state,
+		);
+		translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![finish_choice("stop")]),
+			&mut state,
+		);
+
+		let events = finish_stream(&mut state);
+		let types: Vec<&str> = events.iter().map(|e| e.event_type()).collect();
+		assert_eq!(types, ["message_delta", "message_stop"]);
+		assert_eq!(final_usage(&events).input_tokens, 42);
+	}
+
+	#[test]
+	fn role_from_first_delta() {
+		let delta = |role: Option<&str>, content: Option<&str>| {
+			make_chunk(
+				"c1",
+				"gpt-4",
+				vec![ChunkChoice {
+					index: 0,
+					delta: Delta {
+						content: content.map(str::to_string),
+						role: role.map(str::to_string),
+						tool_calls: None,
+					},
+					finish_reason: None,
+					logprobs: None,
+				}],
+			)
+		};
+		let role = |events: &[StreamEvent]| match &events[0] {
+			StreamEvent::MessageStart { message } => message.role.clone(),
+			other => panic!("expected message_start, got {other:?}"),
+		};
+
+		// The role-only delta sends nothing, but its role is remembered
+		let mut state = StreamState::new(false);
+		assert!(translate_chunk(&delta(Some("assistant"), None), &mut state).is_empty());
+		let events = translate_chunk(&delta(None, Some("Hi")), &mut state);
+		assert_eq!(role(&events), "assistant");
+
+		let mut state = StreamState::new(false);
+		let events = translate_chunk(&delta(Some("model"), Some("Hi")), &mut state);
+		assert_eq!(role(&events), "model");
+
+		let mut state = StreamState::new(false);
+		let events = translate_chunk(&delta(None, Some("Hi")), &mut state);
+		assert_eq!(role(&events), "assistant");
+	}
+
 	#[test]
 	fn tool_call_creates_new_block() {
 		let mut state = StreamState::new(false);
@@ -438,4 +1033,509 @@ mod tests {
 		assert!(types.contains(&"content_block_start"));
 		assert!(types.contains(&"content_block_delta"));
 	}
+
+	fn tool_call_start(index: u32, id: &str, name: &str) -> DeltaToolCall {
+		DeltaToolCall {
+			index,
+			id: Some(id.to_string()),
+			r#type: Some("function".to_string()),
+			function: Some(DeltaFunction {
+				name: Some(name.to_string()),
+				arguments: Some("{}".to_string()),
+			}),
+		}
+	}
+
+	fn content_and_tool(content: Option<&str>, tool_calls: Vec<DeltaToolCall>) -> ChunkChoice {
+		ChunkChoice {
+			index: 0,
+			delta: Delta {
+				content: content.map(str::to_string),
+				role: None,
+				tool_calls: Some(tool_calls),
+			},
+			finish_reason: None,
+			logprobs: None,
+		}
+	}
+
+	/// Flatten events into `(type, index)` pairs, plus the text of any text delta.
+	fn summarise(events: &[StreamEvent]) -> Vec<(String, Option<u32>)> {
+		events
+			.iter()
+			.map(|e| match e {
+				StreamEvent::ContentBlockStart {
+					index,
+					content_block,
+				} => {
+					let kind = match content_block {
+						ContentBlockStartBody::Text { .. } => "start:text",
+						ContentBlockStartBody::ToolUse { .. } => "start:tool_use",
+						ContentBlockStartBody::Thinking { .. } => "start:thinking",
+					};
+					(kind.to_string(), Some(*index))
+				}
+				StreamEvent::ContentBlockDelta { index, delta } => match delta {
+					ContentDelta::Text { text } => (format!("text:{text}"), Some(*index)),
+					ContentDelta::InputJson { partial_json } => {
+						(format!("json:{partial_json}"), Some(*index))
+					}
+					ContentDelta::Thinking { thinking } => {
+						(format!("thinking:{thinking}"), Some(*index))
+					}
+				},
+				StreamEvent::ContentBlockStop { index } => ("stop".to_string(), Some(*index)),
+				other => (other.event_type().to_string(), None),
+			})
+			.collect()
+	}
+
+	fn s(kind: &str, index: Option<u32>) -> (String, Option<u32>) {
+		(kind.to_string(), index)
+	}
+
+	#[test]
+	fn renamed_tool_gets_original_name() {
+		let mut state = StreamState::new(false);
+		state
+			.tool_names
+			.insert("mcp_fs_read".to_string(), "mcp.fs.read".to_string());
+		let chunk = make_chunk(
+			"c1",
+			"gpt-4",
+			vec![content_and_tool(
+				None,
+				vec![tool_call_start(0, "call_1", "mcp_fs_read")],
+			)],
+		);
+		let events = translate_chunk(&chunk, &mut state);
+
+		assert!(events.iter().any(|e| matches!(
+			e,
+			StreamEvent::ContentBlockStart {
+				content_block: ContentBlockStartBody::ToolUse { name, .. },
+				..
+			} if name == "mcp.fs.read"
+		)));
+	}
+
+	#[test]
+	fn content_then_tool_call_in_one_chunk() {
+		let mut state = StreamState::new(false);
+		let chunk = make_chunk(
+			"c1",
+			"gpt-4",
+			vec![content_and_tool(
+				Some("Let me check."),
+				vec![tool_call_start(0, "call_1", "lookup")],
+			)],
+		);
+		let events = translate_chunk(&chunk, &mut state);
+
+		assert_eq!(
+			summarise(&events),
+			vec![
+				s("message_start", None),
+				s("start:text", Some(0)),
+				s("text:Let me check.", Some(0)),
+				s("stop", Some(0)),
+				s("start:tool_use", Some(1)),
+				s("json:{}", Some(1)),
+			]
+		);
+		assert!(state.is_tool_block_open());
+	}
+
+	#[test]
+	fn content_then_tool_call_in_one_chunk_with_thinking_emulation() {
+		let mut state = StreamState::new(true);
+		let chunk = make_chunk(
+			"c1",
+			"gpt-4",
+			vec![content_and_tool(
+				Some("Let me check."),
+				vec![tool_call_start(0, "call_1", "lookup")],
+			)],
+		);
+		let events = translate_chunk(&chunk, &mut state);
+
+		// The parser's held-back reserve must be emitted before the tool block
+		let text: String = events
+			.iter()
+			.filter_map(|e| match e {
+				StreamEvent::ContentBlockDelta {
+					index: 0,
+					delta: ContentDelta::Text { text },
+				} => Some(text.as_str()),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(text, "Let me check.");
+
+		let summary = summarise(&events);
+		let tool_start = summary
+			.iter()
+			.position(|e| e.0 == "start:tool_use")
+			.unwrap();
+		assert_eq!(summary[tool_start - 1], s("stop", Some(0)));
+		assert_eq!(summary[tool_start].1, Some(1));
+		assert!(state.thinking_parser.unwrap().finish().is_none());
+	}
+
+	#[test]
+	fn alternating_text_and_tool_calls_across_chunks() {
+		let mut state = StreamState::new(false);
+		let chunks = [
+			make_chunk("c1", "gpt-4", vec![text_delta("First")]),
+			make_chunk(
+				"c1",
+				"gpt-4",
+				vec![content_and_tool(
+					None,
+					vec![tool_call_start(0, "call_1", "a")],
+				)],
+			),
+			make_chunk("c1", "gpt-4", vec![text_delta("Second")]),
+			make_chunk(
+				"c1",
+				"gpt-4",
+				vec![content_and_tool(
+					None,
+					vec![tool_call_start(1, "call_2", "b")],
+				)],
+			),
+			make_chunk("c1", "gpt-4", vec![finish_choice("tool_calls")]),
+		];
+
+		let events: Vec<StreamEvent> = chunks
+			.iter()
+			.flat_map(|c| translate_chunk(c, &mut state))
+			.collect();
+
+		assert_eq!(
+			summarise(&events),
+			vec![
+				s("message_start", None),
+				s("start:text", Some(0)),
+				s("text:First", Some(0)),
+				s("stop", Some(0)),
+				s("start:tool_use", Some(1)),
+				s("json:{}", Some(1)),
+				s("stop", Some(1)),
+				s("start:text", Some(2)),
+				s("text:Second", Some(2)),
+				s("stop", Some(2)),
+				s("start:tool_use", Some(3)),
+				s("json:{}", Some(3)),
+				s("stop", Some(3)),
+			]
+		);
+		assert_eq!(
+			summarise(&finish_stream(&mut state)),
+			vec![s("message_delta", None), s("message_stop", None)]
+		);
+	}
+
+	#[test]
+	fn block_indices_unique_and_increasing() {
+		let mut state = StreamState::new(true);
+		let mut chunks = Vec::new();
+		for i in 0..5 {
+			chunks.push(make_chunk(
+				"c1",
+				"gpt-4",
+				vec![text_delta(&format!("<thinking>t{i}</thinking>text {i}"))],
+			));
+			chunks.push(make_chunk(
+				"c1",
+				"gpt-4",
+				vec![content_and_tool(
+					Some("more"),
+					vec![tool_call_start(i, &format!("call_{i}"), "a")],
+				)],
+			));
+		}
+		chunks.push(make_chunk("c1", "gpt-4", vec![text_delta("done")]));
+		chunks.push(make_chunk("c1", "gpt-4", vec![finish_choice("stop")]));
+
+		let mut events: Vec<StreamEvent> = chunks
+			.iter()
+			.flat_map(|c| translate_chunk(c, &mut state))
+			.collect();
+		events.extend(finish_stream(&mut state));
+
+		let mut open = None;
+		let mut started = Vec::new();
+		for event in &events {
+			match event {
+				StreamEvent::ContentBlockStart { index, .. } => {
+					assert_eq!(open, None, "block {index} started while another is open");
+					open = Some(*index);
+					started.push(*index);
+				}
+				StreamEvent::ContentBlockDelta { index, .. } => assert_eq!(open, Some(*index)),
+				StreamEvent::ContentBlockStop { index } => {
+					assert_eq!(open, Some(*index));
+					open = None;
+				}
+				_ => {}
+			}
+		}
+		assert_eq!(open, None);
+		// thinking, text and tool use five times, then the final text
+		assert_eq!(started, (0..16).collect::<Vec<u32>>());
+	}
+
+	#[test]
+	fn response_replays_as_stream_events() {
+		use crate::translate::types::{TextBlock, ToolUseBlock};
+
+		let resp = MessagesResponse {
+			id: "msg_1".to_string(),
+			r#type: "message",
+			role: "assistant",
+			content: vec![
+				AssistantContentBlock::Text(TextBlock {
+					text: "Let me check".to_string(),
+					cache_control: None,
+				}),
+				AssistantContentBlock::ToolUse(ToolUseBlock {
+					id: "call_1".to_string(),
+					name: "lookup".to_string(),
+					input: serde_json::json!({"q": "x"}),
+					cache_control: None,
+				}),
+			],
+			model: "claude-sonnet-4".to_string(),
+			stop_reason: Some(StopReason::ToolUse),
+			stop_sequence: None,
+			usage: AnthropicUsage {
+				input_tokens: 7,
+				output_tokens: 3,
+				cache_creation_input_tokens: None,
+				cache_read_input_tokens: None,
+			},
+			system_fingerprint: None,
+		};
+
+		let events = response_events(&resp);
+		assert_eq!(
+			summarise(&events),
+			vec![
+				s("message_start", None),
+				s("start:text", Some(0)),
+				s("text:Let me check", Some(0)),
+				s("stop", Some(0)),
+				s("start:tool_use", Some(1)),
+				s(r#"json:{"q":"x"}"#, Some(1)),
+				s("stop", Some(1)),
+				s("message_delta", None),
+				s("message_stop", None),
+			]
+		);
+		assert_eq!(final_usage(&events).output_tokens, 3);
+	}
+
+	#[test]
+	fn message_start_carries_system_fingerprint() {
+		let mut state = StreamState::new(false);
+		let mut chunk = make_chunk("c1", "gpt-4", vec![text_delta("Hi")]);
+		chunk.system_fingerprint = Some("fp_abc123".to_string());
+		let events = translate_chunk(&chunk, &mut state);
+		let json = serde_json::to_value(&events[0]).unwrap();
+		assert_eq!(json["message"]["system_fingerprint"], "fp_abc123");
+
+		let mut state = StreamState::new(false);
+		let chunk = make_chunk("c1", "gpt-4", vec![text_delta("Hi")]);
+		let events = translate_chunk(&chunk, &mut state);
+		let json = serde_json::to_value(&events[0]).unwrap();
+		assert!(json["message"].get("system_fingerprint").is_none());
+	}
+
+	#[test]
+	fn stream_closed_without_finish_is_terminated() {
+		let mut state = StreamState::new(false);
+		translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Hi")]),
+			&mut state,
+		);
+
+		let events = finish_stream(&mut state);
+		assert_eq!(
+			summarise(&events),
+			vec![
+				s("stop", Some(0)),
+				s("message_delta", None),
+				s("message_stop", None),
+			]
+		);
+		let StreamEvent::MessageDelta { ref delta, .. } = events[1] else {
+			panic!("expected message_delta");
+		};
+		assert!(matches!(delta.stop_reason, Some(StopReason::EndTurn)));
+	}
+
+	#[test]
+	fn length_finish_closes_truncated_tool_arguments() {
+		let mut state = StreamState::new(false);
+		let mut start = tool_call_start(0, "call_1", "read_file");
+		start.function.as_mut().unwrap().arguments = None;
+		let mut events = translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![content_and_tool(None, vec![start])]),
+			&mut state,
+		);
+		for part in [r#"{"path": "src/"#, r#"main.rs", "lines": [1, "#] {
+			let args = DeltaToolCall {
+				index: 0,
+				id: None,
+				r#type: None,
+				function: Some(DeltaFunction {
+					name: None,
+					arguments: Some(part.to_string()),
+				}),
+			};
+			events.extend(translate_chunk(
+				&make_chunk("c1", "gpt-4", vec![content_and_tool(None, vec![args])]),
+				&mut state,
+			));
+		}
+		let mut finish = make_chunk("c1", "gpt-4", vec![finish_choice("length")]);
+		finish.usage = Some(usage(10, 20));
+		events.extend(translate_chunk(&finish, &mut state));
+
+		let input: String = events
+			.iter()
+			.filter_map(|e| match e {
+				StreamEvent::ContentBlockDelta {
+					delta: ContentDelta::InputJson { partial_json },
+					..
+				} => Some(partial_json.as_str()),
+				_ => None,
+			})
+			.collect();
+		let input: serde_json::Value = serde_json::from_str(&input).unwrap();
+		assert_eq!(
+			input,
+			serde_json::json!({"path": "src/main.rs", "lines": [1, null]})
+		);
+
+		let stop_at = events
+			.iter()
+			.position(|e| matches!(e, StreamEvent::ContentBlockStop { index: 0 }))
+			.unwrap();
+		assert!(matches!(
+			events[stop_at - 1],
+			StreamEvent::ContentBlockDelta { index: 0, .. }
+		));
+		let StreamEvent::MessageDelta { ref delta, .. } = events[stop_at + 1] else {
+			panic!("expected message_delta");
+		};
+		assert!(matches!(delta.stop_reason, Some(StopReason::MaxTokens)));
+	}
+
+	#[test]
+	fn stream_closed_after_tool_call_stops_for_tool_use() {
+		let mut state = StreamState::new(false);
+		translate_chunk(
+			&make_chunk(
+				"c1",
+				"gpt-4",
+				vec![content_and_tool(
+					None,
+					vec![tool_call_start(0, "call_1", "a")],
+				)],
+			),
+			&mut state,
+		);
+
+		let events = finish_stream(&mut state);
+		let StreamEvent::MessageDelta { ref delta, .. } = events[1] else {
+			panic!("expected message_delta");
+		};
+		assert!(matches!(delta.stop_reason, Some(StopReason::ToolUse)));
+	}
+
+	#[test]
+	fn stream_closed_without_finish_flushes_thinking_parser_first() {
+		let mut state = StreamState::new(true);
+		translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Short")]),
+			&mut state,
+		);
+
+		assert_eq!(
+			summarise(&finish_stream(&mut state)),
+			vec![
+				s("start:text", Some(0)),
+				s("text:Short", Some(0)),
+				s("stop", Some(0)),
+				s("message_delta", None),
+				s("message_stop", None),
+			]
+		);
+	}
+
+	#[test]
+	fn thinking_dropped_when_enabled() {
+		let mut state = StreamState::new(true);
+		state.drop_thinking = true;
+		let mut events = Vec::new();
+		for text in [
+			"Sure. <think",
+			"ing>secret",
+			" plans</thinking>Done",
+			"<thinking>more",
+		] {
+			events.extend(translate_chunk(
+				&make_chunk("c1", "gpt-4", vec![text_delta(text)]),
+				&mut state,
+			));
+		}
+		events.extend(translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![finish_choice("stop")]),
+			&mut state,
+		));
+		events.extend(finish_stream(&mut state));
+
+		let summary = summarise(&events);
+		assert!(
+			summary.iter().all(|(event, _)| !event.contains("thinking")),
+			"{summary:?}"
+		);
+		let text: String = summary
+			.iter()
+			.filter_map(|(event, _)| event.strip_prefix("text:"))
+			.collect();
+		assert_eq!(text, "Sure. Done");
+		assert_eq!(
+			summary
+				.iter()
+				.filter(|(event, _)| event == "start:text")
+				.count(),
+			1
+		);
+	}
+
+	#[test]
+	fn finish_flushes_thinking_parser_before_closing() {
+		let mut state = StreamState::new(true);
+		translate_chunk(
+			&make_chunk("c1", "gpt-4", vec![text_delta("Short")]),
+			&mut state,
+		);
+		let mut finish = make_chunk("c1", "gpt-4", vec![finish_choice("stop")]);
+		finish.usage = Some(usage(1, 1));
+
+		assert_eq!(
+			summarise(&translate_chunk(&finish, &mut state)),
+			vec![
+				s("start:text", Some(0)),
+				s("text:Short", Some(0)),
+				s("stop", Some(0)),
+				s("message_delta", None),
+				s("message_stop", None),
+			]
+		);
+		assert!(finish_stream(&mut state).is_empty());
+	}
 }
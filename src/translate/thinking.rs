@@ -23,6 +23,7 @@ pub fn parse_thinking_blocks(text: &str) -> Vec<AssistantContentBlock> {
 		if !prefix.trim().is_empty() {
 			blocks.push(AssistantContentBlock::Text(TextBlock {
 				text: prefix.to_string(),
+				cache_control: None,
 			}));
 		}
 
@@ -40,6 +41,7 @@ pub fn parse_thinking_blocks(text: &str) -> Vec<AssistantContentBlock> {
 			// Unclosed thinking tag - treat the rest as text
 			blocks.push(AssistantContentBlock::Text(TextBlock {
 				text: remaining.to_string(),
+				cache_control: None,
 			}));
 			remaining = "";
 			break;
@@ -50,6 +52,7 @@ pub fn parse_thinking_blocks(text: &str) -> Vec<AssistantContentBlock> {
 	if !remaining.is_empty() {
 		blocks.push(AssistantContentBlock::Text(TextBlock {
 			text: remaining.to_string(),
+			cache_control: None,
 		}));
 	}
 
@@ -57,6 +60,7 @@ pub fn parse_thinking_blocks(text: &str) -> Vec<AssistantContentBlock> {
 	if !found_thinking {
 		return vec![AssistantContentBlock::Text(TextBlock {
 			text: text.to_string(),
+			cache_control: None,
 		})];
 	}
 
@@ -244,6 +248,22 @@ impl ThinkingStreamParser {
 		events
 	}
 
+	/// Emit the held-back reserve without ending the stream.
+	///
+	/// Used when another kind of block (e.g. a tool call) is about to start,
+	/// so a partial tag can no longer be completed by the next chunk.
+	pub fn flush(&mut self) -> Option<ThinkingEvent> {
+		if self.buffer.is_empty() {
+			return None;
+		}
+		let pending = std::mem::take(&mut self.buffer);
+		if self.in_thinking {
+			Some(ThinkingEvent::ThinkingDelta(pending))
+		} else {
+			Some(ThinkingEvent::TextDelta(pending))
+		}
+	}
+
 	/// Flush any remaining buffered content.
 	///
 	/// Call this when the stream is complete to emit any final text.
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::copilot::types::Usage;
 use crate::translate::thinking::ThinkingStreamParser;
 
 // --- Messages Request ---
@@ -29,6 +30,10 @@ pub struct MessagesRequest {
 	pub tool_choice: Option<AnthropicToolChoice>,
 	#[serde(default)]
 	pub thinking: Option<ThinkingConfig>,
+	/// The code execution container to reuse. Copilot has no code execution,
+	/// so this is only kept to be logged.
+	#[serde(default)]
+	pub container: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -61,19 +66,49 @@ pub enum AnthropicMessage {
 }
 
 #[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
+#[serde(from = "ContentRepr<UserContentBlock>")]
 pub enum UserContent {
 	Text(String),
 	Blocks(Vec<UserContentBlock>),
 }
 
 #[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
+#[serde(from = "ContentRepr<AssistantContentBlock>")]
 pub enum AssistantContent {
 	Text(String),
 	Blocks(Vec<AssistantContentBlock>),
 }
 
+/// Message content as sent: some clients send a lone block without the
+/// array around it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ContentRepr<B> {
+	Text(String),
+	Blocks(Vec<B>),
+	Block(B),
+}
+
+impl From<ContentRepr<UserContentBlock>> for UserContent {
+	fn from(content: ContentRepr<UserContentBlock>) -> Self {
+		match content {
+			ContentRepr::Text(text) => Self::Text(text),
+			ContentRepr::Blocks(blocks) => Self::Blocks(blocks),
+			ContentRepr::Block(block) => Self::Blocks(vec![block]),
+		}
+	}
+}
+
+impl From<ContentRepr<AssistantContentBlock>> for AssistantContent {
+	fn from(content: ContentRepr<AssistantContentBlock>) -> Self {
+		match content {
+			ContentRepr::Text(text) => Self::Text(text),
+			ContentRepr::Blocks(blocks) => Self::Blocks(blocks),
+			ContentRepr::Block(block) => Self::Blocks(vec![block]),
+		}
+	}
+}
+
 // --- Content Blocks ---
 
 #[derive(Debug, Clone, Deserialize)]
@@ -83,8 +118,24 @@ pub enum UserContentBlock {
 	Text(TextBlock),
 	#[serde(rename = "image")]
 	Image(ImageBlock),
+	#[serde(rename = "document")]
+	Document(DocumentBlock),
 	#[serde(rename = "tool_result")]
 	ToolResult(ToolResultBlock),
+	/// Server-side tool blocks (code execution, web search, MCP, ...) with no
+	/// chat completions equivalent, dropped in translation.
+	#[serde(
+		rename = "server_tool_use",
+		alias = "code_execution_tool_result",
+		alias = "bash_code_execution_tool_result",
+		alias = "text_editor_code_execution_tool_result",
+		alias = "web_search_tool_result",
+		alias = "web_fetch_tool_result",
+		alias = "container_upload",
+		alias = "mcp_tool_use",
+		alias = "mcp_tool_result"
+	)]
+	ServerTool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -96,22 +147,80 @@ pub enum AssistantContentBlock {
 	ToolUse(ToolUseBlock),
 	#[serde(rename = "thinking")]
 	Thinking(ThinkingBlock),
+	/// Only in request history: responses never carry images.
+	#[serde(rename = "image", skip_serializing)]
+	Image(ImageBlock),
+	/// Server-side tool blocks (code execution, web search, MCP, ...) with no
+	/// chat completions equivalent, dropped in translation.
+	#[serde(
+		skip_serializing,
+		rename = "server_tool_use",
+		alias = "code_execution_tool_result",
+		alias = "bash_code_execution_tool_result",
+		alias = "text_editor_code_execution_tool_result",
+		alias = "web_search_tool_result",
+		alias = "web_fetch_tool_result",
+		alias = "container_upload",
+		alias = "mcp_tool_use",
+		alias = "mcp_tool_result"
+	)]
+	ServerTool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextBlock {
 	pub text: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub cache_control: Option<CacheControl>,
+}
+
+/// Prompt caching breakpoint marker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheControl {
+	pub r#type: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub ttl: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ImageBlock {
 	pub source: ImageSource,
+	#[serde(default)]
+	pub cache_control: Option<CacheControl>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DocumentBlock {
+	pub source: DocumentSource,
+	#[serde(default)]
+	pub title: Option<String>,
+	#[serde(default)]
+	pub cache_control: Option<CacheControl>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DocumentSource {
+	/// `base64` (a PDF), `text`, or `url`.
+	pub r#type: String,
+	#[serde(default)]
+	pub media_type: Option<String>,
+	/// Base64 PDF data, or the text itself for `text` sources.
+	#[serde(default)]
+	pub data: String,
+	#[serde(default)]
+	pub url: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ImageSource {
-	pub media_type: String,
+	#[serde(default)]
+	pub media_type: Option<String>,
+	/// Base64 image data, for `base64` sources.
+	#[serde(default)]
 	pub data: String,
+	/// Where the image is, for `url` sources.
+	#[serde(default)]
+	pub url: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -132,6 +241,8 @@ pub enum ToolResultContentBlock {
 pub struct ToolResultBlock {
 	pub tool_use_id: String,
 	pub content: ToolResultContent,
+	#[serde(default)]
+	pub cache_control: Option<CacheControl>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -139,6 +250,8 @@ pub struct ToolUseBlock {
 	pub id: String,
 	pub name: String,
 	pub input: serde_json::Value,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub cache_control: Option<CacheControl>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -154,6 +267,11 @@ pub struct AnthropicTool {
 	#[serde(default)]
 	pub description: Option<String>,
 	pub input_schema: serde_json::Value,
+	/// Whether calls must match `input_schema` exactly.
+	#[serde(default)]
+	pub strict: Option<bool>,
+	#[serde(default)]
+	pub cache_control: Option<CacheControl>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -161,6 +279,8 @@ pub struct AnthropicToolChoice {
 	pub r#type: String,
 	#[serde(default)]
 	pub name: Option<String>,
+	#[serde(default)]
+	pub disable_parallel_tool_use: Option<bool>,
 }
 
 // --- Non-streaming Response ---
@@ -175,6 +295,9 @@ pub struct MessagesResponse {
 	pub stop_reason: Option<StopReason>,
 	pub stop_sequence: Option<String>,
 	pub usage: AnthropicUsage,
+	/// Non-standard: upstream's `system_fingerprint`, to detect backend changes.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub system_fingerprint: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize)]
@@ -183,6 +306,20 @@ pub enum StopReason {
 	EndTurn,
 	MaxTokens,
 	ToolUse,
+	/// Upstream's content filter cut the response off.
+	Refusal,
+	/// A long agentic turn paused for the client to continue it.
+	PauseTurn,
+}
+
+impl StopReason {
+	/// With `USE_PAUSE_TURN`, report tool use as a paused turn.
+	pub fn pausing(self, pause_turn: bool) -> Self {
+		match self {
+			Self::ToolUse if pause_turn => Self::PauseTurn,
+			other => other,
+		}
+	}
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -230,7 +367,6 @@ pub enum StreamEvent {
 	Ping {},
 
 	#[serde(rename = "error")]
-	#[expect(dead_code, reason = "SSE error event, reserved for future use")]
 	Error { error: StreamError },
 }
 
@@ -253,12 +389,15 @@ impl StreamEvent {
 pub struct MessageStartBody {
 	pub id: String,
 	pub r#type: &'static str,
-	pub role: &'static str,
+	pub role: String,
 	pub content: Vec<()>,
 	pub model: String,
 	pub stop_reason: Option<StopReason>,
 	pub stop_sequence: Option<String>,
 	pub usage: AnthropicUsage,
+	/// Non-standard: upstream's `system_fingerprint`, to detect backend changes.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub system_fingerprint: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -305,28 +444,65 @@ pub struct StreamError {
 
 pub struct StreamState {
 	pub message_start_sent: bool,
+	/// Id and model of the first chunk, for a `message_start` sent at the end
+	/// if no chunk ever warranted one.
+	pub first_chunk: Option<(String, String)>,
+	/// Index of the open block, or of the next one to open.
 	pub content_block_index: u32,
 	pub content_block_open: bool,
+	/// How many blocks have been started, to check indices are never reused.
+	pub blocks_started: u32,
 	pub tool_calls: HashMap<u32, ToolCallState>,
 	pub thinking_parser: Option<ThinkingStreamParser>,
+	/// Reported in `message_start`, and at the end if upstream never sends usage.
+	pub estimated_input_tokens: u64,
+	/// Role from the first delta that carried one.
+	pub role: Option<String>,
+	/// Latest usage seen from upstream.
+	pub usage: Option<Usage>,
+	/// Finish seen but not yet emitted, because usage hasn't arrived yet.
+	pub pending_stop: Option<StopReason>,
+	/// Upstream sent a `finish_reason`.
+	pub finish_seen: bool,
+	/// Model name reported to the client in place of the upstream one.
+	pub display_model: Option<String>,
+	/// Original names of tools that were renamed upstream, by upstream name.
+	pub tool_names: HashMap<String, String>,
+	/// Report tool use as `pause_turn`.
+	pub pause_turn: bool,
+	/// Discard thinking rather than stream it.
+	pub drop_thinking: bool,
 }
 
 pub struct ToolCallState {
 	pub anthropic_block_index: u32,
+	/// Arguments streamed so far, to close them if the stream is cut off.
+	pub arguments: String,
 }
 
 impl StreamState {
 	pub fn new(emulate_thinking: bool) -> Self {
 		Self {
 			message_start_sent: false,
+			first_chunk: None,
 			content_block_index: 0,
 			content_block_open: false,
+			blocks_started: 0,
 			tool_calls: HashMap::new(),
 			thinking_parser: if emulate_thinking {
 				Some(ThinkingStreamParser::new())
 			} else {
 				None
 			},
+			estimated_input_tokens: 0,
+			role: None,
+			usage: None,
+			pending_stop: None,
+			finish_seen: false,
+			display_model: None,
+			tool_names: HashMap::new(),
+			pause_turn: false,
+			drop_thinking: false,
 		}
 	}
 
@@ -0,0 +1,207 @@
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::copilot::client::fetch_models;
+use crate::copilot::types::ModelsResponse;
+use crate::routes::messages::cache_models;
+use crate::state::AppState;
+
+/// Spawn a loop that keeps the default Copilot token and the models list
+/// cached with `WARMUP`, so a request after a quiet spell doesn't wait on
+/// either. Only runs if a default token is configured.
+pub fn spawn_warmup_loop(state: Arc<AppState>) {
+	let Some(interval) = state.warmup_interval else {
+		return;
+	};
+	let Some(gh_token) = state.default_github_token.clone() else {
+		warn!("WARMUP is set but GH_TOKEN isn't, so there's nothing to warm up");
+		return;
+	};
+
+	tokio::spawn(async move {
+		let state = &*state;
+		loop {
+			let warmed = warm_up_with(
+				state,
+				interval,
+				|| {
+					state.token_cache.get_copilot_token(
+						&gh_token,
+						&state.client,
+						&state.vscode_version,
+					)
+				},
+				|token: String| async move {
+					fetch_models(
+						&state.client,
+						&token,
+						&state.account_type(),
+						&state.vscode_version,
+						&state.copilot_integration_id,
+						state.models_timeout,
+					)
+					.await
+				},
+			)
+			.await;
+			if let Err(e) = warmed {
+				warn!(error = %e, retry_in = ?interval, "warmup failed");
+			}
+			tokio::time::sleep(interval).await;
+		}
+	});
+}
+
+/// Make sure the token is cached, then refetch the models list if it would
+/// expire before the next run.
+async fn warm_up_with<T, TFut, TErr, M, MFut>(
+	state: &AppState,
+	interval: Duration,
+	token: T,
+	fetch: M,
+) -> anyhow::Result<()>
+where
+	T: FnOnce() -> TFut,
+	TFut: Future<Output = Result<String, TErr>>,
+	TErr: Display,
+	M: FnOnce(String) -> MFut,
+	MFut: Future<Output = anyhow::Result<ModelsResponse>>,
+{
+	let token = token()
+		.await
+		.map_err(|e| anyhow::anyhow!("token exchange failed: {e}"))?;
+
+	if fresh_until_next_warmup(state, interval).await {
+		debug!("models cache fresh until the next warmup");
+		return Ok(());
+	}
+
+	// A request may have fetched the models while this waited for the lock
+	let _fetching = state.models_fetch.lock().await;
+	if fresh_until_next_warmup(state, interval).await {
+		debug!("models fetched by a concurrent request, skipping warmup");
+		return Ok(());
+	}
+
+	cache_models(state, fetch(token).await?).await;
+	info!("warmed up models cache");
+	Ok(())
+}
+
+async fn fresh_until_next_warmup(state: &AppState, interval: Duration) -> bool {
+	state
+		.models_cache_age()
+		.await
+		.is_some_and(|age| age + interval < state.models_cache_ttl)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+	use std::time::{SystemTime, UNIX_EPOCH};
+
+	use super::*;
+	use crate::copilot::types::CopilotTokenResponse;
+	use crate::rename::ModelRenamer;
+
+	const INTERVAL: Duration = Duration::from_secs(300);
+
+	async fn warm_up(state: &AppState, exchanges: &AtomicU32, fetches: &AtomicU32) {
+		warm_up_with(
+			state,
+			INTERVAL,
+			|| {
+				state
+					.token_cache
+					.get_copilot_token_with("ghu_default", || async {
+						let n = exchanges.fetch_add(1, Ordering::SeqCst) + 1;
+						Ok(CopilotTokenResponse {
+							token: format!("copilot-{n}"),
+							refresh_in: 1500,
+							expires_at: SystemTime::now()
+								.duration_since(UNIX_EPOCH)
+								.unwrap()
+								.as_secs() + 1800,
+						})
+					})
+			},
+			|token: String| async move {
+				assert_eq!(token, "copilot-1");
+				fetches.fetch_add(1, Ordering::SeqCst);
+				Ok(serde_json::from_value(serde_json::json!({
+					"object": "list",
+					"data": [{"id": "claude-sonnet-4", "name": "Claude Sonnet 4"}]
+				}))?)
+			},
+		)
+		.await
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn warmup_fills_both_caches() {
+		let state = AppState::new(
+			Some("ghu_default".to_string()),
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		let (exchanges, fetches) = (AtomicU32::new(0), AtomicU32::new(0));
+
+		warm_up(&state, &exchanges, &fetches).await;
+		assert_eq!(exchanges.load(Ordering::SeqCst), 1);
+		assert_eq!(fetches.load(Ordering::SeqCst), 1);
+		assert!(state.models_cache_age().await.is_some());
+		assert_eq!(
+			state.resolve_model("claude-sonnet-4").await,
+			"claude-sonnet-4"
+		);
+		let cached = state
+			.token_cache
+			.get_copilot_token_with("ghu_default", || async { panic!("token should be cached") })
+			.await
+			.unwrap();
+		assert_eq!(cached, "copilot-1");
+
+		// Both still fresh
+		warm_up(&state, &exchanges, &fetches).await;
+		assert_eq!(exchanges.load(Ordering::SeqCst), 1);
+		assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+		// Models that would go stale before the next run are refetched
+		state.models.write().await.as_mut().unwrap().cached_at =
+			SystemTime::now() - (state.models_cache_ttl - INTERVAL / 2);
+		warm_up(&state, &exchanges, &fetches).await;
+		assert_eq!(exchanges.load(Ordering::SeqCst), 1);
+		assert_eq!(fetches.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn warmup_skips_fetch_made_while_waiting() {
+		let state = AppState::new(
+			Some("ghu_default".to_string()),
+			"individual".to_string(),
+			"1.100.0".to_string(),
+			ModelRenamer::from_env(),
+		)
+		.unwrap();
+		let (exchanges, fetches) = (AtomicU32::new(0), AtomicU32::new(0));
+
+		// A request fetches the models while warmup waits for the lock
+		let fetching = state.models_fetch.lock().await;
+		tokio::join!(warm_up(&state, &exchanges, &fetches), async {
+			state
+				.store_models(
+					serde_json::from_value(serde_json::json!({"object": "list", "data": []}))
+						.unwrap(),
+				)
+				.await;
+			drop(fetching);
+		});
+		assert_eq!(fetches.load(Ordering::SeqCst), 0);
+	}
+}